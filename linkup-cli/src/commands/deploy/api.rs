@@ -1,5 +1,7 @@
+use std::future::Future;
+
 use reqwest::{multipart, Client};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 
 use super::{
@@ -41,11 +43,12 @@ pub trait CloudflareApi {
         zone_id: String,
         comment: String,
     ) -> Result<Option<DNSRecord>, DeployError>;
+    /// Returns the ID of the new record.
     async fn create_dns_record(
         &self,
         zone_id: String,
         record: DNSRecord,
-    ) -> Result<(), DeployError>;
+    ) -> Result<String, DeployError>;
     async fn remove_dns_record(
         &self,
         zone_id: String,
@@ -60,12 +63,13 @@ pub trait CloudflareApi {
         pattern: String,
         script_name: String,
     ) -> Result<Option<String>, DeployError>;
+    /// Returns the ID of the new route.
     async fn create_worker_route(
         &self,
         zone_id: String,
         pattern: String,
         script_name: String,
-    ) -> Result<(), DeployError>;
+    ) -> Result<String, DeployError>;
     async fn remove_worker_route(
         &self,
         zone_id: String,
@@ -105,6 +109,8 @@ pub trait CloudflareApi {
 #[derive(Deserialize, Debug)]
 struct CloudflareWorkerScript {
     id: String,
+    #[serde(default)]
+    migration_tag: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -164,22 +170,31 @@ struct CfBinding {
     // Possibly other fields like namespace_id, secret text, etc.
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct CloudflareErrorInfo {
-    code: Option<u32>,
-    message: String,
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CloudflareErrorInfo {
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl std::fmt::Display for CloudflareErrorInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} (code {})", self.message, code),
+            None => write!(f, "{}", self.message),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
-struct KvNamespace {
-    id: String,
-    title: String,
+struct CloudflareErrorResponse {
+    #[serde(default)]
+    errors: Vec<CloudflareErrorInfo>,
 }
 
 #[derive(Deserialize, Debug)]
-struct ListKvNamespacesResponse {
-    success: bool,
-    result: Option<Vec<KvNamespace>>,
+struct KvNamespace {
+    id: String,
+    title: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -199,15 +214,10 @@ struct DnsRecordResult {
     comment: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct ListDnsRecordsResponse {
-    success: bool,
-    result: Option<Vec<DnsRecordResult>>,
-}
-
 #[derive(Deserialize, Debug)]
 struct CreateDnsRecordResponse {
     success: bool,
+    result: Option<CreatedResource>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -218,14 +228,14 @@ struct WorkerRoute {
 }
 
 #[derive(Deserialize, Debug)]
-struct ListWorkerRoutesResponse {
+struct CreateWorkerRouteResponse {
     success: bool,
-    result: Option<Vec<WorkerRoute>>,
+    result: Option<CreatedResource>,
 }
 
 #[derive(Deserialize, Debug)]
-struct CreateWorkerRouteResponse {
-    success: bool,
+struct CreatedResource {
+    id: String,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -250,12 +260,6 @@ struct ZoneInfo {
     name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct RulesetListResponse {
-    pub result: Option<Vec<ListRuleset>>,
-    pub success: bool,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListRuleset {
     pub id: String,
@@ -286,6 +290,84 @@ struct CloudflareApiResponse {
     success: bool,
 }
 
+/// Cloudflare's pagination details of list responses. Cursor-paginated endpoints, like
+/// rulesets, leave out the page numbers.
+#[derive(Deserialize, Debug)]
+struct ResultInfo {
+    page: Option<u32>,
+    per_page: Option<u32>,
+    total_pages: Option<u32>,
+    total_count: Option<u32>,
+}
+
+impl ResultInfo {
+    fn next_page(&self) -> Option<u32> {
+        let page = self.page?;
+        let total_pages = match (self.total_pages, self.total_count, self.per_page) {
+            (Some(total_pages), _, _) => total_pages,
+            (None, Some(total_count), Some(per_page)) if per_page > 0 => {
+                total_count.div_ceil(per_page)
+            }
+            _ => return None,
+        };
+
+        (page < total_pages).then_some(page + 1)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ListResponse<T> {
+    success: bool,
+    result: Option<Vec<T>>,
+    result_info: Option<ResultInfo>,
+}
+
+const LIST_PAGE_SIZE: u32 = 50;
+
+// Requests pages until the `result_info` of the last one says there are no more. Endpoints
+// that aren't paginated have no `result_info`, so they end after the first page.
+async fn collect_pages<T, F, Fut>(mut fetch_page: F) -> Result<Vec<T>, DeployError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<ListResponse<T>, DeployError>>,
+{
+    let mut items = Vec::new();
+    let mut page = 1;
+
+    loop {
+        let response = fetch_page(page).await?;
+        if !response.success {
+            return Err(DeployError::OtherError);
+        }
+
+        items.extend(response.result.unwrap_or_default());
+
+        match response.result_info.and_then(|info| info.next_page()) {
+            Some(next_page) => page = next_page,
+            None => return Ok(items),
+        }
+    }
+}
+
+async fn error_from_response(resp: reqwest::Response) -> DeployError {
+    let status = resp.status().to_string();
+    match resp.text().await {
+        Ok(text) => error_from_body(&status, &text),
+        Err(e) => e.into(),
+    }
+}
+
+// Cloudflare describes failures in an `errors` array, which lets callers match on the error
+// codes. Anything else is surfaced as is.
+fn error_from_body(status: &str, body: &str) -> DeployError {
+    match serde_json::from_str::<CloudflareErrorResponse>(body) {
+        Ok(response) if !response.errors.is_empty() => {
+            DeployError::CloudflareErrors(response.errors)
+        }
+        _ => DeployError::UnexpectedResponse(format!("{}: {}", status, body)),
+    }
+}
+
 pub struct AccountCloudflareApi {
     account_id: String,
     zone_ids: Vec<String>,
@@ -309,6 +391,27 @@ impl AccountCloudflareApi {
     }
 }
 
+impl AccountCloudflareApi {
+    async fn list_all<T: DeserializeOwned>(&self, url: &str) -> Result<Vec<T>, DeployError> {
+        collect_pages(|page| async move {
+            let resp = self
+                .client
+                .get(url)
+                .headers(self.api_auth.headers())
+                .query(&[("page", page), ("per_page", LIST_PAGE_SIZE)])
+                .send()
+                .await?;
+
+            if !resp.status().is_success() {
+                return Err(error_from_response(resp).await);
+            }
+
+            Ok(resp.json().await?)
+        })
+        .await
+    }
+}
+
 impl CloudflareApi for AccountCloudflareApi {
     fn zone_ids(&self) -> &Vec<String> {
         &self.zone_ids
@@ -331,12 +434,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: CloudflareListWorkersResponse = resp.json().await?;
@@ -348,7 +446,9 @@ impl CloudflareApi for AccountCloudflareApi {
         if let Some(scripts) = data.result {
             for script in scripts {
                 if script.id == script_name {
-                    return Ok(Some(WorkerScriptInfo {}));
+                    return Ok(Some(WorkerScriptInfo {
+                        migration_tag: script.migration_tag,
+                    }));
                 }
             }
         }
@@ -378,12 +478,7 @@ impl CloudflareApi for AccountCloudflareApi {
         }
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         // Parse JSON for the list of versions
@@ -416,12 +511,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp2.status().is_success() {
-            let status = resp2.status().to_string();
-            let text = resp2.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp2).await);
         }
 
         let version_data: CfSingleVersionResponse = resp2.json().await?;
@@ -459,33 +549,7 @@ impl CloudflareApi for AccountCloudflareApi {
             self.account_id, script_name
         );
 
-        // Prepare metadata JSON
-        let mut bindings_json: Vec<serde_json::Value> = metadata
-            .bindings
-            .iter()
-            .map(|b| {
-                json!({
-                    "type": b.type_,
-                    "name": b.name,
-                    "namespace_id": b.namespace_id,
-                })
-            })
-            .collect();
-
-        let tag_binding = json!({
-            "type": "plain_text",
-            "name": WORKER_VERSION_TAG,
-            "text": metadata.tag,
-        });
-
-        bindings_json.push(tag_binding);
-
-        let metadata_json = json!({
-            "main_module": metadata.main_module,
-            "compatibility_date": metadata.compatibility_date,
-            "bindings": bindings_json,
-        })
-        .to_string();
+        let metadata_json = worker_metadata_json(&metadata).to_string();
 
         // Create multipart form
         let mut form = multipart::Form::new();
@@ -511,12 +575,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         // Optionally, parse the response to confirm success
@@ -530,8 +589,9 @@ impl CloudflareApi for AccountCloudflareApi {
     }
 
     async fn remove_worker_script(&self, script_name: String) -> Result<(), DeployError> {
+        // Forced, as the script's Durable Objects would keep it from being removed otherwise
         let url = format!(
-            "https://api.cloudflare.com/client/v4/accounts/{}/workers/scripts/{}",
+            "https://api.cloudflare.com/client/v4/accounts/{}/workers/scripts/{}?force=true",
             self.account_id, script_name
         );
 
@@ -543,12 +603,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CloudflareApiResponse = resp.json().await?;
@@ -568,32 +623,11 @@ impl CloudflareApi for AccountCloudflareApi {
             self.account_id
         );
 
-        let resp = self
-            .client
-            .get(&url)
-            .headers(self.api_auth.headers())
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
-        }
+        let namespaces: Vec<KvNamespace> = self.list_all(&url).await?;
 
-        let data: ListKvNamespacesResponse = resp.json().await?;
-        if !data.success {
-            return Err(DeployError::OtherError);
-        }
-
-        if let Some(namespaces) = data.result {
-            for ns in namespaces {
-                if ns.title == namespace_name {
-                    return Ok(Some(ns.id));
-                }
+        for ns in namespaces {
+            if ns.title == namespace_name {
+                return Ok(Some(ns.id));
             }
         }
         Ok(None)
@@ -618,12 +652,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: CreateKvNamespaceResponse = resp.json().await?;
@@ -654,12 +683,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CloudflareApiResponse = resp.json().await?;
@@ -681,12 +705,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: CloudflareZoneResponse = resp.json().await?;
@@ -716,41 +735,20 @@ impl CloudflareApi for AccountCloudflareApi {
             zone_id
         );
 
-        let resp = self
-            .client
-            .get(&url)
-            .headers(self.api_auth.headers())
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
-        }
-
-        let data: ListDnsRecordsResponse = resp.json().await?;
-        if !data.success {
-            return Err(DeployError::OtherError);
-        }
-
-        if let Some(records) = data.result {
-            if let Some(r) = records
-                .into_iter()
-                .find(|r| r.comment == Some(comment.clone()))
-            {
-                return Ok(Some(DNSRecord {
-                    id: r.id,
-                    name: r.name,
-                    record_type: r.record_type,
-                    content: r.content,
-                    comment: comment.clone(),
-                    proxied: r.proxied.unwrap_or(false),
-                }));
-            }
+        let records: Vec<DnsRecordResult> = self.list_all(&url).await?;
+
+        if let Some(r) = records
+            .into_iter()
+            .find(|r| r.comment == Some(comment.clone()))
+        {
+            return Ok(Some(DNSRecord {
+                id: r.id,
+                name: r.name,
+                record_type: r.record_type,
+                content: r.content,
+                comment: comment.clone(),
+                proxied: r.proxied.unwrap_or(false),
+            }));
         }
 
         Ok(None)
@@ -760,7 +758,7 @@ impl CloudflareApi for AccountCloudflareApi {
         &self,
         zone_id: String,
         record: DNSRecord,
-    ) -> Result<(), DeployError> {
+    ) -> Result<String, DeployError> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
             zone_id
@@ -783,12 +781,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CreateDnsRecordResponse = resp.json().await?;
@@ -796,7 +789,12 @@ impl CloudflareApi for AccountCloudflareApi {
             return Err(DeployError::OtherError);
         }
 
-        Ok(())
+        match result_data.result {
+            Some(record) => Ok(record.id),
+            None => Err(DeployError::UnexpectedResponse(
+                "No DNS record ID returned".to_string(),
+            )),
+        }
     }
 
     async fn remove_dns_record(
@@ -817,12 +815,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CloudflareApiResponse = resp.json().await?;
@@ -847,12 +840,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: GetSubdomainResponse = resp.json().await?;
@@ -880,32 +868,11 @@ impl CloudflareApi for AccountCloudflareApi {
             zone_id
         );
 
-        let resp = self
-            .client
-            .get(&url)
-            .headers(self.api_auth.headers())
-            .send()
-            .await?;
+        let routes: Vec<WorkerRoute> = self.list_all(&url).await?;
 
-        if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
-        }
-
-        let data: ListWorkerRoutesResponse = resp.json().await?;
-        if !data.success {
-            return Err(DeployError::OtherError);
-        }
-
-        if let Some(routes) = data.result {
-            for route in routes {
-                if route.pattern == pattern && route.script.as_deref() == Some(&script_name) {
-                    return Ok(Some(route.id));
-                }
+        for route in routes {
+            if route.pattern == pattern && route.script.as_deref() == Some(&script_name) {
+                return Ok(Some(route.id));
             }
         }
 
@@ -917,7 +884,7 @@ impl CloudflareApi for AccountCloudflareApi {
         zone_id: String,
         pattern: String,
         script_name: String,
-    ) -> Result<(), DeployError> {
+    ) -> Result<String, DeployError> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/workers/routes",
             zone_id
@@ -937,12 +904,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CreateWorkerRouteResponse = resp.json().await?;
@@ -950,7 +912,12 @@ impl CloudflareApi for AccountCloudflareApi {
             return Err(DeployError::OtherError);
         }
 
-        Ok(())
+        match result_data.result {
+            Some(route) => Ok(route.id),
+            None => Err(DeployError::UnexpectedResponse(
+                "No worker route ID returned".to_string(),
+            )),
+        }
     }
 
     async fn remove_worker_route(
@@ -971,12 +938,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let result_data: CloudflareApiResponse = resp.json().await?;
@@ -998,38 +960,15 @@ impl CloudflareApi for AccountCloudflareApi {
             zone_id
         );
 
-        let resp = self
-            .client
-            .get(&url)
-            .headers(self.api_auth.headers())
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
-        }
-
-        let data: RulesetListResponse = resp.json().await?;
-        if !data.success {
-            return Err(DeployError::OtherError);
-        }
+        let rulesets: Vec<ListRuleset> = self.list_all(&url).await?;
 
-        if let Some(rulesets) = data.result {
-            for rs in rulesets {
-                if rs.phase == phase && rs.name == name {
-                    return Ok(Some(rs.id));
-                }
+        for rs in rulesets {
+            if rs.phase == phase && rs.name == name {
+                return Ok(Some(rs.id));
             }
-
-            Ok(None)
-        } else {
-            Ok(None)
         }
+
+        Ok(None)
     }
 
     async fn create_ruleset(
@@ -1059,12 +998,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: RulesetResponse = resp.json().await?;
@@ -1105,12 +1039,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: RulesetResponse = resp.json().await?;
@@ -1144,12 +1073,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: RulesetResponse = resp.json().await?;
@@ -1178,12 +1102,7 @@ impl CloudflareApi for AccountCloudflareApi {
             .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status().to_string();
-            let text = resp.text().await?;
-            return Err(DeployError::UnexpectedResponse(format!(
-                "{}: {}",
-                status, text
-            )));
+            return Err(error_from_response(resp).await);
         }
 
         let data: RulesetResponse = resp.json().await?;
@@ -1202,3 +1121,219 @@ impl CloudflareApi for AccountCloudflareApi {
         }
     }
 }
+
+/// The `metadata` part of a worker script upload.
+fn worker_metadata_json(metadata: &WorkerMetadata) -> serde_json::Value {
+    let mut bindings_json: Vec<serde_json::Value> = metadata
+        .bindings
+        .iter()
+        .map(|b| {
+            json!({
+                "type": b.type_,
+                "name": b.name,
+                "namespace_id": b.namespace_id,
+            })
+        })
+        .collect();
+
+    bindings_json.extend(metadata.durable_objects.iter().map(|b| {
+        json!({
+            "type": "durable_object_namespace",
+            "name": b.name,
+            "class_name": b.class_name,
+        })
+    }));
+
+    let tag_binding = json!({
+        "type": "plain_text",
+        "name": WORKER_VERSION_TAG,
+        "text": metadata.tag,
+    });
+
+    bindings_json.push(tag_binding);
+
+    let mut metadata_json = json!({
+        "main_module": metadata.main_module,
+        "compatibility_date": metadata.compatibility_date,
+        "compatibility_flags": metadata.compatibility_flags,
+        "bindings": bindings_json,
+    });
+    if let Some(migration) = &metadata.migration {
+        metadata_json["migrations"] = json!({
+            "new_tag": migration.new_tag,
+            "new_sqlite_classes": migration.new_sqlite_classes,
+        });
+    }
+
+    metadata_json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::deploy::resources::{WorkerDurableObjectBinding, WorkerMigration};
+
+    use super::*;
+
+    #[test]
+    fn test_error_from_body() {
+        let body = r#"{
+            "success": false,
+            "errors": [
+                { "code": 10000, "message": "Authentication error" },
+                { "message": "Something else" }
+            ],
+            "messages": [],
+            "result": null
+        }"#;
+
+        let error = error_from_body("403 Forbidden", body);
+        assert!(error.has_error_code(10000));
+        assert!(!error.has_error_code(10001));
+        match error {
+            DeployError::CloudflareErrors(errors) => {
+                assert_eq!(
+                    errors,
+                    vec![
+                        CloudflareErrorInfo {
+                            code: Some(10000),
+                            message: "Authentication error".to_string(),
+                        },
+                        CloudflareErrorInfo {
+                            code: None,
+                            message: "Something else".to_string(),
+                        },
+                    ]
+                );
+            }
+            other => panic!("expected Cloudflare errors, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_pages() {
+        let pages = [
+            r#"{
+                "success": true,
+                "result": [{ "id": "1", "title": "one" }, { "id": "2", "title": "two" }],
+                "result_info": { "page": 1, "per_page": 2, "total_pages": 2, "count": 2, "total_count": 3 }
+            }"#,
+            r#"{
+                "success": true,
+                "result": [{ "id": "3", "title": "three" }],
+                "result_info": { "page": 2, "per_page": 2, "total_pages": 2, "count": 1, "total_count": 3 }
+            }"#,
+        ];
+        let mut requested_pages = Vec::new();
+
+        let namespaces: Vec<KvNamespace> = collect_pages(|page| {
+            requested_pages.push(page);
+            let body = pages[page as usize - 1];
+            async move { Ok(serde_json::from_str(body).unwrap()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(requested_pages, vec![1, 2]);
+        let ids: Vec<&str> = namespaces.iter().map(|ns| ns.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_collect_pages_without_result_info() {
+        let mut requests = 0;
+
+        let namespaces: Vec<KvNamespace> = collect_pages(|_| {
+            requests += 1;
+            async {
+                Ok(serde_json::from_str(
+                    r#"{ "success": true, "result": [{ "id": "1", "title": "one" }] }"#,
+                )
+                .unwrap())
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(requests, 1);
+        assert_eq!(namespaces.len(), 1);
+    }
+
+    #[test]
+    fn test_result_info_from_total_count() {
+        let info: ResultInfo =
+            serde_json::from_str(r#"{ "page": 1, "per_page": 50, "total_count": 51 }"#).unwrap();
+        assert_eq!(info.next_page(), Some(2));
+
+        let info: ResultInfo =
+            serde_json::from_str(r#"{ "page": 2, "per_page": 50, "total_count": 51 }"#).unwrap();
+        assert_eq!(info.next_page(), None);
+    }
+
+    #[test]
+    fn test_cursor_paginated_result_info() {
+        let response: ListResponse<KvNamespace> = serde_json::from_str(
+            r#"{
+                "success": true,
+                "result": [{ "id": "1", "title": "one" }],
+                "result_info": { "cursor": "", "count": 1 }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.result_info.unwrap().next_page(), None);
+    }
+
+    #[test]
+    fn test_worker_metadata_json() {
+        let metadata = WorkerMetadata {
+            main_module: "shim.mjs".to_string(),
+            bindings: vec![],
+            durable_objects: vec![WorkerDurableObjectBinding {
+                name: "LINKUP_CACHE_STATS".to_string(),
+                class_name: "CacheStatsCounter".to_string(),
+            }],
+            migration: Some(WorkerMigration {
+                new_tag: "v1".to_string(),
+                new_sqlite_classes: vec!["CacheStatsCounter".to_string()],
+            }),
+            compatibility_date: "2024-12-18".to_string(),
+            compatibility_flags: vec!["nodejs_compat".to_string()],
+            tag: "abc123".to_string(),
+        };
+
+        let json = worker_metadata_json(&metadata);
+
+        assert_eq!(json["compatibility_flags"], json!(["nodejs_compat"]));
+        assert_eq!(json["compatibility_date"], "2024-12-18");
+        assert_eq!(
+            json["bindings"][0],
+            json!({
+                "type": "durable_object_namespace",
+                "name": "LINKUP_CACHE_STATS",
+                "class_name": "CacheStatsCounter",
+            })
+        );
+        assert_eq!(json["bindings"][1]["text"], "abc123");
+        assert_eq!(
+            json["migrations"],
+            json!({ "new_tag": "v1", "new_sqlite_classes": ["CacheStatsCounter"] })
+        );
+
+        let without_migration = WorkerMetadata {
+            migration: None,
+            ..metadata
+        };
+        assert!(worker_metadata_json(&without_migration)
+            .get("migrations")
+            .is_none());
+    }
+
+    #[test]
+    fn test_error_from_body_without_envelope() {
+        let error = error_from_body("502 Bad Gateway", "<html>bad gateway</html>");
+        assert!(matches!(
+            error,
+            DeployError::UnexpectedResponse(message) if message == "502 Bad Gateway: <html>bad gateway</html>"
+        ));
+    }
+}
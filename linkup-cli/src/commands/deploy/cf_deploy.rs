@@ -1,9 +1,12 @@
+use serde::Serialize;
+
 use crate::commands::deploy::auth::get_auth;
-use crate::commands::deploy::resources::cf_resources;
+use crate::commands::deploy::resources::{cf_resources, validate_worker_script_name};
 
-use super::api::{AccountCloudflareApi, CloudflareApi};
+use super::api::{AccountCloudflareApi, CloudflareApi, CloudflareErrorInfo};
+use super::cf_status::{deploy_status, DeployStatusArgs};
 use super::console_notify::ConsoleNotifier;
-use super::resources::TargetCfResources;
+use super::resources::{DeployResult, TargetCfResources};
 
 #[derive(thiserror::Error, Debug)]
 pub enum DeployError {
@@ -13,8 +16,37 @@ pub enum DeployError {
     CloudflareApiError(#[from] reqwest::Error),
     #[error("Unexpected Cloudflare API response: {0}")]
     UnexpectedResponse(String),
+    #[error("Cloudflare API returned errors: {}", format_errors(.0))]
+    CloudflareErrors(Vec<CloudflareErrorInfo>),
     #[error("Other failure")]
     OtherError,
+    #[error("Canceled by user")]
+    Canceled,
+    #[error("Deploy failed for accounts: {}", .0.join(", "))]
+    AccountsFailed(Vec<String>),
+    #[error("Invalid worker script name '{0}', it can only have up to 63 lowercase letters, digits and dashes, and can't start or end with a dash")]
+    InvalidWorkerScriptName(String),
+}
+
+impl DeployError {
+    /// Whether Cloudflare reported the error with the given code, e.g. 10000 for
+    /// authentication errors.
+    pub fn has_error_code(&self, code: u32) -> bool {
+        match self {
+            DeployError::CloudflareErrors(errors) => {
+                errors.iter().any(|error| error.code == Some(code))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn format_errors(errors: &[CloudflareErrorInfo]) -> String {
+    errors
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub trait DeployNotifier {
@@ -22,59 +54,238 @@ pub trait DeployNotifier {
     fn notify(&self, message: &str);
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// Print what the deploy changed in each account as JSON. Progress goes to stderr.
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDeployment {
+    pub account_id: String,
+    pub result: Option<DeployResult>,
+    pub error: Option<String>,
+    /// The changes to the account were not confirmed, so nothing was deployed to it.
+    pub skipped: bool,
+}
+
+/// A Cloudflare account to deploy to, with the zones that belong to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountZones {
+    pub account_id: String,
+    pub zone_ids: Vec<String>,
+}
+
+// Parses `ACCOUNT_ID:ZONE_ID,ZONE_ID`
+fn parse_account_zones(value: &str) -> Result<AccountZones, String> {
+    let (account_id, zone_ids) = value
+        .split_once(':')
+        .ok_or_else(|| "expected ACCOUNT_ID:ZONE_ID,ZONE_ID".to_string())?;
+    let zone_ids: Vec<String> = zone_ids.split(',').map(str::to_string).collect();
+    if account_id.is_empty() || zone_ids.iter().any(String::is_empty) {
+        return Err("expected ACCOUNT_ID:ZONE_ID,ZONE_ID".to_string());
+    }
+
+    Ok(AccountZones {
+        account_id: account_id.to_string(),
+        zone_ids,
+    })
+}
+
 #[derive(clap::Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct DeployArgs {
+    #[command(subcommand)]
+    command: Option<DeployCommand>,
+
     #[arg(
         short = 'a',
         long = "account-id",
         help = "Cloudflare account ID",
-        value_name = "ACCOUNT_ID"
+        value_name = "ACCOUNT_ID",
+        required_unless_present = "accounts",
+        conflicts_with = "accounts",
+        requires = "zone_ids"
     )]
-    account_id: String,
+    account_id: Option<String>,
 
     #[arg(
         short = 'z',
         long = "zone-ids",
-        help = "Cloudflare zone IDs",
+        help = "Cloudflare zone IDs of the account",
         value_name = "ZONE_IDS",
         num_args = 1..,
-        required = true
+        requires = "account_id"
     )]
     zone_ids: Vec<String>,
+
+    #[arg(
+        long = "account",
+        help = "Cloudflare account ID and the IDs of its zones. Repeat it to deploy to several accounts.",
+        value_name = "ACCOUNT_ID:ZONE_ID,ZONE_ID",
+        value_parser = parse_account_zones
+    )]
+    accounts: Vec<AccountZones>,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        alias = "non-interactive",
+        help = "Apply the changes without asking for confirmation. Implied when the CI env var is set."
+    )]
+    yes: bool,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    #[arg(
+        long = "compatibility-flag",
+        help = "Compatibility flag to run the worker with, e.g. nodejs_compat. Repeat it for several flags.",
+        value_name = "FLAG"
+    )]
+    compatibility_flags: Vec<String>,
+
+    #[arg(
+        long = "worker-script-name",
+        help = "Name of the worker script, defaults to linkup-worker. Pass the same name to destroy.",
+        value_name = "NAME"
+    )]
+    worker_script_name: Option<String>,
+}
+
+impl DeployArgs {
+    /// The accounts to deploy to, each with its own zones.
+    pub fn accounts(&self) -> Vec<AccountZones> {
+        match &self.account_id {
+            Some(account_id) => vec![AccountZones {
+                account_id: account_id.clone(),
+                zone_ids: self.zone_ids.clone(),
+            }],
+            None => self.accounts.clone(),
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum DeployCommand {
+    #[clap(about = "Show the linkup resources currently deployed, without changing anything")]
+    Status(DeployStatusArgs),
 }
 
 pub async fn deploy(args: &DeployArgs) -> Result<(), DeployError> {
-    // pub async fn deploy(account_id: &str, zone_ids: &[String]) -> Result<(), DeployError> {
-    println!("Deploying to Cloudflare...");
-    println!("Account ID: {}", args.account_id);
-    println!("Zone IDs: {:?}", args.zone_ids);
+    if let Some(DeployCommand::Status(status_args)) = &args.command {
+        return deploy_status(status_args).await;
+    }
+
+    let notifier = ConsoleNotifier::new()
+        .with_auto_confirm(args.yes)
+        .with_stderr(args.output == OutputFormat::Json);
+    notifier.notify("Deploying to Cloudflare...");
+
+    let mut accounts = Vec::new();
+    for account in args.accounts() {
+        let cloudflare_api =
+            AccountCloudflareApi::new(account.account_id.clone(), account.zone_ids, get_auth()?);
+
+        accounts.push((account.account_id, cloudflare_api));
+    }
+
+    let mut resources = cf_resources().with_compatibility_flags(args.compatibility_flags.clone());
+    if let Some(name) = &args.worker_script_name {
+        validate_worker_script_name(name)?;
+        resources = resources.with_worker_script_name(name);
+    }
 
-    let auth = get_auth()?;
-    let zone_ids_strings: Vec<String> = args.zone_ids.iter().map(|s| s.to_string()).collect();
+    let deployments = deploy_to_accounts(&resources, &accounts, &notifier).await;
+    if args.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&deployments).unwrap());
+    }
 
-    let cloudflare_api =
-        AccountCloudflareApi::new(args.account_id.to_string(), zone_ids_strings, auth);
-    let notifier = ConsoleNotifier::new();
+    check_deployments(&deployments)
+}
 
-    let resources = cf_resources();
+/// Deploy to each account in turn. A failing account is reported in the summary at the end and
+/// does not stop the deploys to the accounts after it.
+pub async fn deploy_to_accounts(
+    resources: &TargetCfResources,
+    accounts: &[(String, impl CloudflareApi)],
+    notifier: &impl DeployNotifier,
+) -> Vec<AccountDeployment> {
+    let mut summary = vec!["Summary:".to_string()];
+    let mut deployments = Vec::with_capacity(accounts.len());
+
+    for (account_id, api) in accounts {
+        notifier.notify(&format!("Account ID: {}", account_id));
+        notifier.notify(&format!("Zone IDs: {:?}", api.zone_ids()));
+
+        let deployment = match deploy_to_cloudflare(resources, api, notifier).await {
+            Ok(result) => {
+                summary.push(format!("  {}: ok", account_id));
+                AccountDeployment {
+                    account_id: account_id.clone(),
+                    result: Some(result),
+                    error: None,
+                    skipped: false,
+                }
+            }
+            Err(DeployError::Canceled) => {
+                summary.push(format!("  {}: skipped", account_id));
+                AccountDeployment {
+                    account_id: account_id.clone(),
+                    result: None,
+                    error: None,
+                    skipped: true,
+                }
+            }
+            Err(error) => {
+                notifier.notify(&format!("Deployment to {} failed: {}", account_id, error));
+                summary.push(format!("  {}: failed ({})", account_id, error));
+                AccountDeployment {
+                    account_id: account_id.clone(),
+                    result: None,
+                    error: Some(error.to_string()),
+                    skipped: false,
+                }
+            }
+        };
+        deployments.push(deployment);
+    }
 
-    deploy_to_cloudflare(&resources, &cloudflare_api, &notifier).await?;
+    if accounts.len() > 1 {
+        notifier.notify(&summary.join("\n"));
+    }
 
-    Ok(())
+    deployments
+}
+
+pub fn check_deployments(deployments: &[AccountDeployment]) -> Result<(), DeployError> {
+    let failed_accounts: Vec<String> = deployments
+        .iter()
+        .filter(|deployment| deployment.error.is_some())
+        .map(|deployment| deployment.account_id.clone())
+        .collect();
+
+    if failed_accounts.is_empty() {
+        Ok(())
+    } else {
+        Err(DeployError::AccountsFailed(failed_accounts))
+    }
 }
 
 pub async fn deploy_to_cloudflare(
     resources: &TargetCfResources,
     api: &impl CloudflareApi,
     notifier: &impl DeployNotifier,
-) -> Result<(), DeployError> {
+) -> Result<DeployResult, DeployError> {
     // 1) Check what needs to change
     let plan = resources.check_deploy_plan(api).await?;
 
     // 2) If nothing changed, we can just early-out
     if plan.is_empty() {
         notifier.notify("No changes needed. Cloudflare resources are already up to date.");
-        return Ok(());
+        return Ok(DeployResult::default());
     }
 
     // 3) Otherwise, show some summary to the user and ask for confirmation
@@ -84,15 +295,15 @@ pub async fn deploy_to_cloudflare(
 
     if !notifier.ask_confirmation() {
         notifier.notify("Deployment canceled by user.");
-        return Ok(());
+        return Err(DeployError::Canceled);
     }
 
     // 4) Execute the plan
     notifier.notify("Applying changes to Cloudflare...");
-    resources.execute_deploy_plan(api, &plan, notifier).await?;
+    let result = resources.execute_deploy_plan(api, &plan, notifier).await?;
     notifier.notify("Deployment complete.");
 
-    Ok(())
+    Ok(result)
 }
 
 #[cfg(test)]
@@ -102,9 +313,11 @@ mod tests {
     use crate::commands::deploy::{
         self,
         cf_destroy::destroy_from_cloudflare,
+        cf_status::{get_deploy_status, DeployStatus, DeployedResource, ZoneStatus},
         resources::{
             rules_equal, DNSRecord, Rule, TargectCfZoneResources, TargetCacheRules,
             TargetDNSRecord, TargetWorkerRoute, WorkerMetadata, WorkerScriptInfo, WorkerScriptPart,
+            ZoneResource,
         },
     };
 
@@ -122,10 +335,14 @@ export default {
         zone_ids: Vec<String>,
 
         pub existing_info: Option<WorkerScriptInfo>,
+        pub kv_namespace_id: RefCell<Option<String>>,
         pub create_called_with: RefCell<Option<(String, WorkerMetadata, Vec<WorkerScriptPart>)>>,
 
         pub dns_records: RefCell<Vec<DNSRecord>>,
         pub worker_routes: RefCell<Vec<(String, String, String)>>,
+        pub worker_script_version: Option<String>,
+        pub ruleset_id: Option<String>,
+        pub fail_script_upload: bool,
     }
 
     impl TestCloudflareApi {
@@ -133,9 +350,13 @@ export default {
             Self {
                 zone_ids,
                 existing_info: None,
+                kv_namespace_id: RefCell::new(Some("existing-namespace-id".to_string())),
                 create_called_with: RefCell::new(None),
                 dns_records: RefCell::new(vec![]),
                 worker_routes: RefCell::new(vec![]),
+                worker_script_version: None,
+                ruleset_id: None,
+                fail_script_upload: false,
             }
         }
     }
@@ -158,6 +379,10 @@ export default {
             metadata: WorkerMetadata,
             parts: Vec<WorkerScriptPart>,
         ) -> Result<(), DeployError> {
+            if self.fail_script_upload {
+                return Err(DeployError::OtherError);
+            }
+
             *self.create_called_with.borrow_mut() = Some((script_name, metadata, parts));
             Ok(())
         }
@@ -170,10 +395,11 @@ export default {
             &self,
             _namespace_name: String,
         ) -> Result<Option<String>, DeployError> {
-            Ok(Some("existing-namespace-id".to_string()))
+            Ok(self.kv_namespace_id.borrow().clone())
         }
 
         async fn create_kv_namespace(&self, _namespace_id: String) -> Result<String, DeployError> {
+            *self.kv_namespace_id.borrow_mut() = Some("new-namespace-id".to_string());
             Ok("new-namespace-id".to_string())
         }
 
@@ -199,9 +425,10 @@ export default {
             &self,
             _zone_id: String,
             record: DNSRecord,
-        ) -> Result<(), DeployError> {
+        ) -> Result<String, DeployError> {
+            let id = format!("dns-id-for-{}", record.name);
             self.dns_records.borrow_mut().push(record);
-            Ok(())
+            Ok(id)
         }
 
         async fn remove_dns_record(
@@ -239,11 +466,12 @@ export default {
             zone_id: String,
             pattern: String,
             script_name: String,
-        ) -> Result<(), DeployError> {
+        ) -> Result<String, DeployError> {
+            let id = format!("route-id-for-{}", pattern);
             self.worker_routes
                 .borrow_mut()
                 .push((zone_id, pattern, script_name));
-            Ok(())
+            Ok(id)
         }
 
         async fn remove_worker_route(
@@ -267,7 +495,7 @@ export default {
             _name: String,
             _phase: String,
         ) -> Result<Option<String>, DeployError> {
-            Ok(None)
+            Ok(self.ruleset_id.clone())
         }
 
         async fn create_ruleset(
@@ -308,7 +536,7 @@ export default {
             &self,
             _script_name: String,
         ) -> Result<Option<String>, DeployError> {
-            Ok(None)
+            Ok(self.worker_script_version.clone())
         }
     }
 
@@ -333,6 +561,7 @@ export default {
         TargetCfResources {
             worker_script_name: "linkup-integration-test-script".to_string(),
             worker_script_entry: "index.js".to_string(),
+            worker_compatibility_flags: Vec::new(),
             worker_script_parts: vec![WorkerScriptPart {
                 name: "index.js".to_string(),
                 data: LOCAL_SCRIPT_CONTENT.as_bytes().to_vec(),
@@ -367,6 +596,7 @@ export default {
     #[tokio::test]
     async fn test_deploy_to_cloudflare_creates_script_when_none_exists() {
         let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+        *api.kv_namespace_id.borrow_mut() = None;
 
         let notifier = TestNotifier {
             messages: RefCell::new(vec![]),
@@ -375,8 +605,15 @@ export default {
         };
 
         // Call deploy_to_cloudflare directly
-        let result = deploy_to_cloudflare(&test_resources(), &api, &notifier).await;
-        assert!(result.is_ok());
+        let result = deploy_to_cloudflare(&test_resources(), &api, &notifier)
+            .await
+            .unwrap();
+
+        assert_eq!(result.kv_namespace_id, Some("new-namespace-id".to_string()));
+        assert_eq!(
+            result.worker_script_version,
+            Some(test_resources().worker_version_hash())
+        );
 
         // Check that a worker script was created
         let created = api.create_called_with.borrow();
@@ -395,6 +632,252 @@ export default {
         );
     }
 
+    #[tokio::test]
+    async fn test_deploy_to_cloudflare_uploads_compatibility_flags() {
+        let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let res = test_resources().with_compatibility_flags(vec!["nodejs_compat".to_string()]);
+        assert_ne!(
+            res.worker_version_hash(),
+            test_resources().worker_version_hash()
+        );
+
+        deploy_to_cloudflare(&res, &api, &notifier).await.unwrap();
+
+        let created = api.create_called_with.borrow();
+        let (_, metadata, _) = created.as_ref().unwrap();
+        assert_eq!(
+            metadata.compatibility_flags,
+            vec!["nodejs_compat".to_string()]
+        );
+        assert_eq!(metadata.tag, res.worker_version_hash());
+    }
+
+    #[tokio::test]
+    async fn test_deploy_to_cloudflare_creates_durable_objects_once() {
+        let mut api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        // A new script creates the Durable Object classes it binds
+        deploy_to_cloudflare(&test_resources(), &api, &notifier)
+            .await
+            .unwrap();
+        {
+            let created = api.create_called_with.borrow();
+            let (_, metadata, _) = created.as_ref().unwrap();
+            assert_eq!(metadata.durable_objects.len(), 1);
+            assert_eq!(metadata.durable_objects[0].name, "LINKUP_CACHE_STATS");
+            let migration = metadata.migration.as_ref().unwrap();
+            assert_eq!(migration.new_sqlite_classes, vec!["CacheStatsCounter"]);
+        }
+
+        // A script deployed before the Durable Objects is uploaded again to create them
+        *api.create_called_with.borrow_mut() = None;
+        api.worker_script_version = Some(test_resources().worker_version_hash());
+        api.existing_info = Some(WorkerScriptInfo {
+            migration_tag: None,
+        });
+        deploy_to_cloudflare(&test_resources(), &api, &notifier)
+            .await
+            .unwrap();
+        assert!(api.create_called_with.borrow().is_some());
+
+        // And is left alone once it has them
+        *api.create_called_with.borrow_mut() = None;
+        api.existing_info = Some(WorkerScriptInfo {
+            migration_tag: Some("v1".to_string()),
+        });
+        deploy_to_cloudflare(&test_resources(), &api, &notifier)
+            .await
+            .unwrap();
+        assert!(api.create_called_with.borrow().is_none());
+    }
+
+    #[test]
+    fn test_worker_version_hash_separates_compatibility_flags() {
+        let flags = |flags: &[&str]| {
+            test_resources()
+                .with_compatibility_flags(flags.iter().map(|flag| flag.to_string()).collect())
+                .worker_version_hash()
+        };
+
+        assert_ne!(flags(&["ab", "c"]), flags(&["a", "bc"]));
+        assert_ne!(flags(&["abc"]), flags(&["ab", "c"]));
+        assert_eq!(flags(&[]), test_resources().worker_version_hash());
+    }
+
+    #[tokio::test]
+    async fn test_deploy_to_cloudflare_with_worker_script_name() {
+        let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let res = test_resources().with_worker_script_name("custom-linkup");
+        deploy_to_cloudflare(&res, &api, &notifier).await.unwrap();
+
+        let created = api.create_called_with.borrow();
+        let (script_name, _, _) = created.as_ref().unwrap();
+        assert_eq!(script_name, "custom-linkup");
+
+        let routes = api.worker_routes.borrow();
+        assert!(!routes.is_empty());
+        assert!(routes
+            .iter()
+            .all(|(_, _, script)| script == "custom-linkup"));
+
+        let dns_records = api.dns_records.borrow();
+        assert!(!dns_records.is_empty());
+        assert!(dns_records
+            .iter()
+            .all(|record| record.comment.starts_with("custom-linkup-")));
+    }
+
+    #[tokio::test]
+    async fn test_deploy_status_reports_existing_resources() {
+        let mut api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+        api.worker_script_version = Some("deployed-version".to_string());
+        api.ruleset_id = Some("existing-ruleset-id".to_string());
+        api.dns_records.borrow_mut().push(DNSRecord {
+            id: "existing-dns-id".to_string(),
+            name: "linkup-integration-test-script-linkup-integration-test".to_string(),
+            record_type: "CNAME".to_string(),
+            content: "example.workers.dev".to_string(),
+            comment: "linkup-integration-test-script-linkup-integration-test".to_string(),
+            proxied: true,
+        });
+
+        let status = get_deploy_status(&test_resources(), &api).await.unwrap();
+
+        assert_eq!(
+            status,
+            DeployStatus {
+                worker_script_name: "linkup-integration-test-script".to_string(),
+                worker_script_version: Some("deployed-version".to_string()),
+                kv_namespace: DeployedResource {
+                    name: "linkup-integration-test-kv".to_string(),
+                    id: Some("existing-namespace-id".to_string()),
+                },
+                zones: vec![ZoneStatus {
+                    zone_id: "test-zone-id".to_string(),
+                    zone_name: "example.com".to_string(),
+                    dns_records: vec![DeployedResource {
+                        name: "linkup-integration-test-script-linkup-integration-test".to_string(),
+                        id: Some("existing-dns-id".to_string()),
+                    }],
+                    // The route was never created
+                    worker_routes: vec![DeployedResource {
+                        name: "linkup-integration-test.example.com/*".to_string(),
+                        id: None,
+                    }],
+                    cache_ruleset: DeployedResource {
+                        name: "linkup-integration-test-cache-rules".to_string(),
+                        id: Some("existing-ruleset-id".to_string()),
+                    },
+                }],
+            }
+        );
+
+        let text = status.to_string();
+        assert!(text.contains("Worker script linkup-integration-test-script: deployed-version"));
+        assert!(text.contains("  Worker route linkup-integration-test.example.com/*: missing"));
+    }
+
+    #[test]
+    fn test_validate_worker_script_name() {
+        assert!(validate_worker_script_name("linkup-worker").is_ok());
+        assert!(validate_worker_script_name(&"a".repeat(63)).is_ok());
+
+        for name in [
+            "",
+            "Linkup",
+            "linkup_worker",
+            "-linkup",
+            "linkup-",
+            &"a".repeat(64),
+        ] {
+            assert!(
+                matches!(
+                    validate_worker_script_name(name),
+                    Err(DeployError::InvalidWorkerScriptName(n)) if n == name
+                ),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deploy_to_cloudflare_returns_executed_actions() {
+        let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let res = test_resources();
+        let result = deploy_to_cloudflare(&res, &api, &notifier).await.unwrap();
+
+        assert_eq!(result.kv_namespace_id, None);
+        assert_eq!(
+            result.worker_script_version,
+            Some(res.worker_version_hash())
+        );
+        assert_eq!(
+            result.worker_routes,
+            vec![ZoneResource {
+                zone_id: "test-zone-id".to_string(),
+                name: "linkup-integration-test.example.com/*".to_string(),
+                id: "route-id-for-linkup-integration-test.example.com/*".to_string(),
+            }]
+        );
+        assert_eq!(
+            result.rulesets,
+            vec![ZoneResource {
+                zone_id: "test-zone-id".to_string(),
+                name: "linkup-integration-test-cache-rules".to_string(),
+                id: "new-ruleset-id".to_string(),
+            }]
+        );
+
+        let dns_names: Vec<String> = api
+            .dns_records
+            .borrow()
+            .iter()
+            .map(|record| record.name.clone())
+            .collect();
+        assert_eq!(
+            result
+                .dns_records
+                .iter()
+                .map(|record| record.name.clone())
+                .collect::<Vec<_>>(),
+            dns_names
+        );
+        assert!(result
+            .dns_records
+            .iter()
+            .all(|record| record.id == format!("dns-id-for-{}", record.name)));
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["rulesets"][0]["id"], "new-ruleset-id");
+    }
+
     #[tokio::test]
     async fn test_deploy_to_cloudflare_creates_script_when_content_differs() {
         let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
@@ -437,7 +920,7 @@ export default {
         };
 
         let result = deploy_to_cloudflare(&test_resources(), &api, &notifier).await;
-        assert!(result.is_ok());
+        assert!(matches!(result, Err(DeployError::Canceled)));
 
         assert_eq!(*notifier.confirmations_asked.borrow(), 1);
 
@@ -458,12 +941,15 @@ export default {
         let res = test_resources();
 
         // Call deploy_to_cloudflare directly
-        let result = deploy_to_cloudflare(&res, &api, &notifier).await;
-        assert!(result.is_ok());
+        let result = deploy_to_cloudflare(&res, &api, &notifier).await.unwrap();
 
         // Check DNS record created
         let dns_records = api.dns_records.borrow();
         assert_eq!(dns_records.len(), 1);
+        assert_eq!(
+            result.dns_records[0].id,
+            "dns-id-for-linkup-integration-test"
+        );
         assert_eq!(dns_records[0].name, "linkup-integration-test");
         assert!(dns_records[0]
             .content
@@ -474,6 +960,168 @@ export default {
         assert_eq!(routes.len(), 1);
         assert_eq!(routes[0].1, "linkup-integration-test.example.com/*");
         assert_eq!(routes[0].2, "linkup-integration-test-script");
+        assert_eq!(
+            result.worker_routes[0].id,
+            "route-id-for-linkup-integration-test.example.com/*"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deploy_with_auto_confirm_does_not_prompt() {
+        let api = TestCloudflareApi::new(vec!["test-zone-id".to_string()]);
+        // Reading stdin here would block the test
+        let notifier = ConsoleNotifier::new().with_auto_confirm(true);
+
+        let result = deploy_to_cloudflare(&test_resources(), &api, &notifier).await;
+        assert!(result.is_ok());
+
+        assert!(api.create_called_with.borrow().is_some());
+        assert_eq!(api.dns_records.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deploy_to_multiple_accounts() {
+        let accounts = vec![
+            (
+                "account-1".to_string(),
+                TestCloudflareApi::new(vec!["zone-1".to_string()]),
+            ),
+            (
+                "account-2".to_string(),
+                TestCloudflareApi::new(vec!["zone-2".to_string()]),
+            ),
+        ];
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let deployments = deploy_to_accounts(&test_resources(), &accounts, &notifier).await;
+        assert!(check_deployments(&deployments).is_ok());
+
+        let json = serde_json::to_value(&deployments).unwrap();
+        assert_eq!(json[0]["account_id"], "account-1");
+        assert_eq!(json[1]["account_id"], "account-2");
+        assert!(json[1]["error"].is_null());
+
+        // Each account planned and applied its own changes
+        assert_eq!(*notifier.confirmations_asked.borrow(), 2);
+        for (_, api) in &accounts {
+            assert!(api.create_called_with.borrow().is_some());
+            assert_eq!(api.dns_records.borrow().len(), 1);
+        }
+
+        let messages = notifier.messages.borrow();
+        assert!(messages.contains(&"Zone IDs: [\"zone-1\"]".to_string()));
+        assert!(messages.contains(&"Zone IDs: [\"zone-2\"]".to_string()));
+        assert_eq!(
+            messages.last().unwrap(),
+            "Summary:\n  account-1: ok\n  account-2: ok"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deploy_failure_does_not_stop_other_accounts() {
+        let mut failing_api = TestCloudflareApi::new(vec!["zone-2".to_string()]);
+        failing_api.fail_script_upload = true;
+
+        let accounts = vec![
+            (
+                "account-1".to_string(),
+                TestCloudflareApi::new(vec!["zone-1".to_string()]),
+            ),
+            ("account-2".to_string(), failing_api),
+            (
+                "account-3".to_string(),
+                TestCloudflareApi::new(vec!["zone-3".to_string()]),
+            ),
+        ];
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: true,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let deployments = deploy_to_accounts(&test_resources(), &accounts, &notifier).await;
+
+        match check_deployments(&deployments) {
+            Err(DeployError::AccountsFailed(failed)) => assert_eq!(failed, vec!["account-2"]),
+            other => panic!("expected AccountsFailed, got {:?}", other),
+        }
+
+        let json = serde_json::to_value(&deployments).unwrap();
+        assert!(json[0]["error"].is_null());
+        assert_eq!(json[1]["error"], "Other failure");
+        assert!(json[1]["result"].is_null());
+        assert!(json[2]["error"].is_null());
+
+        // The accounts before and after the failing one were still deployed
+        assert!(accounts[0].1.create_called_with.borrow().is_some());
+        assert!(accounts[2].1.create_called_with.borrow().is_some());
+        assert_eq!(accounts[2].1.dns_records.borrow().len(), 1);
+
+        let messages = notifier.messages.borrow();
+        assert_eq!(
+            messages.last().unwrap(),
+            "Summary:\n  account-1: ok\n  account-2: failed (Other failure)\n  account-3: ok"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deploy_declined_accounts_are_skipped() {
+        let accounts = vec![
+            (
+                "account-1".to_string(),
+                TestCloudflareApi::new(vec!["zone-1".to_string()]),
+            ),
+            (
+                "account-2".to_string(),
+                TestCloudflareApi::new(vec!["zone-2".to_string()]),
+            ),
+        ];
+
+        let notifier = TestNotifier {
+            messages: RefCell::new(vec![]),
+            confirmation_response: false,
+            confirmations_asked: RefCell::new(0),
+        };
+
+        let deployments = deploy_to_accounts(&test_resources(), &accounts, &notifier).await;
+        assert!(check_deployments(&deployments).is_ok());
+
+        let json = serde_json::to_value(&deployments).unwrap();
+        assert_eq!(json[0]["skipped"], true);
+        assert!(json[0]["result"].is_null());
+        assert!(json[0]["error"].is_null());
+        assert_eq!(json[1]["skipped"], true);
+        for (_, api) in &accounts {
+            assert!(api.create_called_with.borrow().is_none());
+        }
+
+        let messages = notifier.messages.borrow();
+        assert_eq!(
+            messages.last().unwrap(),
+            "Summary:\n  account-1: skipped\n  account-2: skipped"
+        );
+    }
+
+    #[test]
+    fn test_parse_account_zones() {
+        assert_eq!(
+            parse_account_zones("account-1:zone-1,zone-2").unwrap(),
+            AccountZones {
+                account_id: "account-1".to_string(),
+                zone_ids: vec!["zone-1".to_string(), "zone-2".to_string()],
+            }
+        );
+
+        assert!(parse_account_zones("account-1").is_err());
+        assert!(parse_account_zones(":zone-1").is_err());
+        assert!(parse_account_zones("account-1:").is_err());
+        assert!(parse_account_zones("account-1:zone-1,").is_err());
     }
 
     #[tokio::test]
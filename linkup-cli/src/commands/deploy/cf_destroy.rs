@@ -1,6 +1,8 @@
 use crate::commands::deploy::{
-    api::AccountCloudflareApi, auth::get_auth, console_notify::ConsoleNotifier,
-    resources::cf_resources,
+    api::AccountCloudflareApi,
+    auth::get_auth,
+    console_notify::ConsoleNotifier,
+    resources::{cf_resources, validate_worker_script_name},
 };
 
 use super::{
@@ -26,21 +28,40 @@ pub struct DestroyArgs {
         required = true
     )]
     zone_ids: Vec<String>,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        alias = "non-interactive",
+        help = "Apply the changes without asking for confirmation. Implied when the CI env var is set."
+    )]
+    yes: bool,
+
+    #[arg(
+        long = "worker-script-name",
+        help = "Name the worker script was deployed with, defaults to linkup-worker.",
+        value_name = "NAME"
+    )]
+    worker_script_name: Option<String>,
 }
 
 pub async fn destroy(args: &DestroyArgs) -> Result<(), DeployError> {
-    println!("Destroying from Cloudflare...");
-    println!("Account ID: {}", args.account_id);
-    println!("Zone IDs: {:?}", args.zone_ids);
+    let notifier = ConsoleNotifier::new().with_auto_confirm(args.yes);
+    notifier.notify("Destroying from Cloudflare...");
+    notifier.notify(&format!("Account ID: {}", args.account_id));
+    notifier.notify(&format!("Zone IDs: {:?}", args.zone_ids));
 
     let auth = get_auth()?;
     let zone_ids_strings: Vec<String> = args.zone_ids.iter().map(|s| s.to_string()).collect();
 
     let cloudflare_api =
         AccountCloudflareApi::new(args.account_id.to_string(), zone_ids_strings, auth);
-    let notifier = ConsoleNotifier::new();
 
-    let resources = cf_resources();
+    let mut resources = cf_resources();
+    if let Some(name) = &args.worker_script_name {
+        validate_worker_script_name(name)?;
+        resources = resources.with_worker_script_name(name);
+    }
 
     destroy_from_cloudflare(&resources, &cloudflare_api, &notifier).await?;
 
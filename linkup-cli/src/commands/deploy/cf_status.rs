@@ -0,0 +1,201 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{
+    api::{AccountCloudflareApi, CloudflareApi},
+    auth::get_auth,
+    cf_deploy::OutputFormat,
+    resources::{cf_resources, validate_worker_script_name, TargetCfResources},
+    DeployError,
+};
+
+#[derive(clap::Args)]
+pub struct DeployStatusArgs {
+    #[arg(
+        short = 'a',
+        long = "account-id",
+        help = "Cloudflare account ID",
+        value_name = "ACCOUNT_ID"
+    )]
+    account_id: String,
+
+    #[arg(
+        short = 'z',
+        long = "zone-ids",
+        help = "Cloudflare zone IDs",
+        value_name = "ZONE_IDS",
+        num_args = 1..,
+        required = true
+    )]
+    zone_ids: Vec<String>,
+
+    #[arg(
+        long = "worker-script-name",
+        help = "Name the worker script was deployed with, defaults to linkup-worker.",
+        value_name = "NAME"
+    )]
+    worker_script_name: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+/// What of the linkup resources is currently deployed to an account.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DeployStatus {
+    pub worker_script_name: String,
+    /// Version tag of the uploaded worker script, `None` if it isn't deployed.
+    pub worker_script_version: Option<String>,
+    pub kv_namespace: DeployedResource,
+    pub zones: Vec<ZoneStatus>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ZoneStatus {
+    pub zone_id: String,
+    pub zone_name: String,
+    pub dns_records: Vec<DeployedResource>,
+    pub worker_routes: Vec<DeployedResource>,
+    pub cache_ruleset: DeployedResource,
+}
+
+/// A resource linkup deploys, with its Cloudflare ID if it exists.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DeployedResource {
+    pub name: String,
+    pub id: Option<String>,
+}
+
+pub async fn deploy_status(args: &DeployStatusArgs) -> Result<(), DeployError> {
+    let cloudflare_api =
+        AccountCloudflareApi::new(args.account_id.clone(), args.zone_ids.clone(), get_auth()?);
+
+    let mut resources = cf_resources();
+    if let Some(name) = &args.worker_script_name {
+        validate_worker_script_name(name)?;
+        resources = resources.with_worker_script_name(name);
+    }
+
+    let status = get_deploy_status(&resources, &cloudflare_api).await?;
+
+    match args.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&status).unwrap()),
+        OutputFormat::Text => print!("{}", status),
+    }
+
+    Ok(())
+}
+
+/// Look up each of `resources` without changing anything, the same way destroying finds them.
+pub async fn get_deploy_status(
+    resources: &TargetCfResources,
+    api: &impl CloudflareApi,
+) -> Result<DeployStatus, DeployError> {
+    let worker_script_version = api
+        .get_worker_script_version(resources.worker_script_name.clone())
+        .await?;
+
+    let kv_namespace = DeployedResource {
+        name: resources.kv_name.clone(),
+        id: api.get_kv_namespace_id(resources.kv_name.clone()).await?,
+    };
+
+    let mut zones = Vec::with_capacity(api.zone_ids().len());
+    for zone_id in api.zone_ids() {
+        let zone_name = api.get_zone_name(zone_id.clone()).await?;
+
+        let mut dns_records = Vec::new();
+        for dns_record in &resources.zone_resources.dns_records {
+            let record_tag = dns_record.comment();
+            let existing_dns = api
+                .get_dns_record(zone_id.clone(), record_tag.clone())
+                .await?;
+
+            dns_records.push(DeployedResource {
+                name: record_tag,
+                id: existing_dns.map(|record| record.id),
+            });
+        }
+
+        let mut worker_routes = Vec::new();
+        for route_config in &resources.zone_resources.routes {
+            let pattern = route_config.worker_route(zone_name.clone());
+            let route_id = api
+                .get_worker_route(
+                    zone_id.clone(),
+                    pattern.clone(),
+                    route_config.script.clone(),
+                )
+                .await?;
+
+            worker_routes.push(DeployedResource {
+                name: pattern,
+                id: route_id,
+            });
+        }
+
+        let cache_rules = &resources.zone_resources.cache_rules;
+        let cache_ruleset = DeployedResource {
+            name: cache_rules.name.clone(),
+            id: api
+                .get_ruleset(
+                    zone_id.clone(),
+                    cache_rules.name.clone(),
+                    cache_rules.phase.clone(),
+                )
+                .await?,
+        };
+
+        zones.push(ZoneStatus {
+            zone_id: zone_id.clone(),
+            zone_name,
+            dns_records,
+            worker_routes,
+            cache_ruleset,
+        });
+    }
+
+    Ok(DeployStatus {
+        worker_script_name: resources.worker_script_name.clone(),
+        worker_script_version,
+        kv_namespace,
+        zones,
+    })
+}
+
+impl fmt::Display for DeployedResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}",
+            self.name,
+            self.id.as_deref().unwrap_or("missing")
+        )
+    }
+}
+
+impl fmt::Display for DeployStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Worker script {}: {}",
+            self.worker_script_name,
+            self.worker_script_version.as_deref().unwrap_or("missing")
+        )?;
+        writeln!(f, "KV namespace {}", self.kv_namespace)?;
+
+        for zone in &self.zones {
+            writeln!(f, "Zone {} ({}):", zone.zone_name, zone.zone_id)?;
+            for dns_record in &zone.dns_records {
+                writeln!(f, "  DNS record {}", dns_record)?;
+            }
+            for worker_route in &zone.worker_routes {
+                writeln!(f, "  Worker route {}", worker_route)?;
+            }
+            writeln!(f, "  Cache ruleset {}", zone.cache_ruleset)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,20 +1,69 @@
-use std::io::{self, Write};
+use std::{
+    env,
+    io::{self, Write},
+};
 
 use super::cf_deploy::DeployNotifier;
 
-pub struct ConsoleNotifier;
+pub struct ConsoleNotifier {
+    quiet: bool,
+    auto_confirm: bool,
+    stderr: bool,
+}
 
 impl ConsoleNotifier {
     pub fn new() -> Self {
-        ConsoleNotifier
+        ConsoleNotifier {
+            quiet: crate::is_quiet(),
+            auto_confirm: is_ci(),
+            stderr: false,
+        }
+    }
+
+    /// Write notifications and prompts to stderr, leaving stdout for machine-readable output.
+    pub fn with_stderr(mut self, stderr: bool) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// Answer yes to every confirmation instead of prompting on stdin. This is always the case
+    /// when running on CI.
+    pub fn with_auto_confirm(mut self, auto_confirm: bool) -> Self {
+        self.auto_confirm = self.auto_confirm || auto_confirm;
+        self
+    }
+
+    fn write_notification(&self, out: &mut impl Write, message: &str) {
+        if self.quiet {
+            return;
+        }
+
+        writeln!(out, "{}", message).ok();
     }
 }
 
 impl DeployNotifier for ConsoleNotifier {
     fn ask_confirmation(&self) -> bool {
-        print!("Do you want to proceed? [y/N]: ");
-        // Flush stdout to ensure prompt is shown before reading input
-        io::stdout().flush().ok();
+        if self.auto_confirm {
+            self.notify("Proceeding without confirmation.");
+            return true;
+        }
+
+        // `--quiet` hides what would be confirmed, so don't ask for a blind yes
+        if self.quiet {
+            eprintln!("Confirmation needed, but --quiet hides what to confirm. Run with --yes to proceed.");
+            return false;
+        }
+
+        let prompt = "Do you want to proceed? [y/N]: ";
+        // Flush to ensure prompt is shown before reading input
+        if self.stderr {
+            eprint!("{}", prompt);
+            io::stderr().flush().ok();
+        } else {
+            print!("{}", prompt);
+            io::stdout().flush().ok();
+        }
 
         let mut input = String::new();
         io::stdin().read_line(&mut input).ok();
@@ -24,6 +73,74 @@ impl DeployNotifier for ConsoleNotifier {
     }
 
     fn notify(&self, message: &str) {
-        println!("{}", message);
+        if self.stderr {
+            self.write_notification(&mut io::stderr(), message);
+        } else {
+            self.write_notification(&mut io::stdout(), message);
+        }
+    }
+}
+
+// Most CI providers set `CI=true`.
+fn is_ci() -> bool {
+    env::var("CI").is_ok_and(|ci| !ci.is_empty() && ci != "false" && ci != "0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_notifier_writes_nothing() {
+        let notifier = ConsoleNotifier {
+            quiet: true,
+            auto_confirm: false,
+            stderr: false,
+        };
+        let mut out = Vec::new();
+
+        notifier.write_notification(&mut out, "Deploying worker script...");
+
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_notifier_writes_message() {
+        let notifier = ConsoleNotifier {
+            quiet: false,
+            auto_confirm: false,
+            stderr: false,
+        };
+        let mut out = Vec::new();
+
+        notifier.write_notification(&mut out, "Deploying worker script...");
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Deploying worker script...\n"
+        );
+    }
+
+    #[test]
+    fn test_auto_confirm_does_not_prompt() {
+        let notifier = ConsoleNotifier {
+            quiet: true,
+            auto_confirm: false,
+            stderr: false,
+        }
+        .with_auto_confirm(true);
+
+        assert!(notifier.ask_confirmation());
+    }
+
+    #[test]
+    fn test_quiet_notifier_refuses_to_prompt() {
+        let notifier = ConsoleNotifier {
+            quiet: true,
+            auto_confirm: false,
+            stderr: false,
+        };
+
+        assert!(!notifier.ask_confirmation());
     }
 }
@@ -2,8 +2,10 @@ mod api;
 mod auth;
 mod cf_deploy;
 mod cf_destroy;
+mod cf_status;
 mod console_notify;
 mod resources;
 
-pub use cf_deploy::{deploy, DeployArgs, DeployError};
+pub use cf_deploy::{deploy, DeployArgs, DeployError, DeployNotifier};
 pub use cf_destroy::{destroy, DestroyArgs};
+pub use console_notify::ConsoleNotifier;
@@ -6,6 +6,13 @@ use sha2::{Digest, Sha256};
 use super::{api::CloudflareApi, cf_deploy::DeployNotifier, DeployError};
 
 const LINKUP_SCRIPT_NAME: &str = "linkup-worker";
+// The Durable Object the worker counts cache stats in, see worker/src/cache_stats.rs
+const CACHE_STATS_BINDING: &str = "LINKUP_CACHE_STATS";
+const CACHE_STATS_CLASS: &str = "CacheStatsCounter";
+// Tag of the last Durable Object migration of the worker
+const WORKER_MIGRATION_TAG: &str = "v1";
+// Cloudflare's limit for worker script names
+const MAX_SCRIPT_NAME_LENGTH: usize = 63;
 // To build the worker script, run in the worker directory:
 // cargo install -q worker-build && worker-build --release
 const LINKUP_WORKER_SHIM: &[u8] = include_bytes!("../../../../worker/build/worker/shim.mjs");
@@ -17,6 +24,8 @@ pub struct TargetCfResources {
     pub worker_script_name: String,
     pub worker_script_parts: Vec<WorkerScriptPart>,
     pub worker_script_entry: String,
+    /// Cloudflare compatibility flags the worker runs with, e.g. `nodejs_compat`.
+    pub worker_compatibility_flags: Vec<String>,
     pub kv_name: String,
     pub zone_resources: TargectCfZoneResources,
 }
@@ -60,13 +69,20 @@ pub struct TargetCacheRules {
 }
 
 #[derive(Debug, Clone)]
-pub struct WorkerScriptInfo {}
+pub struct WorkerScriptInfo {
+    /// Tag of the last Durable Object migration applied to the script.
+    pub migration_tag: Option<String>,
+}
 
 #[derive(Debug, Clone)]
 pub struct WorkerMetadata {
     pub main_module: String,
     pub bindings: Vec<WorkerKVBinding>,
+    pub durable_objects: Vec<WorkerDurableObjectBinding>,
+    /// Durable Object classes to create with the upload, if the script doesn't have them yet.
+    pub migration: Option<WorkerMigration>,
     pub compatibility_date: String,
+    pub compatibility_flags: Vec<String>,
     pub tag: String,
 }
 
@@ -103,6 +119,18 @@ pub struct WorkerKVBinding {
     pub namespace_id: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct WorkerDurableObjectBinding {
+    pub name: String,
+    pub class_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerMigration {
+    pub new_tag: String,
+    pub new_sqlite_classes: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct DNSRecord {
     pub id: String,
@@ -179,6 +207,26 @@ pub struct RulesetPlan {
     pub rules: Vec<Rule>,
 }
 
+/// What executing a `DeployPlan` changed on Cloudflare.
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct DeployResult {
+    /// ID of the KV namespace, if it had to be created.
+    pub kv_namespace_id: Option<String>,
+    /// Version hash of the worker script, if it was uploaded.
+    pub worker_script_version: Option<String>,
+    pub dns_records: Vec<ZoneResource>,
+    pub worker_routes: Vec<ZoneResource>,
+    /// Cache rulesets that were created or had their rules replaced.
+    pub rulesets: Vec<ZoneResource>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ZoneResource {
+    pub zone_id: String,
+    pub name: String,
+    pub id: String,
+}
+
 #[derive(Debug, Default)]
 pub struct DestroyPlan {
     /// Name of worker script that should be removed (if any).
@@ -209,6 +257,25 @@ impl DestroyPlan {
 }
 
 impl TargetCfResources {
+    pub fn with_compatibility_flags(mut self, flags: Vec<String>) -> Self {
+        self.worker_compatibility_flags = flags;
+        self
+    }
+
+    /// Use `name` for the worker script, and for the DNS records and routes that point at it.
+    pub fn with_worker_script_name(mut self, name: impl Into<String>) -> Self {
+        self.worker_script_name = name.into();
+
+        for dns_record in &mut self.zone_resources.dns_records {
+            dns_record.script = self.worker_script_name.clone();
+        }
+        for route in &mut self.zone_resources.routes {
+            route.script = self.worker_script_name.clone();
+        }
+
+        self
+    }
+
     /// Collect all plan actions into a single DeployPlan.
     pub async fn check_deploy_plan(
         &self,
@@ -254,9 +321,22 @@ impl TargetCfResources {
         let last_version = api.get_worker_script_version(script_name.clone()).await?;
         let current_version = self.worker_version_hash();
 
+        // The Durable Object classes are created once, by the first upload that has them
+        let migration_tag = api
+            .get_worker_script_info(script_name.clone())
+            .await?
+            .and_then(|info| info.migration_tag);
+        let migration = match migration_tag.as_deref() {
+            Some(WORKER_MIGRATION_TAG) => None,
+            _ => Some(WorkerMigration {
+                new_tag: WORKER_MIGRATION_TAG.to_string(),
+                new_sqlite_classes: vec![CACHE_STATS_CLASS.to_string()],
+            }),
+        };
+
         // Decide if we need to upload:
         let needs_upload = if let Some(last_version) = last_version {
-            last_version != current_version
+            last_version != current_version || migration.is_some()
         } else {
             true
         };
@@ -270,7 +350,13 @@ impl TargetCfResources {
                     name: "LINKUP_SESSIONS".to_string(),
                     namespace_id: "<to-be-filled-on-deploy>".to_string(),
                 }],
+                durable_objects: vec![WorkerDurableObjectBinding {
+                    name: CACHE_STATS_BINDING.to_string(),
+                    class_name: CACHE_STATS_CLASS.to_string(),
+                }],
+                migration,
                 compatibility_date: "2024-12-18".to_string(),
+                compatibility_flags: self.worker_compatibility_flags.clone(),
                 tag: current_version,
             };
             Ok(Some(WorkerScriptPlan::Upload {
@@ -391,7 +477,9 @@ impl TargetCfResources {
         api: &impl CloudflareApi,
         plan: &DeployPlan,
         notifier: &impl DeployNotifier,
-    ) -> Result<(), DeployError> {
+    ) -> Result<DeployResult, DeployError> {
+        let mut result = DeployResult::default();
+
         // We may need the worker subdomain for DNS records, so fetch it once:
         let worker_subdomain = api.get_worker_subdomain().await?;
 
@@ -400,6 +488,7 @@ impl TargetCfResources {
             notifier.notify(&format!("Creating KV namespace: {}", namespace_name));
             let new_id = api.create_kv_namespace(namespace_name.clone()).await?;
             notifier.notify(&format!("KV namespace created with ID: {}", new_id));
+            result.kv_namespace_id = Some(new_id);
         }
 
         // 2) Reconcile Worker Script
@@ -428,9 +517,11 @@ impl TargetCfResources {
             }
 
             notifier.notify("Uploading worker script...");
+            let version = final_metadata.tag.clone();
             api.create_worker_script(script_name.clone(), final_metadata, parts.clone())
                 .await?;
             notifier.notify("Worker script uploaded successfully.");
+            result.worker_script_version = Some(version);
         }
 
         // 3) Reconcile DNS records
@@ -452,7 +543,13 @@ impl TargetCfResources {
                 "Creating DNS record '{}' in zone {} -> {}",
                 final_record.name, zone_id, final_record.content
             ));
-            api.create_dns_record(zone_id.clone(), final_record).await?;
+            let name = final_record.name.clone();
+            let id = api.create_dns_record(zone_id.clone(), final_record).await?;
+            result.dns_records.push(ZoneResource {
+                zone_id: zone_id.clone(),
+                name,
+                id,
+            });
         }
 
         // 4) Reconcile Worker Routes
@@ -466,8 +563,14 @@ impl TargetCfResources {
                 "Creating route '{}' in zone {} -> script '{}'",
                 pattern, zone_id, script_name
             ));
-            api.create_worker_route(zone_id.clone(), pattern.clone(), script_name.clone())
+            let id = api
+                .create_worker_route(zone_id.clone(), pattern.clone(), script_name.clone())
                 .await?;
+            result.worker_routes.push(ZoneResource {
+                zone_id: zone_id.clone(),
+                name: pattern.clone(),
+                id,
+            });
         }
 
         // 5) Reconcile Cache Rulesets
@@ -503,11 +606,16 @@ impl TargetCfResources {
                 "Updating cache ruleset '{}' with new rules in zone {}",
                 final_id, zone_id
             ));
-            api.update_ruleset_rules(zone_id.clone(), final_id, rules.clone())
+            api.update_ruleset_rules(zone_id.clone(), final_id.clone(), rules.clone())
                 .await?;
+            result.rulesets.push(ZoneResource {
+                zone_id: zone_id.clone(),
+                name: self.zone_resources.cache_rules.name.clone(),
+                id: final_id,
+            });
         }
 
-        Ok(())
+        Ok(result)
     }
 
     /// Gather all the resources that actually exist and need to be removed.
@@ -650,6 +758,13 @@ impl TargetCfResources {
             hasher.update(&part.data);
         }
 
+        // Changing the flags changes how the same script runs, so it needs a new upload too.
+        // Each flag is prefixed with its length, so that ["ab", "c"] and ["a", "bc"] differ.
+        for flag in &self.worker_compatibility_flags {
+            hasher.update((flag.len() as u64).to_le_bytes());
+            hasher.update(flag.as_bytes());
+        }
+
         // Finalize the hasher and convert to a hex string
         let hash_bytes = hasher.finalize();
         hex::encode(hash_bytes)
@@ -685,10 +800,29 @@ pub fn rules_equal(current: &[Rule], desired: &[Rule]) -> bool {
     true
 }
 
+/// Check `name` against Cloudflare's rules for worker script names: at most 63 lowercase letters,
+/// digits and dashes, not starting or ending with a dash.
+pub fn validate_worker_script_name(name: &str) -> Result<(), DeployError> {
+    let is_valid = !name.is_empty()
+        && name.len() <= MAX_SCRIPT_NAME_LENGTH
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(DeployError::InvalidWorkerScriptName(name.to_string()))
+    }
+}
+
 pub fn cf_resources() -> TargetCfResources {
     TargetCfResources {
         worker_script_name: LINKUP_SCRIPT_NAME.to_string(),
         worker_script_entry: "shim.mjs".to_string(),
+        worker_compatibility_flags: Vec::new(),
         worker_script_parts: vec![
             WorkerScriptPart {
                 name: "shim.mjs".to_string(),
@@ -2,13 +2,19 @@ use std::{
     env,
     fmt::Display,
     fs::{self},
+    io::stdout,
+    path::PathBuf,
 };
 
 use clap::crate_version;
 use colored::Colorize;
 use serde::Serialize;
 
-use crate::{linkup_dir_path, local_config::LocalState, services, CliError};
+use crate::{
+    linkup_dir_path,
+    local_config::{resolve_config_path, LocalState, ResolvedConfigPath},
+    services, CliError,
+};
 
 use super::local_dns;
 
@@ -17,10 +23,18 @@ pub struct Args {
     // Output status in JSON format
     #[arg(long)]
     json: bool,
+
+    // Write the report to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
 }
 
-pub fn health(args: &Args) -> Result<(), CliError> {
-    let health = Health::load()?;
+pub fn health(args: &Args, config_arg: &Option<String>) -> Result<(), CliError> {
+    if args.output.is_some() {
+        colored::control::set_override(false);
+    }
+
+    let health = Health::load(config_arg)?;
 
     let health = if args.json {
         serde_json::to_string_pretty(&health).unwrap()
@@ -28,9 +42,7 @@ pub fn health(args: &Args) -> Result<(), CliError> {
         format!("{}", health)
     };
 
-    println!("{}", health);
-
-    Ok(())
+    super::write_report(&health, args.output.as_deref(), &mut stdout())
 }
 
 #[derive(Debug, Serialize)]
@@ -151,13 +163,15 @@ impl BackgroudServices {
 #[derive(Debug, Serialize)]
 struct Linkup {
     version: String,
+    /// The config file commands like `linkup start` would load, if any.
+    config: Option<ResolvedConfigPath>,
     config_location: String,
     config_exists: bool,
     config_content: Vec<String>,
 }
 
 impl Linkup {
-    fn load() -> Result<Self, CliError> {
+    fn load(config_arg: &Option<String>) -> Result<Self, CliError> {
         let dir_path = linkup_dir_path();
         let files: Vec<String> = fs::read_dir(&dir_path)?
             .map(|f| f.unwrap().file_name().into_string().unwrap())
@@ -165,6 +179,7 @@ impl Linkup {
 
         Ok(Self {
             version: crate_version!().to_string(),
+            config: resolve_config_path(config_arg).ok(),
             config_location: dir_path.to_str().unwrap_or_default().to_string(),
             config_exists: dir_path.exists(),
             config_content: files,
@@ -196,7 +211,7 @@ struct Health {
 }
 
 impl Health {
-    pub fn load() -> Result<Self, CliError> {
+    pub fn load(config_arg: &Option<String>) -> Result<Self, CliError> {
         let session = match Session::load() {
             Ok(session) => Some(session),
             Err(CliError::NoState(_)) => None,
@@ -211,7 +226,7 @@ impl Health {
             session,
             environment_variables: EnvironmentVariables::load(),
             background_services: BackgroudServices::load(),
-            linkup: Linkup::load()?,
+            linkup: Linkup::load(config_arg)?,
             local_dns: LocalDNS::load()?,
         })
     }
@@ -301,6 +316,10 @@ impl Display for Health {
 
         writeln!(f, "{}", "Linkup:".bold().italic())?;
         writeln!(f, "  Version: {}", self.linkup.version)?;
+        match &self.linkup.config {
+            Some(config) => writeln!(f, "  Config file: {} (from {})", config.path, config.source)?,
+            None => writeln!(f, "  Config file: {}", "NONE".yellow())?,
+        }
         writeln!(
             f,
             "  Config folder location: {}",
@@ -43,6 +43,10 @@ pub async fn local(args: &Args) -> Result<(), CliError> {
     state.save()?;
     upload_state(&state).await?;
 
+    if crate::is_quiet() {
+        return Ok(());
+    }
+
     if args.all {
         println!("Linkup is routing all traffic to the local servers");
     } else {
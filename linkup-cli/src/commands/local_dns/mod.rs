@@ -0,0 +1,135 @@
+use std::io::{self, Write};
+
+use clap::Subcommand;
+use colored::Colorize;
+
+use crate::{
+    is_sudo,
+    local_config::{config_path, get_config},
+    services, sudo_su, CliError, Result, LINKUP_CF_TLS_API_ENV_VAR,
+};
+
+mod resolver;
+
+use resolver::{platform_resolver, Resolver};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub subcommand: LocalDNSSubcommand,
+}
+
+#[derive(Subcommand)]
+pub enum LocalDNSSubcommand {
+    Install,
+    Uninstall,
+}
+
+pub fn local_dns(args: &Args, config: &Option<String>) -> Result<()> {
+    match args.subcommand {
+        LocalDNSSubcommand::Install => install(config),
+        LocalDNSSubcommand::Uninstall => uninstall(config),
+    }
+}
+
+pub fn install(config_arg: &Option<String>) -> Result<()> {
+    let config_path = config_path(config_arg)?;
+    let input_config = get_config(&config_path)?;
+
+    install_domains(&input_config.top_level_domains(), &mut io::stdout())?;
+    install_caddy_packages(&mut io::stdout())
+}
+
+/// Point the resolvers of `domains` at the local DNS server. Runs on every
+/// `linkup start --local-dns`, so it only changes the resolvers that are out of date. Messages
+/// go to `out`.
+pub fn install_domains(domains: &[String], out: &mut dyn Write) -> Result<()> {
+    if std::env::var(LINKUP_CF_TLS_API_ENV_VAR).is_err() {
+        writeln!(
+            out,
+            "local-dns uses Cloudflare to enable https through local certificates."
+        )
+        .ok();
+        writeln!(
+            out,
+            "To use it, you need to set the {} environment variable.",
+            LINKUP_CF_TLS_API_ENV_VAR
+        )
+        .ok();
+        return Err(CliError::LocalDNSInstall(format!(
+            "{} env var is not set",
+            LINKUP_CF_TLS_API_ENV_VAR
+        )));
+    }
+
+    let resolver = platform_resolver();
+
+    if !is_sudo() {
+        writeln!(out, "Linkup needs sudo access to:").ok();
+        for step in resolver.install_steps() {
+            writeln!(out, "  - {}", step).ok();
+        }
+
+        sudo_su()?;
+    }
+
+    let diff = resolver.reconcile(domains)?;
+    if diff.is_empty() {
+        writeln!(out, "Resolvers are already up to date.").ok();
+    } else {
+        for domain in &diff.added {
+            writeln!(out, "{} {}", "+".green(), domain).ok();
+        }
+        for domain in &diff.removed {
+            writeln!(out, "{} {}", "-".red(), domain).ok();
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the Caddy packages local DNS needs. This is the slow part of the setup and only happens
+/// once, when Caddy doesn't have them yet.
+pub fn install_caddy_packages(out: &mut dyn Write) -> Result<()> {
+    if services::Caddy::has_extra_packages() {
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "Installing extra caddy packages, this could take a while..."
+    )
+    .ok();
+    services::Caddy::install_extra_packages().map_err(|e| CliError::LocalDNSInstall(e.to_string()))
+}
+
+pub fn uninstall(config_arg: &Option<String>) -> Result<()> {
+    let config_path = config_path(config_arg)?;
+    let input_config = get_config(&config_path)?;
+
+    uninstall_domains(&input_config.top_level_domains())
+}
+
+pub fn uninstall_domains(domains: &[String]) -> Result<()> {
+    let resolver = platform_resolver();
+
+    if !is_sudo() {
+        println!("Linkup needs sudo access to:");
+        for step in resolver.uninstall_steps() {
+            println!("  - {}", step);
+        }
+    }
+
+    resolver.uninstall(domains)?;
+
+    Ok(())
+}
+
+/// Local DNS can only set up resolvers on macOS and on Linux with systemd-resolved.
+pub fn is_supported() -> bool {
+    cfg!(any(target_os = "linux", target_os = "macos"))
+}
+
+pub fn list_resolvers() -> std::result::Result<Vec<String>, std::io::Error> {
+    platform_resolver().list()
+}
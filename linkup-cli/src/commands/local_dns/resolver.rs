@@ -0,0 +1,571 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::{CliError, Result, LINKUP_LOCALDNS_PORT};
+
+/// Platform specific configuration of the system DNS resolver, so that lookups for the linkup
+/// domains are sent to the local DNS server.
+pub trait Resolver {
+    /// Things that will be done with sudo on install, shown to the user before asking for it.
+    fn install_steps(&self) -> Vec<String>;
+
+    /// Things that will be done with sudo on uninstall, shown to the user before asking for it.
+    fn uninstall_steps(&self) -> Vec<String>;
+
+    fn install(&self, domains: &[String]) -> Result<()>;
+
+    fn uninstall(&self, domains: &[String]) -> Result<()>;
+
+    /// The domains that are currently routed to the local DNS server.
+    fn list(&self) -> std::result::Result<Vec<String>, std::io::Error>;
+
+    /// The subset of [`Resolver::list`] that was created by linkup and is safe to remove.
+    fn list_managed(&self) -> std::result::Result<Vec<String>, std::io::Error> {
+        self.list()
+    }
+
+    /// Makes the routed domains match `domains`: missing ones are installed and the ones
+    /// linkup created for domains that are no longer configured are removed.
+    fn reconcile(&self, domains: &[String]) -> Result<ResolverDiff> {
+        let diff = ResolverDiff::new(&self.list_managed()?, domains);
+
+        if !diff.removed.is_empty() {
+            self.uninstall(&diff.removed)?;
+        }
+
+        if !diff.added.is_empty() {
+            self.install(&diff.added)?;
+        }
+
+        Ok(diff)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ResolverDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ResolverDiff {
+    fn new(existing: &[String], desired: &[String]) -> Self {
+        let added = desired
+            .iter()
+            .filter(|domain| !existing.contains(domain))
+            .cloned()
+            .collect();
+
+        let removed = existing
+            .iter()
+            .filter(|domain| !desired.contains(domain))
+            .cloned()
+            .collect();
+
+        Self { added, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn platform_resolver() -> impl Resolver {
+    SystemdResolved::new()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn platform_resolver() -> impl Resolver {
+    MacosResolver::new()
+}
+
+/// Uses one file per domain under `/etc/resolver`, which is read by the macOS resolver.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub struct MacosResolver {
+    resolver_dir: PathBuf,
+}
+
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+impl MacosResolver {
+    pub fn new() -> Self {
+        Self {
+            resolver_dir: PathBuf::from("/etc/resolver"),
+        }
+    }
+
+    fn resolver_file_content(&self) -> String {
+        format!("nameserver 127.0.0.1\nport {}\n", LINKUP_LOCALDNS_PORT)
+    }
+}
+
+impl Resolver for MacosResolver {
+    fn install_steps(&self) -> Vec<String> {
+        vec![
+            format!("Ensure there is a folder {}", self.resolver_dir.display()),
+            format!(
+                "Create file(s) for {}/<domain>",
+                self.resolver_dir.display()
+            ),
+            "Flush DNS cache".to_string(),
+        ]
+    }
+
+    fn uninstall_steps(&self) -> Vec<String> {
+        vec![
+            format!("Delete file(s) on {}", self.resolver_dir.display()),
+            "Flush DNS cache".to_string(),
+        ]
+    }
+
+    fn install(&self, domains: &[String]) -> Result<()> {
+        sudo_create_dir(&self.resolver_dir)?;
+
+        for domain in domains {
+            sudo_write_file(
+                &self.resolver_dir.join(domain),
+                &self.resolver_file_content(),
+            )
+            .map_err(|err| {
+                CliError::LocalDNSInstall(format!(
+                    "Failed to install resolver for domain {} to {}/{}. Reason: {}",
+                    domain,
+                    self.resolver_dir.display(),
+                    domain,
+                    err
+                ))
+            })?;
+        }
+
+        flush_dns_cache()?;
+
+        #[cfg(target_os = "macos")]
+        kill_dns_responder()?;
+
+        Ok(())
+    }
+
+    fn uninstall(&self, domains: &[String]) -> Result<()> {
+        for domain in domains {
+            let file = self.resolver_dir.join(domain);
+            Command::new("sudo")
+                .arg("rm")
+                .arg("-rf")
+                .arg(&file)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_err(|err| {
+                    CliError::LocalDNSUninstall(format!(
+                        "Failed to delete {}. Reason: {}",
+                        file.display(),
+                        err
+                    ))
+                })?;
+        }
+
+        flush_dns_cache()?;
+
+        #[cfg(target_os = "macos")]
+        kill_dns_responder()?;
+
+        Ok(())
+    }
+
+    fn list(&self) -> std::result::Result<Vec<String>, std::io::Error> {
+        let resolvers_dir = match fs::read_dir(&self.resolver_dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::NotFound => return Ok(vec![]),
+                _ => return Err(err),
+            },
+        };
+
+        let resolvers = resolvers_dir
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+
+        Ok(resolvers)
+    }
+
+    fn list_managed(&self) -> std::result::Result<Vec<String>, std::io::Error> {
+        // Other tools can also put files in the resolver dir, only touch the ones pointing
+        // at the linkup local DNS server.
+        let expected_content = self.resolver_file_content();
+
+        let managed = self
+            .list()?
+            .into_iter()
+            .filter(|domain| {
+                fs::read_to_string(self.resolver_dir.join(domain))
+                    .map(|content| content.trim() == expected_content.trim())
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        Ok(managed)
+    }
+}
+
+/// Uses a dummy network link with per-link DNS in systemd-resolved. The link only has routing
+/// domains (`~<domain>`) and is never a default route, so only lookups for the linkup domains are
+/// sent to the local DNS server.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct SystemdResolved {
+    link: String,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+impl SystemdResolved {
+    pub fn new() -> Self {
+        Self {
+            link: "linkup0".to_string(),
+        }
+    }
+
+    /// The `resolvectl` calls that point the link at the local DNS server for `domains` only.
+    fn link_config_commands(&self, domains: &[String]) -> Vec<Vec<String>> {
+        let mut domain_args = vec!["domain".to_string(), self.link.clone()];
+        domain_args.extend(domains.iter().map(|domain| format!("~{}", domain)));
+
+        vec![
+            vec![
+                "dns".to_string(),
+                self.link.clone(),
+                format!("127.0.0.1:{}", LINKUP_LOCALDNS_PORT),
+            ],
+            domain_args,
+            vec![
+                "default-route".to_string(),
+                self.link.clone(),
+                "false".to_string(),
+            ],
+        ]
+    }
+
+    /// Parses the output of `resolvectl domain <link>`, e.g. `Link 5 (linkup0): ~example.com`.
+    fn parse_domains(output: &str) -> Vec<String> {
+        output
+            .lines()
+            .filter_map(|line| line.split_once("):").map(|(_, domains)| domains))
+            .flat_map(|domains| domains.split_whitespace())
+            .map(|domain| domain.trim_start_matches('~').to_string())
+            .filter(|domain| !domain.is_empty())
+            .collect()
+    }
+
+    fn link_exists(&self) -> bool {
+        Command::new("ip")
+            .args(["link", "show", &self.link])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    fn apply(&self, domains: &[String]) -> Result<()> {
+        if domains.is_empty() {
+            if self.link_exists() {
+                sudo_run(&["ip", "link", "delete", &self.link])?;
+            }
+        } else {
+            if !self.link_exists() {
+                sudo_run(&["ip", "link", "add", &self.link, "type", "dummy"])?;
+            }
+            sudo_run(&["ip", "link", "set", &self.link, "up"])?;
+
+            for args in self.link_config_commands(domains) {
+                let mut command = vec!["resolvectl"];
+                command.extend(args.iter().map(String::as_str));
+                sudo_run(&command)?;
+            }
+        }
+
+        flush_dns_cache()
+    }
+}
+
+impl Resolver for SystemdResolved {
+    fn install_steps(&self) -> Vec<String> {
+        vec![
+            format!("Create the network link {}", self.link),
+            format!(
+                "Route only the linkup domains on {} to the local DNS server",
+                self.link
+            ),
+            "Flush DNS cache".to_string(),
+        ]
+    }
+
+    fn uninstall_steps(&self) -> Vec<String> {
+        vec![
+            format!("Update or delete the network link {}", self.link),
+            "Flush DNS cache".to_string(),
+        ]
+    }
+
+    fn install(&self, domains: &[String]) -> Result<()> {
+        let mut all_domains = self.list()?;
+        for domain in domains {
+            if !all_domains.contains(domain) {
+                all_domains.push(domain.clone());
+            }
+        }
+
+        self.apply(&all_domains)
+            .map_err(|err| CliError::LocalDNSInstall(err.to_string()))
+    }
+
+    fn uninstall(&self, domains: &[String]) -> Result<()> {
+        let remaining: Vec<String> = self
+            .list()?
+            .into_iter()
+            .filter(|domain| !domains.contains(domain))
+            .collect();
+
+        self.apply(&remaining)
+            .map_err(|err| CliError::LocalDNSUninstall(err.to_string()))
+    }
+
+    fn list(&self) -> std::result::Result<Vec<String>, std::io::Error> {
+        if !self.link_exists() {
+            return Ok(vec![]);
+        }
+
+        let output = Command::new("resolvectl")
+            .args(["domain", &self.link])
+            .stderr(Stdio::null())
+            .output()?;
+
+        Ok(Self::parse_domains(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+}
+
+/// Runs `command` with sudo and fails if it doesn't exit successfully.
+fn sudo_run(command: &[&str]) -> Result<()> {
+    let status = Command::new("sudo")
+        .args(command)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    if !status.success() {
+        return Err(CliError::LocalDNSInstall(format!(
+            "Failed to run {}",
+            command.join(" ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn sudo_create_dir(path: &std::path::Path) -> Result<()> {
+    Command::new("sudo")
+        .arg("mkdir")
+        .arg("-p")
+        .arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|err| {
+            CliError::LocalDNSInstall(format!(
+                "failed to create {} folder. Reason: {}",
+                path.display(),
+                err
+            ))
+        })?;
+
+    Ok(())
+}
+
+fn sudo_write_file(path: &std::path::Path, content: &str) -> Result<()> {
+    let mut child = Command::new("sudo")
+        .arg("tee")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(content.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(CliError::WriteFile(format!(
+            "failed to write {}",
+            path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+fn flush_dns_cache() -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let status_flush = Command::new("resolvectl")
+        .args(["flush-caches"])
+        .status()
+        .map_err(|_err| {
+            CliError::LocalDNSInstall("Failed to run resolvectl flush-caches".into())
+        })?;
+
+    #[cfg(target_os = "macos")]
+    let status_flush = Command::new("dscacheutil")
+        .args(["-flushcache"])
+        .status()
+        .map_err(|_err| {
+            CliError::LocalDNSInstall("Failed to run dscacheutil -flushcache".into())
+        })?;
+
+    if !status_flush.success() {
+        return Err(CliError::LocalDNSInstall("Failed flush DNS cache".into()));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn kill_dns_responder() -> Result<()> {
+    let status_kill_responder = Command::new("sudo")
+        .args(["killall", "-HUP", "mDNSResponder"])
+        .status()
+        .map_err(|_err| {
+            CliError::LocalDNSInstall("Failed to run killall -HUP mDNSResponder".into())
+        })?;
+
+    if !status_kill_responder.success() {
+        return Err(CliError::LocalDNSInstall(
+            "Failed to run killall -HUP mDNSResponder".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_macos_resolver_file_content() {
+        let resolver = MacosResolver::new();
+
+        assert_eq!(
+            resolver.resolver_file_content(),
+            "nameserver 127.0.0.1\nport 8053\n"
+        );
+    }
+
+    #[test]
+    fn test_resolver_diff() {
+        let existing = vec!["example.com".to_string(), "old-example.com".to_string()];
+        let desired = vec!["example.com".to_string(), "new-example.com".to_string()];
+
+        let diff = ResolverDiff::new(&existing, &desired);
+
+        assert_eq!(
+            diff,
+            ResolverDiff {
+                added: vec!["new-example.com".to_string()],
+                removed: vec!["old-example.com".to_string()],
+            }
+        );
+        assert!(!diff.is_empty());
+        assert!(ResolverDiff::new(&desired, &desired).is_empty());
+    }
+
+    #[test]
+    fn test_macos_resolver_only_manages_linkup_files() {
+        let resolver_dir =
+            std::env::temp_dir().join(format!("linkup-resolver-test-{}", std::process::id()));
+        fs::create_dir_all(&resolver_dir).unwrap();
+
+        let resolver = MacosResolver {
+            resolver_dir: resolver_dir.clone(),
+        };
+        fs::write(
+            resolver_dir.join("old-example.com"),
+            resolver.resolver_file_content(),
+        )
+        .unwrap();
+        fs::write(
+            resolver_dir.join("example.com"),
+            resolver.resolver_file_content(),
+        )
+        .unwrap();
+        fs::write(resolver_dir.join("corp.internal"), "nameserver 10.0.0.1\n").unwrap();
+
+        let mut managed = resolver.list_managed().unwrap();
+        managed.sort();
+
+        let diff = ResolverDiff::new(&managed, &["example.com".to_string()]);
+
+        fs::remove_dir_all(&resolver_dir).unwrap();
+
+        assert_eq!(
+            managed,
+            vec!["example.com".to_string(), "old-example.com".to_string()]
+        );
+        assert_eq!(
+            diff,
+            ResolverDiff {
+                added: vec![],
+                removed: vec!["old-example.com".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_systemd_resolved_link_config() {
+        let resolver = SystemdResolved::new();
+        let domains = vec!["example.com".to_string(), "other-example.com".to_string()];
+
+        let commands: Vec<String> = resolver
+            .link_config_commands(&domains)
+            .iter()
+            .map(|args| args.join(" "))
+            .collect();
+
+        assert_eq!(
+            commands,
+            vec![
+                "dns linkup0 127.0.0.1:8053",
+                "domain linkup0 ~example.com ~other-example.com",
+                "default-route linkup0 false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_systemd_resolved_local_dns_is_never_a_default_route() {
+        let resolver = SystemdResolved::new();
+        let domains = vec!["example.com".to_string()];
+
+        for args in resolver.link_config_commands(&domains) {
+            // DNS is only ever set on the linkup link, never globally
+            assert_eq!(args[1], "linkup0");
+
+            match args[0].as_str() {
+                "domain" => assert!(args[2..].iter().all(|domain| domain.starts_with('~'))),
+                "default-route" => assert_eq!(args[2], "false"),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_systemd_resolved_parse_domains() {
+        assert_eq!(
+            SystemdResolved::parse_domains("Link 5 (linkup0): ~example.com ~other-example.com\n"),
+            vec!["example.com".to_string(), "other-example.com".to_string()]
+        );
+        assert!(SystemdResolved::parse_domains("Link 5 (linkup0):\n").is_empty());
+    }
+}
@@ -4,6 +4,7 @@ pub mod health;
 pub mod local;
 pub mod local_dns;
 pub mod preview;
+pub mod ps;
 pub mod remote;
 pub mod reset;
 pub mod server;
@@ -20,6 +21,7 @@ pub use {health::health, health::Args as HealthArgs};
 pub use {local::local, local::Args as LocalArgs};
 pub use {local_dns::local_dns, local_dns::Args as LocalDnsArgs};
 pub use {preview::preview, preview::Args as PreviewArgs};
+pub use {ps::ps, ps::Args as PsArgs};
 pub use {remote::remote, remote::Args as RemoteArgs};
 pub use {reset::reset, reset::Args as ResetArgs};
 pub use {server::server, server::Args as ServerArgs};
@@ -28,3 +30,60 @@ pub use {status::status, status::Args as StatusArgs};
 pub use {stop::stop, stop::Args as StopArgs};
 pub use {uninstall::uninstall, uninstall::Args as UninstallArgs};
 pub use {update::update, update::Args as UpdateArgs};
+
+use std::{fs, io::Write, path::Path};
+
+use crate::CliError;
+
+/// Write the report of a command like `health` or `status` to `output`, or to `stdout` when no
+/// file is given.
+fn write_report(
+    report: &str,
+    output: Option<&Path>,
+    stdout: &mut impl Write,
+) -> Result<(), CliError> {
+    match output {
+        Some(path) => fs::write(path, format!("{}\n", report)).map_err(|err| {
+            CliError::FileErr(
+                format!("Failed to write the report to {}", path.display()),
+                err.to_string(),
+            )
+        }),
+        None => Ok(writeln!(stdout, "{}", report)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn test_write_report_to_file() {
+        let path = env::temp_dir().join(format!("linkup-report-{}.json", process::id()));
+        let mut stdout = Vec::new();
+
+        write_report(r#"{"name": "tiny-cow"}"#, Some(&path), &mut stdout).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"name\": \"tiny-cow\"}\n"
+        );
+        assert!(stdout.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_report_to_stdout() {
+        let mut stdout = Vec::new();
+
+        write_report("Session Name: tiny-cow", None, &mut stdout).unwrap();
+
+        assert_eq!(
+            String::from_utf8(stdout).unwrap(),
+            "Session Name: tiny-cow\n"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::commands::status::{format_state_domains, SessionStatus};
+use crate::commands::update;
 use crate::local_config::{config_path, get_config};
 use crate::worker_client::WorkerClient;
 use crate::CliError;
@@ -33,6 +34,8 @@ pub async fn preview(args: &Args, config: &Option<String>) -> Result<(), CliErro
         return Ok(());
     }
 
+    update::ensure_supported_by_worker(&url).await?;
+
     let preview_name = WorkerClient::from(&input_config)
         .preview(&create_preview_request)
         .await
@@ -0,0 +1,92 @@
+use crate::{
+    services::{self, ServiceProcess},
+    CliError,
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    // Output processes in JSON format
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn ps(args: &Args) -> Result<(), CliError> {
+    let processes = services::find_service_processes();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&processes).unwrap());
+    } else {
+        print!("{}", format_processes(&processes));
+    }
+
+    Ok(())
+}
+
+fn format_processes(processes: &[ServiceProcess]) -> String {
+    if processes.is_empty() {
+        return "No linkup processes running\n".to_string();
+    }
+
+    let id_width = processes
+        .iter()
+        .map(|process| process.service_id.len())
+        .max()
+        .unwrap_or_default()
+        .max("SERVICE".len());
+
+    let mut output = format!(
+        "{:<id_width$}  {:>8}  {:>10}  COMMAND\n",
+        "SERVICE", "PID", "UPTIME"
+    );
+    for process in processes {
+        output.push_str(&format!(
+            "{:<id_width$}  {:>8}  {:>10}  {}\n",
+            process.service_id,
+            process.pid,
+            format_uptime(process.uptime),
+            process.command
+        ));
+    }
+
+    output
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m", hours, minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(125), "2m 5s");
+        assert_eq!(format_uptime(7380), "2h 3m");
+    }
+
+    #[test]
+    fn test_format_processes() {
+        let processes = vec![ServiceProcess {
+            service_id: "linkup-local-server".to_string(),
+            linkup_home: None,
+            pid: 4242,
+            command: "linkup server --port 9066".to_string(),
+            uptime: 61,
+        }];
+
+        assert_eq!(
+            format_processes(&processes),
+            "SERVICE                   PID      UPTIME  COMMAND\n\
+             linkup-local-server      4242       1m 1s  linkup server --port 9066\n"
+        );
+        assert_eq!(format_processes(&[]), "No linkup processes running\n");
+    }
+}
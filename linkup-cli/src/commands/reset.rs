@@ -4,10 +4,20 @@ use crate::{commands, local_config::LocalState, CliError};
 pub struct Args {}
 
 pub async fn reset(_args: &Args) -> Result<(), CliError> {
-    let _ = LocalState::load()?;
+    let state = LocalState::load()?;
 
     commands::stop(&commands::StopArgs {}, false)?;
-    commands::start(&commands::StartArgs { no_tunnel: false }, false, &None).await?;
+    let start_args = commands::StartArgs {
+        no_tunnel: false,
+        config_from_stdin: false,
+        expose: false,
+        yes: false,
+        // Stopping removed the local DNS that start set up, so set it up again
+        local_dns: state.linkup.local_dns == Some(true),
+        dns_only: false,
+        progress: commands::start::Progress::Text,
+    };
+    commands::start(&start_args, false, &None).await?;
 
     Ok(())
 }
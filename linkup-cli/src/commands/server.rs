@@ -1,4 +1,11 @@
-use std::fs;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+
+use linkup::SessionSource;
+use linkup_local_server::{ClientCertificate, ServerOptions, LINKUP_LOCALSERVER_PORT};
 
 use crate::CliError;
 
@@ -6,13 +13,79 @@ use crate::CliError;
 pub struct Args {
     #[arg(long)]
     pidfile: String,
+
+    /// Redirect requests for session domains to https instead of proxying them.
+    #[arg(long)]
+    redirect_to_https: bool,
+
+    /// Listen on all interfaces instead of only on loopback.
+    #[arg(long)]
+    expose: bool,
+
+    #[arg(long, default_value_t = LINKUP_LOCALSERVER_PORT)]
+    port: u16,
+
+    /// List the session names that were looked up when a request can't be matched to a session.
+    #[arg(long)]
+    debug_session_lookup: bool,
+
+    /// Show how a request was matched against the session's domains and routes when it has no
+    /// target service.
+    #[arg(long)]
+    debug_routing: bool,
+
+    /// Comma separated parts of a request the session may be taken from: url, forwarded_host,
+    /// referer, origin, tracestate and baggage. All of them by default.
+    #[arg(long, value_delimiter = ',')]
+    trusted_session_sources: Option<Vec<SessionSource>>,
+
+    /// Only take the session from the url and the tracestate header.
+    #[arg(long, conflicts_with = "trusted_session_sources")]
+    locked_down: bool,
+
+    /// PEM certificate presented to upstream services that require TLS client authentication.
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key of --client-cert.
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// PEM file with extra CA certificates to trust for upstream services.
+    #[arg(long)]
+    upstream_ca: Option<PathBuf>,
 }
 
 pub async fn server(args: &Args) -> Result<(), CliError> {
     let pid = std::process::id();
     fs::write(&args.pidfile, pid.to_string())?;
 
-    let res = linkup_local_server::start_server().await;
+    let mut options = ServerOptions {
+        redirect_to_https: args.redirect_to_https,
+        port: args.port,
+        debug_session_lookup: args.debug_session_lookup,
+        debug_routing: args.debug_routing,
+        upstream_ca_certificates: args.upstream_ca.clone(),
+        version: env!("CARGO_PKG_VERSION"),
+        ..Default::default()
+    };
+    if args.expose {
+        options.bind_address = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    }
+    if let Some(sources) = &args.trusted_session_sources {
+        options.trusted_session_sources = sources.clone();
+    }
+    if args.locked_down {
+        options.trusted_session_sources = SessionSource::LOCKED_DOWN.to_vec();
+    }
+    if let (Some(cert_path), Some(key_path)) = (&args.client_cert, &args.client_key) {
+        options.client_certificate = Some(ClientCertificate {
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+        });
+    }
+
+    let res = linkup_local_server::start_server(options).await;
 
     if let Err(pid_file_err) = fs::remove_file(&args.pidfile) {
         eprintln!("Failed to remove pidfile: {}", pid_file_err);
@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fs,
-    io::stdout,
+    io::{self, stdout, Write},
     path::{Path, PathBuf},
     sync,
     thread::{self, sleep, JoinHandle},
@@ -10,17 +10,45 @@ use std::{
 
 use colored::Colorize;
 use crossterm::{cursor, ExecutableCommand};
+use linkup::{ConfigWarning, Session};
 
 use crate::{
-    commands::status::{format_state_domains, SessionStatus},
+    commands::{
+        deploy::{ConsoleNotifier, DeployNotifier},
+        local_dns,
+        status::{format_state_domains, SessionStatus},
+        update::ensure_supported_by_worker,
+    },
     env_files::write_to_env_file,
-    local_config::{config_path, config_to_state, get_config},
-    services::{self, BackgroundService},
+    linkup_dir_path,
+    local_config::{config_path, config_to_state, get_config, ServerConfig, STDIN_CONFIG_PATH},
+    services::{self, BackgroundService, ServiceProcess},
+    signal,
 };
 use crate::{local_config::LocalState, CliError};
 
 const LOADING_CHARS: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Progress {
+    #[default]
+    Text,
+    /// Print every update of the background services as a line of JSON to stdout, e.g. for
+    /// editor integrations.
+    Json,
+}
+
+impl Progress {
+    /// Where messages for people go. With JSON progress stdout only carries the updates, so they
+    /// go to stderr.
+    fn messages(&self) -> Box<dyn Write> {
+        match self {
+            Progress::Text => Box::new(io::stdout()),
+            Progress::Json => Box::new(io::stderr()),
+        }
+    }
+}
+
 #[derive(clap::Args)]
 pub struct Args {
     #[clap(
@@ -29,6 +57,46 @@ pub struct Args {
         help = "Start linkup in partial mode without a tunnel. Not all requests will succeed."
     )]
     pub no_tunnel: bool,
+
+    #[clap(
+        long,
+        help = "Read the config from stdin instead of a file. Same as `--config -`."
+    )]
+    pub config_from_stdin: bool,
+
+    #[clap(
+        long,
+        help = "Make the local server reachable from other machines by listening on all interfaces."
+    )]
+    pub expose: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "Stop linkup processes left over from a previous session without asking."
+    )]
+    pub yes: bool,
+
+    #[clap(
+        long,
+        help = "Set up local DNS for the session's domains, like `linkup local-dns install`. `linkup stop` removes it again."
+    )]
+    pub local_dns: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "expose",
+        help = "Only start the local DNS server for the session's domains, without the local server, tunnel, Caddy or service commands. Combine with --local-dns to also set up the resolvers."
+    )]
+    pub dns_only: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Progress::Text,
+        help = "How to report the progress of starting the background services."
+    )]
+    pub progress: Progress,
 }
 
 pub async fn start<'a>(
@@ -36,11 +104,22 @@ pub async fn start<'a>(
     fresh_state: bool,
     config_arg: &Option<String>,
 ) -> Result<(), CliError> {
-    env_logger::init();
+    let config_arg = &if args.config_from_stdin {
+        Some(STDIN_CONFIG_PATH.to_string())
+    } else {
+        config_arg.clone()
+    };
+
+    let mut messages = args.progress.messages();
 
     let mut state = if fresh_state {
         let is_paid = services::CloudflareTunnel::use_paid_tunnels();
-        let state = load_and_save_state(config_arg, args.no_tunnel, is_paid)?;
+        let state = load_and_save_state(
+            config_arg,
+            args.no_tunnel || args.dns_only,
+            is_paid,
+            &mut messages,
+        )?;
         set_linkup_env(state.clone())?;
 
         state
@@ -48,22 +127,45 @@ pub async fn start<'a>(
         LocalState::load()?
     };
 
+    // Without the local server nothing is sent to the worker
+    if !args.dns_only {
+        ensure_supported_by_worker(&state.linkup.remote).await?;
+    }
+
+    check_dns_only_session(args, &state)?;
+
+    let stray_processes = find_stray_processes(&state, services::find_service_processes());
+    if !stray_processes.is_empty() && confirm_stop_processes(args, &stray_processes) {
+        stop_processes(&stray_processes)?;
+    }
+
+    // Caddy and dnsmasq only start for domains that have local DNS, so this goes first
+    if args.local_dns
+        || local_dns_is_missing(&state, &local_dns::list_resolvers().unwrap_or_default())
+    {
+        setup_local_dns(&mut state, install_local_dns, &mut messages)?;
+    }
+
     let status_update_channel = sync::mpsc::channel::<services::RunUpdate>();
 
-    let local_server = services::LocalServer::new();
+    let local_server = services::LocalServer::new().with_expose(args.expose);
     let cloudflare_tunnel = services::CloudflareTunnel::new();
     let caddy = services::Caddy::new();
     let dnsmasq = services::Dnsmasq::new();
+    let service_commands = services::ServiceCommands::new();
+    let selected_services = services_to_start(args);
 
     #[cfg(target_os = "linux")]
-    {
+    if selected_services.contains(&services::Caddy::NAME) {
         use crate::{is_sudo, sudo_su};
         match (caddy.should_start(&state.domain_strings()), is_sudo()) {
             // Should start Caddy and is not sudo
             (Ok(true), false) => {
-                println!(
+                writeln!(
+                    messages,
                     "On linux binding port 443 and 80 requires sudo. And this is necessary to start caddy."
-                );
+                )
+                .ok();
 
                 sudo_su()?;
             }
@@ -75,19 +177,20 @@ pub async fn start<'a>(
     }
 
     let mut display_thread: Option<JoinHandle<()>> = None;
+    let mut progress_thread: Option<JoinHandle<()>> = None;
     let display_channel = sync::mpsc::channel::<bool>();
 
     // If we are doing RUST_LOG=debug to debug if there is anything wrong, having the display thread make so it
     // overwrites some of the output since it does some cursor moving.
     // So in that case, we do not start the display thread.
-    if !log::log_enabled!(log::Level::Debug) {
+    // With `--quiet` there is nothing to display, so the progress updates are just dropped.
+    if args.progress == Progress::Json {
+        progress_thread = Some(thread::spawn(move || {
+            write_json_progress(status_update_channel.1, &mut stdout())
+        }));
+    } else if !log::log_enabled!(log::Level::Debug) && !crate::is_quiet() {
         display_thread = Some(spawn_display_thread(
-            &[
-                services::LocalServer::NAME,
-                services::CloudflareTunnel::NAME,
-                services::Caddy::NAME,
-                services::Dnsmasq::NAME,
-            ],
+            &selected_services,
             status_update_channel.1,
             display_channel.1,
         ));
@@ -98,15 +201,17 @@ pub async fn start<'a>(
     // send the message to the display thread to stop and we join it.
     let mut exit_error: Option<Box<dyn std::error::Error>> = None;
 
-    match local_server
-        .run_with_progress(&mut state, status_update_channel.0.clone())
-        .await
-    {
-        Ok(_) => (),
-        Err(err) => exit_error = Some(Box::new(err)),
+    if selected_services.contains(&services::LocalServer::NAME) {
+        match local_server
+            .run_with_progress(&mut state, status_update_channel.0.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => exit_error = Some(Box::new(err)),
+        }
     }
 
-    if exit_error.is_none() {
+    if exit_error.is_none() && selected_services.contains(&services::CloudflareTunnel::NAME) {
         match cloudflare_tunnel
             .run_with_progress(&mut state, status_update_channel.0.clone())
             .await
@@ -116,7 +221,7 @@ pub async fn start<'a>(
         }
     }
 
-    if exit_error.is_none() {
+    if exit_error.is_none() && selected_services.contains(&services::Caddy::NAME) {
         match caddy
             .run_with_progress(&mut state, status_update_channel.0.clone())
             .await
@@ -126,7 +231,7 @@ pub async fn start<'a>(
         }
     }
 
-    if exit_error.is_none() {
+    if exit_error.is_none() && selected_services.contains(&services::Dnsmasq::NAME) {
         match dnsmasq
             .run_with_progress(&mut state, status_update_channel.0.clone())
             .await
@@ -136,22 +241,189 @@ pub async fn start<'a>(
         }
     }
 
+    if exit_error.is_none() && selected_services.contains(&services::ServiceCommands::NAME) {
+        match service_commands
+            .run_with_progress(&mut state, status_update_channel.0.clone())
+            .await
+        {
+            Ok(_) => (),
+            Err(err) => exit_error = Some(Box::new(err)),
+        }
+    }
+
     if let Some(display_thread) = display_thread {
         display_channel.0.send(true).unwrap();
         display_thread.join().unwrap();
     }
 
+    // Every update has been sent, closing the channel lets the progress thread finish
+    drop(status_update_channel.0);
+    if let Some(progress_thread) = progress_thread {
+        progress_thread.join().unwrap();
+    }
+
     if let Some(exit_error) = exit_error {
         return Err(CliError::StartErr(exit_error.to_string()));
     }
 
-    let status = SessionStatus {
-        name: state.linkup.session_name.clone(),
-        domains: format_state_domains(&state.linkup.session_name, &state.domains),
+    if !crate::is_quiet() && args.progress == Progress::Text {
+        let status = SessionStatus {
+            name: state.linkup.session_name.clone(),
+            domains: format_state_domains(&state.linkup.session_name, &state.domains),
+        };
+
+        println!();
+        status.print();
+    }
+
+    Ok(())
+}
+
+/// `--dns-only` doesn't start the local server, so it can't create a session. It resolves the
+/// domains of the session an earlier `linkup start` created.
+fn check_dns_only_session(args: &Args, state: &LocalState) -> Result<(), CliError> {
+    if args.dns_only && state.linkup.session_name.is_empty() {
+        return Err(CliError::StartErr(
+            "--dns-only needs an existing session, run `linkup start` without it first".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The names of the background services to start, in the order they are started.
+fn services_to_start(args: &Args) -> Vec<&'static str> {
+    if args.dns_only {
+        return vec![services::Dnsmasq::NAME];
+    }
+
+    vec![
+        services::LocalServer::NAME,
+        services::CloudflareTunnel::NAME,
+        services::Caddy::NAME,
+        services::Dnsmasq::NAME,
+        services::ServiceCommands::NAME,
+    ]
+}
+
+fn install_local_dns(domains: &[String], out: &mut dyn Write) -> Result<(), CliError> {
+    local_dns::install_domains(domains, out)?;
+    local_dns::install_caddy_packages(out)
+}
+
+/// Set up local DNS for the domains of `state` with `install`, remembering it in the state so that
+/// `linkup stop` can tear it down. Only warns on platforms without local DNS support.
+fn setup_local_dns(
+    state: &mut LocalState,
+    install: fn(&[String], &mut dyn Write) -> Result<(), CliError>,
+    out: &mut dyn Write,
+) -> Result<(), CliError> {
+    if !local_dns::is_supported() {
+        writeln!(
+            out,
+            "{}",
+            "local-dns is not supported on this platform, starting without it.".yellow()
+        )
+        .ok();
+
+        return Ok(());
+    }
+
+    install(&state.top_level_domains(), out)?;
+
+    state.linkup.local_dns = Some(true);
+    state.save()
+}
+
+/// Whether the session has local DNS, but some of its domains have no resolver. The Linux
+/// resolvers don't survive a reboot, so they are set up again on the next start.
+fn local_dns_is_missing(state: &LocalState, resolvers: &[String]) -> bool {
+    state.linkup.local_dns == Some(true)
+        && state
+            .top_level_domains()
+            .iter()
+            .any(|domain| !resolvers.contains(domain))
+}
+
+/// Linkup-owned processes that the current state doesn't know about, e.g. the local server or a
+/// tunnel of a session that crashed. They can hold on to ports the new session needs. Processes of
+/// linkups running from another `LINKUP_HOME` are not ours to stop.
+fn find_stray_processes(state: &LocalState, processes: Vec<ServiceProcess>) -> Vec<ServiceProcess> {
+    let linkup_home = linkup_dir_path().to_string_lossy().into_owned();
+    let processes: Vec<ServiceProcess> = processes
+        .into_iter()
+        .filter(|process| process.linkup_home.as_ref() == Some(&linkup_home))
+        .collect();
+
+    // The processes of a background service are all fine as long as one of them is the one in
+    // its pidfile, e.g. `cargo run` and the local server it runs.
+    let running_pids: HashMap<&str, Option<String>> = HashMap::from([
+        (
+            services::LocalServer::SERVICE_ID,
+            services::LocalServer::new().running_pid(),
+        ),
+        (
+            services::CloudflareTunnel::SERVICE_ID,
+            services::CloudflareTunnel::new().running_pid(),
+        ),
+        (
+            services::Dnsmasq::SERVICE_ID,
+            services::Dnsmasq::new().running_pid(),
+        ),
+    ]);
+
+    let is_tracked = |service_id: &str| match running_pids.get(service_id) {
+        Some(Some(running_pid)) => processes.iter().any(|process| {
+            process.service_id == service_id && process.pid.to_string() == *running_pid
+        }),
+        Some(None) => false,
+        // Service commands are stopped by name, so any process of a current service is tracked
+        None => state
+            .services
+            .iter()
+            .any(|service| service.command.is_some() && service.name == service_id),
     };
 
-    println!();
-    status.print();
+    let tracked_ids: Vec<String> = processes
+        .iter()
+        .map(|process| process.service_id.clone())
+        .filter(|service_id| is_tracked(service_id))
+        .collect();
+
+    processes
+        .into_iter()
+        .filter(|process| !tracked_ids.contains(&process.service_id))
+        .collect()
+}
+
+/// Asks to stop the processes like deploys ask to apply their changes, skipped with `--yes` or
+/// on CI.
+fn confirm_stop_processes(args: &Args, processes: &[ServiceProcess]) -> bool {
+    let notifier = ConsoleNotifier::new()
+        .with_auto_confirm(args.yes)
+        .with_stderr(args.progress == Progress::Json);
+
+    let mut message = "Found linkup processes left over from a previous session:".to_string();
+    for process in processes {
+        message.push_str(&format!(
+            "\n  {} (pid {}): {}",
+            process.service_id, process.pid, process.command
+        ));
+    }
+    notifier.notify(&message);
+
+    notifier.ask_confirmation()
+}
+
+fn stop_processes(processes: &[ServiceProcess]) -> Result<(), CliError> {
+    for process in processes {
+        log::debug!("Stopping {} (pid {})", process.service_id, process.pid);
+
+        match signal::send_signal(&process.pid.to_string(), signal::Signal::SIGTERM) {
+            Ok(_) | Err(signal::PidError::NoSuchProcess(_)) => (),
+            Err(e) => return Err(CliError::StopErr(e.to_string())),
+        }
+    }
 
     Ok(())
 }
@@ -254,11 +526,29 @@ fn spawn_display_thread(
     })
 }
 
+/// Write every update from `status_update_receiver` as a line of JSON, until all of its senders
+/// are dropped.
+fn write_json_progress(
+    status_update_receiver: sync::mpsc::Receiver<services::RunUpdate>,
+    writer: &mut impl Write,
+) {
+    for status_update in status_update_receiver {
+        let line = serde_json::to_string(&status_update).expect("Failed to serialize update");
+
+        if writeln!(writer, "{}", line)
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
 fn set_linkup_env(state: LocalState) -> Result<(), CliError> {
     // Set env vars to linkup
     for service in &state.services {
         if let Some(d) = &service.directory {
-            set_service_env(d.clone(), state.linkup.config_path.clone())?
+            set_service_env(d.clone(), &state.linkup.config_dir())?
         }
     }
     Ok(())
@@ -268,6 +558,7 @@ fn load_and_save_state(
     config_arg: &Option<String>,
     no_tunnel: bool,
     is_paid: bool,
+    messages: &mut dyn Write,
 ) -> Result<LocalState, CliError> {
     let previous_state = LocalState::load();
     let config_path = config_path(config_arg)?;
@@ -275,30 +566,45 @@ fn load_and_save_state(
 
     let mut state = config_to_state(input_config.clone(), config_path, no_tunnel, is_paid);
 
-    // Reuse previous session name if possible
-    if let Ok(ps) = previous_state {
-        state.linkup.session_name = ps.linkup.session_name;
-        state.linkup.session_token = ps.linkup.session_token;
-
-        // Maintain tunnel state until it is rewritten
-        if !no_tunnel && ps.linkup.tunnel.is_some() {
-            state.linkup.tunnel = ps.linkup.tunnel;
+    if !crate::is_quiet() {
+        for warning in config_warnings(&state) {
+            writeln!(messages, "{}", format!("warning: {}", warning).yellow()).ok();
         }
     }
 
+    if let Ok(previous_state) = previous_state {
+        reuse_previous_state(&mut state, previous_state, no_tunnel);
+    }
+
     state.save()?;
     Ok(state)
 }
 
-fn set_service_env(directory: String, config_path: String) -> Result<(), CliError> {
-    let config_dir = Path::new(&config_path).parent().ok_or_else(|| {
-        CliError::SetServiceEnv(
-            directory.clone(),
-            "config_path does not have a parent directory".to_string(),
-        )
-    })?;
+/// Carry over what a new state from the config doesn't know about the running session.
+fn reuse_previous_state(state: &mut LocalState, previous_state: LocalState, no_tunnel: bool) {
+    // Reuse previous session name if possible
+    state.linkup.session_name = previous_state.linkup.session_name;
+    state.linkup.session_token = previous_state.linkup.session_token;
+    state.linkup.local_server_port = previous_state.linkup.local_server_port;
+    // Local DNS stays set up until `linkup stop` removes it
+    state.linkup.local_dns = previous_state.linkup.local_dns;
+
+    // Maintain tunnel state until it is rewritten
+    if !no_tunnel && previous_state.linkup.tunnel.is_some() {
+        state.linkup.tunnel = previous_state.linkup.tunnel;
+    }
+}
+
+fn config_warnings(state: &LocalState) -> Vec<ConfigWarning> {
+    match Session::try_from(ServerConfig::from(state).local) {
+        Ok(session) => session.validate(),
+        // Invalid configs are reported when the session is uploaded
+        Err(_) => Vec::new(),
+    }
+}
 
-    let service_path = PathBuf::from(config_dir).join(&directory);
+fn set_service_env(directory: String, config_dir: &Path) -> Result<(), CliError> {
+    let service_path = config_dir.join(&directory);
 
     let dev_env_files_result = fs::read_dir(service_path);
     let dev_env_files: Vec<_> = match dev_env_files_result {
@@ -331,3 +637,236 @@ fn set_service_env(directory: String, config_path: String) -> Result<(), CliErro
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io, process};
+
+    use clap::Parser;
+
+    use crate::{local_config::config_to_state, services::service_env, LINKUP_HOME_ENV};
+
+    use super::*;
+
+    fn test_state(services: &str) -> LocalState {
+        let config = serde_yaml::from_str(&format!(
+            "linkup:\n  remote: https://remote-linkup.example.com\nservices: {}\ndomains: []\n",
+            services
+        ))
+        .unwrap();
+
+        config_to_state(config, "./config.yaml".to_string(), true, false)
+    }
+
+    fn tagged_processes(service_id: &str) -> Vec<ServiceProcess> {
+        services::find_service_processes()
+            .into_iter()
+            .filter(|process| process.service_id == service_id)
+            .collect()
+    }
+
+    fn parse_start_args(args: &[&str]) -> Args {
+        let argv = ["linkup", "start"].iter().chain(args);
+        match crate::Cli::try_parse_from(argv).unwrap().command {
+            crate::Commands::Start(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_dns_only_starts_only_dnsmasq() {
+        let started = services_to_start(&parse_start_args(&["--dns-only"]));
+        assert_eq!(started, vec![services::Dnsmasq::NAME]);
+        assert!(!started.contains(&services::LocalServer::NAME));
+
+        let started = services_to_start(&parse_start_args(&[]));
+        assert!(started.contains(&services::LocalServer::NAME));
+        assert!(started.contains(&services::Dnsmasq::NAME));
+
+        assert!(crate::Cli::try_parse_from(["linkup", "start", "--dns-only", "--expose"]).is_err());
+    }
+
+    #[test]
+    fn test_dns_only_needs_a_session() {
+        let args = parse_start_args(&["--dns-only"]);
+        let mut state = test_state("[]");
+
+        assert!(matches!(
+            check_dns_only_session(&args, &state),
+            Err(CliError::StartErr(_))
+        ));
+        assert!(check_dns_only_session(&parse_start_args(&[]), &state).is_ok());
+
+        state.linkup.session_name = "mysession".to_string();
+        assert!(check_dns_only_session(&args, &state).is_ok());
+    }
+
+    #[test]
+    fn test_stray_process_is_detected_and_stopped() {
+        let service_id = format!("test-stray-{}", process::id());
+        let mut child = process::Command::new("sleep")
+            .arg("30")
+            .envs(service_env(&service_id))
+            .spawn()
+            .unwrap();
+
+        let stray_processes =
+            find_stray_processes(&test_state("[]"), tagged_processes(&service_id));
+        assert_eq!(stray_processes.len(), 1);
+        assert_eq!(stray_processes[0].pid, child.id());
+
+        stop_processes(&stray_processes).unwrap();
+
+        let status = child.wait().unwrap();
+        assert!(!status.success());
+        assert!(tagged_processes(&service_id).is_empty());
+    }
+
+    #[test]
+    fn test_processes_of_current_services_are_not_stray() {
+        let service_id = format!("test-tracked-{}", process::id());
+        let mut child = process::Command::new("sleep")
+            .arg("30")
+            .envs(service_env(&service_id))
+            .spawn()
+            .unwrap();
+
+        let state = test_state(&format!(
+            "\n  - name: {}\n    remote: https://example.com\n    local: http://localhost:3000\n    command: sleep 30\n",
+            service_id
+        ));
+        let stray_processes = find_stray_processes(&state, tagged_processes(&service_id));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(stray_processes.is_empty());
+    }
+
+    #[test]
+    fn test_processes_of_other_linkup_homes_are_not_stray() {
+        let service_id = format!("test-other-home-{}", process::id());
+        let mut child = process::Command::new("sleep")
+            .arg("30")
+            .envs(service_env(&service_id))
+            .env(LINKUP_HOME_ENV, "/tmp/another-linkup-home")
+            .spawn()
+            .unwrap();
+
+        let processes = tagged_processes(&service_id);
+        let stray_processes = find_stray_processes(&test_state("[]"), processes.clone());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(processes.len(), 1);
+        assert!(stray_processes.is_empty());
+    }
+
+    struct TestService;
+
+    impl BackgroundService<io::Error> for TestService {
+        const NAME: &str = "Test service";
+
+        async fn run_with_progress(
+            &self,
+            _local_state: &mut LocalState,
+            status_sender: sync::mpsc::Sender<services::RunUpdate>,
+        ) -> Result<(), io::Error> {
+            self.notify_update(&status_sender, services::RunStatus::Starting);
+            self.notify_update_with_details(&status_sender, services::RunStatus::Started, "Ready");
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_progress_matches_updates() {
+        let (status_sender, status_receiver) = sync::mpsc::channel();
+        TestService
+            .run_with_progress(&mut test_state("[]"), status_sender)
+            .await
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_json_progress(status_receiver, &mut output);
+
+        let updates: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                serde_json::json!({"id": "Test service", "status": "starting", "details": null}),
+                serde_json::json!({"id": "Test service", "status": "started", "details": "Ready"}),
+            ]
+        );
+    }
+
+    thread_local! {
+        static INSTALLED_DOMAINS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    fn record_install(domains: &[String], _out: &mut dyn Write) -> Result<(), CliError> {
+        INSTALLED_DOMAINS.with(|installed| *installed.borrow_mut() = Some(domains.to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_dns_is_set_up_for_top_level_domains() {
+        let config = serde_yaml::from_str(
+            "linkup:\n  remote: https://remote-linkup.example.com\nservices: []\ndomains:\n  - domain: example.com\n    default_service: frontend\n  - domain: api.example.com\n    default_service: backend\n",
+        )
+        .unwrap();
+        let mut state = config_to_state(config, "./config.yaml".to_string(), true, false);
+
+        setup_local_dns(&mut state, record_install, &mut io::sink()).unwrap();
+
+        if local_dns::is_supported() {
+            assert_eq!(
+                INSTALLED_DOMAINS.with(|installed| installed.borrow().clone()),
+                Some(vec!["example.com".to_string()])
+            );
+            assert_eq!(state.linkup.local_dns, Some(true));
+        } else {
+            assert_eq!(
+                INSTALLED_DOMAINS.with(|installed| installed.borrow().clone()),
+                None
+            );
+            assert_eq!(state.linkup.local_dns, None);
+        }
+    }
+
+    #[test]
+    fn test_local_dns_is_carried_over_from_previous_state() {
+        let mut previous_state = test_state("[]");
+        previous_state.linkup.session_name = "previous-session".to_string();
+        previous_state.linkup.local_dns = Some(true);
+
+        let mut state = test_state("[]");
+        reuse_previous_state(&mut state, previous_state, true);
+
+        assert_eq!(state.linkup.session_name, "previous-session");
+        assert_eq!(state.linkup.local_dns, Some(true));
+    }
+
+    #[test]
+    fn test_local_dns_is_missing_after_reboot() {
+        let config = serde_yaml::from_str(
+            "linkup:\n  remote: https://remote-linkup.example.com\nservices: []\ndomains:\n  - domain: example.com\n    default_service: frontend\n",
+        )
+        .unwrap();
+        let mut state = config_to_state(config, "./config.yaml".to_string(), true, false);
+        let resolvers = vec!["example.com".to_string()];
+
+        // Sessions without local DNS never get it set up behind their back
+        assert!(!local_dns_is_missing(&state, &[]));
+
+        state.linkup.local_dns = Some(true);
+        assert!(local_dns_is_missing(&state, &[]));
+        assert!(!local_dns_is_missing(&state, &resolvers));
+    }
+}
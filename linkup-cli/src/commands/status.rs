@@ -1,19 +1,20 @@
 use colored::{ColoredString, Colorize};
 use crossterm::{cursor, execute, style::Print, terminal};
-use linkup::{get_additional_headers, HeaderMap, StorableDomain, TargetService};
+use linkup::{get_additional_headers, HeaderMap, SessionKeyConfig, StorableDomain, TargetService};
 use serde::{Deserialize, Serialize};
 use std::{
+    fmt::{self, Display, Formatter},
     io::stdout,
     ops::Deref,
+    path::PathBuf,
     sync::mpsc::Receiver,
     thread::{self, sleep},
     time::Duration,
 };
-use url::Url;
 
 use crate::{
     local_config::{LocalService, LocalState, ServiceTarget},
-    CliError, LINKUP_LOCALSERVER_PORT,
+    CliError,
 };
 
 const LOADING_CHARS: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
@@ -28,13 +29,17 @@ pub struct Args {
 
     #[arg(short, long)]
     all: bool,
+
+    // Write the status to this file instead of stdout, once every service has been checked
+    #[arg(short, long)]
+    output: Option<PathBuf>,
 }
 
 pub fn status(args: &Args) -> Result<(), CliError> {
     // TODO(augustocesar)[2024-10-28]: Remove --all/-a in a future release.
     // Do not print the warning in case of JSON so it doesn't break any usage if the result of the command
     // is passed on to somewhere else.
-    if args.all && !args.json {
+    if args.all && !args.json && args.output.is_none() {
         let warning = "--all/-a is a noop now. All services statuses will always be shown. \
             This arg will be removed in a future release.\n";
         println!("{}", warning.yellow());
@@ -42,10 +47,11 @@ pub fn status(args: &Args) -> Result<(), CliError> {
 
     let state = LocalState::load()?;
     let linkup_services = linkup_services(&state);
+    let session_key = state.session_key();
     let all_services = state.services.into_iter().chain(linkup_services);
 
     let (services_statuses, status_receiver) =
-        prepare_services_statuses(&state.linkup.session_name, all_services);
+        prepare_services_statuses(&state.linkup.session_name, &session_key, all_services);
 
     let mut status = Status {
         session: SessionStatus {
@@ -62,7 +68,7 @@ pub fn status(args: &Args) -> Result<(), CliError> {
             .then(a.name.cmp(&b.name))
     });
 
-    if args.json {
+    if args.json || args.output.is_some() {
         status_receiver.iter().for_each(|(name, server_status)| {
             for service_status in status.services.iter_mut() {
                 if service_status.name == name {
@@ -71,10 +77,14 @@ pub fn status(args: &Args) -> Result<(), CliError> {
             }
         });
 
-        println!(
-            "{}",
+        let report = if args.json {
             serde_json::to_string_pretty(&status).expect("Failed to serialize status")
-        );
+        } else {
+            colored::control::set_override(false);
+            status.text_report()
+        };
+
+        super::write_report(&report, args.output.as_deref(), &mut stdout())?;
     } else {
         status.session.print();
         println!();
@@ -149,6 +159,19 @@ struct Status {
     services: Vec<ServiceStatus>,
 }
 
+impl Status {
+    /// The status as plain text, with every column of the table.
+    fn text_report(&self) -> String {
+        let mut report = format!("{}\n", self.session);
+        report.push_str(&table_header(u16::MAX));
+        for service in &self.services {
+            report.push_str(&service.as_table_row(0, u16::MAX));
+        }
+
+        report.trim_end().to_string()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SessionStatus {
     pub name: String,
@@ -157,11 +180,19 @@ pub struct SessionStatus {
 
 impl SessionStatus {
     pub fn print(&self) {
-        println!("Session Name: {}", self.name);
-        println!("Domains: ");
+        print!("{}", self);
+    }
+}
+
+impl Display for SessionStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Session Name: {}", self.name)?;
+        writeln!(f, "Domains: ")?;
         for domain in &self.domains {
-            println!("    {}", domain);
+            writeln!(f, "    {}", domain)?;
         }
+
+        Ok(())
     }
 }
 
@@ -282,7 +313,7 @@ pub fn format_state_domains(session_name: &str, domains: &[StorableDomain]) -> V
 }
 
 fn linkup_services(state: &LocalState) -> Vec<LocalService> {
-    let local_url = Url::parse(&format!("http://localhost:{}", LINKUP_LOCALSERVER_PORT)).unwrap();
+    let local_url = state.local_server_url();
 
     vec![
         LocalService {
@@ -292,6 +323,18 @@ fn linkup_services(state: &LocalState) -> Vec<LocalService> {
             current: ServiceTarget::Local,
             directory: None,
             rewrites: vec![],
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
+            command: None,
+            readiness: None,
+            split: None,
+            retries: None,
         },
         LocalService {
             name: "linkup_remote_server".to_string(),
@@ -300,6 +343,18 @@ fn linkup_services(state: &LocalState) -> Vec<LocalService> {
             current: ServiceTarget::Remote,
             directory: None,
             rewrites: vec![],
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
+            command: None,
+            readiness: None,
+            split: None,
+            retries: None,
         },
         LocalService {
             name: "tunnel".to_string(),
@@ -308,11 +363,27 @@ fn linkup_services(state: &LocalState) -> Vec<LocalService> {
             current: ServiceTarget::Remote,
             directory: None,
             rewrites: vec![],
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
+            command: None,
+            readiness: None,
+            split: None,
+            retries: None,
         },
     ]
 }
 
-fn service_status(service: &LocalService, session_name: &str) -> ServerStatus {
+fn service_status(
+    service: &LocalService,
+    session_name: &str,
+    session_key: &SessionKeyConfig,
+) -> ServerStatus {
     let url = match service.current {
         ServiceTarget::Local => service.local.clone(),
         ServiceTarget::Remote => service.remote.clone(),
@@ -322,6 +393,7 @@ fn service_status(service: &LocalService, session_name: &str) -> ServerStatus {
         url.as_ref(),
         &HeaderMap::new(),
         session_name,
+        session_key,
         &TargetService {
             name: service.name.clone(),
             url: url.to_string(),
@@ -352,6 +424,7 @@ pub fn server_status(url: String, extra_headers: Option<HeaderMap>) -> ServerSta
 
 fn prepare_services_statuses<I>(
     session_name: &str,
+    session_key: &SessionKeyConfig,
     services: I,
 ) -> (Vec<ServiceStatus>, Receiver<(String, ServerStatus)>)
 where
@@ -384,9 +457,10 @@ where
         let tx = tx.clone();
         let service_clone = service.clone();
         let session_name = session_name.to_string();
+        let session_key = session_key.clone();
 
         thread::spawn(move || {
-            let status = service_status(&service_clone, &session_name);
+            let status = service_status(&service_clone, &session_name, &session_key);
 
             tx.send((service_clone.name.clone(), status))
                 .expect("Failed to send service status");
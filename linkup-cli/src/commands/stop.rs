@@ -1,6 +1,7 @@
 use std::fs::{self};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
+use crate::commands::local_dns;
 use crate::env_files::clear_env_file;
 use crate::local_config::LocalState;
 use crate::{services, CliError};
@@ -9,17 +10,39 @@ use crate::{services, CliError};
 pub struct Args {}
 
 pub fn stop(_args: &Args, clear_env: bool) -> Result<(), CliError> {
-    match (LocalState::load(), clear_env) {
+    let state = LocalState::load();
+
+    if let Ok(state) = &state {
+        services::ServiceCommands::new()
+            .stop(&state.services)
+            .unwrap();
+
+        // Only undo local DNS that `linkup start --local-dns` set up
+        if state.linkup.local_dns == Some(true) {
+            match local_dns::uninstall_domains(&state.top_level_domains()) {
+                Ok(()) => {
+                    let mut state = state.clone();
+                    state.linkup.local_dns = None;
+                    if let Err(e) = state.save() {
+                        eprintln!("Could not save the state: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Could not remove local DNS: {}", e),
+            }
+        }
+    }
+
+    match (state, clear_env) {
         (Ok(state), true) => {
             // Reset env vars back to what they were before
             for service in &state.services {
                 let remove_res = match &service.directory {
-                    Some(d) => remove_service_env(d.clone(), state.linkup.config_path.clone()),
+                    Some(d) => remove_service_env(d.clone(), &state.linkup.config_dir()),
                     None => Ok(()),
                 };
 
                 if let Err(e) = remove_res {
-                    println!("Could not remove env for service {}: {}", service.name, e);
+                    eprintln!("Could not remove env for service {}: {}", service.name, e);
                 }
             }
         }
@@ -34,20 +57,15 @@ pub fn stop(_args: &Args, clear_env: bool) -> Result<(), CliError> {
     services::Caddy::new().stop().unwrap();
     services::Dnsmasq::new().stop().unwrap();
 
-    println!("Stopped linkup");
+    if !crate::is_quiet() {
+        println!("Stopped linkup");
+    }
 
     Ok(())
 }
 
-fn remove_service_env(directory: String, config_path: String) -> Result<(), CliError> {
-    let config_dir = Path::new(&config_path).parent().ok_or_else(|| {
-        CliError::SetServiceEnv(
-            directory.clone(),
-            "config_path does not have a parent directory".to_string(),
-        )
-    })?;
-
-    let service_path = PathBuf::from(config_dir).join(&directory);
+fn remove_service_env(directory: String, config_dir: &Path) -> Result<(), CliError> {
+    let service_path = config_dir.join(&directory);
 
     let env_files_result = fs::read_dir(service_path);
     let env_files: Vec<_> = match env_files_result {
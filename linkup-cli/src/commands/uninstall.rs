@@ -38,7 +38,9 @@ pub fn uninstall(_args: &Args) -> Result<(), CliError> {
             .status()?;
     }
 
-    println!("linkup uninstalled!");
+    if !crate::is_quiet() {
+        println!("linkup uninstalled!");
+    }
 
     Ok(())
 }
@@ -1,26 +1,24 @@
 use std::{
     env::{self},
-    fmt::Display,
     fs,
     path::PathBuf,
     time::{self, Duration},
 };
 
 use flate2::read::GzDecoder;
+use linkup::{Version, VersionInfo};
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use tar::Archive;
 use url::Url;
 
-use crate::{linkup_file_path, CliError};
+use crate::{linkup_file_path, worker_client::WorkerClient, CliError};
 
 const CACHED_LATEST_RELEASE_FILE: &str = "latest_release.json";
 const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
-    #[error("{0}")]
-    InvalidVersion(String),
     #[error("File missing from dowloaded compressed archive")]
     MissingBinary,
     #[error("ReqwestError: {0}")]
@@ -45,10 +43,14 @@ pub async fn update(_args: &Args) -> Result<(), CliError> {
             fs::rename(&new_exe_path, &current_exe)
                 .expect("failed to move the new exe as the current exe");
 
-            println!("Finished update!");
+            if !crate::is_quiet() {
+                println!("Finished update!");
+            }
         }
         None => {
-            println!("No new version available.");
+            if !crate::is_quiet() {
+                println!("No new version available.");
+            }
         }
     }
 
@@ -59,57 +61,45 @@ pub async fn new_version_available() -> bool {
     available_update().await.is_some()
 }
 
-// --------------------------------------------------------------------------------------------------------------------
-
-struct Version {
-    major: u16,
-    minor: u16,
-    patch: u16,
-}
+/// Fails before a session is created when the worker no longer accepts this CLI, instead of
+/// letting the session request itself fail. Workers that predate `/linkup/version` are assumed to
+/// support every client.
+pub async fn ensure_supported_by_worker(remote: &Url) -> Result<(), CliError> {
+    let version_info = match WorkerClient::new(remote).version().await {
+        Ok(version_info) => version_info,
+        Err(error) => {
+            log::debug!("Failed to fetch the worker version: {}", error);
 
-impl Version {
-    // NOTE: This is a super simple and naïve implementation of this. For our case I think should be enough,
-    //       but we could consider using something like https://docs.rs/semver/latest/semver/ if we want something
-    //       more robust.
-    fn is_outdated(&self, other: &Self) -> bool {
-        let same_major = self.major == other.major;
-        let same_minor = self.minor == other.minor;
-        let same_patch = self.patch == other.patch;
-
-        match (same_major, same_minor, same_patch) {
-            (true, true, true) => false,
-            (true, true, false) => self.patch < other.patch,
-            (true, false, _) => self.minor < other.minor,
-            (false, _, _) => self.major < other.major,
+            return Ok(());
         }
-    }
-}
-
-impl Display for Version {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+    };
 
-        Ok(())
-    }
+    check_supported(&version_info, CURRENT_VERSION)
 }
 
-impl TryFrom<&str> for Version {
-    type Error = Error;
+fn check_supported(version_info: &VersionInfo, current_version: &str) -> Result<(), CliError> {
+    let current_version = Version::try_from(current_version)
+        .map_err(|error| CliError::ParseErr(current_version.to_string(), error.to_string()))?;
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let (major, minor, patch) = match value.split('.').collect::<Vec<&str>>()[..] {
-            [major, minor, patch] => (major, minor, patch),
-            _ => return Err(Error::InvalidVersion(value.to_string())),
-        };
+    match version_info.supports_client(&current_version) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(CliError::UnsupportedClientVersion(
+            current_version.to_string(),
+            version_info.min_supported_client_version.clone(),
+        )),
+        Err(error) => {
+            log::warn!(
+                "Worker reported an invalid minimum client version: {}",
+                error
+            );
 
-        Ok(Self {
-            major: major.parse::<u16>().unwrap(),
-            minor: minor.parse::<u16>().unwrap(),
-            patch: patch.parse::<u16>().unwrap(),
-        })
+            Ok(())
+        }
     }
 }
 
+// --------------------------------------------------------------------------------------------------------------------
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Asset {
     name: String,
@@ -332,3 +322,35 @@ fn now() -> u64 {
 
     since_the_epoch.as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version_info(min_supported_client_version: &str) -> VersionInfo {
+        VersionInfo {
+            version: "0.1.0".to_string(),
+            git_sha: None,
+            min_supported_client_version: min_supported_client_version.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_supported() {
+        assert!(check_supported(&version_info("1.5.0"), "1.5.0").is_ok());
+        assert!(check_supported(&version_info("1.5.0"), "1.6.0-beta.1").is_ok());
+        assert!(matches!(
+            check_supported(&version_info("1.5.0"), "1.5.0-beta.1"),
+            Err(CliError::UnsupportedClientVersion(current, min)) if current == "1.5.0-beta.1" && min == "1.5.0"
+        ));
+        assert!(matches!(
+            check_supported(&version_info("2.0.0"), "1.9.9"),
+            Err(CliError::UnsupportedClientVersion(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_check_supported_ignores_invalid_worker_version() {
+        assert!(check_supported(&version_info("latest"), "1.5.0").is_ok());
+    }
+}
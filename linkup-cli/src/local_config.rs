@@ -2,14 +2,17 @@ use std::{
     env,
     fmt::{self, Display, Formatter},
     fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
 };
 
-use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use linkup::{
-    CreatePreviewRequest, StorableDomain, StorableRewrite, StorableService, StorableSession,
+    BodyRewrite, CreatePreviewRequest, RequestCompression, RequestSigning, RewriteOrder,
+    SessionKeyConfig, SessionSource, StickyKey, StorableCacheRoute, StorableDomain,
+    StorableMockResponse, StorableRewrite, StorableService, StorableSession, TrafficSplit,
     UpdateSessionRequest,
 };
 
@@ -81,12 +84,42 @@ impl LocalState {
         }
     }
 
+    /// The key the session name is sent under in tracing headers. Invalid keys are rejected by
+    /// the servers when the session is created.
+    pub fn session_key(&self) -> SessionKeyConfig {
+        self.linkup
+            .session_key
+            .clone()
+            .and_then(|key| SessionKeyConfig::new(key).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn local_server_port(&self) -> u16 {
+        self.linkup
+            .local_server_port
+            .unwrap_or(services::LINKUP_LOCAL_SERVER_PORT)
+    }
+
+    /// The url the tunnel forwards requests to.
+    pub fn local_server_url(&self) -> Url {
+        self.linkup
+            .local_server
+            .clone()
+            .unwrap_or_default()
+            .url(self.local_server_port())
+            .expect("local server host is validated when the config is read")
+    }
+
     pub fn domain_strings(&self) -> Vec<String> {
         self.domains
             .iter()
             .map(|storable_domain| storable_domain.domain.clone())
             .collect::<Vec<String>>()
     }
+
+    pub fn top_level_domains(&self) -> Vec<String> {
+        top_level_domains(&self.domains)
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -97,7 +130,74 @@ pub struct LinkupState {
     pub remote: Url,
     pub tunnel: Option<Url>,
     pub is_paid: Option<bool>,
-    pub cache_routes: Option<Vec<String>>,
+    pub cache_routes: Option<Vec<StorableCacheRoute>>,
+    pub rewrites: Option<Vec<StorableRewrite>>,
+    pub rewrite_order: Option<RewriteOrder>,
+    pub frame_ancestors: Option<Vec<String>>,
+    pub session_key: Option<String>,
+    /// Port the local server was last started on.
+    pub local_server_port: Option<u16>,
+    pub local_server: Option<LocalServerConfig>,
+    /// Set when `linkup start --local-dns` set up local DNS, so that `linkup stop` removes it.
+    pub local_dns: Option<bool>,
+    /// Directory that relative paths in a config read from stdin are resolved from. Those
+    /// configs have no file, so `config_path` is `-`.
+    pub config_dir: Option<String>,
+    /// Parts of a request the local server takes the session from. All of them when unset.
+    pub trusted_session_sources: Option<Vec<SessionSource>>,
+}
+
+impl LinkupState {
+    /// Directory that relative paths in the config, like service directories, are resolved from.
+    pub fn config_dir(&self) -> PathBuf {
+        match &self.config_dir {
+            Some(config_dir) => PathBuf::from(config_dir),
+            None => Path::new(&self.config_path)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// How the tunnel reaches the local server, when that is not plain http on localhost. For example
+/// when linkup runs inside a container, or behind something that terminates TLS. Also the TLS
+/// settings the local server uses towards upstream services.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct LocalServerConfig {
+    pub scheme: Option<LocalServerScheme>,
+    pub host: Option<String>,
+    /// PEM certificate presented to upstream services that require TLS client authentication.
+    pub client_cert: Option<String>,
+    /// PEM private key of `client_cert`.
+    pub client_key: Option<String>,
+    /// PEM file with extra CA certificates to trust for upstream services.
+    pub upstream_ca: Option<String>,
+}
+
+impl LocalServerConfig {
+    fn url(&self, port: u16) -> Result<Url, url::ParseError> {
+        let scheme = self.scheme.unwrap_or(LocalServerScheme::Http);
+        let host = self.host.as_deref().unwrap_or("localhost");
+
+        Url::parse(&format!("{}://{}:{}", scheme, host, port))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalServerScheme {
+    Http,
+    Https,
+}
+
+impl Display for LocalServerScheme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalServerScheme::Http => write!(f, "http"),
+            LocalServerScheme::Https => write!(f, "https"),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -108,6 +208,40 @@ pub struct LocalService {
     pub current: ServiceTarget,
     pub directory: Option<String>,
     pub rewrites: Vec<StorableRewrite>,
+    pub signing: Option<RequestSigning>,
+    pub forwarded_header: Option<bool>,
+    pub via_header: Option<bool>,
+    pub rewrite_redirects: Option<bool>,
+    pub static_dir: Option<String>,
+    pub mock: Option<StorableMockResponse>,
+    pub body_rewrites: Option<Vec<BodyRewrite>>,
+    pub compress_request: Option<RequestCompression>,
+    pub command: Option<String>,
+    pub readiness: Option<services::Readiness>,
+    pub split: Option<ServiceSplit>,
+    pub retries: Option<u8>,
+}
+
+impl LocalService {
+    /// The split of a service that is switched to local but only gets part of its traffic there.
+    fn traffic_split(&self, local_location: &Url) -> Option<TrafficSplit> {
+        match (&self.current, &self.split) {
+            (ServiceTarget::Local, Some(split)) => Some(TrafficSplit {
+                location: local_location.clone(),
+                percent: split.local_percent,
+                key: split.key.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Send only `local_percent` percent of the traffic of a local service to it, the rest keeps
+/// going to the remote service.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ServiceSplit {
+    pub local_percent: u8,
+    pub key: Option<StickyKey>,
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
@@ -134,16 +268,34 @@ pub struct YamlLocalConfig {
 
 impl YamlLocalConfig {
     pub fn top_level_domains(&self) -> Vec<String> {
-        self.domains
-            .iter()
-            .filter(|&d| {
-                !self
-                    .domains
-                    .iter()
-                    .any(|other| other.domain != d.domain && d.domain.ends_with(&other.domain))
-            })
-            .map(|d| d.domain.clone())
-            .collect::<Vec<String>>()
+        top_level_domains(&self.domains)
+    }
+
+    /// Every domain default service or route service that isn't one of `services`, as
+    /// `<location> -> <service>`.
+    fn undefined_service_references(&self) -> Vec<String> {
+        let is_defined = |name: &str| self.services.iter().any(|service| service.name == name);
+        let mut references = Vec::new();
+
+        for domain in &self.domains {
+            if !is_defined(&domain.default_service) {
+                references.push(format!(
+                    "domains[{}].default_service -> {}",
+                    domain.domain, domain.default_service
+                ));
+            }
+
+            for (i, route) in domain.routes.iter().flatten().enumerate() {
+                if !linkup::is_service_template(&route.service) && !is_defined(&route.service) {
+                    references.push(format!(
+                        "domains[{}].routes[{}].service -> {}",
+                        domain.domain, i, route.service
+                    ));
+                }
+            }
+        }
+
+        references
     }
 
     pub fn create_preview_request(&self, services: &[(String, String)]) -> CreatePreviewRequest {
@@ -163,7 +315,19 @@ impl YamlLocalConfig {
                 StorableService {
                     name,
                     location,
+                    alternate_locations: None,
+                    sticky_key: None,
+                    split: None,
+                    retries: yaml_local_service.retries,
                     rewrites: yaml_local_service.rewrites.clone(),
+                    signing: yaml_local_service.signing.clone(),
+                    forwarded_header: yaml_local_service.forwarded_header,
+                    via_header: yaml_local_service.via_header,
+                    rewrite_redirects: yaml_local_service.rewrite_redirects,
+                    static_dir: None,
+                    mock: yaml_local_service.mock.clone(),
+                    body_rewrites: yaml_local_service.body_rewrites.clone(),
+                    compress_request: yaml_local_service.compress_request,
                 }
             })
             .collect();
@@ -172,6 +336,11 @@ impl YamlLocalConfig {
             services,
             domains: self.domains.clone(),
             cache_routes: self.linkup.cache_routes.clone(),
+            rewrites: self.linkup.rewrites.clone(),
+            rewrite_order: self.linkup.rewrite_order,
+            frame_ancestors: self.linkup.frame_ancestors.clone(),
+            session_key: self.linkup.session_key.clone(),
+            trusted_session_sources: self.linkup.trusted_session_sources(),
         }
     }
 }
@@ -179,7 +348,25 @@ impl YamlLocalConfig {
 #[derive(Deserialize, Clone)]
 pub struct LinkupConfig {
     pub remote: Url,
-    cache_routes: Option<Vec<String>>,
+    cache_routes: Option<Vec<StorableCacheRoute>>,
+    rewrites: Option<Vec<StorableRewrite>>,
+    rewrite_order: Option<RewriteOrder>,
+    frame_ancestors: Option<Vec<String>>,
+    session_key: Option<String>,
+    local_server: Option<LocalServerConfig>,
+    trusted_session_sources: Option<Vec<SessionSource>>,
+    locked_down: Option<bool>,
+}
+
+impl LinkupConfig {
+    /// The sources the session can be found from, `locked_down` being a shorthand for
+    /// `SessionSource::LOCKED_DOWN`.
+    fn trusted_session_sources(&self) -> Option<Vec<SessionSource>> {
+        match self.locked_down {
+            Some(true) => Some(SessionSource::LOCKED_DOWN.to_vec()),
+            _ => self.trusted_session_sources.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -189,6 +376,18 @@ pub struct YamlLocalService {
     local: Url,
     directory: Option<String>,
     rewrites: Option<Vec<StorableRewrite>>,
+    signing: Option<RequestSigning>,
+    forwarded_header: Option<bool>,
+    via_header: Option<bool>,
+    rewrite_redirects: Option<bool>,
+    static_dir: Option<String>,
+    mock: Option<StorableMockResponse>,
+    body_rewrites: Option<Vec<BodyRewrite>>,
+    compress_request: Option<RequestCompression>,
+    command: Option<String>,
+    readiness: Option<services::Readiness>,
+    split: Option<ServiceSplit>,
+    retries: Option<u8>,
 }
 
 #[derive(Debug)]
@@ -197,24 +396,41 @@ pub struct ServerConfig {
     pub remote: StorableSession,
 }
 
+/// The domains that aren't a subdomain of another one of `domains`.
+fn top_level_domains(domains: &[StorableDomain]) -> Vec<String> {
+    domains
+        .iter()
+        .filter(|&d| {
+            !domains
+                .iter()
+                .any(|other| other.domain != d.domain && d.domain.ends_with(&other.domain))
+        })
+        .map(|d| d.domain.clone())
+        .collect::<Vec<String>>()
+}
+
 pub fn config_to_state(
     yaml_config: YamlLocalConfig,
     config_path: String,
     no_tunnel: bool,
     is_paid: bool,
 ) -> LocalState {
-    let random_token: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
+    let random_token = linkup::generate_secret();
+    // Service directories of a config read from stdin are relative to where linkup was started
+    let stdin_config_dir = (config_path == STDIN_CONFIG_PATH).then(|| {
+        env::current_dir()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
 
     let tunnel = match no_tunnel {
         true => None,
         false => Some(Url::parse("http://tunnel-not-yet-set").expect("default url parses")),
     };
 
-    let linkup = LinkupState {
+    let trusted_session_sources = yaml_config.linkup.trusted_session_sources();
+    let mut linkup = LinkupState {
         is_paid: Some(is_paid),
         session_name: String::new(),
         session_token: random_token,
@@ -222,7 +438,25 @@ pub fn config_to_state(
         remote: yaml_config.linkup.remote,
         tunnel,
         cache_routes: yaml_config.linkup.cache_routes,
+        rewrites: yaml_config.linkup.rewrites,
+        rewrite_order: yaml_config.linkup.rewrite_order,
+        frame_ancestors: yaml_config.linkup.frame_ancestors,
+        session_key: yaml_config.linkup.session_key,
+        local_server_port: None,
+        local_server: yaml_config.linkup.local_server,
+        local_dns: None,
+        config_dir: stdin_config_dir,
+        trusted_session_sources,
     };
+    // Like service directories, static dirs and certificates are relative to the config file
+    let config_dir = linkup.config_dir();
+    let resolve_path = |path: String| config_dir.join(path).to_string_lossy().into_owned();
+
+    if let Some(local_server) = &mut linkup.local_server {
+        local_server.client_cert = local_server.client_cert.take().map(resolve_path);
+        local_server.client_key = local_server.client_key.take().map(resolve_path);
+        local_server.upstream_ca = local_server.upstream_ca.take().map(resolve_path);
+    }
 
     let services = yaml_config
         .services
@@ -234,6 +468,18 @@ pub fn config_to_state(
             current: ServiceTarget::Remote,
             directory: yaml_service.directory,
             rewrites: yaml_service.rewrites.unwrap_or_default(),
+            signing: yaml_service.signing,
+            forwarded_header: yaml_service.forwarded_header,
+            via_header: yaml_service.via_header,
+            rewrite_redirects: yaml_service.rewrite_redirects,
+            static_dir: yaml_service.static_dir.map(resolve_path),
+            mock: yaml_service.mock,
+            body_rewrites: yaml_service.body_rewrites,
+            compress_request: yaml_service.compress_request,
+            command: yaml_service.command,
+            readiness: yaml_service.readiness,
+            split: yaml_service.split,
+            retries: yaml_service.retries,
         })
         .collect::<Vec<LocalService>>();
 
@@ -246,56 +492,160 @@ pub fn config_to_state(
     }
 }
 
+/// Passing this as the config path (`-c -`) reads the config from stdin. Relative service
+/// directories are then resolved from the current working directory.
+pub const STDIN_CONFIG_PATH: &str = "-";
+
+/// Where the config path in use came from.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Flag,
+    Env,
+    Stdin,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Flag => write!(f, "--config"),
+            ConfigSource::Env => write!(f, "{}", LINKUP_CONFIG_ENV),
+            ConfigSource::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ResolvedConfigPath {
+    pub path: String,
+    pub source: ConfigSource,
+}
+
 pub fn config_path(config_arg: &Option<String>) -> Result<String, CliError> {
-    match config_arg {
-        Some(path) => {
-            let absolute_path = fs::canonicalize(path)
-                .map_err(|_| CliError::NoConfig("Unable to resolve absolute path".to_string()))?;
-            Ok(absolute_path.to_string_lossy().into_owned())
+    let resolved = resolve_config_path(config_arg)?;
+
+    log::info!("Using config {} (from {})", resolved.path, resolved.source);
+
+    Ok(resolved.path)
+}
+
+/// The config path `config_path` picks for `config_arg`, along with where it came from.
+pub fn resolve_config_path(config_arg: &Option<String>) -> Result<ResolvedConfigPath, CliError> {
+    resolve_config_path_from(config_arg, env::var(LINKUP_CONFIG_ENV).ok())
+}
+
+fn resolve_config_path_from(
+    config_arg: &Option<String>,
+    env_value: Option<String>,
+) -> Result<ResolvedConfigPath, CliError> {
+    let (path, source) = match (config_arg, env_value) {
+        (Some(path), _) if path == STDIN_CONFIG_PATH => {
+            return Ok(ResolvedConfigPath {
+                path: STDIN_CONFIG_PATH.to_string(),
+                source: ConfigSource::Stdin,
+            })
         }
-        None => match env::var(LINKUP_CONFIG_ENV) {
-            Ok(val) => {
-                let absolute_path = fs::canonicalize(val).map_err(|_| {
-                    CliError::NoConfig("Unable to resolve absolute path".to_string())
-                })?;
-                Ok(absolute_path.to_string_lossy().into_owned())
-            }
-            Err(_) => Err(CliError::NoConfig(
+        (Some(path), _) => (path.clone(), ConfigSource::Flag),
+        (None, Some(val)) => (val, ConfigSource::Env),
+        (None, None) => {
+            return Err(CliError::NoConfig(
                 "No config argument provided and LINKUP_CONFIG environment variable not set"
                     .to_string(),
-            )),
-        },
-    }
+            ))
+        }
+    };
+
+    let absolute_path = fs::canonicalize(path)
+        .map_err(|_| CliError::NoConfig("Unable to resolve absolute path".to_string()))?;
+
+    Ok(ResolvedConfigPath {
+        path: absolute_path.to_string_lossy().into_owned(),
+        source,
+    })
 }
 
 pub fn get_config(config_path: &str) -> Result<YamlLocalConfig, CliError> {
-    let content = match fs::read_to_string(config_path) {
-        Ok(content) => content,
-        Err(_) => {
-            return Err(CliError::BadConfig(format!(
-                "Failed to read the config file at {}",
-                config_path
-            )))
-        }
-    };
+    if config_path == STDIN_CONFIG_PATH {
+        return read_config(io::stdin().lock(), "stdin");
+    }
+
+    match fs::File::open(config_path) {
+        Ok(file) => read_config(file, config_path),
+        Err(_) => Err(CliError::BadConfig(format!(
+            "Failed to read the config file at {}",
+            config_path
+        ))),
+    }
+}
 
-    let yaml_config: YamlLocalConfig = match serde_yaml::from_str(&content) {
+fn read_config(mut reader: impl Read, source: &str) -> Result<YamlLocalConfig, CliError> {
+    let mut content = String::new();
+    if reader.read_to_string(&mut content).is_err() {
+        return Err(CliError::BadConfig(format!(
+            "Failed to read the config file at {}",
+            source
+        )));
+    }
+
+    // Aliases are resolved while parsing, merge keys (`<<: *anchor`) have to be applied after
+    let yaml_config: YamlLocalConfig = match serde_yaml::from_str(&content)
+        .and_then(|mut value: serde_yaml::Value| value.apply_merge().map(|_| value))
+        .and_then(serde_yaml::from_value)
+    {
         Ok(config) => config,
         Err(_) => {
             return Err(CliError::BadConfig(format!(
                 "Failed to deserialize the config file at {}",
-                config_path
+                source
             )))
         }
     };
 
+    let dangling_references = yaml_config.undefined_service_references();
+    if !dangling_references.is_empty() {
+        return Err(CliError::BadConfig(format!(
+            "The config file at {} references services that are not defined: {}",
+            source,
+            dangling_references.join(", ")
+        )));
+    }
+
+    if yaml_config.linkup.locked_down == Some(true)
+        && yaml_config.linkup.trusted_session_sources.is_some()
+    {
+        return Err(CliError::BadConfig(format!(
+            "The config file at {} sets both linkup.locked_down and linkup.trusted_session_sources",
+            source
+        )));
+    }
+
+    if let Some(local_server) = &yaml_config.linkup.local_server {
+        if local_server
+            .url(services::LINKUP_LOCAL_SERVER_PORT)
+            .is_err()
+        {
+            return Err(CliError::BadConfig(format!(
+                "The config file at {} has an invalid linkup.local_server.host: {}",
+                source,
+                local_server.host.as_deref().unwrap_or_default()
+            )));
+        }
+
+        if local_server.client_cert.is_some() != local_server.client_key.is_some() {
+            return Err(CliError::BadConfig(format!(
+                "The config file at {} needs both linkup.local_server.client_cert and client_key",
+                source
+            )));
+        }
+    }
+
     Ok(yaml_config)
 }
 
 // This method gets the local state and uploads it to both the local linkup server and
 // the remote linkup server (worker).
 pub async fn upload_state(state: &LocalState) -> Result<String, worker_client::Error> {
-    let local_url = services::LocalServer::url();
+    let local_url = services::LocalServer::url(state.local_server_port());
 
     let server_config = ServerConfig::from(state);
     let session_name = &state.linkup.session_name;
@@ -329,6 +679,11 @@ async fn upload_config_to_server(
         services: config.services,
         domains: config.domains,
         cache_routes: config.cache_routes,
+        rewrites: config.rewrites,
+        rewrite_order: config.rewrite_order,
+        frame_ancestors: config.frame_ancestors,
+        session_key: config.session_key,
+        trusted_session_sources: config.trusted_session_sources,
     };
 
     let session_name = WorkerClient::new(linkup_url)
@@ -343,28 +698,64 @@ impl From<&LocalState> for ServerConfig {
         let local_server_services = state
             .services
             .iter()
-            .map(|service| StorableService {
-                name: service.name.clone(),
-                location: if service.current == ServiceTarget::Remote {
-                    service.remote.clone()
-                } else {
-                    service.local.clone()
-                },
-                rewrites: Some(service.rewrites.clone()),
+            .map(|service| {
+                let split = service.traffic_split(&service.local);
+
+                StorableService {
+                    name: service.name.clone(),
+                    location: if service.current == ServiceTarget::Remote || split.is_some() {
+                        service.remote.clone()
+                    } else {
+                        service.local.clone()
+                    },
+                    alternate_locations: None,
+                    sticky_key: None,
+                    split,
+                    retries: service.retries,
+                    rewrites: Some(service.rewrites.clone()),
+                    signing: service.signing.clone(),
+                    forwarded_header: service.forwarded_header,
+                    via_header: service.via_header,
+                    rewrite_redirects: service.rewrite_redirects,
+                    static_dir: if service.current == ServiceTarget::Local {
+                        service.static_dir.clone()
+                    } else {
+                        None
+                    },
+                    mock: service.mock.clone(),
+                    body_rewrites: service.body_rewrites.clone(),
+                    compress_request: service.compress_request,
+                }
             })
             .collect::<Vec<StorableService>>();
 
         let remote_server_services = state
             .services
             .iter()
-            .map(|service| StorableService {
-                name: service.name.clone(),
-                location: if service.current == ServiceTarget::Remote {
-                    service.remote.clone()
-                } else {
-                    state.get_tunnel_url()
-                },
-                rewrites: Some(service.rewrites.clone()),
+            .map(|service| {
+                let split = service.traffic_split(&state.get_tunnel_url());
+
+                StorableService {
+                    name: service.name.clone(),
+                    location: if service.current == ServiceTarget::Remote || split.is_some() {
+                        service.remote.clone()
+                    } else {
+                        state.get_tunnel_url()
+                    },
+                    alternate_locations: None,
+                    sticky_key: None,
+                    split,
+                    retries: service.retries,
+                    rewrites: Some(service.rewrites.clone()),
+                    signing: service.signing.clone(),
+                    forwarded_header: service.forwarded_header,
+                    via_header: service.via_header,
+                    rewrite_redirects: service.rewrite_redirects,
+                    static_dir: None,
+                    mock: service.mock.clone(),
+                    body_rewrites: service.body_rewrites.clone(),
+                    compress_request: service.compress_request,
+                }
             })
             .collect::<Vec<StorableService>>();
 
@@ -373,6 +764,12 @@ impl From<&LocalState> for ServerConfig {
             services: local_server_services,
             domains: state.domains.clone(),
             cache_routes: state.linkup.cache_routes.clone(),
+            rewrites: state.linkup.rewrites.clone(),
+            rewrite_order: state.linkup.rewrite_order,
+            frame_ancestors: state.linkup.frame_ancestors.clone(),
+            session_key: state.linkup.session_key.clone(),
+            expires_at: None,
+            trusted_session_sources: state.linkup.trusted_session_sources.clone(),
         };
 
         let remote_storable_session = StorableSession {
@@ -380,6 +777,12 @@ impl From<&LocalState> for ServerConfig {
             services: remote_server_services,
             domains: state.domains.clone(),
             cache_routes: state.linkup.cache_routes.clone(),
+            rewrites: state.linkup.rewrites.clone(),
+            rewrite_order: state.linkup.rewrite_order,
+            frame_ancestors: state.linkup.frame_ancestors.clone(),
+            session_key: state.linkup.session_key.clone(),
+            expires_at: None,
+            trusted_session_sources: state.linkup.trusted_session_sources.clone(),
         };
 
         ServerConfig {
@@ -392,6 +795,7 @@ impl From<&LocalState> for ServerConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use linkup::Session;
     use url::Url;
 
     const CONF_STR: &str = r#"
@@ -401,6 +805,7 @@ services:
   - name: frontend
     remote: http://remote-service1.example.com
     local: http://localhost:8000
+    static_dir: dist
     rewrites:
       - source: /foo/(.*)
         target: /bar/$1
@@ -408,6 +813,11 @@ services:
     remote: http://remote-service2.example.com
     local: http://localhost:8001
     directory: ../backend
+    command: cargo run
+    readiness:
+      http: /health
+    signing:
+      secret: my-secret
 domains:
   - domain: example.com
     default_service: frontend
@@ -459,14 +869,263 @@ domains:
             Url::parse("http://localhost:8001").unwrap()
         );
         assert_eq!(local_state.services[1].rewrites.len(), 0);
+        assert!(local_state.services[0].signing.is_none());
+        assert_eq!(
+            local_state.services[1].signing.as_ref().unwrap().secret,
+            "my-secret"
+        );
         assert_eq!(
             local_state.services[1].directory,
             Some("../backend".to_string())
         );
+        assert_eq!(
+            local_state.services[0].static_dir,
+            Some("./path/to/dist".to_string())
+        );
+        assert!(local_state.services[1].static_dir.is_none());
+        assert!(local_state.services[0].command.is_none());
+        assert_eq!(
+            local_state.services[1].command,
+            Some("cargo run".to_string())
+        );
+        assert_eq!(
+            local_state.services[1].readiness,
+            Some(services::Readiness::Http {
+                http: "/health".to_string()
+            })
+        );
 
         assert_eq!(local_state.domains.len(), 2);
         assert_eq!(local_state.domains[0].domain, "example.com");
         assert_eq!(local_state.domains[0].default_service, "frontend");
         assert!(local_state.domains[0].routes.is_some());
     }
+
+    #[test]
+    fn test_config_path_stdin() {
+        assert_eq!(
+            config_path(&Some(STDIN_CONFIG_PATH.to_string())).unwrap(),
+            STDIN_CONFIG_PATH
+        );
+    }
+
+    #[test]
+    fn test_resolve_config_path_reports_source() {
+        let dir = env::temp_dir().join(format!("linkup-config-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let flag_config = dir.join("flag.yaml");
+        let env_config = dir.join("env.yaml");
+        fs::write(&flag_config, CONF_STR).unwrap();
+        fs::write(&env_config, CONF_STR).unwrap();
+
+        let flag_arg = Some(flag_config.to_string_lossy().into_owned());
+        let env_value = Some(env_config.to_string_lossy().into_owned());
+        let canonical = |path: &Path| {
+            fs::canonicalize(path)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        // The flag wins over the environment variable
+        let resolved = resolve_config_path_from(&flag_arg, env_value.clone()).unwrap();
+        assert_eq!(resolved.source, ConfigSource::Flag);
+        assert_eq!(resolved.path, canonical(&flag_config));
+
+        let resolved = resolve_config_path_from(&None, env_value).unwrap();
+        assert_eq!(resolved.source, ConfigSource::Env);
+        assert_eq!(resolved.path, canonical(&env_config));
+
+        let resolved =
+            resolve_config_path_from(&Some(STDIN_CONFIG_PATH.to_string()), None).unwrap();
+        assert_eq!(resolved.source, ConfigSource::Stdin);
+        assert_eq!(resolved.path, STDIN_CONFIG_PATH);
+
+        assert!(matches!(
+            resolve_config_path_from(&None, None),
+            Err(CliError::NoConfig(_))
+        ));
+
+        // `config_path` hands out the same path that is reported
+        assert_eq!(
+            config_path(&flag_arg).unwrap(),
+            resolve_config_path(&flag_arg).unwrap().path
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_config_from_pipe() {
+        let yaml_config = read_config(CONF_STR.as_bytes(), "stdin").unwrap();
+        let local_state = config_to_state(yaml_config, STDIN_CONFIG_PATH.to_string(), false, false);
+
+        let service_names: Vec<&str> = local_state
+            .services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(service_names, vec!["frontend", "backend"]);
+        assert_eq!(local_state.linkup.config_path, STDIN_CONFIG_PATH);
+        assert_eq!(local_state.linkup.config_dir(), env::current_dir().unwrap());
+
+        // JSON is valid YAML, so it can be piped in as well
+        let json_config = r#"{
+            "linkup": {"remote": "https://remote-linkup.example.com"},
+            "services": [{"name": "web", "remote": "http://remote.example.com", "local": "http://localhost:3000"}],
+            "domains": [{"domain": "example.com", "default_service": "web"}]
+        }"#;
+        let yaml_config = read_config(json_config.as_bytes(), "stdin").unwrap();
+        assert_eq!(yaml_config.services[0].name, "web");
+    }
+
+    #[test]
+    fn test_read_config_with_anchors_and_aliases() {
+        let config = r#"
+linkup:
+  remote: https://remote-linkup.example.com
+# Shared by both services below
+x-rewrites: &rewrites
+  - source: /foo/(.*)
+    target: /bar/$1
+services:
+  - &web
+    name: web
+    remote: http://remote-web.example.com
+    local: http://localhost:8000
+    rewrites: *rewrites
+  - <<: *web
+    name: web-canary # only the name differs
+domains:
+  - domain: example.com
+    default_service: web
+  - domain: canary.example.com
+    default_service: web-canary
+"#;
+
+        let yaml_config = read_config(config.as_bytes(), "stdin").unwrap();
+        let state = config_to_state(yaml_config, "./config.yaml".to_string(), false, false);
+        let session: Session = ServerConfig::from(&state).remote.try_into().unwrap();
+
+        let web = session.services.get("web").unwrap();
+        let canary = session.services.get("web-canary").unwrap();
+        assert_eq!(canary.origin, web.origin);
+        assert_eq!(canary.origin.as_str(), "http://remote-web.example.com/");
+        assert_eq!(canary.rewrites.len(), 1);
+        assert_eq!(
+            canary.rewrites[0].source.as_str(),
+            web.rewrites[0].source.as_str()
+        );
+        assert_eq!(canary.rewrites[0].target, web.rewrites[0].target);
+    }
+
+    #[test]
+    fn test_server_config_with_split() {
+        let config = CONF_STR.replace(
+            "    command: cargo run\n",
+            "    command: cargo run\n    split:\n      local_percent: 10\n",
+        );
+        let yaml_config = read_config(config.as_bytes(), "stdin").unwrap();
+        let mut state = config_to_state(yaml_config, "./config.yaml".to_string(), false, false);
+        state.linkup.tunnel = Some(Url::parse("https://tunnel.example.com").unwrap());
+
+        // Remote services get all the traffic
+        let server_config = ServerConfig::from(&state);
+        assert!(server_config.local.services[1].split.is_none());
+        assert!(server_config.remote.services[1].split.is_none());
+
+        state.services[1].current = ServiceTarget::Local;
+        let server_config = ServerConfig::from(&state);
+
+        let local = &server_config.local.services[1];
+        assert_eq!(
+            local.location.as_str(),
+            "http://remote-service2.example.com/"
+        );
+        assert_eq!(
+            local.split,
+            Some(TrafficSplit {
+                location: Url::parse("http://localhost:8001").unwrap(),
+                percent: 10,
+                key: None,
+            })
+        );
+
+        let remote = &server_config.remote.services[1];
+        assert_eq!(
+            remote.location.as_str(),
+            "http://remote-service2.example.com/"
+        );
+        assert_eq!(
+            remote.split.as_ref().unwrap().location.as_str(),
+            "https://tunnel.example.com/"
+        );
+    }
+
+    #[test]
+    fn test_server_config_with_locked_down() {
+        let config = CONF_STR.replace(
+            "  remote: https://remote-linkup.example.com\n",
+            "  remote: https://remote-linkup.example.com\n  locked_down: true\n",
+        );
+        let yaml_config = read_config(config.as_bytes(), "stdin").unwrap();
+        let state = config_to_state(yaml_config, "./config.yaml".to_string(), false, false);
+
+        // The worker only knows about the session, so it has to carry the sources
+        let server_config = ServerConfig::from(&state);
+        assert_eq!(
+            server_config.remote.trusted_session_sources,
+            Some(SessionSource::LOCKED_DOWN.to_vec())
+        );
+        assert_eq!(
+            server_config.local.trusted_session_sources,
+            Some(SessionSource::LOCKED_DOWN.to_vec())
+        );
+    }
+
+    #[test]
+    fn test_read_config_with_undefined_services() {
+        let config = CONF_STR
+            .replace("default_service: backend", "default_service: phantom")
+            .replace("  service: backend", "  service: ghost");
+
+        match read_config(config.as_bytes(), "stdin") {
+            Err(CliError::BadConfig(message)) => {
+                assert!(message.contains("domains[example.com].routes[0].service -> ghost"));
+                assert!(message.contains("domains[api.example.com].default_service -> phantom"));
+            }
+            Err(other) => panic!("expected BadConfig, got {}", other),
+            Ok(_) => panic!("config with undefined services should not load"),
+        }
+    }
+
+    #[test]
+    fn test_read_config_with_invalid_local_server_host() {
+        let config = CONF_STR.replace(
+            "  remote: https://remote-linkup.example.com\n",
+            "  remote: https://remote-linkup.example.com\n  local_server:\n    host: not a host\n",
+        );
+
+        match read_config(config.as_bytes(), "stdin") {
+            Err(CliError::BadConfig(message)) => {
+                assert!(message.contains("linkup.local_server.host: not a host"));
+            }
+            Err(other) => panic!("expected BadConfig, got {}", other),
+            Ok(_) => panic!("config with an invalid local server host should not load"),
+        }
+
+        let config = CONF_STR.replace(
+            "  remote: https://remote-linkup.example.com\n",
+            "  remote: https://remote-linkup.example.com\n  local_server:\n    host: linkup.internal\n",
+        );
+        assert!(read_config(config.as_bytes(), "stdin").is_ok());
+    }
+
+    #[test]
+    fn test_read_config_invalid() {
+        assert!(matches!(
+            read_config("not: [a, config".as_bytes(), "stdin"),
+            Err(CliError::BadConfig(_))
+        ));
+    }
 }
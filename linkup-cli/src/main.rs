@@ -1,7 +1,13 @@
-use std::{env, fs, io::ErrorKind, path::PathBuf, process};
-
-use clap::{Parser, Subcommand};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::{ArgAction, Parser, Subcommand};
 use colored::Colorize;
+use log::LevelFilter;
 use thiserror::Error;
 
 mod commands;
@@ -13,16 +19,35 @@ mod system;
 mod worker_client;
 
 const LINKUP_CONFIG_ENV: &str = "LINKUP_CONFIG";
-const LINKUP_LOCALSERVER_PORT: u16 = 9066;
+pub const LINKUP_HOME_ENV: &str = "LINKUP_HOME";
+const LINKUP_LOCALDNS_PORT: u16 = 8053;
 const LINKUP_DIR: &str = ".linkup";
 const LINKUP_STATE_FILE: &str = "state";
 const LINKUP_CF_TLS_API_ENV_VAR: &str = "LINKUP_CF_API_TOKEN";
 
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Whether the CLI was invoked with `--quiet`. When set, only errors and the
+/// output a command was explicitly asked for (e.g. `status`) should be printed.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
 pub fn linkup_dir_path() -> PathBuf {
-    let storage_dir = match env::var("HOME") {
-        Ok(val) => val,
-        Err(_e) => "/var/tmp".to_string(),
-    };
+    dir_path_from(env::var(LINKUP_HOME_ENV).ok(), env::var("HOME").ok())
+}
+
+// `LINKUP_HOME` replaces the whole linkup dir, while `HOME` only decides where `.linkup` lives.
+fn dir_path_from(linkup_home: Option<String>, home: Option<String>) -> PathBuf {
+    if let Some(linkup_home) = linkup_home.filter(|linkup_home| !linkup_home.is_empty()) {
+        return PathBuf::from(linkup_home);
+    }
+
+    let storage_dir = home.unwrap_or_else(|| "/var/tmp".to_string());
 
     let mut path = PathBuf::new();
     path.push(storage_dir);
@@ -37,19 +62,27 @@ pub fn linkup_file_path(file: &str) -> PathBuf {
 }
 
 fn ensure_linkup_dir() -> Result<()> {
-    let path = linkup_dir_path();
-
-    match fs::create_dir(&path) {
-        Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            ErrorKind::AlreadyExists => Ok(()),
-            _ => Err(CliError::BadConfig(format!(
-                "Could not create linkup dir at {}: {}",
-                path.display(),
-                e
-            ))),
-        },
+    ensure_dir(&linkup_dir_path())
+}
+
+fn ensure_dir(path: &Path) -> Result<()> {
+    // A `LINKUP_HOME` can point somewhere whose parents don't exist yet
+    if let Err(e) = fs::create_dir_all(path) {
+        return Err(CliError::BadConfig(format!(
+            "Could not create linkup dir at {}: {}",
+            path.display(),
+            e
+        )));
     }
+
+    if !path.is_dir() {
+        return Err(CliError::BadConfig(format!(
+            "Linkup dir at {} is not a directory",
+            path.display()
+        )));
+    }
+
+    Ok(())
 }
 
 fn is_sudo() -> bool {
@@ -140,6 +173,8 @@ pub enum CliError {
     FileErr(String, String),
     #[error("{0}")]
     IOError(#[from] std::io::Error),
+    #[error("linkup {0} is not supported by the remote worker, which requires at least {1}. Run `linkup update` and try again.")]
+    UnsupportedClientVersion(String, String),
     #[error("{0}")]
     WorkerClientErr(#[from] worker_client::Error),
     #[error("{0}")]
@@ -165,10 +200,27 @@ struct Cli {
         short,
         long,
         value_name = "CONFIG",
-        help = "Path to config file, overriding environment variable."
+        help = "Path to config file, overriding environment variable. Use `-` to read it from stdin."
     )]
     config: Option<String>,
 
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Suppress progress and informational output. Errors are still printed."
+    )]
+    quiet: bool,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Log more details: -v for info, -vv for debug and -vvv for trace. Overrides RUST_LOG."
+    )]
+    verbose: u8,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -196,6 +248,9 @@ enum Commands {
     #[clap(about = "View linkup component and service status")]
     Status(commands::StatusArgs),
 
+    #[clap(about = "List the processes started by linkup")]
+    Ps(commands::PsArgs),
+
     #[clap(about = "Speed up your local environment by routing traffic locally when possible")]
     LocalDNS(commands::LocalDnsArgs),
 
@@ -223,27 +278,49 @@ enum Commands {
     Server(commands::ServerArgs),
 }
 
+// Without `-v` the level comes from RUST_LOG, but `--quiet` still keeps it to errors.
+fn log_builder(verbose: u8, quiet: bool) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    let level = match verbose {
+        0 if quiet => Some(LevelFilter::Error),
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+
+    builder
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    if commands::update::new_version_available().await {
-        println!(
+    let cli = Cli::parse();
+    set_quiet(cli.quiet);
+    log_builder(cli.verbose, cli.quiet).init();
+
+    // On stderr, so that it doesn't get mixed into output meant for other programs
+    if !is_quiet() && commands::update::new_version_available().await {
+        eprintln!(
             "{}",
             "⚠️ New version of linkup is available! Run `linkup update` to update it.".yellow()
         );
     }
 
-    let cli = Cli::parse();
-
     ensure_linkup_dir()?;
 
     match &cli.command {
-        Commands::Health(args) => commands::health(args),
+        Commands::Health(args) => commands::health(args, &cli.config),
         Commands::Start(args) => commands::start(args, true, &cli.config).await,
         Commands::Stop(args) => commands::stop(args, true),
         Commands::Reset(args) => commands::reset(args).await,
         Commands::Local(args) => commands::local(args).await,
         Commands::Remote(args) => commands::remote(args).await,
         Commands::Status(args) => commands::status(args),
+        Commands::Ps(args) => commands::ps(args),
         Commands::LocalDNS(args) => commands::local_dns(args, &cli.config),
         Commands::Completion(args) => commands::completion(args),
         Commands::Preview(args) => commands::preview(args, &cli.config).await,
@@ -254,3 +331,143 @@ async fn main() -> Result<()> {
         Commands::Destroy(args) => commands::destroy(args).await.map_err(CliError::from),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_linkup_home_overrides_linkup_dir() {
+        let home = Some("/home/user".to_string());
+
+        assert_eq!(
+            dir_path_from(None, home.clone()),
+            PathBuf::from("/home/user/.linkup")
+        );
+        assert_eq!(
+            dir_path_from(Some("/ci/linkup".to_string()), home.clone()),
+            PathBuf::from("/ci/linkup")
+        );
+        assert_eq!(
+            dir_path_from(Some("".to_string()), home),
+            PathBuf::from("/home/user/.linkup")
+        );
+        assert_eq!(dir_path_from(None, None), PathBuf::from("/var/tmp/.linkup"));
+    }
+
+    #[test]
+    fn test_ensure_dir() {
+        let base = env::temp_dir().join(format!("linkup-home-{}", process::id()));
+        let linkup_home = base.join("nested").join("linkup");
+
+        ensure_dir(&linkup_home).unwrap();
+        assert!(linkup_home.is_dir());
+        // Already existing is fine
+        ensure_dir(&linkup_home).unwrap();
+
+        let file = base.join("file");
+        fs::write(&file, "").unwrap();
+        assert!(ensure_dir(&file).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_deploy_status_subcommand() {
+        assert!(Cli::try_parse_from(["linkup", "deploy", "-a", "account", "-z", "zone"]).is_ok());
+        assert!(
+            Cli::try_parse_from(["linkup", "deploy", "status", "-a", "account", "-z", "zone"])
+                .is_ok()
+        );
+        assert!(Cli::try_parse_from(["linkup", "deploy", "status", "-z", "zone"]).is_err());
+    }
+
+    #[test]
+    fn test_deploy_zones_per_account() {
+        let cli = Cli::try_parse_from([
+            "linkup",
+            "deploy",
+            "--account",
+            "account-1:zone-1,zone-2",
+            "--account",
+            "account-2:zone-3",
+        ])
+        .unwrap();
+
+        let Commands::Deploy(args) = cli.command else {
+            panic!("expected the deploy command");
+        };
+        let accounts = args.accounts();
+        assert_eq!(accounts[0].account_id, "account-1");
+        assert_eq!(accounts[0].zone_ids, vec!["zone-1", "zone-2"]);
+        assert_eq!(accounts[1].account_id, "account-2");
+        assert_eq!(accounts[1].zone_ids, vec!["zone-3"]);
+
+        // Zones given with -z belong to the single -a account
+        assert!(Cli::try_parse_from([
+            "linkup",
+            "deploy",
+            "-a",
+            "account-1",
+            "-z",
+            "zone-1",
+            "--account",
+            "account-2:zone-3",
+        ])
+        .is_err());
+        assert!(Cli::try_parse_from(["linkup", "deploy", "-a", "account-1"]).is_err());
+        assert!(Cli::try_parse_from(["linkup", "deploy", "--account", "account-1"]).is_err());
+    }
+
+    #[test]
+    fn test_quiet_flag_is_global() {
+        let cli = Cli::try_parse_from(["linkup", "stop", "-q"]).unwrap();
+        assert!(cli.quiet);
+
+        let cli = Cli::try_parse_from(["linkup", "--quiet", "status"]).unwrap();
+        assert!(cli.quiet);
+
+        let cli = Cli::try_parse_from(["linkup", "status"]).unwrap();
+        assert!(!cli.quiet);
+    }
+
+    #[test]
+    fn test_verbose_flag_is_counted() {
+        let cli = Cli::try_parse_from(["linkup", "status"]).unwrap();
+        assert_eq!(cli.verbose, 0);
+
+        let cli = Cli::try_parse_from(["linkup", "-vv", "status"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+
+        let cli = Cli::try_parse_from(["linkup", "start", "-v", "--verbose", "-q"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+        assert!(cli.quiet);
+    }
+
+    #[test]
+    fn test_log_builder_levels() {
+        fn enabled(logger: &env_logger::Logger, level: log::Level) -> bool {
+            log::Log::enabled(
+                logger,
+                &log::Metadata::builder()
+                    .level(level)
+                    .target("linkup")
+                    .build(),
+            )
+        }
+
+        let logger = log_builder(2, false).build();
+        assert!(enabled(&logger, log::Level::Debug));
+        assert!(!enabled(&logger, log::Level::Trace));
+
+        // Verbosity wins over --quiet, which otherwise only lets errors through
+        let logger = log_builder(3, true).build();
+        assert!(enabled(&logger, log::Level::Trace));
+
+        let logger = log_builder(0, true).build();
+        assert!(enabled(&logger, log::Level::Error));
+        assert!(!enabled(&logger, log::Level::Warn));
+    }
+}
@@ -9,7 +9,7 @@ use crate::{
     LINKUP_CF_TLS_API_ENV_VAR,
 };
 
-use super::{local_server::LINKUP_LOCAL_SERVER_PORT, BackgroundService};
+use super::BackgroundService;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -23,8 +23,22 @@ pub enum Error {
     MissingRedisInstalation,
     #[error("Failed to stop pid: {0}")]
     StoppingPid(#[from] signal::PidError),
+    #[error("Failed to install the Caddy package {0}")]
+    InstallingPackage(String),
 }
 
+/// Packages local DNS needs on top of a plain Caddy, with the module each of them adds.
+const EXTRA_PACKAGES: [(&str, &str); 2] = [
+    (
+        "github.com/caddy-dns/cloudflare",
+        "dns.providers.cloudflare",
+    ),
+    (
+        "github.com/pberkel/caddy-storage-redis",
+        "caddy.storage.redis",
+    ),
+];
+
 pub struct Caddy {
     caddyfile_path: PathBuf,
     stdout_file_path: PathBuf,
@@ -42,27 +56,35 @@ impl Caddy {
         }
     }
 
-    pub fn install_extra_packages() {
-        Command::new("sudo")
-            .args(["caddy", "add-package", "github.com/caddy-dns/cloudflare"])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .unwrap();
-
-        Command::new("sudo")
-            .args([
-                "caddy",
-                "add-package",
-                "github.com/pberkel/caddy-storage-redis",
-            ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .unwrap();
+    pub fn install_extra_packages() -> Result<(), Error> {
+        for (package, _) in EXTRA_PACKAGES {
+            let status = Command::new("sudo")
+                .args(["caddy", "add-package", package])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+
+            if !status.success() {
+                return Err(Error::InstallingPackage(package.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether Caddy already has the modules of every extra package.
+    pub fn has_extra_packages() -> bool {
+        let Ok(output) = Command::new("caddy").arg("list-modules").output() else {
+            return false;
+        };
+        let modules = String::from_utf8_lossy(&output.stdout);
+
+        EXTRA_PACKAGES
+            .iter()
+            .all(|(_, module)| modules.lines().any(|line| line.trim() == *module))
     }
 
-    fn start(&self, domains: &[String]) -> Result<(), Error> {
+    fn start(&self, domains: &[String], local_server_port: u16) -> Result<(), Error> {
         log::debug!("Starting {}", Self::NAME);
 
         if std::env::var(LINKUP_CF_TLS_API_ENV_VAR).is_err() {
@@ -74,7 +96,7 @@ impl Caddy {
             .map(|domain| format!("{domain}, *.{domain}"))
             .collect();
 
-        self.write_caddyfile(&domains_and_subdomains)?;
+        self.write_caddyfile(&domains_and_subdomains, local_server_port)?;
 
         let stdout_file = fs::File::create(&self.stdout_file_path)?;
         let stderr_file = fs::File::create(&self.stderr_file_path)?;
@@ -122,7 +144,7 @@ impl Caddy {
         Ok(())
     }
 
-    fn write_caddyfile(&self, domains: &[String]) -> Result<(), Error> {
+    fn write_caddyfile(&self, domains: &[String], local_server_port: u16) -> Result<(), Error> {
         let mut redis_storage = String::new();
 
         if let Ok(redis_url) = std::env::var("LINKUP_CERT_STORAGE_REDIS_URL") {
@@ -170,7 +192,7 @@ impl Caddy {
             self.stdout_file_path.display(),
             redis_storage,
             domains.join(", "),
-            LINKUP_LOCAL_SERVER_PORT,
+            local_server_port,
             LINKUP_CF_TLS_API_ENV_VAR,
         );
 
@@ -244,7 +266,7 @@ impl BackgroundService<Error> for Caddy {
             return Ok(());
         }
 
-        if let Err(e) = self.start(domains) {
+        if let Err(e) = self.start(domains, state.local_server_port()) {
             self.notify_update_with_details(
                 &status_sender,
                 super::RunStatus::Error,
@@ -1,4 +1,5 @@
 mod paid_tunnel;
+mod propagation;
 
 use std::{
     env,
@@ -9,19 +10,27 @@ use std::{
     time::Duration,
 };
 
-use hickory_resolver::{
-    config::{ResolverConfig, ResolverOpts},
-    proto::rr::RecordType,
-    TokioAsyncResolver,
-};
 use log::{debug, error};
 use regex::Regex;
 use tokio::time::sleep;
 use url::Url;
 
-use crate::{linkup_file_path, local_config::LocalState, signal};
+use crate::{
+    linkup_file_path,
+    local_config::LocalState,
+    signal,
+    system::{RealSystem, System},
+    CliError,
+};
+
+use super::{service_env, BackgroundService};
 
-use super::{local_server::LINKUP_LOCAL_SERVER_PORT, BackgroundService};
+/// Domain of the Cloudflare zone that paid tunnels get their DNS records in.
+const LINKUP_CLOUDFLARE_TUNNEL_DOMAIN_ENV: &str = "LINKUP_CLOUDFLARE_TUNNEL_DOMAIN";
+// Used when the tunnel domain isn't set, for setups from before it was configurable
+const DEFAULT_TUNNEL_DOMAIN: &str = "mentimeter.dev";
+// Longest label allowed in a DNS name
+const MAX_DNS_LABEL_LENGTH: usize = 63;
 
 #[derive(thiserror::Error, Debug)]
 #[allow(dead_code)]
@@ -40,6 +49,8 @@ pub enum Error {
     DNSNotPropagated,
     #[error("Invalid session name: '{0}'")]
     InvalidSessionName(String),
+    #[error("Failed to set up paid tunnel: {0}")]
+    PaidTunnel(#[from] CliError),
 }
 
 pub struct CloudflareTunnel {
@@ -49,6 +60,9 @@ pub struct CloudflareTunnel {
 }
 
 impl CloudflareTunnel {
+    /// Value of `LINKUP_SERVICE_ID` for the cloudflared process.
+    pub const SERVICE_ID: &str = "linkup-cloudflared";
+
     pub fn new() -> Self {
         Self {
             stdout_file_path: linkup_file_path("cloudflared-stdout"),
@@ -63,13 +77,20 @@ impl CloudflareTunnel {
             && env::var("LINKUP_CF_API_TOKEN").is_ok()
     }
 
-    fn start_free(&self) -> Result<(), Error> {
+    /// The domain of `LINKUP_CLOUDFLARE_ZONE_ID`, which paid tunnel hostnames are created under.
+    pub fn paid_tunnel_domain() -> String {
+        env::var(LINKUP_CLOUDFLARE_TUNNEL_DOMAIN_ENV)
+            .ok()
+            .filter(|domain| !domain.is_empty())
+            .unwrap_or_else(|| DEFAULT_TUNNEL_DOMAIN.to_string())
+    }
+
+    fn start_free(&self, target: &str) -> Result<(), Error> {
         let stdout_file = File::create(&self.stdout_file_path)?;
         let stderr_file = File::create(&self.stderr_file_path)?;
 
-        let url = format!("http://localhost:{}", LINKUP_LOCAL_SERVER_PORT);
-
         process::Command::new("cloudflared")
+            .envs(service_env(Self::SERVICE_ID))
             .process_group(0)
             .stdout(stdout_file)
             .stderr(stderr_file)
@@ -77,7 +98,7 @@ impl CloudflareTunnel {
             .args([
                 "tunnel",
                 "--url",
-                &url,
+                target,
                 "--pidfile",
                 self.pidfile_path.to_str().unwrap(),
             ])
@@ -86,7 +107,14 @@ impl CloudflareTunnel {
         Ok(())
     }
 
-    async fn start_paid(&self, linkup_session_name: &str) -> Result<(), Error> {
+    /// Starts a named tunnel, creating it first if needed. `sys` is where the Cloudflare
+    /// account id and credentials are read from.
+    async fn start_paid(
+        &self,
+        sys: &dyn System,
+        linkup_session_name: &str,
+        target: &str,
+    ) -> Result<(), Error> {
         let stdout_file = File::create(&self.stdout_file_path)?;
         let stderr_file = File::create(&self.stderr_file_path)?;
 
@@ -95,13 +123,10 @@ impl CloudflareTunnel {
             linkup_session_name
         );
 
-        let tunnel_name = format!("tunnel-{}", linkup_session_name);
-        let mut tunnel_id = match paid_tunnel::get_tunnel_id(&tunnel_name).await {
-            Ok(Some(id)) => id,
-            Ok(None) => "".to_string(),
-            // Err(e) => return Err(e),
-            Err(e) => panic!("{}", e),
-        };
+        let tunnel_name = paid_tunnel_name(linkup_session_name)?;
+        let mut tunnel_id = paid_tunnel::get_tunnel_id(sys, &tunnel_name)
+            .await?
+            .unwrap_or_default();
 
         let mut create_tunnel = false;
 
@@ -130,19 +155,20 @@ impl CloudflareTunnel {
         if create_tunnel {
             log::debug!("Creating tunnel...");
 
-            tunnel_id = paid_tunnel::create_tunnel(&tunnel_name).await.unwrap();
-            paid_tunnel::create_dns_record(&tunnel_id, &tunnel_name)
-                .await
-                .unwrap();
+            tunnel_id = paid_tunnel::create_tunnel(&tunnel_name, target).await?;
+            paid_tunnel::create_dns_record(&tunnel_id, &tunnel_name).await?;
         }
 
         process::Command::new("cloudflared")
+            .envs(service_env(Self::SERVICE_ID))
             .process_group(0)
             .stdout(stdout_file)
             .stderr(stderr_file)
             .stdin(Stdio::null())
             .args([
                 "tunnel",
+                "--url",
+                target,
                 "--pidfile",
                 self.pidfile_path.to_str().unwrap(),
                 "run",
@@ -167,10 +193,7 @@ impl CloudflareTunnel {
 
     fn url(&self, linkup_session_name: &str) -> Result<Url, Error> {
         if Self::use_paid_tunnels() {
-            Ok(Url::parse(
-                format!("https://tunnel-{}.mentimeter.dev", linkup_session_name).as_str(),
-            )
-            .expect("Failed to parse tunnel URL"))
+            paid_tunnel_url(linkup_session_name, &Self::paid_tunnel_domain())
         } else {
             let tunnel_url_re = Regex::new(r"https://[a-zA-Z0-9-]+\.trycloudflare\.com")
                 .expect("Failed to compile regex");
@@ -187,11 +210,6 @@ impl CloudflareTunnel {
     }
 
     async fn dns_propagated(&self, linkup_session_name: &str) -> bool {
-        let mut opts = ResolverOpts::default();
-        opts.cache_size = 0; // Disable caching
-
-        let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), opts);
-
         let url = match self.url(linkup_session_name) {
             Ok(url) => url,
             Err(_) => return false,
@@ -199,21 +217,16 @@ impl CloudflareTunnel {
 
         let domain = url.host_str().unwrap();
 
-        let response = resolver.lookup(domain, RecordType::A).await;
-
-        if let Ok(lookup) = response {
-            let addresses = lookup.iter().collect::<Vec<_>>();
-
-            if !addresses.is_empty() {
-                log::debug!("DNS has propogated for {}.", domain);
+        let propagated =
+            propagation::resolved_by_quorum(&propagation::public_resolvers(), domain).await;
 
-                return true;
-            }
+        if propagated {
+            log::debug!("DNS has propogated for {}.", domain);
         } else {
             log::debug!("DNS {} not propagated yet.", domain);
         }
 
-        false
+        propagated
     }
 
     fn update_state(&self, state: &mut LocalState) -> Result<(), Error> {
@@ -258,6 +271,19 @@ impl BackgroundService<Error> for CloudflareTunnel {
             return Err(Error::InvalidSessionName(state.linkup.session_name.clone()));
         }
 
+        // The paid tunnel name ends up as a DNS label, check it before creating anything
+        if Self::use_paid_tunnels() {
+            if let Err(e) = paid_tunnel_name(&state.linkup.session_name) {
+                self.notify_update_with_details(
+                    &status_sender,
+                    super::RunStatus::Error,
+                    "Invalid session name",
+                );
+
+                return Err(e);
+            }
+        }
+
         if self.running_pid().is_some() {
             self.notify_update_with_details(
                 &status_sender,
@@ -271,7 +297,14 @@ impl BackgroundService<Error> for CloudflareTunnel {
         if Self::use_paid_tunnels() {
             self.notify_update_with_details(&status_sender, super::RunStatus::Starting, "Paid");
 
-            if let Err(e) = self.start_paid(&state.linkup.session_name).await {
+            if let Err(e) = self
+                .start_paid(
+                    &RealSystem,
+                    &state.linkup.session_name,
+                    &tunnel_target(state),
+                )
+                .await
+            {
                 self.notify_update_with_details(
                     &status_sender,
                     super::RunStatus::Error,
@@ -283,7 +316,7 @@ impl BackgroundService<Error> for CloudflareTunnel {
         } else {
             self.notify_update_with_details(&status_sender, super::RunStatus::Starting, "Free");
 
-            if let Err(e) = self.start_free() {
+            if let Err(e) = self.start_free(&tunnel_target(state)) {
                 self.notify_update_with_details(
                     &status_sender,
                     super::RunStatus::Error,
@@ -373,6 +406,15 @@ impl BackgroundService<Error> for CloudflareTunnel {
     }
 }
 
+/// The url the tunnel forwards to, as cloudflared expects it (without a trailing slash).
+fn tunnel_target(state: &LocalState) -> String {
+    state
+        .local_server_url()
+        .as_str()
+        .trim_end_matches('/')
+        .to_string()
+}
+
 pub fn is_installed() -> bool {
     let res = Command::new("which")
         .args(["cloudflared"])
@@ -384,3 +426,137 @@ pub fn is_installed() -> bool {
 
     res.success()
 }
+
+fn paid_tunnel_url(linkup_session_name: &str, domain: &str) -> Result<Url, Error> {
+    let tunnel_name = paid_tunnel_name(linkup_session_name)?;
+
+    Url::parse(&format!("https://{}.{}", tunnel_name, domain))
+        .map_err(|_| Error::InvalidSessionName(linkup_session_name.to_string()))
+}
+
+/// Name of the paid tunnel for a session, which is also the DNS label of its hostname.
+fn paid_tunnel_name(linkup_session_name: &str) -> Result<String, Error> {
+    let tunnel_name = format!("tunnel-{}", linkup_session_name);
+
+    let is_dns_label = !linkup_session_name.is_empty()
+        && tunnel_name.len() <= MAX_DNS_LABEL_LENGTH
+        && !tunnel_name.ends_with('-')
+        && tunnel_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+    if is_dns_label {
+        Ok(tunnel_name)
+    } else {
+        Err(Error::InvalidSessionName(linkup_session_name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate;
+
+    use crate::{
+        local_config::{config_to_state, LocalServerConfig, LocalServerScheme},
+        system::MockSystem,
+    };
+
+    use super::*;
+
+    fn test_state() -> LocalState {
+        let config = serde_yaml::from_str(
+            "linkup:\n  remote: https://remote-linkup.example.com\nservices: []\ndomains: []\n",
+        )
+        .unwrap();
+
+        config_to_state(config, "./config.yaml".to_string(), false, false)
+    }
+
+    #[test]
+    fn test_tunnel_target_defaults_to_http_localhost() {
+        let state = test_state();
+
+        assert_eq!(tunnel_target(&state), "http://localhost:9066");
+    }
+
+    #[test]
+    fn test_tunnel_target_follows_local_server_config() {
+        let mut state = test_state();
+        state.linkup.local_server_port = Some(9443);
+        state.linkup.local_server = Some(LocalServerConfig {
+            scheme: Some(LocalServerScheme::Https),
+            host: Some("host.docker.internal".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(tunnel_target(&state), "https://host.docker.internal:9443");
+    }
+
+    #[test]
+    fn test_paid_tunnel_url_uses_configured_domain() {
+        let url = paid_tunnel_url("tiny-cow", "tunnels.example.org").unwrap();
+        assert_eq!(url.as_str(), "https://tunnel-tiny-cow.tunnels.example.org/");
+
+        let url = paid_tunnel_url("tiny-cow", DEFAULT_TUNNEL_DOMAIN).unwrap();
+        assert_eq!(url.as_str(), "https://tunnel-tiny-cow.mentimeter.dev/");
+    }
+
+    #[test]
+    fn test_paid_tunnel_name_is_a_dns_label() {
+        assert_eq!(paid_tunnel_name("tiny-cow").unwrap(), "tunnel-tiny-cow");
+        assert_eq!(paid_tunnel_name(&"a".repeat(56)).unwrap().len(), 63);
+
+        for name in [
+            "",
+            "tiny_cow",
+            "tiny.cow",
+            "tiny cow",
+            "tiny-cow-",
+            "tïny-cow",
+        ] {
+            assert!(
+                matches!(paid_tunnel_name(name), Err(Error::InvalidSessionName(n)) if n == name),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_paid_tunnel_name_rejects_long_names() {
+        let name = "a".repeat(57);
+
+        assert!(matches!(
+            paid_tunnel_name(&name),
+            Err(Error::InvalidSessionName(n)) if n == name
+        ));
+        assert!(paid_tunnel_url(&name, DEFAULT_TUNNEL_DOMAIN).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start_paid_returns_tunnel_lookup_error() {
+        let dir = env::temp_dir().join(format!("linkup-paid-tunnel-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tunnel = CloudflareTunnel {
+            stdout_file_path: dir.join("cloudflared-stdout"),
+            stderr_file_path: dir.join("cloudflared-stderr"),
+            pidfile_path: dir.join("cloudflared-pid"),
+        };
+
+        // Without an account id the lookup fails before sending any request
+        let mut sys = MockSystem::new();
+        sys.expect_get_env()
+            .with(predicate::eq("LINKUP_CLOUDFLARE_ACCOUNT_ID"))
+            .returning(|key| Err(CliError::GetEnvVar(key.to_string())));
+        let result = tunnel
+            .start_paid(&sys, "tiny-cow", "http://localhost:9066")
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::PaidTunnel(CliError::GetEnvVar(var))) if var == "LINKUP_CLOUDFLARE_ACCOUNT_ID"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,22 +3,27 @@ use std::{env, fs, path::Path};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 
 use crate::system::{FileLike, RealSystem, System};
-use crate::{CliError, LINKUP_LOCALSERVER_PORT};
+use crate::CliError;
 use serde::{Deserialize, Serialize};
 
 use base64::prelude::*;
-use rand::Rng;
 
 #[derive(Serialize, Deserialize, Debug)]
-struct GetTunnelApiResponse {
-    result: Vec<TunnelResultItem>,
+struct ListTunnelsApiResponse {
+    result: Vec<Tunnel>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TunnelResultItem {
-    id: String,
-    name: String,
-    deleted_at: Option<String>,
+/// A Cloudflare tunnel, as returned by the `cfd_tunnel` endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tunnel {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    pub deleted_at: Option<String>,
+    /// One of `inactive`, `degraded`, `healthy` or `down`.
+    #[serde(default)]
+    pub status: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -46,7 +51,7 @@ struct DNSRecord {
 }
 #[derive(Serialize, Deserialize, Debug)]
 struct CreateTunnelResponse {
-    result: TunnelResultItem,
+    result: Tunnel,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -57,34 +62,43 @@ struct Config {
     credentials_file: String,
 }
 
-pub async fn get_tunnel_id(tunnel_name: &str) -> Result<Option<String>, CliError> {
-    let account_id = env::var("LINKUP_CLOUDFLARE_ACCOUNT_ID")
+/// Lists the tunnels of the account, only the ones called `name` if given. Deleted tunnels are
+/// included too, see `Tunnel::deleted_at`.
+pub async fn list_tunnels(sys: &dyn System, name: Option<&str>) -> Result<Vec<Tunnel>, CliError> {
+    let account_id = sys
+        .get_env("LINKUP_CLOUDFLARE_ACCOUNT_ID")
         .map_err(|_| CliError::GetEnvVar("LINKUP_CLOUDFLARE_ACCOUNT_ID".to_string()))?;
     let url = format!(
         "https://api.cloudflare.com/client/v4/accounts/{}/cfd_tunnel",
         account_id
     );
-    let (client, headers) = prepare_client_and_headers(&RealSystem)?;
-    let query_url = format!("{}?name={}", url, tunnel_name);
+    let (client, headers) = prepare_client_and_headers(sys)?;
+    let query_url = match name {
+        Some(name) => format!("{}?name={}", url, name),
+        None => url,
+    };
 
-    let parsed: GetTunnelApiResponse =
+    let parsed: ListTunnelsApiResponse =
         send_request(&client, &query_url, headers, None, "GET").await?;
-    if parsed.result.is_empty() {
-        Ok(None)
-    } else {
-        // Check if there exists a tunnel with this name that hasn't been deleted
-        match parsed
-            .result
-            .iter()
-            .find(|tunnel| tunnel.deleted_at.is_none())
-        {
-            Some(tunnel) => Ok(Some(tunnel.id.clone())),
-            None => Ok(None),
-        }
-    }
+
+    Ok(parsed.result)
 }
 
-pub async fn create_tunnel(tunnel_name: &str) -> Result<String, CliError> {
+pub async fn get_tunnel_id(
+    sys: &dyn System,
+    tunnel_name: &str,
+) -> Result<Option<String>, CliError> {
+    let tunnels = list_tunnels(sys, Some(tunnel_name)).await?;
+
+    Ok(find_active_tunnel(&tunnels).map(|tunnel| tunnel.id.clone()))
+}
+
+// Check if there exists a tunnel that hasn't been deleted
+fn find_active_tunnel(tunnels: &[Tunnel]) -> Option<&Tunnel> {
+    tunnels.iter().find(|tunnel| tunnel.deleted_at.is_none())
+}
+
+pub async fn create_tunnel(tunnel_name: &str, target: &str) -> Result<String, CliError> {
     let tunnel_secret = generate_tunnel_secret();
     let account_id = env::var("LINKUP_CLOUDFLARE_ACCOUNT_ID")
         .map_err(|_| CliError::GetEnvVar("LINKUP_CLOUDFLARE_ACCOUNT_ID".to_string()))?;
@@ -107,7 +121,7 @@ pub async fn create_tunnel(tunnel_name: &str) -> Result<String, CliError> {
             err.to_string(),
         )
     })?;
-    create_config_yml(&RealSystem, &parsed.result.id).map_err(|err| {
+    create_config_yml(&RealSystem, &parsed.result.id, target).map_err(|err| {
         CliError::FileErr("Failed to create config YML".to_string(), err.to_string())
     })?;
 
@@ -196,10 +210,9 @@ async fn send_request<T: for<'de> serde::Deserialize<'de>>(
     }
 }
 
+// Unlike `linkup::generate_secret`, cloudflared expects the tunnel secret in standard base64.
 fn generate_tunnel_secret() -> String {
-    let mut rng = rand::thread_rng();
-    let random_bytes: [u8; 32] = rng.gen();
-    BASE64_STANDARD.encode(random_bytes)
+    BASE64_STANDARD.encode(linkup::random_bytes::<32>())
 }
 
 fn save_tunnel_credentials(
@@ -243,7 +256,7 @@ fn save_tunnel_credentials(
     Ok(())
 }
 
-fn create_config_yml(sys: &dyn System, tunnel_id: &str) -> Result<(), CliError> {
+fn create_config_yml(sys: &dyn System, tunnel_id: &str, target: &str) -> Result<(), CliError> {
     // Determine the directory path
     let home_dir = sys
         .get_env("HOME")
@@ -263,7 +276,7 @@ fn create_config_yml(sys: &dyn System, tunnel_id: &str) -> Result<(), CliError>
     let file_path_str = file_path.to_string_lossy().to_string();
 
     let config = Config {
-        url: format!("http://localhost:{}", LINKUP_LOCALSERVER_PORT),
+        url: target.to_string(),
         tunnel: tunnel_id.to_string(),
         credentials_file: file_path_str,
     };
@@ -383,7 +396,7 @@ mod tests {
             .with(predicate::always(), predicate::eq(content))
             .returning(|_, _| Ok(()));
 
-        let result = create_config_yml(&mock_sys, "TUNNEL_ID");
+        let result = create_config_yml(&mock_sys, "TUNNEL_ID", "http://localhost:9066");
         assert!(result.is_ok());
     }
 
@@ -418,7 +431,7 @@ mod tests {
             .with(predicate::always(), predicate::eq(content))
             .returning(|_, _| Ok(()));
 
-        let result = create_config_yml(&mock_sys, "TUNNEL_ID");
+        let result = create_config_yml(&mock_sys, "TUNNEL_ID", "http://localhost:9066");
         assert!(result.is_ok());
     }
 
@@ -457,6 +470,56 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_list_tunnels_response() {
+        let body = r#"{
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result": [
+                {
+                    "id": "f70ff985-a4ef-4643-bbbc-4a0ed4fc8415",
+                    "account_tag": "699d98642c564d2e855e9661899b7252",
+                    "created_at": "2024-01-01T10:00:00Z",
+                    "deleted_at": "2024-01-02T10:00:00Z",
+                    "name": "tunnel-tiny-cow",
+                    "status": "inactive"
+                },
+                {
+                    "id": "0d5f14a4-23ab-4d6a-a3b4-33d2c6a1a4e1",
+                    "account_tag": "699d98642c564d2e855e9661899b7252",
+                    "created_at": "2024-01-03T10:00:00Z",
+                    "deleted_at": null,
+                    "name": "tunnel-tiny-cow",
+                    "status": "healthy",
+                    "connections": []
+                }
+            ],
+            "result_info": { "page": 1, "per_page": 20, "count": 2, "total_count": 2 }
+        }"#;
+
+        let parsed: ListTunnelsApiResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed.result.len(), 2);
+
+        let active = find_active_tunnel(&parsed.result).unwrap();
+        assert_eq!(active.id, "0d5f14a4-23ab-4d6a-a3b4-33d2c6a1a4e1");
+        assert_eq!(active.status.as_deref(), Some("healthy"));
+        assert_eq!(active.created_at.as_deref(), Some("2024-01-03T10:00:00Z"));
+    }
+
+    #[test]
+    fn test_find_active_tunnel_all_deleted() {
+        let tunnels = vec![Tunnel {
+            id: "TUNNEL_ID".to_string(),
+            name: "tunnel-tiny-cow".to_string(),
+            created_at: None,
+            deleted_at: Some("2024-01-02T10:00:00Z".to_string()),
+            status: None,
+        }];
+
+        assert_eq!(find_active_tunnel(&tunnels), None);
+    }
+
     #[test]
     fn test_generate_tunnel_secret() {
         let secret = generate_tunnel_secret();
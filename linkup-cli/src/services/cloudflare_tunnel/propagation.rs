@@ -0,0 +1,119 @@
+use futures::future::join_all;
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    proto::rr::RecordType,
+    TokioAsyncResolver,
+};
+
+pub trait HostResolver {
+    async fn resolves(&self, host: &str) -> bool;
+}
+
+impl HostResolver for TokioAsyncResolver {
+    async fn resolves(&self, host: &str) -> bool {
+        match self.lookup(host, RecordType::A).await {
+            Ok(lookup) => lookup.iter().next().is_some(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Resolvers of a few public DNS providers, since records don't reach all of them at once.
+pub fn public_resolvers() -> Vec<TokioAsyncResolver> {
+    let mut opts = ResolverOpts::default();
+    opts.cache_size = 0; // Disable caching
+
+    [
+        ResolverConfig::google(),
+        ResolverConfig::cloudflare(),
+        ResolverConfig::quad9(),
+    ]
+    .into_iter()
+    .map(|config| TokioAsyncResolver::tokio(config, opts.clone()))
+    .collect()
+}
+
+/// Whether a majority of `resolvers` resolve `host`. The resolvers are queried concurrently.
+pub async fn resolved_by_quorum<R: HostResolver>(resolvers: &[R], host: &str) -> bool {
+    let results = join_all(resolvers.iter().map(|resolver| resolver.resolves(host))).await;
+    let resolved = results.into_iter().filter(|resolved| *resolved).count();
+
+    log::debug!(
+        "{} resolved by {} of {} resolvers",
+        host,
+        resolved,
+        resolvers.len()
+    );
+
+    resolved * 2 > resolvers.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counters {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    struct MockResolver<'a> {
+        resolves: bool,
+        counters: &'a Counters,
+    }
+
+    impl HostResolver for MockResolver<'_> {
+        async fn resolves(&self, _host: &str) -> bool {
+            let in_flight = self.counters.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.counters
+                .max_in_flight
+                .fetch_max(in_flight, Ordering::SeqCst);
+            sleep(Duration::from_millis(10)).await;
+            self.counters.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            self.resolves
+        }
+    }
+
+    fn mock_resolvers<'a>(results: &[bool], counters: &'a Counters) -> Vec<MockResolver<'a>> {
+        results
+            .iter()
+            .map(|resolves| MockResolver {
+                resolves: *resolves,
+                counters,
+            })
+            .collect()
+    }
+
+    async fn quorum(results: &[bool]) -> bool {
+        let counters = Counters::default();
+
+        resolved_by_quorum(&mock_resolvers(results, &counters), "example.com").await
+    }
+
+    #[tokio::test]
+    async fn test_resolved_by_quorum() {
+        assert!(quorum(&[true, true, true]).await);
+        assert!(quorum(&[true, false, true]).await);
+        assert!(!quorum(&[false, true, false]).await);
+        assert!(!quorum(&[true, false, true, false]).await);
+        assert!(!quorum(&[]).await);
+    }
+
+    #[tokio::test]
+    async fn test_resolvers_are_queried_concurrently() {
+        let counters = Counters::default();
+        let resolvers = mock_resolvers(&[true, true, true], &counters);
+
+        assert!(resolved_by_quorum(&resolvers, "example.com").await);
+        assert_eq!(counters.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+}
@@ -3,13 +3,15 @@ use std::{
     fs,
     path::PathBuf,
     process::{Command, Stdio},
+    sync,
 };
 
 use crate::{
     commands::local_dns, linkup_dir_path, linkup_file_path, local_config::LocalState, signal,
+    LINKUP_LOCALDNS_PORT,
 };
 
-use super::BackgroundService;
+use super::{service_env, BackgroundService, RunStatus, RunUpdate};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -17,6 +19,10 @@ pub enum Error {
     FileHandling(#[from] std::io::Error),
     #[error("Failed to stop pid: {0}")]
     StoppingPid(#[from] signal::PidError),
+    #[error("dnsmasq exited with {0}: {1}")]
+    StartFailed(std::process::ExitStatus, String),
+    #[error("There is no session to resolve the domains of")]
+    MissingSessionName,
 }
 
 pub struct Dnsmasq {
@@ -27,9 +33,12 @@ pub struct Dnsmasq {
 }
 
 impl Dnsmasq {
+    /// Value of `LINKUP_SERVICE_ID` for the dnsmasq process.
+    pub const SERVICE_ID: &str = "linkup-dnsmasq";
+
     pub fn new() -> Self {
         Self {
-            port: 8053,
+            port: LINKUP_LOCALDNS_PORT,
             config_file_path: linkup_file_path("dnsmasq-conf"),
             log_file_path: linkup_file_path("dnsmasq-log"),
             pid_file_path: linkup_file_path("dnsmasq-pid"),
@@ -37,6 +46,11 @@ impl Dnsmasq {
     }
 
     fn setup(&self, domains: &[String], linkup_session_name: &str) -> Result<(), Error> {
+        // Without a session name the entries would cover every subdomain of the domains
+        if linkup_session_name.is_empty() {
+            return Err(Error::MissingSessionName);
+        }
+
         let local_domains_template = domains.iter().fold(String::new(), |mut acc, d| {
             let _ = write!(
                 acc,
@@ -63,19 +77,54 @@ pid-file={}\n",
         Ok(())
     }
 
-    fn start(&self) -> Result<(), Error> {
-        log::debug!("Starting {}", Self::NAME);
-
-        Command::new("dnsmasq")
+    fn command(&self) -> Command {
+        let mut command = Command::new("dnsmasq");
+        command
             .current_dir(linkup_dir_path())
+            .envs(service_env(Self::SERVICE_ID))
             .arg("--log-queries")
             .arg("-C")
-            .arg(&self.config_file_path)
+            .arg(&self.config_file_path);
+
+        command
+    }
+
+    /// Run the (daemonizing) dnsmasq command and report how it went. dnsmasq only forks into the
+    /// background once its sockets are bound, so a non-zero exit usually means the port is taken.
+    fn start_with_progress(
+        &self,
+        mut command: Command,
+        status_sender: &sync::mpsc::Sender<RunUpdate>,
+    ) -> Result<(), Error> {
+        log::debug!("Starting {}", Self::NAME);
+
+        let result = command
+            .stdin(Stdio::null())
             .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
+            .output()
+            .map_err(Error::from)
+            .and_then(|output| match output.status.success() {
+                true => Ok(()),
+                false => Err(Error::StartFailed(
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                )),
+            });
+
+        match &result {
+            Ok(_) => self.notify_update(status_sender, RunStatus::Started),
+            Err(Error::StartFailed(_, stderr)) if stderr.contains("listening socket") => self
+                .notify_update_with_details(
+                    status_sender,
+                    RunStatus::Error,
+                    format!("Failed to bind port {}", self.port),
+                ),
+            Err(_) => {
+                self.notify_update_with_details(status_sender, RunStatus::Error, "Failed to start")
+            }
+        }
 
-        Ok(())
+        result
     }
 
     pub fn stop(&self) -> Result<(), Error> {
@@ -144,28 +193,16 @@ impl BackgroundService<Error> for Dnsmasq {
         }
 
         if let Err(e) = self.setup(domains, &state.linkup.session_name) {
-            self.notify_update_with_details(
-                &status_sender,
-                super::RunStatus::Error,
-                "Failed to setup",
-            );
-
-            return Err(e);
-        }
-
-        if let Err(e) = self.start() {
-            self.notify_update_with_details(
-                &status_sender,
-                super::RunStatus::Error,
-                "Failed to start",
-            );
+            let details = match e {
+                Error::MissingSessionName => "No session",
+                _ => "Failed to setup",
+            };
+            self.notify_update_with_details(&status_sender, super::RunStatus::Error, details);
 
             return Err(e);
         }
 
-        self.notify_update(&status_sender, super::RunStatus::Started);
-
-        Ok(())
+        self.start_with_progress(self.command(), &status_sender)
     }
 }
 
@@ -180,3 +217,90 @@ pub fn is_installed() -> bool {
 
     res.success()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn test_dnsmasq(dir: &std::path::Path) -> Dnsmasq {
+        Dnsmasq {
+            port: LINKUP_LOCALDNS_PORT,
+            config_file_path: dir.join("dnsmasq-conf"),
+            log_file_path: dir.join("dnsmasq-log"),
+            pid_file_path: dir.join("dnsmasq-pid"),
+        }
+    }
+
+    #[test]
+    fn test_setup_resolves_session_subdomains() {
+        let dir = env::temp_dir().join(format!("linkup-dnsmasq-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dnsmasq = test_dnsmasq(&dir);
+
+        dnsmasq
+            .setup(&["example.com".to_string()], "mysession")
+            .unwrap();
+        let config = fs::read_to_string(dir.join("dnsmasq-conf")).unwrap();
+
+        let missing_session = dnsmasq.setup(&["example.com".to_string()], "");
+        let config_after_missing_session = fs::read_to_string(dir.join("dnsmasq-conf")).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(config.contains("address=/mysession.example.com/127.0.0.1\n"));
+        assert!(config.contains("address=/mysession.example.com/::1\n"));
+        assert!(config.contains("local=/mysession.example.com/\n"));
+        assert!(config.contains("port=8053\n"));
+
+        assert!(matches!(missing_session, Err(Error::MissingSessionName)));
+        assert_eq!(config_after_missing_session, config);
+    }
+
+    fn fake_dnsmasq(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    fn statuses(receiver: &sync::mpsc::Receiver<RunUpdate>) -> Vec<(String, Option<String>)> {
+        receiver
+            .try_iter()
+            .map(|update| (update.status.to_string(), update.details))
+            .collect()
+    }
+
+    #[test]
+    fn test_reports_started_once_bound() {
+        let (sender, receiver) = sync::mpsc::channel();
+
+        let result = Dnsmasq::new().start_with_progress(fake_dnsmasq("exit 0"), &sender);
+
+        assert!(result.is_ok());
+        assert_eq!(statuses(&receiver), vec![("started".to_string(), None)]);
+    }
+
+    #[test]
+    fn test_reports_error_on_bind_failure() {
+        let (sender, receiver) = sync::mpsc::channel();
+
+        let result = Dnsmasq::new().start_with_progress(
+            fake_dnsmasq(
+                "echo 'dnsmasq: failed to create listening socket for port 8053: Address already in use' >&2; exit 2",
+            ),
+            &sender,
+        );
+
+        assert!(
+            matches!(result, Err(Error::StartFailed(_, stderr)) if stderr.contains("Address already in use"))
+        );
+        assert_eq!(
+            statuses(&receiver),
+            vec![(
+                "error".to_string(),
+                Some("Failed to bind port 8053".to_string())
+            )]
+        );
+    }
+}
@@ -1,14 +1,13 @@
 use std::{
     env,
     fs::File,
+    net::{IpAddr, Ipv4Addr, TcpListener},
     os::unix::process::CommandExt,
     path::PathBuf,
     process::{self, Stdio},
     time::Duration,
 };
 
-use reqwest::StatusCode;
-use tokio::time::sleep;
 use url::Url;
 
 use crate::{
@@ -17,7 +16,7 @@ use crate::{
     signal, worker_client,
 };
 
-use super::BackgroundService;
+use super::{service_env, wait_until_ready, BackgroundService, Probe};
 
 pub const LINKUP_LOCAL_SERVER_PORT: u16 = 9066;
 
@@ -37,23 +36,99 @@ pub struct LocalServer {
     stdout_file_path: PathBuf,
     stderr_file_path: PathBuf,
     pidfile_path: PathBuf,
+    expose: bool,
 }
 
 impl LocalServer {
+    /// Value of `LINKUP_SERVICE_ID` for the local server process.
+    pub const SERVICE_ID: &str = "linkup-local-server";
+
     pub fn new() -> Self {
         Self {
             stdout_file_path: linkup_file_path("localserver-stdout"),
             stderr_file_path: linkup_file_path("localserver-stderr"),
             pidfile_path: linkup_file_path("localserver-pid"),
+            expose: false,
+        }
+    }
+
+    /// Listen on all interfaces instead of only on loopback.
+    pub fn with_expose(mut self, expose: bool) -> Self {
+        self.expose = expose;
+        self
+    }
+
+    pub fn url(port: u16) -> Url {
+        Url::parse(&format!("http://localhost:{}", port)).expect("linkup url invalid")
+    }
+
+    fn bind_address(&self) -> IpAddr {
+        match self.expose {
+            true => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            false => IpAddr::V4(Ipv4Addr::LOCALHOST),
         }
     }
 
-    pub fn url() -> Url {
-        Url::parse(&format!("http://localhost:{}", LINKUP_LOCAL_SERVER_PORT))
-            .expect("linkup url invalid")
+    /// Pick the port to start the server on: `preferred` when it is free, otherwise whatever
+    /// free port the OS hands out. The choice is recorded on the state, so that the tunnel, Caddy
+    /// and `linkup status` point at the actual port.
+    fn allocate_port(&self, state: &mut LocalState, preferred: u16) -> Result<u16, Error> {
+        let port = match TcpListener::bind((self.bind_address(), preferred)) {
+            Ok(_) => preferred,
+            Err(_) => TcpListener::bind((self.bind_address(), 0))?
+                .local_addr()?
+                .port(),
+        };
+
+        if port != preferred {
+            log::debug!("Port {} is taken, using port {} instead", preferred, port);
+        }
+
+        state.linkup.local_server_port = Some(port);
+        state
+            .save()
+            .expect("failed to update local state file with local server port");
+
+        Ok(port)
+    }
+
+    /// Arguments of `linkup server`, with the options from the `linkup` section of the config.
+    fn server_args(&self, port: u16, state: &LocalState) -> Vec<String> {
+        let mut args = vec![
+            "server".to_string(),
+            "--pidfile".to_string(),
+            self.pidfile_path.to_string_lossy().into_owned(),
+            "--port".to_string(),
+            port.to_string(),
+        ];
+
+        if self.expose {
+            args.push("--expose".to_string());
+        }
+
+        if let Some(sources) = &state.linkup.trusted_session_sources {
+            let sources: Vec<String> = sources.iter().map(|source| source.to_string()).collect();
+            args.push("--trusted-session-sources".to_string());
+            args.push(sources.join(","));
+        }
+
+        let config = state.linkup.local_server.clone().unwrap_or_default();
+        let tls_args = [
+            ("--client-cert", config.client_cert),
+            ("--client-key", config.client_key),
+            ("--upstream-ca", config.upstream_ca),
+        ];
+        for (flag, path) in tls_args {
+            if let Some(path) = path {
+                args.push(flag.to_string());
+                args.push(path);
+            }
+        }
+
+        args
     }
 
-    fn start(&self) -> Result<(), Error> {
+    fn start(&self, port: u16, state: &LocalState) -> Result<(), Error> {
         log::debug!("Starting {}", Self::NAME);
 
         let stdout_file = File::create(&self.stdout_file_path)?;
@@ -62,23 +137,16 @@ impl LocalServer {
         // When running with cargo (e.g. `cargo run -- start`), we should start the server also with cargo.
         let mut command = if env::var("CARGO").is_ok() {
             let mut cmd = process::Command::new("cargo");
-            cmd.args([
-                "run",
-                "--",
-                "server",
-                "--pidfile",
-                self.pidfile_path.to_str().unwrap(),
-            ]);
+            cmd.args(["run", "--"]);
 
             cmd
         } else {
-            let mut cmd = process::Command::new("linkup");
-            cmd.args(["server", "--pidfile", self.pidfile_path.to_str().unwrap()]);
-
-            cmd
+            process::Command::new("linkup")
         };
 
         command
+            .args(self.server_args(port, state))
+            .envs(service_env(Self::SERVICE_ID))
             .process_group(0)
             .stdout(stdout_file)
             .stderr(stderr_file)
@@ -99,16 +167,12 @@ impl LocalServer {
         signal::get_running_pid(&self.pidfile_path)
     }
 
-    async fn reachable(&self) -> bool {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(1))
-            .build()
-            .expect("failed while creating an HTTP client to check readiness of LocalServer");
-
-        let url = format!("{}linkup-check", Self::url());
-        let response = client.get(url).send().await;
-
-        matches!(response, Ok(res) if res.status() == StatusCode::OK)
+    fn probe(port: u16) -> Probe {
+        Probe::Http(
+            Self::url(port)
+                .join("linkup-check")
+                .expect("linkup url invalid"),
+        )
     }
 
     async fn update_state(&self, state: &mut LocalState) -> Result<(), Error> {
@@ -133,7 +197,7 @@ impl BackgroundService<Error> for LocalServer {
     ) -> Result<(), Error> {
         self.notify_update(&status_sender, super::RunStatus::Starting);
 
-        if self.reachable().await {
+        if Self::probe(state.local_server_port()).check().await {
             self.notify_update_with_details(
                 &status_sender,
                 super::RunStatus::Started,
@@ -143,7 +207,20 @@ impl BackgroundService<Error> for LocalServer {
             return Ok(());
         }
 
-        if let Err(e) = self.start() {
+        let port = match self.allocate_port(state, LINKUP_LOCAL_SERVER_PORT) {
+            Ok(port) => port,
+            Err(e) => {
+                self.notify_update_with_details(
+                    &status_sender,
+                    super::RunStatus::Error,
+                    "Failed to find a free port",
+                );
+
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = self.start(port, state) {
             self.notify_update_with_details(
                 &status_sender,
                 super::RunStatus::Error,
@@ -153,33 +230,28 @@ impl BackgroundService<Error> for LocalServer {
             return Err(e);
         }
 
-        let mut reachable = self.reachable().await;
-        let mut attempts: u8 = 0;
-        loop {
-            match (reachable, attempts) {
-                (true, _) => break,
-                (false, 0..10) => {
-                    sleep(Duration::from_millis(1000)).await;
-                    attempts += 1;
-
-                    self.notify_update_with_details(
-                        &status_sender,
-                        super::RunStatus::Starting,
-                        format!("Waiting for server... retry #{}", attempts),
-                    );
-
-                    reachable = self.reachable().await;
-                }
-                (false, 10..) => {
-                    self.notify_update_with_details(
-                        &status_sender,
-                        super::RunStatus::Error,
-                        "Failed to reach server",
-                    );
-
-                    return Err(Error::ServerUnreachable);
-                }
-            }
+        let reachable = wait_until_ready(
+            &Self::probe(port),
+            10,
+            Duration::from_millis(1000),
+            |attempt| {
+                self.notify_update_with_details(
+                    &status_sender,
+                    super::RunStatus::Starting,
+                    format!("Waiting for server... retry #{}", attempt),
+                )
+            },
+        )
+        .await;
+
+        if !reachable {
+            self.notify_update_with_details(
+                &status_sender,
+                super::RunStatus::Error,
+                "Failed to reach server",
+            );
+
+            return Err(Error::ServerUnreachable);
         }
 
         match self.update_state(state).await {
@@ -195,3 +267,102 @@ impl BackgroundService<Error> for LocalServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::local_config::config_to_state;
+
+    use super::*;
+
+    fn test_state() -> LocalState {
+        state_with_linkup_config("")
+    }
+
+    fn state_with_linkup_config(linkup_config: &str) -> LocalState {
+        let config = serde_yaml::from_str(&format!(
+            "linkup:\n  remote: https://remote-linkup.example.com\n{}services: []\ndomains: []\n",
+            linkup_config
+        ))
+        .unwrap();
+
+        config_to_state(config, "./config.yaml".to_string(), true, false)
+    }
+
+    #[test]
+    fn test_server_args_pass_trusted_session_sources() {
+        let local_server = LocalServer::new();
+
+        let args = local_server.server_args(9066, &test_state());
+        assert!(!args.contains(&"--trusted-session-sources".to_string()));
+
+        let state = state_with_linkup_config("  trusted_session_sources: [url, referer]\n");
+        let args = local_server.server_args(9066, &state);
+        assert!(args.ends_with(&[
+            "--trusted-session-sources".to_string(),
+            "url,referer".to_string()
+        ]));
+
+        let state = state_with_linkup_config("  locked_down: true\n");
+        let args = local_server.server_args(9066, &state);
+        assert!(args.ends_with(&[
+            "--trusted-session-sources".to_string(),
+            "url,tracestate".to_string()
+        ]));
+    }
+
+    #[test]
+    fn test_server_args_pass_certificates_relative_to_config() {
+        let state = state_with_linkup_config(
+            "  local_server:\n    client_cert: certs/client.pem\n    client_key: certs/client.key\n    upstream_ca: /etc/ssl/internal-ca.pem\n",
+        );
+
+        let args = LocalServer::new().server_args(9066, &state);
+
+        assert!(args.ends_with(&[
+            "--client-cert".to_string(),
+            "./certs/client.pem".to_string(),
+            "--client-key".to_string(),
+            "./certs/client.key".to_string(),
+            "--upstream-ca".to_string(),
+            "/etc/ssl/internal-ca.pem".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn test_allocate_port_uses_free_preferred_port() {
+        let mut state = test_state();
+        let free_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let port = LocalServer::new()
+            .allocate_port(&mut state, free_port)
+            .unwrap();
+
+        assert_eq!(port, free_port);
+        assert_eq!(state.local_server_port(), free_port);
+    }
+
+    #[test]
+    fn test_allocate_port_falls_back_when_taken() {
+        let mut state = test_state();
+        assert_eq!(state.local_server_port(), LINKUP_LOCAL_SERVER_PORT);
+
+        let occupied = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = occupied.local_addr().unwrap().port();
+
+        let port = LocalServer::new()
+            .allocate_port(&mut state, taken_port)
+            .unwrap();
+
+        assert_ne!(port, taken_port);
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_ok());
+        assert_eq!(state.linkup.local_server_port, Some(port));
+
+        let saved: LocalState =
+            serde_yaml::from_str(&serde_yaml::to_string(&state).unwrap()).unwrap();
+        assert_eq!(saved.local_server_port(), port);
+    }
+}
@@ -1,11 +1,17 @@
 use std::{fmt::Display, sync};
 
+use serde::Serialize;
+
 mod caddy;
 mod cloudflare_tunnel;
 mod dnsmasq;
 mod local_server;
+mod readiness;
+mod service_commands;
 
-pub use local_server::LocalServer;
+pub use local_server::{LocalServer, LINKUP_LOCAL_SERVER_PORT};
+pub use readiness::{wait_until_ready, Probe, Readiness};
+pub use service_commands::{find_service_processes, service_env, ServiceCommands, ServiceProcess};
 pub use {caddy::is_installed as is_caddy_installed, caddy::Caddy};
 pub use {
     cloudflare_tunnel::is_installed as is_cloudflared_installed,
@@ -15,7 +21,8 @@ pub use {dnsmasq::is_installed as is_dnsmasq_installed, dnsmasq::Dnsmasq};
 
 use crate::local_config::LocalState;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum RunStatus {
     Pending,
     Starting,
@@ -36,7 +43,7 @@ impl Display for RunStatus {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RunUpdate {
     pub id: String,
     pub status: RunStatus,
@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::{sleep, timeout},
+};
+use url::Url;
+
+use crate::local_config::LocalService;
+
+/// How to tell that a local service is ready to receive requests.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Readiness {
+    /// A GET on this path of the service's local url returns 200.
+    Http { http: String },
+    /// This port on the service's local host accepts connections.
+    Tcp { tcp: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    Http(Url),
+    Tcp(String, u16),
+}
+
+impl Probe {
+    /// The probe for a local service. Services without an explicit `readiness` are ready once
+    /// their local port accepts connections.
+    pub fn for_service(service: &LocalService) -> Option<Self> {
+        match &service.readiness {
+            Some(Readiness::Http { http: path }) => service.local.join(path).ok().map(Probe::Http),
+            Some(Readiness::Tcp { tcp: port }) => service
+                .local
+                .host_str()
+                .map(|host| Probe::Tcp(host.to_string(), *port)),
+            None => match (
+                service.local.host_str(),
+                service.local.port_or_known_default(),
+            ) {
+                (Some(host), Some(port)) => Some(Probe::Tcp(host.to_string(), port)),
+                _ => None,
+            },
+        }
+    }
+
+    pub async fn check(&self) -> bool {
+        match self {
+            Probe::Http(url) => {
+                let client = reqwest::Client::builder()
+                    .timeout(Duration::from_secs(1))
+                    .build()
+                    .expect("failed while creating an HTTP client to check readiness");
+
+                let response = client.get(url.clone()).send().await;
+
+                matches!(response, Ok(res) if res.status() == StatusCode::OK)
+            }
+            Probe::Tcp(host, port) => {
+                let Ok(addrs) = lookup_host((host.as_str(), *port)).await else {
+                    return false;
+                };
+
+                for addr in addrs {
+                    let connect = TcpStream::connect(addr);
+                    if matches!(
+                        timeout(Duration::from_millis(500), connect).await,
+                        Ok(Ok(_))
+                    ) {
+                        return true;
+                    }
+                }
+
+                false
+            }
+        }
+    }
+}
+
+/// Poll `probe` until it succeeds, retrying up to `attempts` times with `interval` in between.
+/// `on_retry` is called with the attempt number before every retry, e.g. to report progress.
+pub async fn wait_until_ready(
+    probe: &Probe,
+    attempts: u8,
+    interval: Duration,
+    mut on_retry: impl FnMut(u8),
+) -> bool {
+    let mut attempt: u8 = 0;
+    loop {
+        if probe.check().await {
+            return true;
+        }
+
+        if attempt == attempts {
+            return false;
+        }
+
+        sleep(interval).await;
+        attempt += 1;
+        on_retry(attempt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use crate::local_config::ServiceTarget;
+
+    use super::*;
+
+    fn test_service(local: &str, readiness: Option<Readiness>) -> LocalService {
+        LocalService {
+            name: "backend".to_string(),
+            remote: Url::parse("https://example.com").unwrap(),
+            local: Url::parse(local).unwrap(),
+            current: ServiceTarget::Remote,
+            directory: None,
+            rewrites: vec![],
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
+            command: None,
+            readiness,
+            split: None,
+            retries: None,
+        }
+    }
+
+    #[test]
+    fn test_probe_for_service() {
+        let service = test_service(
+            "http://localhost:9000/app/",
+            Some(Readiness::Http {
+                http: "health".to_string(),
+            }),
+        );
+        assert_eq!(
+            Probe::for_service(&service),
+            Some(Probe::Http(
+                Url::parse("http://localhost:9000/app/health").unwrap()
+            ))
+        );
+
+        let service = test_service("http://localhost:9000", Some(Readiness::Tcp { tcp: 9001 }));
+        assert_eq!(
+            Probe::for_service(&service),
+            Some(Probe::Tcp("localhost".to_string(), 9001))
+        );
+
+        let service = test_service("http://localhost", None);
+        assert_eq!(
+            Probe::for_service(&service),
+            Some(Probe::Tcp("localhost".to_string(), 80))
+        );
+    }
+
+    #[test]
+    fn test_parse_readiness() {
+        let readiness: Readiness = serde_yaml::from_str("http: /health").unwrap();
+        assert_eq!(
+            readiness,
+            Readiness::Http {
+                http: "/health".to_string()
+            }
+        );
+
+        let readiness: Readiness = serde_yaml::from_str("tcp: 5432").unwrap();
+        assert_eq!(readiness, Readiness::Tcp { tcp: 5432 });
+    }
+
+    #[tokio::test]
+    async fn test_waits_until_http_probe_returns_ok() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let served = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).unwrap();
+
+                // Not ready for the first two requests
+                let status = match served.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => "503 Service Unavailable",
+                    _ => "200 OK",
+                };
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let service = test_service(
+            &format!("http://127.0.0.1:{}", port),
+            Some(Readiness::Http {
+                http: "/health".to_string(),
+            }),
+        );
+        let probe = Probe::for_service(&service).unwrap();
+
+        let mut retries = Vec::new();
+        let ready = wait_until_ready(&probe, 5, Duration::from_millis(10), |attempt| {
+            retries.push(attempt)
+        })
+        .await;
+
+        assert!(ready);
+        assert_eq!(retries, vec![1, 2]);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let probe = Probe::Tcp("127.0.0.1".to_string(), port);
+
+        let mut retries = 0;
+        let ready = wait_until_ready(&probe, 2, Duration::from_millis(10), |_| retries += 1).await;
+
+        assert!(!ready);
+        assert_eq!(retries, 2);
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::Duration,
+};
+
+use serde::Serialize;
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+use crate::{
+    linkup_dir_path,
+    local_config::{LocalService, LocalState, ServiceTarget},
+    signal, LINKUP_HOME_ENV,
+};
+
+use super::{wait_until_ready, BackgroundService, Probe, RunStatus, RunUpdate};
+
+/// Every process started from a service `command` gets this environment variable set to the
+/// service name. Child processes inherit it, which is how we find them again when stopping.
+/// The local server, tunnel and dnsmasq are tagged the same way with their own ids.
+pub const LINKUP_SERVICE_ID: &str = "LINKUP_SERVICE_ID";
+
+/// The environment that tags a process as `service_id` of this linkup dir. Along with the id it
+/// carries `LINKUP_HOME`, so that linkups running from other dirs leave each other's processes be.
+pub fn service_env(service_id: &str) -> [(&'static str, OsString); 2] {
+    [
+        (LINKUP_SERVICE_ID, OsString::from(service_id)),
+        (LINKUP_HOME_ENV, linkup_dir_path().into_os_string()),
+    ]
+}
+
+const READINESS_ATTEMPTS: u8 = 30;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed while handing file: {0}")]
+    FileHandling(#[from] std::io::Error),
+    #[error("Failed to stop pid: {0}")]
+    StoppingPid(#[from] signal::PidError),
+    #[error("Service {0} did not become ready")]
+    ServiceUnreachable(String),
+}
+
+pub struct ServiceCommands {
+    logs_dir: PathBuf,
+}
+
+impl ServiceCommands {
+    pub fn new() -> Self {
+        Self {
+            logs_dir: linkup_dir_path(),
+        }
+    }
+
+    fn start_service(&self, service: &LocalService, config_dir: &Path) -> Result<(), Error> {
+        let Some(command) = &service.command else {
+            return Ok(());
+        };
+
+        log::debug!("Starting service {}: {}", service.name, command);
+
+        let stdout_file = File::create(
+            self.logs_dir
+                .join(format!("service-{}-stdout", service.name)),
+        )?;
+        let stderr_file = File::create(
+            self.logs_dir
+                .join(format!("service-{}-stderr", service.name)),
+        )?;
+
+        let working_dir = match &service.directory {
+            Some(directory) => config_dir.join(directory),
+            None => config_dir.to_path_buf(),
+        };
+
+        process::Command::new("sh")
+            .args(["-c", command])
+            .current_dir(working_dir)
+            .envs(service_env(&service.name))
+            .process_group(0)
+            .stdout(stdout_file)
+            .stderr(stderr_file)
+            .stdin(Stdio::null())
+            .spawn()?;
+
+        Ok(())
+    }
+
+    /// Stop every process that was started for one of these services.
+    pub fn stop(&self, services: &[LocalService]) -> Result<(), Error> {
+        for service in services.iter().filter(|service| service.command.is_some()) {
+            log::debug!("Stopping service {}", service.name);
+
+            for pid in find_service_pids(&service.name) {
+                match signal::send_signal(&pid.to_string(), signal::Signal::SIGTERM) {
+                    Ok(_) | Err(signal::PidError::NoSuchProcess(_)) => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BackgroundService<Error> for ServiceCommands {
+    const NAME: &str = "Service commands";
+
+    async fn run_with_progress(
+        &self,
+        state: &mut LocalState,
+        status_sender: std::sync::mpsc::Sender<RunUpdate>,
+    ) -> Result<(), Error> {
+        let services = services_to_run(&state.services);
+
+        if services.is_empty() {
+            self.notify_update_with_details(
+                &status_sender,
+                RunStatus::Skipped,
+                "No service commands",
+            );
+
+            return Ok(());
+        }
+
+        self.notify_update(&status_sender, RunStatus::Starting);
+
+        let config_dir = state.linkup.config_dir();
+
+        for service in services.iter().filter(|service| service.command.is_some()) {
+            if find_service_pids(&service.name).is_empty() {
+                if let Err(e) = self.start_service(service, &config_dir) {
+                    self.notify_update_with_details(
+                        &status_sender,
+                        RunStatus::Error,
+                        format!("Failed to start {}", service.name),
+                    );
+
+                    return Err(e);
+                }
+            }
+        }
+
+        for service in &services {
+            let Some(probe) = Probe::for_service(service) else {
+                continue;
+            };
+
+            let ready = wait_until_ready(
+                &probe,
+                READINESS_ATTEMPTS,
+                Duration::from_millis(1000),
+                |attempt| {
+                    self.notify_update_with_details(
+                        &status_sender,
+                        RunStatus::Starting,
+                        format!("Waiting for {}... retry #{}", service.name, attempt),
+                    )
+                },
+            )
+            .await;
+
+            if !ready {
+                self.notify_update_with_details(
+                    &status_sender,
+                    RunStatus::Error,
+                    format!("{} is not ready", service.name),
+                );
+
+                return Err(Error::ServiceUnreachable(service.name.clone()));
+            }
+        }
+
+        self.notify_update(&status_sender, RunStatus::Started);
+
+        Ok(())
+    }
+}
+
+/// The services that are started and awaited: only the ones routed locally. Services started by
+/// hand are only awaited when they ask for it with `readiness`.
+fn services_to_run(services: &[LocalService]) -> Vec<&LocalService> {
+    services
+        .iter()
+        .filter(|service| service.current == ServiceTarget::Local)
+        .filter(|service| service.command.is_some() || service.readiness.is_some())
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceProcess {
+    pub service_id: String,
+    /// The `LINKUP_HOME` of the linkup that started the process.
+    pub linkup_home: Option<String>,
+    pub pid: u32,
+    pub command: String,
+    /// Seconds since the process started.
+    pub uptime: u64,
+}
+
+/// Find every process that carries `LINKUP_SERVICE_ID` in its environment.
+pub fn find_service_processes() -> Vec<ServiceProcess> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::new()
+            .with_environ(UpdateKind::Always)
+            .with_cmd(UpdateKind::Always),
+    );
+
+    let prefix = format!("{}=", LINKUP_SERVICE_ID);
+    let home_prefix = format!("{}=", LINKUP_HOME_ENV);
+
+    let mut processes: Vec<ServiceProcess> = system
+        .processes()
+        .iter()
+        .filter_map(|(pid, process)| {
+            let service_id = process
+                .environ()
+                .iter()
+                .find_map(|env| env.to_str()?.strip_prefix(&prefix))?;
+            let linkup_home = process
+                .environ()
+                .iter()
+                .find_map(|env| env.to_str()?.strip_prefix(&home_prefix));
+
+            let command = process
+                .cmd()
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            Some(ServiceProcess {
+                service_id: service_id.to_string(),
+                linkup_home: linkup_home.map(str::to_string),
+                pid: pid.as_u32(),
+                command,
+                uptime: process.run_time(),
+            })
+        })
+        .collect();
+
+    processes.sort_by(|a, b| a.service_id.cmp(&b.service_id).then(a.pid.cmp(&b.pid)));
+
+    processes
+}
+
+/// Find the processes that carry `LINKUP_SERVICE_ID=<service_id>` of this linkup dir in their
+/// environment.
+pub fn find_service_pids(service_id: &str) -> Vec<sysinfo::Pid> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::new().with_environ(UpdateKind::Always),
+    );
+
+    let needles = service_env(service_id).map(|(key, value)| {
+        let mut needle = OsString::from(format!("{}=", key));
+        needle.push(value);
+        needle
+    });
+
+    system
+        .processes()
+        .iter()
+        .filter(|(_, process)| {
+            needles.iter().all(|needle| {
+                process
+                    .environ()
+                    .iter()
+                    .any(|env| env.as_os_str() == needle.as_os_str())
+            })
+        })
+        .map(|(pid, _)| *pid)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, thread};
+
+    use url::Url;
+
+    use super::*;
+
+    fn test_service(name: &str, command: &str) -> LocalService {
+        LocalService {
+            name: name.to_string(),
+            remote: Url::parse("https://example.com").unwrap(),
+            local: Url::parse("http://localhost:3000").unwrap(),
+            current: ServiceTarget::Remote,
+            directory: None,
+            rewrites: vec![],
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
+            command: Some(command.to_string()),
+            readiness: None,
+            split: None,
+            retries: None,
+        }
+    }
+
+    fn wait_for(check: impl Fn() -> bool) -> bool {
+        for _ in 0..50 {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        false
+    }
+
+    #[test]
+    fn test_command_is_started_with_service_id_and_stopped() {
+        let dir = env::temp_dir().join(format!("linkup-service-commands-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let service_id = format!("test-service-{}", process::id());
+        let service = test_service(
+            &service_id,
+            "echo \"$LINKUP_SERVICE_ID\" > service-id && sleep 30",
+        );
+        let service_commands = ServiceCommands {
+            logs_dir: dir.clone(),
+        };
+
+        service_commands.start_service(&service, &dir).unwrap();
+
+        let id_file = dir.join("service-id");
+        assert!(wait_for(
+            || fs::read_to_string(&id_file).is_ok_and(|id| id.trim() == service_id)
+        ));
+        assert!(!find_service_pids(&service_id).is_empty());
+
+        service_commands.stop(&[service]).unwrap();
+
+        assert!(wait_for(|| find_service_pids(&service_id).is_empty()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_only_local_services_are_run() {
+        let mut local = test_service("frontend", "npm run dev");
+        local.current = ServiceTarget::Local;
+        let remote = test_service("backend", "cargo run");
+        let mut without_command = test_service("api", "");
+        without_command.current = ServiceTarget::Local;
+        without_command.command = None;
+
+        let services = [local, remote, without_command];
+        let names: Vec<&str> = services_to_run(&services)
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+
+        assert_eq!(names, vec!["frontend"]);
+    }
+
+    #[test]
+    fn test_find_service_processes_lists_tagged_processes() {
+        let service_id = format!("test-ps-{}", process::id());
+        let mut child = process::Command::new("sleep")
+            .arg("30")
+            .envs(service_env(&service_id))
+            .spawn()
+            .unwrap();
+
+        let listed = find_service_processes()
+            .into_iter()
+            .find(|service_process| service_process.service_id == service_id);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let listed = listed.expect("tagged process should be listed");
+        assert_eq!(listed.pid, child.id());
+        assert_eq!(listed.command, "sleep 30");
+        assert_eq!(
+            listed.linkup_home,
+            Some(linkup_dir_path().to_string_lossy().into_owned())
+        );
+    }
+}
@@ -20,7 +20,7 @@ pub struct RealSystem;
 
 impl System for RealSystem {
     fn get_env(&self, key: &str) -> Result<String, CliError> {
-        Ok(env::var(key).unwrap_or_else(|_| panic!("{} is not set", key)))
+        env::var(key).map_err(|_| CliError::GetEnvVar(key.to_string()))
     }
 
     fn create_file(&self, path: PathBuf) -> Result<Box<dyn FileLike>, CliError> {
@@ -1,10 +1,16 @@
-use linkup::{CreatePreviewRequest, UpdateSessionRequest};
+use std::time::Duration;
+
+use linkup::{CreatePreviewRequest, UpdateSessionRequest, VersionInfo};
 use reqwest::StatusCode;
 use serde::Serialize;
 use url::Url;
 
 use crate::local_config::YamlLocalConfig;
 
+// The version check runs before every start and preview, and is skipped when it fails. An
+// unreachable worker should not hang those commands.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("{0}")]
@@ -40,6 +46,24 @@ impl WorkerClient {
         self.post("/linkup", params).await
     }
 
+    pub async fn version(&self) -> Result<VersionInfo, Error> {
+        let endpoint = self.url.join("/linkup/version")?;
+        let response = self
+            .inner
+            .get(endpoint)
+            .timeout(VERSION_TIMEOUT)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            _ => Err(Error::Response(
+                response.status(),
+                response.text().await.unwrap_or_else(|_| "".to_string()),
+            )),
+        }
+    }
+
     async fn post<T: Serialize>(&self, path: &str, params: &T) -> Result<String, Error> {
         let params = serde_json::to_string(params)?;
         let endpoint = self.url.join(path)?;
@@ -69,3 +93,23 @@ impl From<&YamlLocalConfig> for WorkerClient {
         Self::new(&config.linkup.remote)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Instant};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_version_times_out_on_unresponsive_worker() {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let started = Instant::now();
+        let result = WorkerClient::new(&url).version().await;
+
+        assert!(matches!(result, Err(Error::Reqwest(error)) if error.is_timeout()));
+        assert!(started.elapsed() < VERSION_TIMEOUT * 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+// The second route can never match, which `linkup start` warns about
+const CONFIG: &str = r#"
+linkup:
+  remote: https://remote-linkup.example.com
+services:
+  - name: web
+    remote: https://web.example.com
+    local: http://localhost:3000
+domains:
+  - domain: progress-test.example
+    default_service: web
+    routes:
+      - path: ^/api/.*
+        service: web
+      - path: ^/api/v1/users
+        service: web
+"#;
+
+// The session an earlier `linkup start` left behind
+const PREVIOUS_STATE: &str = r#"
+linkup:
+  session_name: progress-session
+  session_token: token
+  config_path: ./linkup-config.yaml
+  remote: https://remote-linkup.example.com
+domains: []
+services: []
+"#;
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("linkup-start-test-{}-{}", name, std::process::id()));
+    fs::create_dir_all(dir.join("home")).unwrap();
+    fs::write(dir.join("linkup-config.yaml"), CONFIG).unwrap();
+
+    dir
+}
+
+fn linkup_start(dir: &Path, args: &[&str]) -> Output {
+    linkup(dir, "start", args)
+}
+
+fn linkup(dir: &Path, command: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_linkup"))
+        .arg("--config")
+        .arg(dir.join("linkup-config.yaml"))
+        .arg(command)
+        .args(args)
+        .env("LINKUP_HOME", dir.join("home"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn json_progress_only_writes_json_to_stdout() {
+    let dir = test_dir("json");
+    fs::write(dir.join("home").join("state"), PREVIOUS_STATE).unwrap();
+
+    let output = linkup_start(&dir, &["--dns-only", "--progress", "json"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    let updates: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line)
+                .unwrap_or_else(|_| panic!("stdout has a line that isn't JSON: {line}"))
+        })
+        .collect();
+    assert!(!updates.is_empty(), "stderr: {stderr}");
+    assert!(updates.iter().all(|update| update["id"] == "Dnsmasq"));
+
+    assert!(stderr.contains("warning: route"), "{stderr}");
+}
+
+#[test]
+fn dns_only_without_a_session_is_refused() {
+    let dir = test_dir("dns-only");
+
+    let output = linkup_start(&dir, &["--dns-only"]);
+    let dnsmasq_config_written = dir.join("home").join("dnsmasq-conf").exists();
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("--dns-only needs an existing session"),
+        "{stderr}"
+    );
+    assert!(!dnsmasq_config_written);
+}
+
+#[test]
+fn quiet_commands_write_nothing_to_stdout() {
+    let dir = test_dir("quiet");
+    fs::write(dir.join("home").join("state"), PREVIOUS_STATE).unwrap();
+
+    let start = linkup_start(&dir, &["--dns-only", "--quiet"]);
+    let stop = linkup(&dir, "stop", &["--quiet"]);
+    let loud_start = linkup_start(&dir, &["--dns-only"]);
+    linkup(&dir, "stop", &[]);
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    for output in [&start, &stop] {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        assert!(
+            output.stdout.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    // Without --quiet the same start does print the session
+    assert!(String::from_utf8_lossy(&loud_start.stdout).contains("progress-session"));
+}
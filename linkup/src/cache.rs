@@ -0,0 +1,304 @@
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, VARY},
+    HeaderMap as HttpHeaderMap, Response, StatusCode,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{HeaderMap, HeaderName};
+
+/// Headers a `304 Not Modified` carries over from the response it stands in for.
+const NOT_MODIFIED_HEADERS: [http::HeaderName; 6] =
+    [CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, VARY];
+
+/// Response header telling whether a cacheable request was served from the cache.
+pub const CACHE_STATUS_HEADER: &str = "x-linkup-cache";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// The client asked not to be served from the cache.
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+/// Body of `POST /linkup/cache/warm`: the session's URLs to fetch so they are cached before
+/// traffic arrives.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WarmCacheRequest {
+    pub session_name: String,
+    pub session_token: String,
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct WarmedUrl {
+    pub url: String,
+    pub status: u16,
+    /// The cache status of the fetch, not set for URLs outside the session's cache routes.
+    pub cache: Option<String>,
+}
+
+/// Query of `GET /linkup/cache/stats`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheStatsRequest {
+    pub session_name: String,
+    pub session_token: String,
+}
+
+/// Body of the `GET /linkup/cache/stats` response: how well the cache served a session.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Size of the responses stored in the cache, going by their `Content-Length`.
+    pub bytes_stored: u64,
+}
+
+impl CacheStats {
+    /// Count a cacheable request as a hit or a miss. Requests that bypassed the cache are not
+    /// counted.
+    pub fn record_status(&mut self, status: CacheStatus) {
+        match status {
+            CacheStatus::Hit => self.hits += 1,
+            CacheStatus::Miss => self.misses += 1,
+            CacheStatus::Bypass => (),
+        }
+    }
+
+    pub fn record_stored(&mut self, bytes: u64) {
+        self.bytes_stored += bytes;
+    }
+
+    /// Adds the counts of `other`, e.g. the ones of a single request to the totals of a session.
+    pub fn add(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.bytes_stored += other.bytes_stored;
+    }
+}
+
+/// How a cacheable request may use the cache, following the client's `Cache-Control` directives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Whether a cached response may be served.
+    pub read: bool,
+    /// Whether the response may be stored.
+    pub write: bool,
+}
+
+impl CachePolicy {
+    pub fn for_request(headers: &HeaderMap) -> Self {
+        let mut policy = Self {
+            read: true,
+            write: true,
+        };
+
+        let Some(cache_control) = headers.get(HeaderName::CacheControl) else {
+            return policy;
+        };
+
+        for directive in cache_control.split(',') {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+
+            if name.eq_ignore_ascii_case("no-store") {
+                policy.read = false;
+                policy.write = false;
+            } else if name.eq_ignore_ascii_case("no-cache") {
+                // The response still has to be revalidated with the origin, but the fresh one can
+                // replace what was cached.
+                policy.read = false;
+            }
+        }
+
+        policy
+    }
+
+    /// The status to report for a cacheable request, given whether a cached response was found.
+    pub fn status(&self, found: bool) -> CacheStatus {
+        match (self.read, found) {
+            (false, _) => CacheStatus::Bypass,
+            (true, true) => CacheStatus::Hit,
+            (true, false) => CacheStatus::Miss,
+        }
+    }
+}
+
+/// Builds a `304 Not Modified` for a conditional request whose `If-None-Match` matches the `ETag`
+/// of the cached response, so the client can keep using its copy instead of receiving the body
+/// again.
+pub fn not_modified_response(
+    request_headers: &HeaderMap,
+    cached_headers: &HttpHeaderMap,
+) -> Option<Response<()>> {
+    let if_none_match = request_headers.get(HeaderName::IfNoneMatch)?;
+    let etag = cached_headers.get(ETAG)?.to_str().ok()?;
+
+    if !etag_matches(if_none_match, etag) {
+        return None;
+    }
+
+    let mut response = Response::new(());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in NOT_MODIFIED_HEADERS {
+        for value in cached_headers.get_all(&name) {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+
+    Some(response)
+}
+
+// If-None-Match uses the weak comparison: `W/"a"` and `"a"` are the same entity.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim().trim_start_matches("W/");
+
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn policy(cache_control: Option<&str>) -> CachePolicy {
+        let mut headers = HeaderMap::new();
+        if let Some(cache_control) = cache_control {
+            headers.insert("Cache-Control", cache_control);
+        }
+
+        CachePolicy::for_request(&headers)
+    }
+
+    #[test]
+    fn test_cache_policy_defaults_to_read_and_write() {
+        let expected = CachePolicy {
+            read: true,
+            write: true,
+        };
+
+        assert_eq!(policy(None), expected);
+        assert_eq!(policy(Some("max-age=0")), expected);
+    }
+
+    #[test]
+    fn test_cache_policy_no_cache_skips_read() {
+        let expected = CachePolicy {
+            read: false,
+            write: true,
+        };
+
+        assert_eq!(policy(Some("no-cache")), expected);
+        assert_eq!(policy(Some("max-age=0, No-Cache")), expected);
+        assert_eq!(policy(Some("no-cache=\"set-cookie\"")), expected);
+    }
+
+    #[test]
+    fn test_cache_policy_no_store_skips_read_and_write() {
+        let expected = CachePolicy {
+            read: false,
+            write: false,
+        };
+
+        assert_eq!(policy(Some("no-store")), expected);
+        assert_eq!(policy(Some("no-cache, no-store")), expected);
+    }
+
+    #[test]
+    fn test_cache_status() {
+        assert_eq!(policy(None).status(true), CacheStatus::Hit);
+        assert_eq!(policy(None).status(false), CacheStatus::Miss);
+        assert_eq!(policy(Some("no-cache")).status(true), CacheStatus::Bypass);
+        assert_eq!(policy(Some("no-store")).status(false), CacheStatus::Bypass);
+
+        assert_eq!(CacheStatus::Bypass.as_str(), "BYPASS");
+    }
+
+    fn cached_headers() -> HttpHeaderMap {
+        let mut headers = HttpHeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        headers.insert("content-type", HeaderValue::from_static("text/html"));
+
+        headers
+    }
+
+    fn conditional_request(if_none_match: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("If-None-Match", if_none_match);
+
+        headers
+    }
+
+    #[test]
+    fn test_not_modified_response_for_matching_etag() {
+        let response =
+            not_modified_response(&conditional_request("\"v1\""), &cached_headers()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), "\"v1\"");
+        assert_eq!(response.headers().get(CACHE_CONTROL).unwrap(), "max-age=60");
+        assert!(response.headers().get("content-type").is_none());
+    }
+
+    #[test]
+    fn test_not_modified_response_uses_weak_comparison() {
+        for if_none_match in ["W/\"v1\"", "\"v0\", \"v1\"", "*"] {
+            assert!(
+                not_modified_response(&conditional_request(if_none_match), &cached_headers())
+                    .is_some(),
+                "{if_none_match} should match"
+            );
+        }
+    }
+
+    #[test]
+    fn test_not_modified_response_requires_a_match() {
+        assert!(not_modified_response(&conditional_request("\"v2\""), &cached_headers()).is_none());
+        assert!(not_modified_response(&HeaderMap::new(), &cached_headers()).is_none());
+
+        let mut without_etag = cached_headers();
+        without_etag.remove(ETAG);
+        assert!(not_modified_response(&conditional_request("*"), &without_etag).is_none());
+    }
+
+    #[test]
+    fn test_cache_stats_count_hits_and_misses() {
+        // A miss that gets stored
+        let mut first = CacheStats::default();
+        first.record_status(CacheStatus::Miss);
+        first.record_stored(1024);
+
+        // Then served from the cache twice
+        let mut second = CacheStats::default();
+        second.record_status(CacheStatus::Hit);
+        second.record_status(CacheStatus::Hit);
+        second.record_status(CacheStatus::Bypass);
+
+        let mut totals = CacheStats::default();
+        totals.add(&first);
+        totals.add(&second);
+        assert_eq!(
+            totals,
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                bytes_stored: 1024,
+            }
+        );
+    }
+}
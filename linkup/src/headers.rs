@@ -3,11 +3,16 @@ use std::collections::HashMap;
 
 use unicase::UniCase;
 
+/// Headers by case-insensitive name. A header can have several values, e.g. `Set-Cookie`.
 #[derive(Debug)]
-pub struct HeaderMap(HashMap<UniCase<String>, String>);
+pub struct HeaderMap(HashMap<UniCase<String>, Vec<String>>);
 
 pub enum HeaderName {
+    Forwarded,
+    Via,
     ForwardedHost,
+    ForwardedProto,
+    CfVisitor,
     TraceParent,
     TraceState,
     Baggage,
@@ -16,12 +21,19 @@ pub enum HeaderName {
     Origin,
     Host,
     SetCookie,
+    Cookie,
+    CacheControl,
+    IfNoneMatch,
 }
 
 impl From<HeaderName> for UniCase<String> {
     fn from(value: HeaderName) -> Self {
         match value {
+            HeaderName::Forwarded => "forwarded".into(),
+            HeaderName::Via => "via".into(),
             HeaderName::ForwardedHost => "x-forwarded-host".into(),
+            HeaderName::ForwardedProto => "x-forwarded-proto".into(),
+            HeaderName::CfVisitor => "cf-visitor".into(),
             HeaderName::TraceParent => "traceparent".into(),
             HeaderName::TraceState => "tracestate".into(),
             HeaderName::Baggage => "baggage".into(),
@@ -30,16 +42,24 @@ impl From<HeaderName> for UniCase<String> {
             HeaderName::Origin => "origin".into(),
             HeaderName::Host => "host".into(),
             HeaderName::SetCookie => "set-cookie".into(),
+            HeaderName::Cookie => "cookie".into(),
+            HeaderName::CacheControl => "cache-control".into(),
+            HeaderName::IfNoneMatch => "if-none-match".into(),
         }
     }
 }
 
+/// Iterates over every value, so a header with several values comes up once for each of them.
 impl IntoIterator for &HeaderMap {
     type Item = (UniCase<String>, String);
-    type IntoIter = std::collections::hash_map::IntoIter<UniCase<String>, String>;
+    type IntoIter = std::vec::IntoIter<(UniCase<String>, String)>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.clone().into_iter()
+        self.0
+            .iter()
+            .flat_map(|(key, values)| values.iter().map(|value| (key.clone(), value.clone())))
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 }
 
@@ -58,8 +78,19 @@ impl HeaderMap {
         self.0.contains_key(&key.into())
     }
 
+    /// The first value of the header.
     pub fn get(&self, key: impl Into<UniCase<String>>) -> Option<&str> {
-        self.0.get(&key.into()).map(String::as_ref)
+        self.0
+            .get(&key.into())
+            .and_then(|values| values.first())
+            .map(String::as_ref)
+    }
+
+    pub fn get_all(&self, key: impl Into<UniCase<String>>) -> &[String] {
+        self.0
+            .get(&key.into())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
     }
 
     pub fn get_or_default<'a>(
@@ -73,34 +104,46 @@ impl HeaderMap {
         }
     }
 
+    /// Replaces every value of the header, returning the first of the previous values.
     pub fn insert(
         &mut self,
         key: impl Into<UniCase<String>>,
         value: impl ToString,
     ) -> Option<String> {
-        let unicase_key = key.into();
-        if unicase_key == HeaderName::SetCookie.into() && self.0.contains_key(&unicase_key) {
-            let cookies = self.0.get(&unicase_key).unwrap();
-            let append_cookie = format!("{}, {}", cookies, value.to_string());
-            return self.0.insert(unicase_key, append_cookie);
-        }
+        self.0
+            .insert(key.into(), vec![value.to_string()])
+            .and_then(|values| values.into_iter().next())
+    }
 
-        self.0.insert(unicase_key, value.to_string())
+    /// Adds a value to the header, keeping the values it already has.
+    pub fn append(&mut self, key: impl Into<UniCase<String>>, value: impl ToString) {
+        self.0
+            .entry(key.into())
+            .or_default()
+            .push(value.to_string());
     }
 
-    pub fn extend(&mut self, iter: &HeaderMap) {
-        self.0.extend(iter)
+    /// Replaces the headers that are in `other` with their values there.
+    pub fn extend(&mut self, other: &HeaderMap) {
+        self.0.extend(
+            other
+                .0
+                .iter()
+                .map(|(key, values)| (key.clone(), values.clone())),
+        )
     }
 
     pub fn remove(&mut self, key: impl Into<UniCase<String>>) -> Option<String> {
-        self.0.remove(&key.into())
+        self.0
+            .remove(&key.into())
+            .and_then(|values| values.into_iter().next())
     }
 
     fn from_http_headers(http_headers: &HttpHeaderMap) -> Self {
         let mut linkup_headers = HeaderMap::new();
         for (key, value) in http_headers.iter() {
             if let Ok(value_str) = value.to_str() {
-                linkup_headers.insert(key.to_string(), value_str);
+                linkup_headers.append(key.to_string(), value_str);
             }
         }
         linkup_headers
@@ -125,10 +168,51 @@ impl From<HeaderMap> for HttpHeaderMap {
         for (key, value) in linkup_headers.into_iter() {
             if let Ok(http_value) = HttpHeaderValue::from_str(&value) {
                 if let Ok(http_key) = http::header::HeaderName::from_bytes(key.as_bytes()) {
-                    http_headers.insert(http_key, http_value);
+                    http_headers.append(http_key, http_value);
                 }
             }
         }
         http_headers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_cookie_headers_round_trip() {
+        let mut http_headers = HttpHeaderMap::new();
+        http_headers.append("set-cookie", HttpHeaderValue::from_static("a=1; Path=/"));
+        http_headers.append("set-cookie", HttpHeaderValue::from_static("b=2; HttpOnly"));
+
+        let mut headers = HeaderMap::from(&http_headers);
+        assert_eq!(headers.get(HeaderName::SetCookie), Some("a=1; Path=/"));
+        assert_eq!(
+            headers.get_all("Set-Cookie"),
+            ["a=1; Path=/", "b=2; HttpOnly"]
+        );
+
+        headers.append(HeaderName::SetCookie, "c=3");
+        let http_headers: HttpHeaderMap = headers.into();
+        let cookies: Vec<&str> = http_headers
+            .get_all("set-cookie")
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect();
+        assert_eq!(cookies, ["a=1; Path=/", "b=2; HttpOnly", "c=3"]);
+    }
+
+    #[test]
+    fn test_insert_replaces_every_value() {
+        let mut headers = HeaderMap::new();
+        headers.append("via", "1.1 first");
+        headers.append("via", "1.1 second");
+
+        assert_eq!(
+            headers.insert("Via", "1.1 linkup"),
+            Some("1.1 first".to_string())
+        );
+        assert_eq!(headers.get_all("via"), ["1.1 linkup"]);
+    }
+}
@@ -1,20 +1,36 @@
+mod cache;
 mod headers;
 mod memory_session_store;
 mod name_gen;
+mod proxy;
+mod secrets;
 mod session;
 mod session_allocator;
+mod session_builder;
+mod signing;
+mod version;
 
-use std::future::Future;
+use std::{future::Future, net::IpAddr, time::Duration};
 
-use http::{HeaderMap as HttpHeaderMap, HeaderValue as HttpHeaderValue};
+use http::{
+    header::{CONTENT_SECURITY_POLICY, X_FRAME_OPTIONS},
+    HeaderMap as HttpHeaderMap, HeaderValue as HttpHeaderValue,
+};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub use cache::*;
 pub use headers::{HeaderMap, HeaderName};
 pub use memory_session_store::*;
 pub use name_gen::{random_animal, random_six_char};
+pub use proxy::*;
+pub use secrets::*;
 pub use session::*;
 pub use session_allocator::*;
+pub use session_builder::*;
+pub use signing::*;
+pub use version::*;
 
 use url::Url;
 
@@ -22,12 +38,100 @@ use url::Url;
 pub enum SessionError {
     #[error("no session found for request {0}")]
     NoSuchSession(String),
+    #[error("no session found for request {url}, tried {}", format_lookups(.attempted))]
+    NoRequestSession {
+        url: String,
+        attempted: Vec<SessionLookup>,
+    },
     #[error("Could not get config: {0}")]
     GetError(String),
     #[error("Could not put config: {0}")]
     PutError(String),
+    #[error("Could not delete config: {0}")]
+    DeleteError(String),
     #[error("Invalid stored config: {0}")]
     ConfigErr(String),
+    #[error("no free session name found in {0} attempts")]
+    NameExhausted(usize),
+}
+
+/// The parts of a request that `SessionAllocator::get_request_session` can take the session
+/// name from, in the order they are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionSource {
+    /// The first subdomain of the request url.
+    Url,
+    ForwardedHost,
+    Referer,
+    Origin,
+    Tracestate,
+    Baggage,
+}
+
+impl SessionSource {
+    pub const ALL: [SessionSource; 6] = [
+        SessionSource::Url,
+        SessionSource::ForwardedHost,
+        SessionSource::Referer,
+        SessionSource::Origin,
+        SessionSource::Tracestate,
+        SessionSource::Baggage,
+    ];
+
+    /// Sources that a third party can't easily set for a request, e.g. any page can link to a
+    /// session domain and make the browser send its own url as referer.
+    pub const LOCKED_DOWN: [SessionSource; 2] = [SessionSource::Url, SessionSource::Tracestate];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SessionSource::Url => "url",
+            SessionSource::ForwardedHost => "forwarded_host",
+            SessionSource::Referer => "referer",
+            SessionSource::Origin => "origin",
+            SessionSource::Tracestate => "tracestate",
+            SessionSource::Baggage => "baggage",
+        }
+    }
+}
+
+impl std::fmt::Display for SessionSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for SessionSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SessionSource::ALL
+            .into_iter()
+            .find(|source| source.as_str() == s)
+            .ok_or_else(|| format!("unknown session source: {}", s))
+    }
+}
+
+/// A session name that was looked up while finding the session of a request, and the part of
+/// the request it was taken from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionLookup {
+    pub source: SessionSource,
+    pub key: String,
+}
+
+impl std::fmt::Display for SessionLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={:?}", self.source, self.key)
+    }
+}
+
+fn format_lookups(lookups: &[SessionLookup]) -> String {
+    lookups
+        .iter()
+        .map(SessionLookup::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 // Since this trait is theoretically public (even though, the idea is for it to be used by the other modules within
@@ -37,6 +141,17 @@ pub trait StringStore {
     fn get(&self, key: String) -> impl Future<Output = Result<Option<String>, SessionError>>;
     fn exists(&self, key: String) -> impl Future<Output = Result<bool, SessionError>>;
     fn put(&self, key: String, value: String) -> impl Future<Output = Result<(), SessionError>>;
+    /// Like `put`, for stores that can drop the key by themselves once `ttl` has passed.
+    fn put_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        _ttl: Duration,
+    ) -> impl Future<Output = Result<(), SessionError>> {
+        self.put(key, value)
+    }
+    /// Deleting a key that doesn't exist is not an error.
+    fn delete(&self, key: String) -> impl Future<Output = Result<(), SessionError>>;
 }
 
 #[derive(PartialEq)]
@@ -49,6 +164,7 @@ pub fn get_additional_headers(
     url: &str,
     headers: &HeaderMap,
     session_name: &str,
+    session_key: &SessionKeyConfig,
     target_service: &TargetService,
 ) -> HeaderMap {
     let mut additional_headers = HeaderMap::new();
@@ -70,7 +186,7 @@ pub fn get_additional_headers(
     }
 
     let tracestate = headers.get(HeaderName::TraceState);
-    let linkup_session = format!("linkup-session={}", session_name,);
+    let linkup_session = format!("{}={}", session_key.key(), session_name);
     match tracestate {
         Some(ts) if !ts.contains(&linkup_session) => {
             let new_tracestate = format!("{},{}", ts, linkup_session);
@@ -84,7 +200,7 @@ pub fn get_additional_headers(
     }
 
     let baggage = headers.get(HeaderName::Baggage);
-    let linkup_session = format!("linkup-session={}", session_name,);
+    let linkup_session = format!("{}={}", session_key.key(), session_name);
     match baggage {
         Some(ts) if !ts.contains(&linkup_session) => {
             let new_baggage = format!("{},{}", ts, linkup_session);
@@ -102,25 +218,155 @@ pub fn get_additional_headers(
     }
 
     if !headers.contains_key(HeaderName::ForwardedHost) {
-        additional_headers.insert(
-            HeaderName::ForwardedHost,
-            format!("{}.{}", session_name, get_target_domain(url, session_name)),
-        );
+        additional_headers.insert(HeaderName::ForwardedHost, forwarded_host(url, session_name));
     }
 
     additional_headers
 }
 
-pub fn additional_response_headers() -> HeaderMap {
-    let mut headers = HeaderMap::new();
+/// The scheme the client used according to a proxy that terminates TLS in front of linkup, like
+/// Cloudflare. `X-Forwarded-Proto` is preferred over `CF-Visitor`, and the first proxy in
+/// `X-Forwarded-Proto` is the one that saw the client.
+pub fn get_forwarded_scheme(headers: &HeaderMap) -> Option<String> {
+    let forwarded_proto = headers
+        .get(HeaderName::ForwardedProto)
+        .and_then(|proto| proto.split(',').next())
+        .map(|proto| proto.trim().to_ascii_lowercase());
+    let cf_visitor = || {
+        let visitor: serde_json::Value =
+            serde_json::from_str(headers.get(HeaderName::CfVisitor)?).ok()?;
+        Some(visitor.get("scheme")?.as_str()?.to_ascii_lowercase())
+    };
+
+    forwarded_proto
+        .filter(|scheme| scheme == "http" || scheme == "https")
+        .or_else(|| cf_visitor().filter(|scheme| scheme == "http" || scheme == "https"))
+}
+
+/// The scheme the client sent a request for `url` with. A scheme reported by a proxy in front
+/// of linkup wins over the one linkup was connected with.
+pub fn get_request_scheme(url: &str, headers: &HeaderMap) -> String {
+    get_forwarded_scheme(headers).unwrap_or_else(|| {
+        Url::parse(url)
+            .map(|url| url.scheme().to_string())
+            .unwrap_or_else(|_| "http".to_string())
+    })
+}
+
+/// Returns a RFC 7239 `Forwarded` header for services that have opted in with `forwarded_header`.
+/// If the request already went through other proxies, the element for this hop is appended to
+/// the existing value.
+pub fn get_forwarded_header(
+    url: &str,
+    headers: &HeaderMap,
+    config: &Session,
+    session_name: &str,
+    target_service: &TargetService,
+    client_addr: Option<IpAddr>,
+) -> HeaderMap {
+    let mut forwarded_headers = HeaderMap::new();
+
+    let enabled = config
+        .services
+        .get(&target_service.name)
+        .map(|service| service.forwarded_header)
+        .unwrap_or(false);
+    if !enabled {
+        return forwarded_headers;
+    }
+
+    let host = match headers.get(HeaderName::ForwardedHost) {
+        Some(forwarded_host) => forwarded_host.to_string(),
+        None => forwarded_host(url, session_name),
+    };
+    let proto = get_request_scheme(url, headers);
+    let for_node = match client_addr {
+        Some(IpAddr::V4(addr)) => addr.to_string(),
+        Some(IpAddr::V6(addr)) => format!("\"[{}]\"", addr),
+        None => "unknown".to_string(),
+    };
+
+    let element = format!(
+        "for={};host={};proto={}",
+        for_node,
+        forwarded_quote(&host),
+        proto
+    );
+
+    let value = match headers.get(HeaderName::Forwarded) {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, element),
+        _ => element,
+    };
+    forwarded_headers.insert(HeaderName::Forwarded, value);
+
+    forwarded_headers
+}
+
+/// Returns a `Via` header marking the request as forwarded by linkup, for services that have
+/// opted in with `via_header`. The entry is appended to the `Via` of earlier proxies and names
+/// the release of the forwarding server, e.g. `1.1 linkup/1.5.0`.
+pub fn get_via_header(
+    headers: &HeaderMap,
+    config: &Session,
+    target_service: &TargetService,
+    version: &str,
+) -> HeaderMap {
+    let mut via_headers = HeaderMap::new();
+
+    let enabled = config
+        .services
+        .get(&target_service.name)
+        .map(|service| service.via_header)
+        .unwrap_or(false);
+    if !enabled {
+        return via_headers;
+    }
+
+    let mut via: Vec<String> = headers
+        .get_all(HeaderName::Via)
+        .iter()
+        .filter(|value| !value.trim().is_empty())
+        .cloned()
+        .collect();
+    via.push(match version {
+        "" => "1.1 linkup".to_string(),
+        version => format!("1.1 linkup/{}", version),
+    });
+    via_headers.insert(HeaderName::Via, via.join(", "));
+
+    via_headers
+}
+
+// Forwarded parameter values must be quoted unless they are a valid token, which excludes ':'.
+fn forwarded_quote(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
 
-    headers.insert(
+/// The CORS headers linkup adds to every response, allowing any origin.
+const ALLOW_ALL_CORS: [(&str, &str); 4] = [
+    (
         "Access-Control-Allow-Methods",
         "GET, POST, PUT, PATCH, DELETE, HEAD, CONNECT, TRACE, OPTIONS",
-    );
-    headers.insert("Access-Control-Allow-Origin", "*");
-    headers.insert("Access-Control-Allow-Headers", "*");
-    headers.insert("Access-Control-Max-Age", "86400");
+    ),
+    ("Access-Control-Allow-Origin", "*"),
+    ("Access-Control-Allow-Headers", "*"),
+    ("Access-Control-Max-Age", "86400"),
+];
+
+pub fn additional_response_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in ALLOW_ALL_CORS {
+        headers.insert(name, value);
+    }
 
     headers
 }
@@ -128,34 +374,64 @@ pub fn additional_response_headers() -> HeaderMap {
 pub fn allow_all_cors() -> HttpHeaderMap {
     let mut headers = HttpHeaderMap::new();
 
-    headers.insert(
-        "Access-Control-Allow-Methods",
-        HttpHeaderValue::from_static(
-            "GET, POST, PUT, PATCH, DELETE, HEAD, CONNECT, TRACE, OPTIONS",
-        ),
-    );
-    headers.insert(
-        "Access-Control-Allow-Origin",
-        HttpHeaderValue::from_static("*"),
-    );
-    headers.insert(
-        "Access-Control-Allow-Headers",
-        HttpHeaderValue::from_static("*"),
-    );
-    headers.insert(
-        "Access-Control-Max-Age",
-        HttpHeaderValue::from_static("86400"),
-    );
+    for (name, value) in ALLOW_ALL_CORS {
+        headers.insert(name, HttpHeaderValue::from_static(value));
+    }
 
     headers
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct TargetService {
     pub name: String,
     pub url: String,
 }
 
+/// The part of a request a routing candidate was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteSource {
+    /// The `linkup-destination` header set by a linkup server earlier on the way.
+    LinkupDestination,
+    Url,
+    ForwardedHost,
+    Referer,
+    Origin,
+}
+
+/// How a route of a domain fared against the request path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteOutcome {
+    Matched,
+    NoMatch,
+    /// The path matched, but the service template named a service that isn't in the session.
+    UnknownService,
+    /// An earlier route already matched.
+    NotTried,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteTrace {
+    pub path: String,
+    /// The route's service, filled in from the path if the route has a service template.
+    pub service: String,
+    pub outcome: RouteOutcome,
+}
+
+/// One way of routing a request that was considered by `get_target_service_candidates`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteMatchTrace {
+    pub source: RouteSource,
+    /// The domain taken from the request, or the service name of a `linkup_destination`.
+    pub key: String,
+    /// Whether the session has a domain or service called `key`.
+    pub configured: bool,
+    pub routes: Vec<RouteTrace>,
+    /// Where the request is sent when this candidate is used.
+    pub target: Option<TargetService>,
+}
+
 // TODO(ostenbom): Accept a http::Uri instead of a string. Change TargetService to use Uri instead of String.
 // Returns a (name, url) pair for the destination service, if the request could be served by the config
 pub fn get_target_service(
@@ -164,88 +440,221 @@ pub fn get_target_service(
     config: &Session,
     session_name: &str,
 ) -> Option<TargetService> {
+    get_target_service_candidates(url, headers, config, session_name)
+        .into_iter()
+        .find(|candidate| candidate.configured)
+        .and_then(|candidate| candidate.target)
+}
+
+/// Every way of routing the request that `get_target_service` considers, in the order they are
+/// tried. The first configured candidate decides the target, the rest help explaining why a
+/// request was routed the way it was.
+pub fn get_target_service_candidates(
+    url: &str,
+    headers: &HeaderMap,
+    config: &Session,
+    session_name: &str,
+) -> Vec<RouteMatchTrace> {
     let mut target = Url::parse(url).unwrap();
     // Ensure always the default port, even when the local server is hit first
     target
         .set_port(None)
         .expect("setting port to None is always valid");
-    let path = target.path();
+    let path = target.path().to_string();
+
+    let mut candidates = Vec::new();
 
     // If there was a destination created in a previous linkup, we don't want to
     // re-do path rewrites, so we use the destination service.
     if let Some(destination_service) = headers.get(HeaderName::LinkupDestination) {
-        if let Some(service) = config.services.get(destination_service) {
-            let target = redirect(target.clone(), &service.origin, Some(path.to_string()));
-            return Some(TargetService {
+        let service = config.services.get(destination_service);
+        candidates.push(RouteMatchTrace {
+            source: RouteSource::LinkupDestination,
+            key: destination_service.to_string(),
+            configured: service.is_some(),
+            routes: Vec::new(),
+            target: service.map(|service| TargetService {
                 name: destination_service.to_string(),
-                url: target.to_string(),
+                url: redirect(
+                    target.clone(),
+                    service.select_origin(headers),
+                    Some(path.clone()),
+                )
+                .to_string(),
+            }),
+        });
+    }
+
+    for (source, domain_name) in domain_lookups(url, headers, session_name) {
+        let Some(domain) = config.domains.get(&domain_name) else {
+            candidates.push(RouteMatchTrace {
+                source,
+                key: domain_name,
+                configured: false,
+                routes: Vec::new(),
+                target: None,
             });
+            continue;
+        };
+
+        let (routes, service_name) = trace_routes(domain, &path, config);
+        let target = config.services.get(&service_name).map(|service| {
+            let new_path = match config.rewrite_order {
+                RewriteOrder::ServiceFirst => {
+                    apply_rewrites(&apply_rewrites(&path, &service.rewrites), &config.rewrites)
+                }
+                RewriteOrder::SessionFirst => {
+                    apply_rewrites(&apply_rewrites(&path, &config.rewrites), &service.rewrites)
+                }
+            };
+
+            TargetService {
+                name: service_name,
+                url: redirect(
+                    target.clone(),
+                    service.select_origin(headers),
+                    Some(new_path),
+                )
+                .to_string(),
+            }
+        });
+
+        candidates.push(RouteMatchTrace {
+            source,
+            key: domain_name,
+            configured: true,
+            routes,
+            target,
+        });
+    }
+
+    candidates
+}
+
+// Tries the routes of `domain` in order, returning how each of them fared and the service the
+// request goes to.
+fn trace_routes(domain: &Domain, path: &str, config: &Session) -> (Vec<RouteTrace>, String) {
+    let mut matched_service: Option<String> = None;
+
+    let routes = domain
+        .routes
+        .iter()
+        .map(|route| {
+            let (service, outcome) = if matched_service.is_some() {
+                (route.service.clone(), RouteOutcome::NotTried)
+            } else {
+                match route.service_for(path) {
+                    None => (route.service.clone(), RouteOutcome::NoMatch),
+                    // A template can name a service that isn't in the session, try the next route
+                    Some(name)
+                        if is_service_template(&route.service)
+                            && !config.services.contains_key(&name) =>
+                    {
+                        (name, RouteOutcome::UnknownService)
+                    }
+                    Some(name) => {
+                        matched_service = Some(name.clone());
+                        (name, RouteOutcome::Matched)
+                    }
+                }
+            };
+
+            RouteTrace {
+                path: route.pattern.clone(),
+                service,
+                outcome,
+            }
+        })
+        .collect();
+
+    let service_name = matched_service.unwrap_or_else(|| domain.default_service.clone());
+
+    (routes, service_name)
+}
+
+/// Returns the configured domain a request belongs to, which is the one whose routes decide
+/// the target service of the request.
+pub fn get_request_domain<'a>(
+    url: &str,
+    headers: &HeaderMap,
+    config: &'a Session,
+    session_name: &str,
+) -> Option<&'a Domain> {
+    domain_lookups(url, headers, session_name)
+        .into_iter()
+        .find_map(|(_, domain)| config.domains.get(&domain))
+}
+
+// The domains a request could belong to, in the order they are tried.
+fn domain_lookups(
+    url: &str,
+    headers: &HeaderMap,
+    session_name: &str,
+) -> Vec<(RouteSource, String)> {
+    let mut lookups = vec![(RouteSource::Url, get_target_domain(url, session_name))];
+
+    let header_sources = [
+        // Forwarded hosts persist over the tunnel
+        (RouteSource::ForwardedHost, HeaderName::ForwardedHost),
+        // This is more for e2e tests to work
+        (RouteSource::Referer, HeaderName::Referer),
+        // This one is for redirects, where the referer doesn't exist
+        (RouteSource::Origin, HeaderName::Origin),
+    ];
+    for (source, header) in header_sources {
+        if let Some(value) = headers.get(header) {
+            lookups.push((source, get_target_domain(value, session_name)));
         }
     }
 
-    let url_target = config.domains.get(&get_target_domain(url, session_name));
-
-    // Forwarded hosts persist over the tunnel
-    let forwarded_host_target = config.domains.get(&get_target_domain(
-        headers.get_or_default(HeaderName::ForwardedHost, "does-not-exist"),
-        session_name,
-    ));
-
-    // This is more for e2e tests to work
-    let referer_target = config.domains.get(&get_target_domain(
-        headers.get_or_default(HeaderName::Referer, "does-not-exist"),
-        session_name,
-    ));
-
-    // This one is for redirects, where the referer doesn't exist
-    let origin_target = config.domains.get(&get_target_domain(
-        headers.get_or_default(HeaderName::Origin, "does-not-exist"),
-        session_name,
-    ));
-
-    let target_domain = if url_target.is_some() {
-        url_target
-    } else if forwarded_host_target.is_some() {
-        forwarded_host_target
-    } else if referer_target.is_some() {
-        referer_target
+    lookups
+}
+
+/// Adds the default response headers configured on `domain` to a response of one of its
+/// services. Unless the domain uses `override`, headers already sent by the service are kept.
+pub fn apply_response_headers(domain: &Domain, response_headers: &mut HttpHeaderMap) {
+    for (name, value) in domain.response_headers.iter() {
+        if domain.response_headers_mode == ResponseHeadersMode::Override
+            || !response_headers.contains_key(name)
+        {
+            response_headers.insert(name, value.clone());
+        }
+    }
+}
+
+/// Restricts which origins may embed responses in frames when the session sets `frame_ancestors`.
+/// An empty list forbids framing altogether, without one framing is left up to the services.
+pub fn apply_framing_headers(config: &Session, response_headers: &mut HttpHeaderMap) {
+    let Some(frame_ancestors) = &config.frame_ancestors else {
+        return;
+    };
+
+    let policy = if frame_ancestors.is_empty() {
+        response_headers.insert(X_FRAME_OPTIONS, HttpHeaderValue::from_static("DENY"));
+        "frame-ancestors 'none'".to_string()
     } else {
-        origin_target
+        // X-Frame-Options can't express an allowlist, and browsers prefer frame-ancestors anyway
+        response_headers.remove(X_FRAME_OPTIONS);
+        format!("frame-ancestors {}", frame_ancestors.join(" "))
     };
 
-    if let Some(domain) = target_domain {
-        let service_name = domain
-            .routes
-            .iter()
-            .find_map(|route| {
-                if route.path.is_match(path) {
-                    Some(route.service.clone())
-                } else {
-                    None
-                }
-            })
-            .unwrap_or_else(|| domain.default_service.clone());
-
-        if let Some(service) = config.services.get(&service_name) {
-            let mut new_path = path.to_string();
-            for modifier in &service.rewrites {
-                if modifier.source.is_match(&new_path) {
-                    new_path = modifier
-                        .source
-                        .replace_all(&new_path, &modifier.target)
-                        .to_string();
-                }
-            }
+    if let Ok(policy) = HttpHeaderValue::from_str(&policy) {
+        response_headers.append(CONTENT_SECURITY_POLICY, policy);
+    }
+}
 
-            let target = redirect(target, &service.origin, Some(new_path));
-            return Some(TargetService {
-                name: service_name,
-                url: target.to_string(),
-            });
+fn apply_rewrites(path: &str, rewrites: &[Rewrite]) -> String {
+    let mut new_path = path.to_string();
+    for modifier in rewrites {
+        if modifier.source.is_match(&new_path) {
+            new_path = modifier
+                .source
+                .replace_all(&new_path, &modifier.target)
+                .to_string();
         }
     }
 
-    None
+    new_path
 }
 
 fn redirect(mut target: Url, source: &Url, path: Option<String>) -> Url {
@@ -263,30 +672,37 @@ fn redirect(mut target: Url, source: &Url, path: Option<String>) -> Url {
     target
 }
 
+// The host the request was sent to, as `<session>.<domain>` and with the port it was sent to
+fn forwarded_host(url: &str, session_name: &str) -> String {
+    let domain = get_target_domain(url, session_name);
+
+    match split_host_port(url).1 {
+        Some(port) => format!("{}.{}:{}", session_name, domain, port),
+        None => format!("{}.{}", session_name, domain),
+    }
+}
+
 fn get_target_domain(url: &str, session_name: &str) -> String {
-    let without_schema = url
-        .strip_prefix("http://")
-        .or_else(|| url.strip_prefix("https://"))
-        .unwrap_or(url);
-
-    let domain_with_path: String = if first_subdomain(url) == *session_name {
-        without_schema
-            .strip_prefix(&format!("{}.", session_name))
-            .map(String::from)
-            .unwrap_or_else(|| without_schema.to_string())
-    } else {
-        without_schema.to_string()
-    };
+    let host = split_host_port(url).0;
 
-    domain_with_path.split('/').collect::<Vec<_>>()[0].to_string()
+    if first_subdomain(url) == *session_name {
+        host.strip_prefix(&format!("{}.", session_name))
+            .unwrap_or(host)
+            .to_string()
+    } else {
+        host.to_string()
+    }
 }
 
 fn first_subdomain(url: &str) -> String {
-    let without_schema = url
-        .strip_prefix("http://")
-        .or_else(|| url.strip_prefix("https://"))
-        .unwrap_or(url);
-    let parts: Vec<&str> = without_schema.split('.').collect();
+    let host = split_host_port(url).0;
+
+    // IP addresses, including bracketed IPv6 ones, never carry a session name
+    if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() {
+        return String::from("DOES-NOT-EXIST");
+    }
+
+    let parts: Vec<&str> = host.split('.').collect();
     if parts.len() <= 2 {
         String::from("DOES-NOT-EXIST")
     } else {
@@ -294,27 +710,69 @@ fn first_subdomain(url: &str) -> String {
     }
 }
 
-fn extract_tracestate_session(tracestate: &str) -> String {
-    extract_tracestate(tracestate, String::from("linkup-session"))
+// Splits a url, or a scheme-less `host[:port]/path`, into its host and port. IPv6 hosts keep
+// their brackets.
+fn split_host_port(url: &str) -> (&str, Option<&str>) {
+    let without_schema = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = without_schema
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let host_and_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host_and_port)| host_and_port);
+
+    if host_and_port.starts_with('[') {
+        return match host_and_port.find(']') {
+            Some(end) => (
+                &host_and_port[..=end],
+                host_and_port[end + 1..].strip_prefix(':'),
+            ),
+            None => (host_and_port, None),
+        };
+    }
+
+    match host_and_port.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => (host, Some(port)),
+        _ => (host_and_port, None),
+    }
+}
+
+fn extract_tracestate_session(tracestate: &str, session_key: &SessionKeyConfig) -> String {
+    tracestate_members(tracestate)
+        .find(|(key, _)| *key == session_key.key())
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_default()
 }
 
-fn extract_tracestate(tracestate: &str, linkup_key: String) -> String {
+// The key and value of every member of a tracestate or baggage header. Members may be
+// surrounded by whitespace and be empty, e.g. after a trailing comma.
+fn tracestate_members(tracestate: &str) -> impl Iterator<Item = (&str, &str)> {
     tracestate
         .split(',')
-        .filter_map(|kv| {
-            let (key, value) = kv.split_once('=')?;
-            if key.trim() == linkup_key {
-                Some(value.trim().to_string())
-            } else {
-                None
-            }
+        .map(str::trim)
+        .filter(|member| !member.is_empty())
+        .filter_map(|member| {
+            let (key, value) = member.split_once('=')?;
+            Some((key.trim(), unquote(value.trim())))
         })
-        .next()
-        .unwrap_or_default()
+}
+
+// Some proxies send baggage values as a quoted string
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
     use super::*;
 
     const CONF_STR: &str = r#"
@@ -373,6 +831,75 @@ mod tests {
     }
     "#;
 
+    #[tokio::test]
+    async fn test_store_session_with_name_generator() {
+        let string_store = MemoryStringStore::default();
+        let names = ["acme-rocket", "acme-rocket", "acme-anvil"];
+        let next = AtomicUsize::new(0);
+        let name_generator =
+            || names[next.fetch_add(1, Ordering::SeqCst) % names.len()].to_string();
+        let sessions = SessionAllocator::new(&string_store).with_name_generator(&name_generator);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let name = sessions
+            .store_session(config.clone(), NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+        assert_eq!(name, "acme-rocket");
+
+        // Taken names are retried with the next generated one
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+        assert_eq!(name, "acme-anvil");
+        assert!(string_store.exists("acme-anvil".to_string()).await.unwrap());
+    }
+
+    // A store where every name is already taken
+    #[derive(Default)]
+    struct FullStringStore {
+        exists_calls: AtomicUsize,
+    }
+
+    impl StringStore for FullStringStore {
+        async fn get(&self, _key: String) -> Result<Option<String>, SessionError> {
+            Ok(None)
+        }
+
+        async fn exists(&self, _key: String) -> Result<bool, SessionError> {
+            self.exists_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        }
+
+        async fn put(&self, _key: String, _value: String) -> Result<(), SessionError> {
+            Ok(())
+        }
+
+        async fn delete(&self, _key: String) -> Result<(), SessionError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_session_names_exhausted() {
+        let string_store = FullStringStore::default();
+        let sessions = SessionAllocator::new(&string_store).with_name_attempts(3);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let result = sessions
+            .store_session(config, NameKind::Animal, "taken-name".to_string())
+            .await;
+
+        assert!(matches!(result, Err(SessionError::NameExhausted(6))));
+        // The desired name, then 3 animal and 3 six character names
+        assert_eq!(string_store.exists_calls.load(Ordering::SeqCst), 7);
+    }
+
     #[tokio::test]
     async fn test_get_request_session_by_subdomain() {
         let string_store = MemoryStringStore::default();
@@ -457,6 +984,7 @@ mod tests {
             "https://tiny-cow.example.com/abc-xyz",
             &headers,
             &session_name,
+            &SessionKeyConfig::default(),
             &target_service,
         );
 
@@ -488,6 +1016,7 @@ mod tests {
             "https://abc.some-tunnel.com/abc-xyz",
             &already_headers,
             &session_name,
+            &SessionKeyConfig::default(),
             &target_service,
         );
 
@@ -506,6 +1035,7 @@ mod tests {
             "https://abc.some-tunnel.com/abc-xyz",
             &already_headers_two,
             &session_name,
+            &SessionKeyConfig::default(),
             &target_service,
         );
 
@@ -526,6 +1056,7 @@ mod tests {
             "https://abc.some-tunnel.com/abc-xyz",
             &already_headers_three,
             &session_name,
+            &SessionKeyConfig::default(),
             &target_service,
         );
 
@@ -542,66 +1073,656 @@ mod tests {
     }
 
     #[test]
-    fn test_get_target_domain() {
-        let url1 = "tiny-cow.example.com/path/to/page.html";
-        let url2 = "api.example.com";
-        let url3 = "https://tiny-cow.example.com/a/b/c?a=b";
-
-        assert_eq!(get_target_domain(url1, "tiny-cow"), "example.com");
-        assert_eq!(get_target_domain(url2, "tiny-cow"), "api.example.com");
-        assert_eq!(get_target_domain(url3, "tiny-cow"), "example.com");
-    }
-
-    #[tokio::test]
-    async fn test_get_target_url() {
-        let string_store = MemoryStringStore::default();
-        let sessions = SessionAllocator::new(&string_store);
-
-        let input_config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
-        let input_config: Session = input_config_value.try_into().unwrap();
-
-        let name = sessions
-            .store_session(input_config, NameKind::Animal, "".to_string())
-            .await
-            .unwrap();
+    fn test_get_request_scheme() {
+        let headers = |pairs: &[(&str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, *value);
+            }
+            headers
+        };
+        let url = "http://localhost:9066/page";
 
-        let (name, config) = sessions
-            .get_request_session(&format!("{}.example.com", name), &HeaderMap::new())
-            .await
-            .unwrap();
+        // Without a forwarded scheme the one of the connection is used
+        assert_eq!(get_request_scheme(url, &HeaderMap::new()), "http");
+        assert_eq!(
+            get_request_scheme("https://tiny-cow.example.com/", &HeaderMap::new()),
+            "https"
+        );
+        assert_eq!(get_forwarded_scheme(&HeaderMap::new()), None);
 
-        // Standard named subdomain
         assert_eq!(
-            get_target_service(
-                &format!("http://{}.example.com/?a=b", &name),
-                &HeaderMap::new(),
-                &config,
-                &name
-            )
-            .unwrap(),
-            TargetService {
-                name: String::from("frontend"),
-                url: String::from("http://localhost:8000/?a=b")
-            },
+            get_request_scheme(url, &headers(&[("x-forwarded-proto", "https")])),
+            "https"
         );
-        // With path
         assert_eq!(
-            get_target_service(
-                &format!("http://{}.example.com/a/b/c/?a=b", &name),
-                &HeaderMap::new(),
-                &config,
-                &name
-            )
-            .unwrap(),
-            TargetService {
-                name: String::from("frontend"),
-                url: String::from("http://localhost:8000/a/b/c/?a=b")
-            },
+            get_request_scheme(url, &headers(&[("x-forwarded-proto", "HTTPS, http")])),
+            "https"
         );
-        // Test rewrites
         assert_eq!(
-            get_target_service(
-                &format!("http://{}.example.com/foo/b/c/?a=b", &name),
+            get_request_scheme(url, &headers(&[("cf-visitor", r#"{"scheme":"https"}"#)])),
+            "https"
+        );
+        assert_eq!(
+            get_request_scheme(
+                "https://tiny-cow.example.com/",
+                &headers(&[
+                    ("x-forwarded-proto", "http"),
+                    ("cf-visitor", r#"{"scheme":"https"}"#)
+                ])
+            ),
+            "http"
+        );
+
+        // Values that aren't a scheme linkup serves are ignored
+        assert_eq!(
+            get_request_scheme(url, &headers(&[("x-forwarded-proto", "ftp")])),
+            "http"
+        );
+        assert_eq!(
+            get_request_scheme(url, &headers(&[("cf-visitor", "not json")])),
+            "http"
+        );
+    }
+
+    #[test]
+    fn test_get_forwarded_header() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["forwarded_header"] = serde_json::Value::Bool(true);
+        let config: Session = config_value.try_into().unwrap();
+
+        let frontend = TargetService {
+            name: String::from("frontend"),
+            url: String::from("http://localhost:8000/abc-xyz"),
+        };
+        let backend = TargetService {
+            name: String::from("backend"),
+            url: String::from("http://localhost:8001/abc-xyz"),
+        };
+
+        let forwarded = get_forwarded_header(
+            "https://tiny-cow.example.com/abc-xyz",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+            &frontend,
+            Some("192.0.2.43".parse().unwrap()),
+        );
+        assert_eq!(
+            forwarded.get(HeaderName::Forwarded).unwrap(),
+            "for=192.0.2.43;host=tiny-cow.example.com;proto=https"
+        );
+
+        // IPv6 clients and hosts with ports need to be quoted
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::ForwardedHost, "tiny-cow.example.com:8443");
+        headers.insert(HeaderName::Forwarded, "for=198.51.100.17");
+        let forwarded = get_forwarded_header(
+            "http://localhost:9066/abc-xyz",
+            &headers,
+            &config,
+            "tiny-cow",
+            &frontend,
+            Some("2001:db8:cafe::17".parse().unwrap()),
+        );
+        assert_eq!(
+            forwarded.get(HeaderName::Forwarded).unwrap(),
+            "for=198.51.100.17, for=\"[2001:db8:cafe::17]\";host=\"tiny-cow.example.com:8443\";proto=http"
+        );
+
+        // Behind a proxy that terminates TLS
+        headers.insert(HeaderName::ForwardedProto, "https");
+        let forwarded = get_forwarded_header(
+            "http://localhost:9066/abc-xyz",
+            &headers,
+            &config,
+            "tiny-cow",
+            &frontend,
+            None,
+        );
+        assert!(forwarded
+            .get(HeaderName::Forwarded)
+            .unwrap()
+            .ends_with(";proto=https"));
+
+        // Unknown client
+        let forwarded = get_forwarded_header(
+            "https://tiny-cow.example.com/abc-xyz",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+            &frontend,
+            None,
+        );
+        assert_eq!(
+            forwarded.get(HeaderName::Forwarded).unwrap(),
+            "for=unknown;host=tiny-cow.example.com;proto=https"
+        );
+
+        // Not opted in
+        let forwarded = get_forwarded_header(
+            "https://tiny-cow.example.com/api/v1/abc-xyz",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+            &backend,
+            Some("192.0.2.43".parse().unwrap()),
+        );
+        assert!(forwarded.get(HeaderName::Forwarded).is_none());
+    }
+
+    #[test]
+    fn test_get_target_domain() {
+        let url1 = "tiny-cow.example.com/path/to/page.html";
+        let url2 = "api.example.com";
+        let url3 = "https://tiny-cow.example.com/a/b/c?a=b";
+
+        assert_eq!(get_target_domain(url1, "tiny-cow"), "example.com");
+        assert_eq!(get_target_domain(url2, "tiny-cow"), "api.example.com");
+        assert_eq!(get_target_domain(url3, "tiny-cow"), "example.com");
+    }
+
+    #[test]
+    fn test_get_target_domain_with_port() {
+        assert_eq!(
+            get_target_domain("tiny-cow.example.com:8443", "tiny-cow"),
+            "example.com"
+        );
+        assert_eq!(
+            get_target_domain("https://tiny-cow.example.com:8443/a/b", "tiny-cow"),
+            "example.com"
+        );
+        assert_eq!(
+            get_target_domain("http://localhost:9066/path", "tiny-cow"),
+            "localhost"
+        );
+    }
+
+    #[test]
+    fn test_get_target_domain_with_ip_host() {
+        assert_eq!(get_target_domain("http://[::1]:8000/", "tiny-cow"), "[::1]");
+        assert_eq!(get_target_domain("[::1]", "tiny-cow"), "[::1]");
+        assert_eq!(
+            get_target_domain("http://127.0.0.1:8000/a.b.c", "tiny-cow"),
+            "127.0.0.1"
+        );
+
+        assert_eq!(first_subdomain("http://[::1]:8000/"), "DOES-NOT-EXIST");
+        assert_eq!(first_subdomain("http://127.0.0.1:8000/"), "DOES-NOT-EXIST");
+        assert_eq!(first_subdomain("tiny-cow.example.com:8443"), "tiny-cow");
+    }
+
+    #[test]
+    fn test_forwarded_host_keeps_port() {
+        assert_eq!(
+            forwarded_host("https://tiny-cow.example.com:8443/a", "tiny-cow"),
+            "tiny-cow.example.com:8443"
+        );
+        assert_eq!(
+            forwarded_host("https://tiny-cow.example.com/a", "tiny-cow"),
+            "tiny-cow.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_request_session_lists_attempted_lookups() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("referer", "http://missing-referer.example.com/page");
+        headers.insert("tracestate", "vendor=abc,linkup-session=missing-trace");
+        headers.insert("baggage", "linkup-session=missing-baggage,user-id=42");
+
+        let error = sessions
+            .get_request_session("http://missing-url.example.com/", &headers)
+            .await
+            .unwrap_err();
+
+        let SessionError::NoRequestSession { url, attempted } = &error else {
+            panic!("unexpected error: {}", error);
+        };
+        assert_eq!(url, "http://missing-url.example.com/");
+        assert_eq!(
+            attempted
+                .iter()
+                .map(|lookup| (lookup.source, lookup.key.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (SessionSource::Url, "missing-url"),
+                (SessionSource::Referer, "missing-referer"),
+                (SessionSource::Tracestate, "missing-trace"),
+                (SessionSource::Baggage, "missing-baggage"),
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "no session found for request http://missing-url.example.com/, tried url=\"missing-url\", referer=\"missing-referer\", tracestate=\"missing-trace\", baggage=\"missing-baggage\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locked_down_session_sources() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        let locked_down =
+            SessionAllocator::new(&string_store).with_trusted_sources(&SessionSource::LOCKED_DOWN);
+
+        for header in ["referer", "origin", "x-forwarded-host"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header, format!("http://{}.example.com", name));
+
+            // Trusted by default
+            sessions
+                .get_request_session("example.com", &headers)
+                .await
+                .unwrap();
+
+            let error = locked_down
+                .get_request_session("example.com", &headers)
+                .await
+                .unwrap_err();
+            let SessionError::NoRequestSession { attempted, .. } = error else {
+                panic!("unexpected error: {}", error);
+            };
+            assert_eq!(attempted.len(), 1);
+            assert_eq!(attempted[0].source, SessionSource::Url);
+        }
+
+        locked_down
+            .get_request_session(&format!("{}.example.com", name), &HeaderMap::new())
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("tracestate", format!("linkup-session={}", name));
+        locked_down
+            .get_request_session("example.com", &headers)
+            .await
+            .unwrap();
+
+        assert_eq!("tracestate".parse(), Ok(SessionSource::Tracestate));
+        assert!("cookie".parse::<SessionSource>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_trusts_only_its_own_sources() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let mut config: Session = config_value.try_into().unwrap();
+        config.trusted_session_sources = Some(SessionSource::LOCKED_DOWN.to_vec());
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        // The server trusts the referer, but the session doesn't
+        let mut headers = HeaderMap::new();
+        headers.insert("referer", format!("http://{}.example.com", name));
+        let error = sessions
+            .get_request_session("example.com", &headers)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, SessionError::NoRequestSession { .. }));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("tracestate", format!("linkup-session={}", name));
+        let (_, session) = sessions
+            .get_request_session("example.com", &headers)
+            .await
+            .unwrap();
+        assert_eq!(
+            session.trusted_session_sources,
+            Some(SessionSource::LOCKED_DOWN.to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_authorized_session() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        let session = sessions
+            .get_authorized_session(&name, "abcxyz")
+            .await
+            .unwrap();
+        assert_eq!(session.session_token, "abcxyz");
+
+        assert!(matches!(
+            sessions.get_authorized_session(&name, "wrong").await,
+            Err(SessionError::NoSuchSession(_))
+        ));
+        assert!(matches!(
+            sessions.get_authorized_session("missing", "abcxyz").await,
+            Err(SessionError::NoSuchSession(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry() {
+        let string_store = MemoryStringStore::default();
+        let at = |timestamp| SessionAllocator::new(&string_store).with_timestamp(timestamp);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let name = at(1000)
+            .with_session_ttl(Duration::from_secs(60))
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+        let stored: serde_json::Value =
+            serde_json::from_str(&string_store.get(name.clone()).await.unwrap().unwrap()).unwrap();
+        assert_eq!(stored["expires_at"], 1060);
+
+        let url = format!("http://{}.example.com/", name);
+        at(1059)
+            .get_request_session(&url, &HeaderMap::new())
+            .await
+            .unwrap();
+        at(1059)
+            .get_authorized_session(&name, "abcxyz")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            at(1060).get_request_session(&url, &HeaderMap::new()).await,
+            Err(SessionError::NoRequestSession { .. })
+        ));
+        assert!(matches!(
+            at(1060).get_authorized_session(&name, "abcxyz").await,
+            Err(SessionError::NoSuchSession(_))
+        ));
+
+        // Sessions stored without a ttl don't expire
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+        let name = at(1000)
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+        at(u64::MAX)
+            .get_authorized_session(&name, "abcxyz")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_preview_name_ignores_expiry() {
+        let string_store = MemoryStringStore::default();
+        let at = |timestamp| {
+            SessionAllocator::new(&string_store)
+                .with_timestamp(timestamp)
+                .with_session_ttl(Duration::from_secs(60))
+        };
+        let preview = || -> Session {
+            let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+            config_value.try_into().unwrap()
+        };
+
+        let name = at(1000)
+            .store_session(preview(), NameKind::SixChar, "".to_string())
+            .await
+            .unwrap();
+        let renewed = at(2000)
+            .store_session(preview(), NameKind::SixChar, "".to_string())
+            .await
+            .unwrap();
+        assert_eq!(renewed, name);
+
+        // Storing it again renews the expiry
+        let stored: serde_json::Value =
+            serde_json::from_str(&string_store.get(name).await.unwrap().unwrap()).unwrap();
+        assert_eq!(stored["expires_at"], 2060);
+    }
+
+    #[tokio::test]
+    async fn test_remove_session() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+        let url = format!("http://{}.example.com/", name);
+        sessions
+            .get_request_session(&url, &HeaderMap::new())
+            .await
+            .unwrap();
+
+        sessions.remove_session(&name).await.unwrap();
+
+        assert!(matches!(
+            sessions.get_request_session(&url, &HeaderMap::new()).await,
+            Err(SessionError::NoRequestSession { .. })
+        ));
+        assert!(matches!(
+            sessions.get_authorized_session(&name, "abcxyz").await,
+            Err(SessionError::NoSuchSession(_))
+        ));
+        assert!(!string_store.exists(name.clone()).await.unwrap());
+
+        // Removing it again is fine
+        sessions.remove_session(&name).await.unwrap();
+    }
+
+    #[test]
+    fn test_sticky_origin_selection() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["alternate_locations"] =
+            serde_json::json!(["http://localhost:8100", "http://localhost:8200"]);
+        config_value["services"][0]["sticky_key"] = serde_json::json!({ "cookie": "sid" });
+        config_value["services"][2]["alternate_locations"] =
+            serde_json::json!(["http://localhost:8101"]);
+        config_value["services"][2]["sticky_key"] = serde_json::json!({ "header": "x-user" });
+        let config: Session = config_value.try_into().unwrap();
+
+        let target_url = |service: &str, headers: &HeaderMap| {
+            let url = match service {
+                "frontend" => "http://tiny-cow.example.com/page",
+                _ => "http://tiny-cow.api.example.com/page",
+            };
+
+            get_target_service(url, headers, &config, "tiny-cow")
+                .unwrap()
+                .url
+        };
+
+        // Without a key requests go to the primary origin
+        assert_eq!(
+            target_url("frontend", &HeaderMap::new()),
+            "http://localhost:8000/page"
+        );
+
+        let mut targets = HashSet::new();
+        for user in 0..20 {
+            let mut headers = HeaderMap::new();
+            headers.insert("cookie", format!("theme=dark; sid=user-{}", user));
+            let frontend_target = target_url("frontend", &headers);
+
+            let mut other_headers = HeaderMap::new();
+            other_headers.insert("cookie", format!("sid=user-{}; lang=en", user));
+            assert_eq!(target_url("frontend", &other_headers), frontend_target);
+
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user", format!("user-{}", user));
+            let target = target_url("backend", &headers);
+
+            headers.insert("x-request-id", format!("request-{}", user));
+            assert_eq!(target_url("backend", &headers), target);
+
+            targets.insert(frontend_target);
+        }
+
+        assert_eq!(
+            targets,
+            HashSet::from([
+                "http://localhost:8000/page".to_string(),
+                "http://localhost:8100/page".to_string(),
+                "http://localhost:8200/page".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_traffic_split() {
+        let split = TrafficSplit {
+            location: Url::parse("http://localhost:9000").unwrap(),
+            percent: 10,
+            key: Some(StickyKey::Header("x-user".to_string())),
+        };
+
+        let included = (0..10_000)
+            .filter(|user| split.includes_key(&format!("user-{}", user)))
+            .count();
+        assert!((900..=1100).contains(&included), "{} of 10000", included);
+
+        for user in 0..100 {
+            let key = format!("user-{}", user);
+            assert_eq!(split.includes_key(&key), split.includes_key(&key));
+        }
+
+        let all = TrafficSplit {
+            percent: 100,
+            ..split.clone()
+        };
+        let none = TrafficSplit {
+            percent: 0,
+            ..split.clone()
+        };
+        assert!((0..100).all(|user| all.includes_key(&user.to_string())));
+        assert!(!(0..100).any(|user| none.includes_key(&user.to_string())));
+    }
+
+    #[test]
+    fn test_traffic_split_origin_selection() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["split"] = serde_json::json!({
+            "location": "http://localhost:9000",
+            "percent": 50,
+            "key": { "header": "x-user" },
+        });
+        let config: Session = config_value.clone().try_into().unwrap();
+
+        let split = config.services["frontend"].split.clone().unwrap();
+        let target_url = |headers: &HeaderMap, session_name: &str| {
+            let url = format!("http://{}.example.com/page", session_name);
+            get_target_service(&url, headers, &config, session_name)
+                .unwrap()
+                .url
+        };
+
+        for user in 0..20 {
+            let key = format!("user-{}", user);
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user", &key);
+
+            let expected = match split.includes_key(&key) {
+                true => "http://localhost:9000/page",
+                false => "http://localhost:8000/page",
+            };
+            assert_eq!(target_url(&headers, "tiny-cow"), expected);
+        }
+
+        // Requests without the key are split by trace, not by session
+        let trace_ids = (0..20).map(|trace| format!("{:032x}", trace));
+        for trace_id in trace_ids {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::TraceParent,
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            );
+
+            let expected = match split.includes_key(&trace_id) {
+                true => "http://localhost:9000/page",
+                false => "http://localhost:8000/page",
+            };
+            assert_eq!(target_url(&headers, "tiny-cow"), expected);
+        }
+
+        // Requests without a trace are split at random
+        let split_off = (0..1_000)
+            .filter(|_| target_url(&HeaderMap::new(), "tiny-cow") == "http://localhost:9000/page")
+            .count();
+        assert!((350..=650).contains(&split_off), "{} of 1000", split_off);
+
+        config_value["services"][0]["split"]["percent"] = serde_json::json!(101);
+        let result: Result<Session, ConfigError> = config_value.try_into();
+        assert!(matches!(result, Err(ConfigError::InvalidSplitPercent(101))));
+    }
+
+    #[tokio::test]
+    async fn test_get_target_url() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let input_config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let input_config: Session = input_config_value.try_into().unwrap();
+
+        let name = sessions
+            .store_session(input_config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        let (name, config) = sessions
+            .get_request_session(&format!("{}.example.com", name), &HeaderMap::new())
+            .await
+            .unwrap();
+
+        // Standard named subdomain
+        assert_eq!(
+            get_target_service(
+                &format!("http://{}.example.com/?a=b", &name),
+                &HeaderMap::new(),
+                &config,
+                &name
+            )
+            .unwrap(),
+            TargetService {
+                name: String::from("frontend"),
+                url: String::from("http://localhost:8000/?a=b")
+            },
+        );
+        // With path
+        assert_eq!(
+            get_target_service(
+                &format!("http://{}.example.com/a/b/c/?a=b", &name),
+                &HeaderMap::new(),
+                &config,
+                &name
+            )
+            .unwrap(),
+            TargetService {
+                name: String::from("frontend"),
+                url: String::from("http://localhost:8000/a/b/c/?a=b")
+            },
+        );
+        // Test rewrites
+        assert_eq!(
+            get_target_service(
+                &format!("http://{}.example.com/foo/b/c/?a=b", &name),
                 &HeaderMap::new(),
                 &config,
                 &name
@@ -642,6 +1763,215 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_session_rewrites() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["rewrites"] = serde_json::json!([
+            { "source": "^/v1/(.*)", "target": "/$1" },
+            { "source": "^/bar/(.*)", "target": "/baz/$1" }
+        ]);
+        let config: Session = config_value.clone().try_into().unwrap();
+        assert_eq!(config.rewrite_order, RewriteOrder::ServiceFirst);
+
+        // Session rewrites apply to services without rewrites of their own
+        let target = get_target_service(
+            "http://other-example.com/v1/page",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        assert_eq!(target.url, "http://localhost:5000/page");
+
+        // By default the service rewrites go first: /foo/x -> /bar/x -> /baz/x
+        let target = get_target_service(
+            "http://example.com/foo/x",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        assert_eq!(target.url, "http://localhost:8000/baz/x");
+
+        // Session rewrites first: /foo/x is not matched by the session rewrites -> /bar/x
+        config_value["rewrite_order"] = serde_json::json!("session_first");
+        let config: Session = config_value.try_into().unwrap();
+        let target = get_target_service(
+            "http://example.com/foo/x",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        assert_eq!(target.url, "http://localhost:8000/bar/x");
+    }
+
+    #[test]
+    fn test_cors_headers_are_in_sync() {
+        let linkup_headers = additional_response_headers();
+        let http_headers = allow_all_cors();
+
+        assert_eq!(linkup_headers.into_iter().count(), http_headers.len());
+        for (name, value) in &http_headers {
+            assert_eq!(
+                linkup_headers.get(name.as_str()),
+                Some(value.to_str().unwrap()),
+                "{name} differs"
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply_response_headers() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["domains"][0]["response_headers"] = serde_json::json!({
+            "x-frame-options": "DENY",
+            "strict-transport-security": "max-age=63072000"
+        });
+        let config: Session = config_value.clone().try_into().unwrap();
+
+        let mut service_headers = HttpHeaderMap::new();
+        service_headers.insert(
+            "x-frame-options",
+            HttpHeaderValue::from_static("SAMEORIGIN"),
+        );
+
+        // By default the headers sent by the service win
+        let domain = get_request_domain(
+            "http://example.com/",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_response_headers(domain, &mut response_headers);
+        assert_eq!(
+            response_headers.get("x-frame-options").unwrap(),
+            "SAMEORIGIN"
+        );
+        assert_eq!(
+            response_headers.get("strict-transport-security").unwrap(),
+            "max-age=63072000"
+        );
+
+        // Other domains don't get the headers
+        let domain = get_request_domain(
+            "http://other-example.com/",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_response_headers(domain, &mut response_headers);
+        assert_eq!(response_headers, service_headers);
+
+        config_value["domains"][0]["response_headers_mode"] = serde_json::json!("override");
+        let config: Session = config_value.try_into().unwrap();
+        let domain = get_request_domain(
+            "http://example.com/",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        )
+        .unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_response_headers(domain, &mut response_headers);
+        assert_eq!(response_headers.get("x-frame-options").unwrap(), "DENY");
+    }
+
+    #[test]
+    fn test_apply_framing_headers() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let mut service_headers = HttpHeaderMap::new();
+        service_headers.insert(X_FRAME_OPTIONS, HttpHeaderValue::from_static("SAMEORIGIN"));
+
+        // Permissive by default, the service headers are left alone
+        let config: Session = config_value.clone().try_into().unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_framing_headers(&config, &mut response_headers);
+        assert_eq!(response_headers, service_headers);
+
+        config_value["frame_ancestors"] = serde_json::json!(["'self'", "https://app.example.com"]);
+        let config: Session = config_value.clone().try_into().unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_framing_headers(&config, &mut response_headers);
+        assert!(response_headers.get(X_FRAME_OPTIONS).is_none());
+        assert_eq!(
+            response_headers.get(CONTENT_SECURITY_POLICY).unwrap(),
+            "frame-ancestors 'self' https://app.example.com"
+        );
+
+        config_value["frame_ancestors"] = serde_json::json!([]);
+        let config: Session = config_value.try_into().unwrap();
+        let mut response_headers = service_headers.clone();
+        apply_framing_headers(&config, &mut response_headers);
+        assert_eq!(response_headers.get(X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(
+            response_headers.get(CONTENT_SECURITY_POLICY).unwrap(),
+            "frame-ancestors 'none'"
+        );
+    }
+
+    // The legacy serpress implementation used a `serpress-session` tracestate key and plain
+    // string maps for headers. Pin the linkup behaviour that replaced it.
+    #[tokio::test]
+    async fn test_only_linkup_session_keys_are_recognised() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+        let name = sessions
+            .store_session(config, NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        let mut legacy_headers = HeaderMap::new();
+        legacy_headers.insert(HeaderName::TraceState, format!("serpress-session={}", name));
+        let result = sessions
+            .get_request_session("example.com", &legacy_headers)
+            .await;
+        assert!(matches!(result, Err(SessionError::NoRequestSession { .. })));
+
+        // Header names are case insensitive and whitespace around list members is ignored
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "TraceState",
+            format!("vendor=abc, linkup-session={} ", name),
+        );
+        let (session_name, config) = sessions
+            .get_request_session("example.com", &headers)
+            .await
+            .unwrap();
+        assert_eq!(session_name, name);
+
+        let target = get_target_service(
+            &format!("http://{}.example.com/api/v1/user", name),
+            &headers,
+            &config,
+            &session_name,
+        )
+        .unwrap();
+        assert_eq!(target.name, "backend");
+        assert_eq!(target.url, "http://localhost:8001/api/v1/user");
+
+        let extra_headers = get_additional_headers(
+            &format!("http://{}.example.com/api/v1/user", name),
+            &headers,
+            &session_name,
+            &SessionKeyConfig::default(),
+            &target,
+        );
+        // The session is already part of the tracestate, so it's not added again
+        assert!(!extra_headers.contains_key(HeaderName::TraceState));
+        assert_eq!(
+            extra_headers.get(HeaderName::Baggage).unwrap(),
+            &format!("linkup-session={}", name)
+        );
+    }
+
     #[tokio::test]
     async fn test_repeatable_rewritten_routes() {
         let string_store = MemoryStringStore::default();
@@ -680,6 +2010,7 @@ mod tests {
             "http://example.com/api/v2/user",
             &HeaderMap::new(),
             &name,
+            &SessionKeyConfig::default(),
             &target,
         );
 
@@ -693,6 +2024,246 @@ mod tests {
         assert_eq!(target.url, "http://localhost:8001/user");
     }
 
+    #[test]
+    fn test_route_service_from_captures() {
+        let session = SessionBuilder::new("token")
+            .add_service("frontend", "http://localhost:8000")
+            .add_service("backend", "http://localhost:8001")
+            .add_service("backend-red", "http://localhost:8002")
+            .add_domain("example.com", "frontend")
+            .add_route(
+                "example.com",
+                "^/team/(?P<team>[^/]+)/api/.*",
+                "backend-$team",
+            )
+            .add_route("example.com", "^/team/.*", "backend")
+            .build()
+            .unwrap();
+        let headers = HeaderMap::new();
+
+        let target = get_target_service(
+            "http://my-session.example.com/team/red/api/users",
+            &headers,
+            &session,
+            "my-session",
+        )
+        .unwrap();
+        assert_eq!(target.name, "backend-red");
+        assert_eq!(target.url, "http://localhost:8002/team/red/api/users");
+
+        // No service for this team, so the next route is tried
+        let target = get_target_service(
+            "http://my-session.example.com/team/blue/api/users",
+            &headers,
+            &session,
+            "my-session",
+        )
+        .unwrap();
+        assert_eq!(target.name, "backend");
+    }
+
+    #[test]
+    fn test_route_service_without_template() {
+        let route: Route = StorableRoute {
+            path: "^/team/(?P<team>[^/]+)/".to_string(),
+            service: "backend".to_string(),
+            match_mode: None,
+            priority: None,
+        }
+        .try_into()
+        .unwrap();
+
+        assert_eq!(
+            route.service_for("/team/red/api"),
+            Some("backend".to_string())
+        );
+        assert_eq!(route.service_for("/other"), None);
+    }
+
+    #[tokio::test]
+    async fn test_custom_session_key_round_trip() {
+        let string_store = MemoryStringStore::default();
+        let sessions = SessionAllocator::new(&string_store);
+
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["session_key"] = "linkup-acme".into();
+        let config: Session = config_value.clone().try_into().unwrap();
+        let name = sessions
+            .store_session(config.clone(), NameKind::Animal, "".to_string())
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::TraceState, "vendor=abc");
+        let target = get_target_service(
+            &format!("http://{}.example.com/", name),
+            &headers,
+            &config,
+            &name,
+        )
+        .unwrap();
+        let extra_headers = get_additional_headers(
+            &format!("http://{}.example.com/", name),
+            &headers,
+            &name,
+            &config.session_key,
+            &target,
+        );
+        assert_eq!(
+            extra_headers.get(HeaderName::TraceState).unwrap(),
+            format!("vendor=abc,linkup-acme={}", name)
+        );
+        assert_eq!(
+            extra_headers.get(HeaderName::Baggage).unwrap(),
+            format!("linkup-acme={}", name)
+        );
+
+        // Read back from the headers that were written
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::TraceState,
+            extra_headers.get(HeaderName::TraceState).unwrap(),
+        );
+        let (found, _) = sessions
+            .get_request_session("http://localhost:9066/", &headers)
+            .await
+            .unwrap();
+        assert_eq!(found, name);
+
+        // The default key doesn't find a session that uses another one
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::Baggage, format!("linkup-session={}", name));
+        assert!(sessions
+            .get_request_session("http://localhost:9066/", &headers)
+            .await
+            .is_err());
+
+        for key in ["Not A Key", "acme-session", "linkup-"] {
+            config_value["session_key"] = key.into();
+            let config: Result<Session, ConfigError> = config_value.clone().try_into();
+            assert!(
+                matches!(config, Err(ConfigError::InvalidSessionKey(invalid)) if invalid == key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_tracestate_session() {
+        let default_key = SessionKeyConfig::default();
+        assert_eq!(
+            extract_tracestate_session("vendor=abc,linkup-session=tiny-cow", &default_key),
+            "tiny-cow"
+        );
+        assert_eq!(
+            extract_tracestate_session("linkup-session=\"tiny-cow\"", &default_key),
+            "tiny-cow"
+        );
+        assert_eq!(
+            extract_tracestate_session("vendor=abc , linkup-session = \"tiny-cow\" ", &default_key),
+            "tiny-cow"
+        );
+        assert_eq!(
+            extract_tracestate_session("linkup-session=tiny-cow,,vendor=abc,", &default_key),
+            "tiny-cow"
+        );
+        assert_eq!(extract_tracestate_session("vendor=abc,", &default_key), "");
+        // A lone quote isn't a quoted value
+        assert_eq!(
+            extract_tracestate_session("linkup-session=\"", &default_key),
+            "\""
+        );
+    }
+
+    #[test]
+    fn test_route_priority() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["domains"][0]["routes"] = serde_json::json!([
+            { "path": "^/api/.*", "service": "backend" },
+            { "path": "^/api/.*", "service": "other-frontend" },
+            { "path": "^/api/users", "service": "frontend", "priority": 10 }
+        ]);
+        let config: Session = config_value.try_into().unwrap();
+        let headers = HeaderMap::new();
+
+        // Declared last, but tried first
+        let target =
+            get_target_service("http://example.com/api/users", &headers, &config, "").unwrap();
+        assert_eq!(target.name, "frontend");
+
+        // Routes with the same priority keep the order they are declared in
+        let target =
+            get_target_service("http://example.com/api/teams", &headers, &config, "").unwrap();
+        assert_eq!(target.name, "backend");
+    }
+
+    #[test]
+    fn test_target_service_candidates() {
+        let session = SessionBuilder::new("token")
+            .add_service("frontend", "http://localhost:8000")
+            .add_service("backend", "http://localhost:8001")
+            .add_domain("example.com", "frontend")
+            .add_domain("api.example.com", "backend")
+            .add_route("example.com", "^/team/(?P<team>[^/]+)/", "backend-$team")
+            .add_route("example.com", "^/team/", "backend")
+            .add_route("example.com", "^/.*", "frontend")
+            .build()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::Referer, "http://my-session.api.example.com/");
+        headers.insert(HeaderName::Origin, "http://unknown.com");
+
+        let candidates = get_target_service_candidates(
+            "http://my-session.example.com/team/red/users",
+            &headers,
+            &session,
+            "my-session",
+        );
+
+        let sources: Vec<(RouteSource, &str, bool)> = candidates
+            .iter()
+            .map(|c| (c.source, c.key.as_str(), c.configured))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                (RouteSource::Url, "example.com", true),
+                (RouteSource::Referer, "api.example.com", true),
+                (RouteSource::Origin, "unknown.com", false),
+            ]
+        );
+
+        let outcomes: Vec<(&str, RouteOutcome)> = candidates[0]
+            .routes
+            .iter()
+            .map(|route| (route.service.as_str(), route.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("backend-red", RouteOutcome::UnknownService),
+                ("backend", RouteOutcome::Matched),
+                ("frontend", RouteOutcome::NotTried),
+            ]
+        );
+
+        let target = candidates[0].target.clone().unwrap();
+        assert_eq!(target.name, "backend");
+        assert_eq!(
+            get_target_service(
+                "http://my-session.example.com/team/red/users",
+                &headers,
+                &session,
+                "my-session"
+            ),
+            Some(target)
+        );
+        assert_eq!(candidates[1].target.as_ref().unwrap().name, "backend");
+
+        let json = serde_json::to_value(&candidates[2]).unwrap();
+        assert_eq!(json["source"], "origin");
+        assert_eq!(json["target"], serde_json::Value::Null);
+    }
+
     #[tokio::test]
     async fn test_iframable() {
         let string_store = MemoryStringStore::default();
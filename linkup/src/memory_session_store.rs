@@ -36,4 +36,13 @@ impl StringStore for MemoryStringStore {
 
         Ok(())
     }
+
+    async fn delete(&self, key: String) -> Result<(), SessionError> {
+        match self.0.write() {
+            Ok(mut l) => Ok(l.remove(&key)),
+            Err(e) => Err(SessionError::DeleteError(e.to_string())),
+        }?;
+
+        Ok(())
+    }
 }
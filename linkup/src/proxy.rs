@@ -0,0 +1,955 @@
+use std::{future::Future, io::Write, net::IpAddr, time::Duration};
+
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use http::{
+    header::{
+        ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, HOST, LOCATION,
+        PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    HeaderMap as HttpHeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri,
+};
+use thiserror::Error;
+use url::{Position, Url};
+
+use crate::{
+    allow_all_cors, apply_framing_headers, apply_response_headers, get_additional_headers,
+    get_forwarded_header, get_request_scheme, get_signing_headers, get_target_service,
+    get_via_header, BodyRewrite, Domain, HeaderMap, HeaderName, RequestCompression, Session,
+    TargetService,
+};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProxyError {
+    #[error("no target service for the request")]
+    NoTarget,
+    #[error("invalid target url: {0}")]
+    InvalidTarget(String),
+}
+
+/// Details about the incoming connection that are not part of the request itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyContext {
+    /// Seconds since the unix epoch, used to sign requests.
+    pub timestamp: u64,
+    pub client_addr: Option<IpAddr>,
+    /// Release of the CLI or worker forwarding the request, named in the `Via` header.
+    pub version: &'static str,
+}
+
+/// Rewrites a request of a session so it can be forwarded to its target service: the linkup
+/// headers are added and the uri is pointed at the service. Both the local server and the
+/// worker forward requests through this, so they can't drift apart.
+pub fn prepare_request<B>(
+    req: &mut Request<B>,
+    url: &str,
+    config: &Session,
+    session_name: &str,
+    context: ProxyContext,
+) -> Result<TargetService, ProxyError> {
+    let headers: HeaderMap = req.headers().into();
+    let target_service =
+        get_target_service(url, &headers, config, session_name).ok_or(ProxyError::NoTarget)?;
+
+    let mut extra_headers = get_additional_headers(
+        url,
+        &headers,
+        session_name,
+        &config.session_key,
+        &target_service,
+    );
+    extra_headers.extend(&get_signing_headers(
+        config,
+        &target_service,
+        context.timestamp,
+    ));
+    extra_headers.extend(&get_forwarded_header(
+        url,
+        &headers,
+        config,
+        session_name,
+        &target_service,
+        context.client_addr,
+    ));
+    extra_headers.extend(&get_via_header(
+        &headers,
+        config,
+        &target_service,
+        context.version,
+    ));
+
+    *req.uri_mut() = Uri::try_from(target_service.url.as_str())
+        .map_err(|_| ProxyError::InvalidTarget(target_service.url.clone()))?;
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .is_some_and(|upgrade| upgrade == "websocket");
+    remove_hop_by_hop_headers(req.headers_mut(), is_websocket);
+    let extra_http_headers: HttpHeaderMap = extra_headers.into();
+    req.headers_mut().extend(extra_http_headers);
+    // Request uri and host headers should not conflict
+    req.headers_mut().remove(HOST);
+    // Compressed responses can't be rewritten, so ask for a plain one
+    if !body_rewrites(config, &target_service).is_empty() {
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    }
+
+    Ok(target_service)
+}
+
+/// Removes the RFC 7230 hop-by-hop headers, which only apply to a single connection and must
+/// not be forwarded, together with the headers the `Connection` header lists. A websocket
+/// handshake keeps `Connection` and `Upgrade`, as the target needs them to switch protocols.
+pub fn remove_hop_by_hop_headers(headers: &mut HttpHeaderMap, is_websocket: bool) {
+    if !is_websocket {
+        let listed: Vec<String> = headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_string())
+            .collect();
+        for name in listed {
+            headers.remove(name.as_str());
+        }
+
+        headers.remove(CONNECTION);
+        headers.remove(UPGRADE);
+    }
+
+    for name in [
+        "keep-alive",
+        "proxy-connection",
+        PROXY_AUTHENTICATE.as_str(),
+        PROXY_AUTHORIZATION.as_str(),
+        TE.as_str(),
+        TRAILER.as_str(),
+        TRANSFER_ENCODING.as_str(),
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// The canned response of a mock service, if that is where the request was routed to.
+pub fn mock_response(config: &Session, target_service: &TargetService) -> Option<Response<String>> {
+    let mock = config.services.get(&target_service.name)?.mock.as_ref()?;
+
+    let mut response = Response::new(mock.body.clone());
+    *response.status_mut() = mock.status;
+    *response.headers_mut() = mock.headers.clone();
+
+    Some(response)
+}
+
+/// The body rewrites of the service a request was routed to.
+pub fn body_rewrites<'a>(config: &'a Session, target_service: &TargetService) -> &'a [BodyRewrite] {
+    config
+        .services
+        .get(&target_service.name)
+        .map(|service| service.body_rewrites.as_slice())
+        .unwrap_or_default()
+}
+
+/// Only uncompressed text bodies are rewritten, anything else (e.g. images) is passed through.
+pub fn is_rewritable_body(content_type: Option<&str>, content_encoding: Option<&str>) -> bool {
+    if content_encoding.is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+        return false;
+    }
+
+    let Some(content_type) = content_type else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
+/// Applies the rewrites to a response body. Rewriting needs the whole body, so callers should
+/// only buffer responses that have rewrites and a rewritable content type. Returns None when the
+/// body isn't valid UTF-8.
+pub fn rewrite_body(body: &[u8], rewrites: &[BodyRewrite]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+
+    Some(rewrites.iter().fold(body.to_string(), |body, rewrite| {
+        body.replace(&rewrite.find, &rewrite.replace)
+    }))
+}
+
+/// The compression the service a request was routed to expects for request bodies.
+pub fn request_compression(
+    config: &Session,
+    target_service: &TargetService,
+) -> Option<RequestCompression> {
+    config.services.get(&target_service.name)?.compress_request
+}
+
+/// How many times a failed request to the service it was routed to is retried. Only `GET` and
+/// `HEAD` requests are retried, other methods aren't safe to send twice.
+pub fn request_retries(config: &Session, target_service: &TargetService, method: &Method) -> u8 {
+    if method != Method::GET && method != Method::HEAD {
+        return 0;
+    }
+
+    config
+        .services
+        .get(&target_service.name)
+        .map_or(0, |service| service.retries)
+}
+
+/// Calls `send` until it succeeds with a response `is_retryable` rejects, or `retries` retries
+/// have failed too, in which case the last error or response is returned. Before every retry it
+/// waits a random time of up to 100ms, 200ms, 400ms and so on. `sleep` does the waiting, so that
+/// the local server and the worker can bring their own timer.
+pub async fn send_with_retries<T, E, F, S>(
+    retries: u8,
+    mut send: impl FnMut() -> F,
+    is_retryable: impl Fn(&T) -> bool,
+    sleep: impl Fn(Duration) -> S,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    S: Future<Output = ()>,
+{
+    let mut attempt = 0;
+    loop {
+        let result = send().await;
+        let failed = match &result {
+            Ok(resp) => is_retryable(resp),
+            Err(_) => true,
+        };
+        if !failed || attempt >= retries {
+            return result;
+        }
+
+        sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Whether a response status means the service or the way to it is unavailable for a moment,
+/// so that the request is worth retrying: 502, 503, 504 and Cloudflare's 530 for an origin that
+/// can't be reached.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 502 | 503 | 504 | 530)
+}
+
+// Exponential backoff with full jitter, so retries of many requests don't arrive all at once.
+// The backoff stops growing at 3.2s.
+fn retry_delay(attempt: u8) -> Duration {
+    let max_delay = Duration::from_millis(100) * 2u32.pow(attempt.min(5).into());
+
+    max_delay.mul_f64(rand::random::<f64>())
+}
+
+/// Points the `Location` of a redirect from a service with `rewrite_redirects` back at the session.
+/// A redirect to the origin of the service itself goes to the host the request was sent to, one
+/// to the origin of another service of the session to the domain that service is the default of.
+/// Any other redirect is passed through.
+pub fn rewrite_redirect_location(
+    response_headers: &mut HttpHeaderMap,
+    status: StatusCode,
+    url: &str,
+    request_headers: &HeaderMap,
+    config: &Session,
+    session_name: &str,
+    target_service: &TargetService,
+) {
+    let enabled = config
+        .services
+        .get(&target_service.name)
+        .is_some_and(|service| service.rewrite_redirects);
+    if !enabled || !status.is_redirection() {
+        return;
+    }
+
+    // Relative locations already stay on the host of the request
+    let Some(location) = response_headers
+        .get(LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| Url::parse(location).ok())
+    else {
+        return;
+    };
+    let Ok(request_url) = Url::parse(url) else {
+        return;
+    };
+
+    let Some((service_name, _)) = config.services.iter().find(|(_, service)| {
+        std::iter::once(&service.origin)
+            .chain(&service.alternate_origins)
+            .chain(service.split.as_ref().map(|split| &split.location))
+            .any(|origin| origin.origin() == location.origin())
+    }) else {
+        return;
+    };
+
+    let host = if *service_name == target_service.name {
+        match request_headers.get(HeaderName::ForwardedHost) {
+            Some(forwarded_host) => forwarded_host.to_string(),
+            None => request_url[Position::BeforeHost..Position::AfterPort].to_string(),
+        }
+    } else {
+        let Some(domain) = config.domain_selection_order.iter().find(|domain| {
+            config
+                .domains
+                .get(*domain)
+                .is_some_and(|domain| domain.default_service == *service_name)
+        }) else {
+            return;
+        };
+
+        format!("{}.{}", session_name, domain)
+    };
+    let scheme = get_request_scheme(url, request_headers);
+
+    let rewritten = format!("{}://{}{}", scheme, host, &location[Position::BeforePath..]);
+    if let Ok(rewritten) = HeaderValue::from_str(&rewritten) {
+        response_headers.insert(LOCATION, rewritten);
+    }
+}
+
+/// Compresses a request body for a service configured with `compress_request`, updating the
+/// content headers to match. Returns None for empty bodies and ones that are already encoded.
+pub fn compress_request_body(
+    headers: &mut HttpHeaderMap,
+    body: &[u8],
+    compression: RequestCompression,
+) -> std::io::Result<Option<Vec<u8>>> {
+    if body.is_empty() || headers.contains_key(CONTENT_ENCODING) {
+        return Ok(None);
+    }
+
+    let compressed = match compression {
+        RequestCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?
+        }
+        RequestCompression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?
+        }
+    };
+
+    let encoding = match compression {
+        RequestCompression::Gzip => "gzip",
+        RequestCompression::Deflate => "deflate",
+    };
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+
+    Ok(Some(compressed))
+}
+
+/// Adds the headers linkup sets on every proxied response.
+pub fn prepare_response_headers(
+    response_headers: &mut HttpHeaderMap,
+    config: &Session,
+    domain: Option<&Domain>,
+) {
+    remove_hop_by_hop_headers(response_headers, false);
+    response_headers.extend(allow_all_cors());
+    if let Some(domain) = domain {
+        apply_response_headers(domain, response_headers);
+    }
+    apply_framing_headers(config, response_headers);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Read};
+
+    use super::*;
+    use crate::{get_request_domain, ConfigError};
+
+    const CONF_STR: &str = r#"
+    {
+        "session_token": "abcxyz",
+        "services": [
+            {
+                "name": "frontend",
+                "location": "http://localhost:8000"
+            },
+            {
+                "name": "backend",
+                "location": "http://localhost:8001/",
+                "rewrites": [
+                    {
+                        "source": "/api/v2/(.*)",
+                        "target": "/$1"
+                    }
+                ]
+            }
+        ],
+        "domains": [
+            {
+                "domain": "example.com",
+                "default_service": "frontend",
+                "routes": [
+                    {
+                        "path": "/api/v2/.*",
+                        "service": "backend"
+                    }
+                ],
+                "response_headers": {
+                    "x-content-type-options": "nosniff"
+                }
+            }
+        ]
+    }
+    "#;
+
+    fn config() -> Session {
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_prepare_request() {
+        let config = config();
+        let url = "http://tiny-cow.example.com/api/v2/user?id=1";
+        let mut req = Request::builder()
+            .uri(url)
+            .header(HOST, "tiny-cow.example.com")
+            .header("traceparent", "00-abc-def-00")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+
+        let target =
+            prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+
+        assert_eq!(target.name, "backend");
+        assert_eq!(req.uri(), "http://localhost:8001/user?id=1");
+        assert!(req.headers().get(HOST).is_none());
+
+        let headers = req.headers();
+        assert_eq!(headers.get("traceparent").unwrap(), "00-abc-def-00");
+        assert_eq!(headers.get(ACCEPT_ENCODING).unwrap(), "gzip");
+        assert_eq!(
+            headers.get("tracestate").unwrap(),
+            "linkup-session=tiny-cow"
+        );
+        assert_eq!(headers.get("baggage").unwrap(), "linkup-session=tiny-cow");
+        assert_eq!(headers.get("linkup-destination").unwrap(), "backend");
+        assert_eq!(
+            headers.get("x-forwarded-host").unwrap(),
+            "tiny-cow.example.com"
+        );
+    }
+
+    #[test]
+    fn test_prepare_request_via_header() {
+        let url = "http://tiny-cow.example.com/";
+        let request = || {
+            Request::builder()
+                .uri(url)
+                .header("via", "1.1 edge-proxy")
+                .body(())
+                .unwrap()
+        };
+
+        // Not added by default
+        let mut req = request();
+        prepare_request(
+            &mut req,
+            url,
+            &config(),
+            "tiny-cow",
+            ProxyContext::default(),
+        )
+        .unwrap();
+        assert_eq!(req.headers().get("via").unwrap(), "1.1 edge-proxy");
+
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["via_header"] = serde_json::Value::Bool(true);
+        let config: Session = config_value.try_into().unwrap();
+
+        let context = ProxyContext {
+            version: "1.5.0",
+            ..Default::default()
+        };
+        let mut req = request();
+        prepare_request(&mut req, url, &config, "tiny-cow", context).unwrap();
+        assert_eq!(
+            req.headers().get("via").unwrap(),
+            "1.1 edge-proxy, 1.1 linkup/1.5.0"
+        );
+
+        let mut req = Request::builder().uri(url).body(()).unwrap();
+        prepare_request(&mut req, url, &config, "tiny-cow", context).unwrap();
+        assert_eq!(req.headers().get_all("via").iter().count(), 1);
+        assert_eq!(req.headers()["via"], "1.1 linkup/1.5.0");
+    }
+
+    #[test]
+    fn test_prepare_request_removes_hop_by_hop_headers() {
+        let config = config();
+        let url = "http://tiny-cow.example.com/";
+        let mut req = Request::builder()
+            .uri(url)
+            .header("connection", "keep-alive, x-internal-hop")
+            .header("keep-alive", "timeout=5")
+            .header("x-internal-hop", "1")
+            .header("transfer-encoding", "chunked")
+            .header("proxy-authorization", "Basic abc")
+            .header("te", "trailers")
+            .header("upgrade", "h2c")
+            .header("x-end-to-end", "1")
+            .body(())
+            .unwrap();
+
+        prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+
+        for name in [
+            "connection",
+            "keep-alive",
+            "x-internal-hop",
+            "transfer-encoding",
+            "proxy-authorization",
+            "te",
+            "upgrade",
+        ] {
+            assert!(req.headers().get(name).is_none(), "{name} was forwarded");
+        }
+        assert_eq!(req.headers().get("x-end-to-end").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_prepare_request_keeps_websocket_upgrade() {
+        let config = config();
+        let url = "http://tiny-cow.example.com/";
+        let mut req = Request::builder()
+            .uri(url)
+            .header("connection", "Upgrade")
+            .header("upgrade", "websocket")
+            .header("keep-alive", "timeout=5")
+            .body(())
+            .unwrap();
+
+        prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+
+        assert_eq!(req.headers().get("connection").unwrap(), "Upgrade");
+        assert_eq!(req.headers().get("upgrade").unwrap(), "websocket");
+        assert!(req.headers().get("keep-alive").is_none());
+    }
+
+    #[test]
+    fn test_prepare_request_without_target() {
+        let config = config();
+        let url = "http://tiny-cow.unknown.com/";
+        let mut req = Request::builder().uri(url).body(()).unwrap();
+
+        let result = prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default());
+
+        assert_eq!(result.unwrap_err(), ProxyError::NoTarget);
+        assert_eq!(req.uri(), url);
+    }
+
+    #[test]
+    fn test_mock_response() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][1]["mock"] = serde_json::json!({
+            "status": 201,
+            "body": "{\"id\": 1}",
+            "content_type": "application/json",
+            "headers": { "x-mocked": "true" }
+        });
+        let config: Session = config_value.try_into().unwrap();
+
+        let url = "http://tiny-cow.example.com/api/v2/user";
+        let mut req = Request::builder().uri(url).body(()).unwrap();
+        let target =
+            prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+
+        let response = mock_response(&config, &target).unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.body(), "{\"id\": 1}");
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(response.headers().get("x-mocked").unwrap(), "true");
+
+        let frontend_url = "http://tiny-cow.example.com/";
+        let mut req = Request::builder().uri(frontend_url).body(()).unwrap();
+        let target = prepare_request(
+            &mut req,
+            frontend_url,
+            &config,
+            "tiny-cow",
+            ProxyContext::default(),
+        )
+        .unwrap();
+        assert!(mock_response(&config, &target).is_none());
+    }
+
+    #[test]
+    fn test_body_rewrites() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["body_rewrites"] = serde_json::json!([
+            { "find": "https://remote.example.com", "replace": "https://tiny-cow.example.com" }
+        ]);
+        let config: Session = config_value.try_into().unwrap();
+
+        let url = "http://tiny-cow.example.com/";
+        let mut req = Request::builder()
+            .uri(url)
+            .header(ACCEPT_ENCODING, "gzip, br")
+            .body(())
+            .unwrap();
+        let target =
+            prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+        let rewrites = body_rewrites(&config, &target);
+        assert_eq!(rewrites.len(), 1);
+        assert_eq!(req.headers().get(ACCEPT_ENCODING).unwrap(), "identity");
+
+        assert!(is_rewritable_body(Some("text/html; charset=utf-8"), None));
+        let html = r#"<a href="https://remote.example.com/about">About</a>"#;
+        assert_eq!(
+            rewrite_body(html.as_bytes(), rewrites).unwrap(),
+            r#"<a href="https://tiny-cow.example.com/about">About</a>"#
+        );
+
+        let backend = TargetService {
+            name: "backend".to_string(),
+            url: "http://localhost:8001/".to_string(),
+        };
+        assert!(body_rewrites(&config, &backend).is_empty());
+    }
+
+    #[test]
+    fn test_is_rewritable_body() {
+        for content_type in [
+            "text/html",
+            "text/css",
+            "application/json",
+            "application/ld+json",
+            "application/javascript; charset=utf-8",
+            "image/svg+xml",
+        ] {
+            assert!(
+                is_rewritable_body(Some(content_type), None),
+                "{content_type}"
+            );
+        }
+
+        assert!(!is_rewritable_body(Some("image/png"), None));
+        assert!(!is_rewritable_body(Some("application/octet-stream"), None));
+        assert!(!is_rewritable_body(None, None));
+        assert!(!is_rewritable_body(Some("text/html"), Some("gzip")));
+        assert!(is_rewritable_body(Some("text/html"), Some("identity")));
+
+        assert!(rewrite_body(&[0xff, 0xd8, 0xff], &[]).is_none());
+    }
+
+    #[test]
+    fn test_compress_request_body() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][1]["compress_request"] = "gzip".into();
+        let config: Session = config_value.try_into().unwrap();
+
+        let url = "http://tiny-cow.example.com/api/v2/user";
+        let mut req = Request::builder().uri(url).body(()).unwrap();
+        let target =
+            prepare_request(&mut req, url, &config, "tiny-cow", ProxyContext::default()).unwrap();
+        let compression = request_compression(&config, &target).unwrap();
+        assert_eq!(compression, RequestCompression::Gzip);
+
+        let body = br#"{"name": "tiny cow"}"#;
+        let mut headers = HttpHeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        let compressed = compress_request_body(&mut headers, body, compression)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(
+            headers.get(CONTENT_LENGTH).unwrap(),
+            &compressed.len().to_string()
+        );
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed.as_bytes(), body);
+
+        // Already encoded and empty bodies are forwarded as is
+        assert!(compress_request_body(&mut headers, body, compression)
+            .unwrap()
+            .is_none());
+        assert!(
+            compress_request_body(&mut HttpHeaderMap::new(), b"", compression)
+                .unwrap()
+                .is_none()
+        );
+
+        let frontend = TargetService {
+            name: "frontend".to_string(),
+            url: "http://localhost:8000/".to_string(),
+        };
+        assert!(request_compression(&config, &frontend).is_none());
+    }
+
+    #[test]
+    fn test_invalid_mock_response() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][1]["mock"] = serde_json::json!({ "status": 42 });
+
+        let config: Result<Session, _> = config_value.try_into();
+        assert!(matches!(config, Err(ConfigError::InvalidStatus(42))));
+    }
+
+    #[test]
+    fn test_request_retries() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][1]["retries"] = serde_json::json!(2);
+        let config: Session = config_value.try_into().unwrap();
+        let target = |name: &str| TargetService {
+            name: name.to_string(),
+            url: "http://localhost:8001/".to_string(),
+        };
+
+        assert_eq!(
+            request_retries(&config, &target("backend"), &Method::GET),
+            2
+        );
+        assert_eq!(
+            request_retries(&config, &target("backend"), &Method::HEAD),
+            2
+        );
+        assert_eq!(
+            request_retries(&config, &target("backend"), &Method::POST),
+            0
+        );
+        assert_eq!(
+            request_retries(&config, &target("backend"), &Method::PUT),
+            0
+        );
+        assert_eq!(
+            request_retries(&config, &target("frontend"), &Method::GET),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retries() {
+        // The first attempt fails, the retry succeeds
+        let attempts = RefCell::new(0);
+        let delays = RefCell::new(Vec::new());
+        let result: Result<&str, &str> = send_with_retries(
+            2,
+            || {
+                *attempts.borrow_mut() += 1;
+                let attempt = *attempts.borrow();
+                async move {
+                    match attempt {
+                        1 => Err("connection reset"),
+                        _ => Ok("response"),
+                    }
+                }
+            },
+            |_| false,
+            |delay| {
+                delays.borrow_mut().push(delay);
+                async {}
+            },
+        )
+        .await;
+
+        assert_eq!(result, Ok("response"));
+        assert_eq!(*attempts.borrow(), 2);
+        assert_eq!(delays.borrow().len(), 1);
+        assert!(delays.borrow()[0] <= Duration::from_millis(100));
+
+        // Every attempt fails, the last error is returned
+        let attempts = RefCell::new(0);
+        let result: Result<(), String> = send_with_retries(
+            2,
+            || {
+                *attempts.borrow_mut() += 1;
+                let attempt = *attempts.borrow();
+                async move { Err(format!("attempt {} failed", attempt)) }
+            },
+            |_| false,
+            |_| async {},
+        )
+        .await;
+
+        assert_eq!(result, Err("attempt 3 failed".to_string()));
+
+        // Without retries the first error is final
+        let attempts = RefCell::new(0);
+        let result: Result<(), &str> = send_with_retries(
+            0,
+            || {
+                *attempts.borrow_mut() += 1;
+                async { Err("connection reset") }
+            },
+            |_| false,
+            |_| async {},
+        )
+        .await;
+
+        assert_eq!(result, Err("connection reset"));
+        assert_eq!(*attempts.borrow(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retries_retries_unavailable_responses() {
+        let send = |statuses: &'static [u16]| {
+            let attempts = RefCell::new(0);
+            async move {
+                let result: Result<StatusCode, ()> = send_with_retries(
+                    2,
+                    || {
+                        *attempts.borrow_mut() += 1;
+                        let status = statuses[*attempts.borrow() - 1];
+                        async move { Ok(StatusCode::from_u16(status).unwrap()) }
+                    },
+                    |status| is_retryable_status(*status),
+                    |_| async {},
+                )
+                .await;
+
+                (result.unwrap().as_u16(), attempts.into_inner())
+            }
+        };
+
+        assert_eq!(send(&[503, 200]).await, (200, 2));
+        assert_eq!(send(&[502, 530, 404]).await, (404, 3));
+        // The last response is returned once every attempt failed
+        assert_eq!(send(&[504, 502, 503]).await, (503, 3));
+        assert_eq!(send(&[500]).await, (500, 1));
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off() {
+        for _ in 0..100 {
+            assert!(retry_delay(0) <= Duration::from_millis(100));
+            assert!(retry_delay(2) <= Duration::from_millis(400));
+            assert!(retry_delay(200) <= Duration::from_millis(3200));
+        }
+    }
+
+    #[test]
+    fn test_rewrite_redirect_location() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["location"] = serde_json::json!("https://web.remote.com");
+        config_value["domains"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "domain": "api.example.com",
+                "default_service": "backend"
+            }));
+        let disabled: Session = config_value.clone().try_into().unwrap();
+        config_value["services"][0]["rewrite_redirects"] = serde_json::json!(true);
+        let config: Session = config_value.try_into().unwrap();
+
+        let frontend = TargetService {
+            name: "frontend".to_string(),
+            url: "https://web.remote.com/login".to_string(),
+        };
+        let redirect =
+            |config: &Session, status: StatusCode, location: &str, headers: &HeaderMap| {
+                let mut response_headers = HttpHeaderMap::new();
+                response_headers.insert(LOCATION, HeaderValue::from_str(location).unwrap());
+                rewrite_redirect_location(
+                    &mut response_headers,
+                    status,
+                    "https://tiny-cow.example.com/login",
+                    headers,
+                    config,
+                    "tiny-cow",
+                    &frontend,
+                );
+
+                response_headers[LOCATION].to_str().unwrap().to_string()
+            };
+        let found = StatusCode::FOUND;
+        let no_headers = HeaderMap::new();
+
+        // To the origin of the service itself
+        assert_eq!(
+            redirect(
+                &config,
+                found,
+                "https://web.remote.com/home?a=b",
+                &no_headers
+            ),
+            "https://tiny-cow.example.com/home?a=b"
+        );
+        // To another service of the session
+        assert_eq!(
+            redirect(&config, found, "http://localhost:8001/users", &no_headers),
+            "https://tiny-cow.api.example.com/users"
+        );
+
+        // The local server sees the session host in X-Forwarded-Host
+        let mut forwarded = HeaderMap::new();
+        forwarded.insert("x-forwarded-host", "tiny-cow.example.com:8443");
+        forwarded.insert("x-forwarded-proto", "https");
+        assert_eq!(
+            redirect(&config, found, "https://web.remote.com/home", &forwarded),
+            "https://tiny-cow.example.com:8443/home"
+        );
+
+        // Passed through
+        for (config, status, location) in [
+            (&config, found, "https://elsewhere.com/home"),
+            (&config, found, "/home"),
+            (&config, StatusCode::CREATED, "https://web.remote.com/home"),
+            (&disabled, found, "https://web.remote.com/home"),
+        ] {
+            assert_eq!(redirect(config, status, location, &no_headers), location);
+        }
+    }
+
+    #[test]
+    fn test_prepare_response_headers() {
+        let config = config();
+        let domain = get_request_domain(
+            "http://tiny-cow.example.com/",
+            &HeaderMap::new(),
+            &config,
+            "tiny-cow",
+        );
+
+        let mut response_headers = HttpHeaderMap::new();
+        response_headers.insert(
+            "access-control-allow-origin",
+            HeaderValue::from_static("https://example.com"),
+        );
+        response_headers.insert("connection", HeaderValue::from_static("keep-alive"));
+        response_headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        prepare_response_headers(&mut response_headers, &config, domain);
+
+        assert!(response_headers.get("connection").is_none());
+        assert!(response_headers.get("transfer-encoding").is_none());
+
+        assert_eq!(
+            response_headers.get("access-control-allow-origin").unwrap(),
+            "*"
+        );
+        assert_eq!(
+            response_headers.get("x-content-type-options").unwrap(),
+            "nosniff"
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use base64::prelude::*;
+use rand::{rngs::OsRng, RngCore};
+
+/// Number of random bytes behind a secret from `generate_secret`.
+pub const SECRET_BYTES: usize = 32;
+
+/// Random bytes from the operating system's CSPRNG.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes
+}
+
+/// A random secret that can be used in urls and headers as is, encoded as URL-safe base64
+/// without padding.
+pub fn generate_secret() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(random_bytes::<SECRET_BYTES>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret();
+
+        // 32 bytes are 43 base64 characters without the padding
+        assert_eq!(secret.len(), 43);
+        assert!(secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(
+            BASE64_URL_SAFE_NO_PAD.decode(&secret).unwrap().len(),
+            SECRET_BYTES
+        );
+        assert_ne!(secret, generate_secret());
+    }
+}
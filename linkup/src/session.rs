@@ -1,13 +1,22 @@
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
+    path::PathBuf,
 };
 use thiserror::Error;
 
+use http::{
+    header::CONTENT_TYPE, HeaderMap as HttpHeaderMap, HeaderName as HttpHeaderName, HeaderValue,
+    StatusCode,
+};
+use rand::Rng;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use url::Url;
 
+use crate::{HeaderMap, HeaderName, RequestSigning, SessionSource};
+
 pub const PREVIEW_SESSION_TOKEN: &str = "preview_session";
 
 #[derive(Clone, Debug)]
@@ -16,13 +25,148 @@ pub struct Session {
     pub services: HashMap<String, Service>,
     pub domains: HashMap<String, Domain>,
     pub domain_selection_order: Vec<String>,
-    pub cache_routes: Option<Vec<Regex>>,
+    pub cache_routes: Option<Vec<CacheRoute>>,
+    pub rewrites: Vec<Rewrite>,
+    pub rewrite_order: RewriteOrder,
+    pub frame_ancestors: Option<Vec<String>>,
+    pub session_key: SessionKeyConfig,
+    /// Seconds since the unix epoch from which the session is no longer found.
+    pub expires_at: Option<u64>,
+    /// The only parts of a request the session may be found from, on top of the sources the
+    /// server trusts. `None` leaves it to the server.
+    pub trusted_session_sources: Option<Vec<SessionSource>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Service {
     pub origin: Url,
+    /// Further origins of the service, see `select_origin`.
+    pub alternate_origins: Vec<Url>,
+    pub sticky_key: Option<StickyKey>,
+    pub split: Option<TrafficSplit>,
+    /// How many times failed `GET` and `HEAD` requests to the service are retried.
+    pub retries: u8,
     pub rewrites: Vec<Rewrite>,
+    pub signing: Option<RequestSigning>,
+    pub forwarded_header: bool,
+    /// Add `linkup/<version>` to the `Via` header of requests forwarded to this service.
+    pub via_header: bool,
+    /// Point redirects to the origin of a service of the session back at the session's domains.
+    pub rewrite_redirects: bool,
+    /// Directory the local server serves the service's files from, instead of proxying to
+    /// `origin`.
+    pub static_dir: Option<PathBuf>,
+    /// Response returned for every request to the service, instead of proxying to `origin`.
+    pub mock: Option<MockResponse>,
+    pub body_rewrites: Vec<BodyRewrite>,
+    pub compress_request: Option<RequestCompression>,
+}
+
+impl Service {
+    /// The origin requests with these headers go to. A service with alternate origins picks one
+    /// by hashing the value of its sticky key, so every request with the same key ends up on the
+    /// same origin. Requests without the key go to the primary origin.
+    ///
+    /// A service with a `split` first sends its share of the traffic to the split location.
+    pub fn select_origin(&self, headers: &HeaderMap) -> &Url {
+        if let Some(split) = &self.split {
+            if split.includes(headers) {
+                return &split.location;
+            }
+        }
+
+        if self.alternate_origins.is_empty() {
+            return &self.origin;
+        }
+
+        let Some(key) = self.sticky_key.as_ref().and_then(|key| key.value(headers)) else {
+            return &self.origin;
+        };
+
+        let index = (key_hash(key) % (self.alternate_origins.len() as u64 + 1)) as usize;
+
+        match index {
+            0 => &self.origin,
+            _ => &self.alternate_origins[index - 1],
+        }
+    }
+}
+
+/// Sends `percent` percent of the traffic of a service to `location` instead of its origin, e.g.
+/// a local origin that is only trusted with part of the traffic.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TrafficSplit {
+    pub location: Url,
+    pub percent: u8,
+    /// Splits requests by the value of this cookie or header. Requests without it, or all
+    /// requests if it isn't set, are split by the trace id of their `traceparent`, so each
+    /// trace is split on its own. Requests without a trace are split at random.
+    pub key: Option<StickyKey>,
+}
+
+impl TrafficSplit {
+    /// Whether a request is part of the split off traffic.
+    pub fn includes(&self, headers: &HeaderMap) -> bool {
+        let key = self
+            .key
+            .as_ref()
+            .and_then(|key| key.value(headers))
+            .or_else(|| trace_id(headers));
+
+        match key {
+            Some(key) => self.includes_key(key),
+            None => rand::thread_rng().gen_range(0..100) < self.percent,
+        }
+    }
+
+    /// Whether requests with this key are part of the split off traffic. The same key always
+    /// gets the same answer.
+    pub fn includes_key(&self, key: &str) -> bool {
+        key_hash(key) % 100 < u64::from(self.percent)
+    }
+}
+
+// The trace id of a W3C `traceparent`, `00-<trace id>-<parent id>-<flags>`.
+fn trace_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(HeaderName::TraceParent)?
+        .split('-')
+        .nth(1)
+        .filter(|trace_id| !trace_id.is_empty())
+}
+
+fn key_hash(key: &str) -> u64 {
+    let hash = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(hash[..8].try_into().expect("sha256 is 32 bytes"))
+}
+
+/// The part of a request that keeps it on the same origin of a service with several.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyKey {
+    Cookie(String),
+    Header(String),
+}
+
+impl StickyKey {
+    fn value<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        match self {
+            StickyKey::Header(name) => headers.get(name.as_str()),
+            StickyKey::Cookie(name) => headers
+                .get(HeaderName::Cookie)?
+                .split(';')
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, value)| value),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub headers: HttpHeaderMap,
+    pub body: String,
 }
 
 #[derive(Clone, Debug)]
@@ -35,12 +179,107 @@ pub struct Rewrite {
 pub struct Domain {
     pub default_service: String,
     pub routes: Vec<Route>,
+    pub response_headers: HttpHeaderMap,
+    pub response_headers_mode: ResponseHeadersMode,
 }
 
 #[derive(Clone, Debug)]
 pub struct Route {
+    /// The configured pattern anchored according to `match_mode`.
     pub path: Regex,
+    /// A service name, or a template like `backend-$team` filled in from the capture groups
+    /// of `path`.
     pub service: String,
+    /// The pattern as it was configured.
+    pub pattern: String,
+    pub match_mode: RouteMatch,
+    /// Routes with a higher priority are tried first, routes with the same priority in the
+    /// order they are configured.
+    pub priority: i32,
+}
+
+impl Route {
+    /// The service this route sends `path` to, or `None` if the route doesn't match.
+    pub fn service_for(&self, path: &str) -> Option<String> {
+        if !is_service_template(&self.service) {
+            return self.path.is_match(path).then(|| self.service.clone());
+        }
+
+        let captures = self.path.captures(path)?;
+        let mut service = String::new();
+        captures.expand(&self.service, &mut service);
+
+        Some(service)
+    }
+}
+
+/// Whether a route's `service` is filled in from the route's capture groups, like `backend-$team`.
+/// Which services a template can resolve to is only known per request, so they are not checked
+/// against the session's services.
+pub fn is_service_template(service: &str) -> bool {
+    service.contains('$')
+}
+
+/// How a route's `path` pattern is matched against the request path.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteMatch {
+    /// The pattern may match anywhere in the path, so `/api` also matches `/v1/api`.
+    /// Anchor the pattern with `^` and `$` yourself for anything stricter.
+    #[default]
+    Contains,
+    /// The pattern must match at the start of the path.
+    Prefix,
+    /// The pattern must match the whole path.
+    Exact,
+}
+
+impl RouteMatch {
+    /// The regex to compile for a configured pattern.
+    fn anchor(self, pattern: &str) -> String {
+        // Alternations need a group to anchor every branch, other patterns are kept readable
+        // so that `validate` can still reason about them.
+        let pattern = if pattern.contains('|') && self != RouteMatch::Contains {
+            format!("(?:{})", pattern)
+        } else {
+            pattern.to_string()
+        };
+
+        let start = if pattern.starts_with('^') { "" } else { "^" };
+        let end = if pattern.ends_with('$') && !pattern.ends_with("\\$") {
+            ""
+        } else {
+            "$"
+        };
+
+        match self {
+            RouteMatch::Contains => pattern,
+            RouteMatch::Prefix => format!("{}{}", start, pattern),
+            RouteMatch::Exact => format!("{}{}{}", start, pattern, end),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheRoute {
+    /// Matched anywhere in the request path, like a route with the default `match`. Anchor it
+    /// with `^` and `$` for anything stricter.
+    pub path: Regex,
+    /// Response statuses that get cached on this route, any 2xx when not set.
+    pub statuses: Option<Vec<StatusCode>>,
+}
+
+impl CacheRoute {
+    pub fn matches(&self, path: &str) -> bool {
+        self.path.is_match(path)
+    }
+
+    pub fn caches_status(&self, status: StatusCode) -> bool {
+        match &self.statuses {
+            Some(statuses) => statuses.contains(&status),
+            None => status.is_success(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -49,14 +288,24 @@ pub struct UpdateSessionRequest {
     pub session_token: String,
     pub services: Vec<StorableService>,
     pub domains: Vec<StorableDomain>,
-    pub cache_routes: Option<Vec<String>>,
+    pub cache_routes: Option<Vec<StorableCacheRoute>>,
+    pub rewrites: Option<Vec<StorableRewrite>>,
+    pub rewrite_order: Option<RewriteOrder>,
+    pub frame_ancestors: Option<Vec<String>>,
+    pub session_key: Option<String>,
+    pub trusted_session_sources: Option<Vec<SessionSource>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CreatePreviewRequest {
     pub services: Vec<StorableService>,
     pub domains: Vec<StorableDomain>,
-    pub cache_routes: Option<Vec<String>>,
+    pub cache_routes: Option<Vec<StorableCacheRoute>>,
+    pub rewrites: Option<Vec<StorableRewrite>>,
+    pub rewrite_order: Option<RewriteOrder>,
+    pub frame_ancestors: Option<Vec<String>>,
+    pub session_key: Option<String>,
+    pub trusted_session_sources: Option<Vec<SessionSource>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -64,14 +313,115 @@ pub struct StorableSession {
     pub session_token: String,
     pub services: Vec<StorableService>,
     pub domains: Vec<StorableDomain>,
-    pub cache_routes: Option<Vec<String>>,
+    pub cache_routes: Option<Vec<StorableCacheRoute>>,
+    pub rewrites: Option<Vec<StorableRewrite>>,
+    pub rewrite_order: Option<RewriteOrder>,
+    pub frame_ancestors: Option<Vec<String>>,
+    pub session_key: Option<String>,
+    pub expires_at: Option<u64>,
+    pub trusted_session_sources: Option<Vec<SessionSource>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct StorableService {
     pub name: String,
     pub location: Url,
+    pub alternate_locations: Option<Vec<Url>>,
+    pub sticky_key: Option<StickyKey>,
+    pub split: Option<TrafficSplit>,
+    pub retries: Option<u8>,
     pub rewrites: Option<Vec<StorableRewrite>>,
+    pub signing: Option<RequestSigning>,
+    pub forwarded_header: Option<bool>,
+    pub via_header: Option<bool>,
+    pub rewrite_redirects: Option<bool>,
+    pub static_dir: Option<String>,
+    pub mock: Option<StorableMockResponse>,
+    pub body_rewrites: Option<Vec<BodyRewrite>>,
+    pub compress_request: Option<RequestCompression>,
+}
+
+/// Encoding applied to the bodies of requests forwarded to a service that only accepts
+/// compressed requests.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestCompression {
+    Gzip,
+    Deflate,
+}
+
+/// Replaces every occurrence of `find` in the text responses of a service.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct BodyRewrite {
+    pub find: String,
+    pub replace: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StorableMockResponse {
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+}
+
+/// Whether the session level rewrites are applied after (default) or before the rewrites
+/// of the target service.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteOrder {
+    #[default]
+    ServiceFirst,
+    SessionFirst,
+}
+
+pub const DEFAULT_SESSION_KEY: &str = "linkup-session";
+
+/// Every session key starts with this, so a request is only looked up by the `tracestate` and
+/// `baggage` members that can carry a session name, not by every member other tools add.
+pub const SESSION_KEY_PREFIX: &str = "linkup-";
+
+/// The key of the `tracestate` and `baggage` members that carry the session name, for sessions
+/// that need something else than `linkup-session`, e.g. to not collide with their own tracing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionKeyConfig {
+    key: String,
+}
+
+impl SessionKeyConfig {
+    /// Fails for keys that are not a valid W3C tracestate key, or don't start with
+    /// `SESSION_KEY_PREFIX`.
+    pub fn new(key: impl Into<String>) -> Result<Self, ConfigError> {
+        let key = key.into();
+
+        let valid = key.len() <= 256
+            && key.len() > SESSION_KEY_PREFIX.len()
+            && key.starts_with(SESSION_KEY_PREFIX)
+            && key
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_-*/@".contains(c));
+        if !valid {
+            return Err(ConfigError::InvalidSessionKey(key));
+        }
+
+        Ok(Self { key })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Default for SessionKeyConfig {
+    fn default() -> Self {
+        Self {
+            key: DEFAULT_SESSION_KEY.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -80,17 +430,69 @@ pub struct StorableRewrite {
     pub target: String,
 }
 
+/// Whether the default response headers of a domain replace the ones sent by the target
+/// service, or are only added when the service did not send them (default).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseHeadersMode {
+    #[default]
+    IfAbsent,
+    Override,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct StorableDomain {
     pub domain: String,
     pub default_service: String,
     pub routes: Option<Vec<StorableRoute>>,
+    pub response_headers: Option<HashMap<String, String>>,
+    pub response_headers_mode: Option<ResponseHeadersMode>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct StorableRoute {
     pub path: String,
     pub service: String,
+    #[serde(rename = "match", default, skip_serializing_if = "Option::is_none")]
+    pub match_mode: Option<RouteMatch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+}
+
+/// A cache route is either just the path regex, or the path with the response statuses to cache.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum StorableCacheRoute {
+    Path(String),
+    WithStatuses { path: String, statuses: Vec<u16> },
+}
+
+/// Non fatal problems with a session config, the session still works but probably not as intended.
+#[derive(Debug, PartialEq)]
+pub enum ConfigWarning {
+    /// A route that can never be matched because an earlier route on the same domain
+    /// matches every path it would.
+    ShadowedRoute {
+        domain: String,
+        pattern: String,
+        shadowed_by: String,
+    },
+}
+
+impl std::fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigWarning::ShadowedRoute {
+                domain,
+                pattern,
+                shadowed_by,
+            } => write!(
+                f,
+                "route '{}' on domain {} is unreachable, it is shadowed by the earlier route '{}'",
+                pattern, domain, shadowed_by
+            ),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -99,16 +501,57 @@ pub enum ConfigError {
     JsonFormat(#[from] serde_json::Error),
     #[error("no such service: {0}")]
     NoSuchService(String),
-    #[error("invalid regex: {0}, {0}")]
-    InvalidRegex(String, regex::Error),
+    #[error("no such domain: {0}")]
+    NoSuchDomain(String),
+    #[error("invalid regex {pattern:?} at {location}: {source}")]
+    InvalidRegex {
+        /// Where in the session the pattern was found, e.g. `domains[example.com].routes[0].path`.
+        location: String,
+        pattern: String,
+        source: regex::Error,
+    },
     #[error("domain config error")]
     DomainConfig,
     #[error("invalid url: {0}")]
     InvalidURL(String),
+    #[error("invalid response header: {0}")]
+    InvalidHeader(String),
+    #[error("invalid response status: {0}")]
+    InvalidStatus(u16),
+    #[error("invalid traffic split percentage: {0}")]
+    InvalidSplitPercent(u8),
+    #[error("invalid session key: {0}")]
+    InvalidSessionKey(String),
     #[error("empty config")]
     Empty,
 }
 
+impl ConfigError {
+    /// Prefix the location of an `InvalidRegex` with the part of the session it was found in.
+    fn within(self, parent: &str) -> Self {
+        match self {
+            ConfigError::InvalidRegex {
+                location,
+                pattern,
+                source,
+            } => ConfigError::InvalidRegex {
+                location: format!("{}.{}", parent, location),
+                pattern,
+                source,
+            },
+            other => other,
+        }
+    }
+}
+
+fn compile_regex(pattern: String, location: &str) -> Result<Regex, ConfigError> {
+    Regex::new(&pattern).map_err(|source| ConfigError::InvalidRegex {
+        location: location.to_string(),
+        pattern,
+        source,
+    })
+}
+
 impl From<UpdateSessionRequest> for StorableSession {
     fn from(req: UpdateSessionRequest) -> Self {
         StorableSession {
@@ -116,6 +559,12 @@ impl From<UpdateSessionRequest> for StorableSession {
             services: req.services,
             domains: req.domains,
             cache_routes: req.cache_routes,
+            rewrites: req.rewrites,
+            rewrite_order: req.rewrite_order,
+            frame_ancestors: req.frame_ancestors,
+            session_key: req.session_key,
+            expires_at: None,
+            trusted_session_sources: req.trusted_session_sources,
         }
     }
 }
@@ -136,6 +585,12 @@ impl From<CreatePreviewRequest> for StorableSession {
             services: req.services,
             domains: req.domains,
             cache_routes: req.cache_routes,
+            rewrites: req.rewrites,
+            rewrite_order: req.rewrite_order,
+            frame_ancestors: req.frame_ancestors,
+            session_key: req.session_key,
+            expires_at: None,
+            trusted_session_sources: req.trusted_session_sources,
         }
     }
 }
@@ -153,14 +608,10 @@ impl TryFrom<StorableRewrite> for Rewrite {
     type Error = ConfigError;
 
     fn try_from(value: StorableRewrite) -> Result<Self, Self::Error> {
-        let source: Result<Regex, regex::Error> = Regex::new(&value.source);
-        match source {
-            Err(e) => Err(ConfigError::InvalidRegex(value.source, e)),
-            Ok(s) => Ok(Rewrite {
-                source: s,
-                target: value.target,
-            }),
-        }
+        Ok(Rewrite {
+            source: compile_regex(value.source, "source")?,
+            target: value.target,
+        })
     }
 }
 
@@ -168,14 +619,49 @@ impl TryFrom<StorableRoute> for Route {
     type Error = ConfigError;
 
     fn try_from(value: StorableRoute) -> Result<Self, Self::Error> {
-        let path = Regex::new(&value.path);
-        match path {
-            Err(e) => Err(ConfigError::InvalidRegex(value.path, e)),
-            Ok(p) => Ok(Route {
-                path: p,
-                service: value.service,
-            }),
-        }
+        let match_mode = value.match_mode.unwrap_or_default();
+
+        Ok(Route {
+            path: Regex::new(&match_mode.anchor(&value.path)).map_err(|source| {
+                ConfigError::InvalidRegex {
+                    location: "path".to_string(),
+                    pattern: value.path.clone(),
+                    source,
+                }
+            })?,
+            service: value.service,
+            pattern: value.path,
+            match_mode,
+            priority: value.priority.unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<StorableCacheRoute> for CacheRoute {
+    type Error = ConfigError;
+
+    fn try_from(value: StorableCacheRoute) -> Result<Self, Self::Error> {
+        let (path, statuses) = match value {
+            StorableCacheRoute::Path(path) => (path, None),
+            StorableCacheRoute::WithStatuses { path, statuses } => (path, Some(statuses)),
+        };
+
+        let statuses = match statuses {
+            Some(statuses) => Some(
+                statuses
+                    .into_iter()
+                    .map(|status| {
+                        StatusCode::from_u16(status).map_err(|_| ConfigError::InvalidStatus(status))
+                    })
+                    .collect::<Result<Vec<StatusCode>, ConfigError>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(CacheRoute {
+            path: compile_regex(path, "path")?,
+            statuses,
+        })
     }
 }
 
@@ -191,29 +677,77 @@ impl TryFrom<StorableSession> for Session {
 
         for stored_service in value.services {
             validate_url_origin(&stored_service.location)?;
+            let alternate_origins = stored_service.alternate_locations.unwrap_or_default();
+            for alternate_origin in &alternate_origins {
+                validate_url_origin(alternate_origin)?;
+            }
+            if let Some(split) = &stored_service.split {
+                validate_url_origin(&split.location)?;
+                if split.percent > 100 {
+                    return Err(ConfigError::InvalidSplitPercent(split.percent));
+                }
+            }
 
-            let rewrites = match stored_service.rewrites {
-                Some(pm) => pm.into_iter().map(|r| r.try_into()).collect(),
-                None => Ok(Vec::new()),
-            }?;
+            let rewrites = stored_service
+                .rewrites
+                .unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    Rewrite::try_from(r).map_err(|e| {
+                        e.within(&format!(
+                            "services[{}].rewrites[{}]",
+                            stored_service.name, i
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<Rewrite>, ConfigError>>()?;
 
             let service = Service {
                 origin: stored_service.location,
+                alternate_origins,
+                sticky_key: stored_service.sticky_key,
+                split: stored_service.split,
+                retries: stored_service.retries.unwrap_or(0),
                 rewrites,
+                signing: stored_service.signing,
+                forwarded_header: stored_service.forwarded_header.unwrap_or(false),
+                via_header: stored_service.via_header.unwrap_or(false),
+                rewrite_redirects: stored_service.rewrite_redirects.unwrap_or(false),
+                static_dir: stored_service.static_dir.map(PathBuf::from),
+                mock: stored_service.mock.map(parse_mock_response).transpose()?,
+                body_rewrites: stored_service.body_rewrites.unwrap_or_default(),
+                compress_request: stored_service.compress_request,
             };
 
             services.insert(stored_service.name, service);
         }
 
         for stored_domain in value.domains {
-            let routes = match stored_domain.routes {
-                Some(dr) => dr.into_iter().map(|r| r.try_into()).collect(),
-                None => Ok(Vec::new()),
-            }?;
+            let mut routes = stored_domain
+                .routes
+                .unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    Route::try_from(r).map_err(|e| {
+                        e.within(&format!("domains[{}].routes[{}]", stored_domain.domain, i))
+                    })
+                })
+                .collect::<Result<Vec<Route>, ConfigError>>()?;
+            // The sort is stable, so routes with the same priority keep their configured order
+            routes.sort_by_key(|route| std::cmp::Reverse(route.priority));
+
+            let response_headers = match stored_domain.response_headers {
+                Some(rh) => parse_response_headers(rh)?,
+                None => HttpHeaderMap::new(),
+            };
 
             let domain = Domain {
                 default_service: stored_domain.default_service,
                 routes,
+                response_headers,
+                response_headers_mode: stored_domain.response_headers_mode.unwrap_or_default(),
             };
 
             domains.insert(stored_domain.domain, domain);
@@ -224,19 +758,40 @@ impl TryFrom<StorableSession> for Session {
         let cache_routes = match value.cache_routes {
             Some(cr) => Some(
                 cr.into_iter()
-                    .map(|r| Regex::new(&r))
-                    .collect::<Result<Vec<Regex>, regex::Error>>()
-                    .map_err(|e| ConfigError::InvalidRegex("cache route".to_string(), e))?,
+                    .enumerate()
+                    .map(|(i, r)| {
+                        CacheRoute::try_from(r)
+                            .map_err(|e| e.within(&format!("cache_routes[{}]", i)))
+                    })
+                    .collect::<Result<Vec<CacheRoute>, ConfigError>>()?,
             ),
             None => None,
         };
 
+        let rewrites = value
+            .rewrites
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| Rewrite::try_from(r).map_err(|e| e.within(&format!("rewrites[{}]", i))))
+            .collect::<Result<Vec<Rewrite>, ConfigError>>()?;
+
         Ok(Session {
             session_token: value.session_token,
             services,
             domains,
             domain_selection_order: choose_domain_ordering(domain_names),
             cache_routes,
+            rewrites,
+            rewrite_order: value.rewrite_order.unwrap_or_default(),
+            frame_ancestors: value.frame_ancestors,
+            session_key: value
+                .session_key
+                .map(SessionKeyConfig::new)
+                .transpose()?
+                .unwrap_or_default(),
+            expires_at: value.expires_at,
+            trusted_session_sources: value.trusted_session_sources,
         })
     }
 }
@@ -257,7 +812,7 @@ impl TryFrom<serde_json::Value> for Session {
 
 impl From<Session> for StorableSession {
     fn from(value: Session) -> Self {
-        let services: Vec<StorableService> = value
+        let mut services: Vec<StorableService> = value
             .services
             .into_iter()
             .map(|(name, service)| {
@@ -279,12 +834,34 @@ impl From<Session> for StorableSession {
                 StorableService {
                     name,
                     location: service.origin,
+                    alternate_locations: (!service.alternate_origins.is_empty())
+                        .then_some(service.alternate_origins),
+                    sticky_key: service.sticky_key,
+                    split: service.split,
+                    retries: (service.retries > 0).then_some(service.retries),
                     rewrites,
+                    signing: service.signing,
+                    forwarded_header: service.forwarded_header.then_some(true),
+                    via_header: service.via_header.then_some(true),
+                    rewrite_redirects: service.rewrite_redirects.then_some(true),
+                    static_dir: service
+                        .static_dir
+                        .map(|static_dir| static_dir.to_string_lossy().into_owned()),
+                    mock: service.mock.map(|mock| StorableMockResponse {
+                        status: Some(mock.status.as_u16()),
+                        body: Some(mock.body),
+                        content_type: None,
+                        headers: storable_headers(&mock.headers),
+                    }),
+                    body_rewrites: (!service.body_rewrites.is_empty())
+                        .then_some(service.body_rewrites),
+                    compress_request: service.compress_request,
                 }
             })
             .collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
 
-        let domains: Vec<StorableDomain> = value
+        let mut domains: Vec<StorableDomain> = value
             .domains
             .into_iter()
             .map(|(domain, domain_data)| {
@@ -297,8 +874,11 @@ impl From<Session> for StorableSession {
                             .routes
                             .into_iter()
                             .map(|route| StorableRoute {
-                                path: route.path.to_string(),
+                                path: route.pattern,
                                 service: route.service,
+                                match_mode: (route.match_mode != RouteMatch::Contains)
+                                    .then_some(route.match_mode),
+                                priority: (route.priority != 0).then_some(route.priority),
                             })
                             .collect(),
                     )
@@ -308,23 +888,170 @@ impl From<Session> for StorableSession {
                     domain,
                     default_service,
                     routes,
+                    response_headers: storable_headers(&domain_data.response_headers),
+                    response_headers_mode: Some(domain_data.response_headers_mode),
                 }
             })
             .collect();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
 
         let cache_routes = value.cache_routes.map(|cr| {
             cr.into_iter()
-                .map(|r| r.to_string())
-                .collect::<Vec<String>>()
+                .map(|r| match r.statuses {
+                    Some(statuses) => StorableCacheRoute::WithStatuses {
+                        path: r.path.to_string(),
+                        statuses: statuses.iter().map(StatusCode::as_u16).collect(),
+                    },
+                    None => StorableCacheRoute::Path(r.path.to_string()),
+                })
+                .collect::<Vec<StorableCacheRoute>>()
         });
 
+        let rewrites = if value.rewrites.is_empty() {
+            None
+        } else {
+            Some(
+                value
+                    .rewrites
+                    .into_iter()
+                    .map(|rewrite| StorableRewrite {
+                        source: rewrite.source.to_string(),
+                        target: rewrite.target,
+                    })
+                    .collect(),
+            )
+        };
+
         StorableSession {
             session_token: value.session_token,
             services,
             domains,
             cache_routes,
+            rewrites,
+            rewrite_order: Some(value.rewrite_order),
+            frame_ancestors: value.frame_ancestors,
+            session_key: Some(value.session_key.key().to_string()),
+            expires_at: value.expires_at,
+            trusted_session_sources: value.trusted_session_sources,
+        }
+    }
+}
+
+impl Session {
+    /// Checks the session for likely mistakes that don't make it invalid.
+    pub fn validate(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+
+        let mut domain_names: Vec<&String> = self.domains.keys().collect();
+        domain_names.sort();
+
+        for domain_name in domain_names {
+            let routes = &self.domains[domain_name].routes;
+
+            for (index, route) in routes.iter().enumerate() {
+                let shadowed_by = routes[..index]
+                    .iter()
+                    .find(|earlier| route_shadows(earlier.path.as_str(), route.path.as_str()));
+
+                if let Some(earlier) = shadowed_by {
+                    warnings.push(ConfigWarning::ShadowedRoute {
+                        domain: domain_name.clone(),
+                        pattern: route.pattern.clone(),
+                        shadowed_by: earlier.pattern.clone(),
+                    });
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+// A pattern of the form `^?<literal>(.*)?$?`, which is what most route paths look like.
+struct LiteralPattern {
+    anchored: bool,
+    literal: String,
+    open_ended: bool,
+}
+
+impl LiteralPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let (anchored, rest) = match pattern.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (end_anchored, rest) = match rest.strip_suffix('$') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (wildcard, rest) = match rest.strip_suffix(".*") {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+
+        let (literal, consumed) = literal_prefix(rest);
+        if consumed != rest.len() {
+            return None;
+        }
+
+        Some(Self {
+            anchored,
+            literal,
+            open_ended: wildcard || !end_anchored,
+        })
+    }
+}
+
+// Returns the unescaped literal text at the start of a pattern and how many bytes of the
+// pattern it spans.
+fn literal_prefix(pattern: &str) -> (String, usize) {
+    let mut literal = String::new();
+    let mut chars = pattern.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some((_, escaped)) if !escaped.is_alphanumeric() => {
+                    literal.push(*escaped);
+                    chars.next();
+                }
+                _ => return (literal, index),
+            },
+            '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' => {
+                // A quantifier applies to the previous character, so it is not part of the literal
+                if matches!(c, '*' | '?' | '{') {
+                    literal.pop();
+                }
+                return (literal, index);
+            }
+            _ => literal.push(c),
         }
     }
+
+    (literal, pattern.len())
+}
+
+// Whether every path matched by `later` is also matched by `earlier`. This is only a heuristic
+// covering the common `^/prefix/.*` style routes, so it can miss shadowed routes but should not
+// report false positives.
+fn route_shadows(earlier: &str, later: &str) -> bool {
+    if earlier == later {
+        return true;
+    }
+
+    let earlier = match LiteralPattern::parse(earlier) {
+        Some(pattern) if pattern.open_ended => pattern,
+        _ => return false,
+    };
+
+    let later_anchored = later.starts_with('^');
+    let (later_prefix, _) = literal_prefix(later.trim_start_matches('^'));
+
+    if earlier.anchored {
+        later_anchored && later_prefix.starts_with(&earlier.literal)
+    } else {
+        later_prefix.contains(&earlier.literal)
+    }
 }
 
 pub fn update_session_req_from_json(input_json: String) -> Result<(String, Session), ConfigError> {
@@ -339,6 +1066,12 @@ pub fn update_session_req_from_json(input_json: String) -> Result<(String, Sessi
                 services: c.services,
                 domains: c.domains,
                 cache_routes: c.cache_routes,
+                rewrites: c.rewrites,
+                rewrite_order: c.rewrite_order,
+                frame_ancestors: c.frame_ancestors,
+                session_key: c.session_key,
+                expires_at: None,
+                trusted_session_sources: c.trusted_session_sources,
             }
             .try_into();
 
@@ -362,6 +1095,12 @@ pub fn create_preview_req_from_json(input_json: String) -> Result<Session, Confi
                 services: c.services,
                 domains: c.domains,
                 cache_routes: None,
+                rewrites: c.rewrites,
+                rewrite_order: c.rewrite_order,
+                frame_ancestors: c.frame_ancestors,
+                session_key: c.session_key,
+                expires_at: None,
+                trusted_session_sources: c.trusted_session_sources,
             }
             .try_into();
 
@@ -400,7 +1139,9 @@ fn validate_service_references(server_config: &StorableSession) -> Result<(), Co
 
         if let Some(routes) = &domain.routes {
             for route in routes {
-                if !service_names.contains(&route.service.as_str()) {
+                if !is_service_template(&route.service)
+                    && !service_names.contains(&route.service.as_str())
+                {
                     return Err(ConfigError::NoSuchService(route.service.to_string()));
                 }
             }
@@ -423,6 +1164,62 @@ fn validate_url_origin(url: &Url) -> Result<(), ConfigError> {
     Ok(())
 }
 
+fn parse_response_headers(headers: HashMap<String, String>) -> Result<HttpHeaderMap, ConfigError> {
+    let mut response_headers = HttpHeaderMap::new();
+    for (name, value) in headers {
+        let header_name = HttpHeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| ConfigError::InvalidHeader(name.clone()))?;
+        let header_value =
+            HeaderValue::from_str(&value).map_err(|_| ConfigError::InvalidHeader(name.clone()))?;
+        response_headers.insert(header_name, header_value);
+    }
+
+    Ok(response_headers)
+}
+
+fn storable_headers(headers: &HttpHeaderMap) -> Option<HashMap<String, String>> {
+    if headers.is_empty() {
+        return None;
+    }
+
+    Some(
+        headers
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.to_string(), value.to_string()))
+            })
+            .collect(),
+    )
+}
+
+fn parse_mock_response(mock: StorableMockResponse) -> Result<MockResponse, ConfigError> {
+    let status = match mock.status {
+        Some(status) => {
+            StatusCode::from_u16(status).map_err(|_| ConfigError::InvalidStatus(status))?
+        }
+        None => StatusCode::OK,
+    };
+
+    let mut headers = match mock.headers {
+        Some(headers) => parse_response_headers(headers)?,
+        None => HttpHeaderMap::new(),
+    };
+    if let Some(content_type) = mock.content_type {
+        let content_type = HeaderValue::from_str(&content_type)
+            .map_err(|_| ConfigError::InvalidHeader(CONTENT_TYPE.to_string()))?;
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+
+    Ok(MockResponse {
+        status,
+        headers,
+        body: mock.body.unwrap_or_default(),
+    })
+}
+
 fn choose_domain_ordering(domains: Vec<String>) -> Vec<String> {
     let mut sorted_domains = domains;
     sorted_domains.sort_by(|a, b| {
@@ -447,11 +1244,14 @@ fn choose_domain_ordering(domains: Vec<String>) -> Vec<String> {
     sorted_domains
 }
 
+/// The JSON a session is stored as. The same session always gives the same JSON, with services
+/// and domains sorted by name and object keys sorted, as preview names are a hash of it.
 pub fn session_to_json(session: Session) -> String {
     let storable_session: StorableSession = session.into();
 
     // This should never fail, due to previous validation
-    serde_json::to_string(&storable_session).unwrap()
+    let value = serde_json::to_value(&storable_session).unwrap();
+    value.to_string()
 }
 
 #[cfg(test)]
@@ -583,11 +1383,198 @@ mod tests {
 
         assert_eq!(server_config.cache_routes.as_ref().unwrap().len(), 1);
         assert_eq!(
-            server_config.cache_routes.as_ref().unwrap()[0].as_str(),
+            server_config.cache_routes.as_ref().unwrap()[0]
+                .path
+                .as_str(),
             "/static/.*"
         );
     }
 
+    #[test]
+    fn test_cache_route_statuses() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["cache_routes"] = serde_json::json!([
+            "/static/.*",
+            { "path": "/api/.*", "statuses": [200, 404] }
+        ]);
+        let config: Session = config_value.try_into().unwrap();
+        let cache_routes = config.cache_routes.unwrap();
+
+        assert!(cache_routes[0].caches_status(StatusCode::OK));
+        assert!(cache_routes[0].caches_status(StatusCode::NO_CONTENT));
+        assert!(!cache_routes[0].caches_status(StatusCode::NOT_FOUND));
+        assert!(!cache_routes[0].caches_status(StatusCode::MOVED_PERMANENTLY));
+
+        assert_eq!(cache_routes[1].path.as_str(), "/api/.*");
+        assert!(cache_routes[1].caches_status(StatusCode::OK));
+        assert!(cache_routes[1].caches_status(StatusCode::NOT_FOUND));
+        assert!(!cache_routes[1].caches_status(StatusCode::NO_CONTENT));
+    }
+
+    #[test]
+    fn test_cache_route_patterns() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["cache_routes"] = serde_json::json!([".*/assets/.*", "^/static/[^/]+\\.js$"]);
+        let config: Session = config_value.clone().try_into().unwrap();
+        let cache_routes = config.cache_routes.unwrap();
+
+        let assets = &cache_routes[0];
+        assert!(assets.matches("/assets/logo.png"));
+        assert!(assets.matches("/app/assets/css/main.css"));
+        assert!(!assets.matches("/assets"));
+        assert!(!assets.matches("/assetsfoo/logo.png"));
+        assert!(!assets.matches("/api/users"));
+
+        let scripts = &cache_routes[1];
+        assert!(scripts.matches("/static/app.js"));
+        assert!(!scripts.matches("/static/app.json"));
+        assert!(!scripts.matches("/static/js/app.js"));
+        assert!(!scripts.matches("/v1/static/app.js"));
+
+        // Globs are not supported, a leading * is reported instead of silently never matching
+        config_value["cache_routes"] = serde_json::json!(["*.js"]);
+        let config: Result<Session, ConfigError> = config_value.try_into();
+        assert!(matches!(
+            config,
+            Err(ConfigError::InvalidRegex { location, .. }) if location == "cache_routes[0].path"
+        ));
+    }
+
+    #[test]
+    fn test_cache_route_invalid_status() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["cache_routes"] = serde_json::json!([
+            { "path": "/api/.*", "statuses": [1000] }
+        ]);
+        let config: Result<Session, ConfigError> = config_value.try_into();
+
+        assert!(matches!(config, Err(ConfigError::InvalidStatus(1000))));
+    }
+
+    #[test]
+    fn test_invalid_route_regex() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["domains"][0]["routes"][0]["path"] = serde_json::json!("/api/(v1/.*");
+        let config: Result<Session, ConfigError> = config_value.try_into();
+
+        let error = config.unwrap_err();
+        assert!(matches!(
+            &error,
+            ConfigError::InvalidRegex { location, pattern, .. }
+                if location == "domains[example.com].routes[0].path" && pattern == "/api/(v1/.*"
+        ));
+        assert!(error
+            .to_string()
+            .starts_with("invalid regex \"/api/(v1/.*\" at domains[example.com].routes[0].path: "));
+    }
+
+    #[test]
+    fn test_invalid_rewrite_regex() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["services"][0]["rewrites"][0]["source"] = serde_json::json!("/foo/[a-");
+        let config: Result<Session, ConfigError> = config_value.try_into();
+
+        let error = config.unwrap_err().to_string();
+        assert!(error
+            .starts_with("invalid regex \"/foo/[a-\" at services[frontend].rewrites[0].source: "));
+    }
+
+    #[test]
+    fn test_route_match_modes() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["domains"][0]["routes"] = serde_json::json!([
+            { "path": "/api", "service": "backend" },
+            { "path": "/api", "service": "backend", "match": "prefix" },
+            { "path": "/api", "service": "backend", "match": "exact" },
+            { "path": "/a|/b", "service": "backend", "match": "exact" }
+        ]);
+        let config: Session = config_value.try_into().unwrap();
+        let routes = &config.domains["example.com"].routes;
+
+        let contains = &routes[0].path;
+        assert!(contains.is_match("/api/users"));
+        assert!(contains.is_match("/not/api"));
+
+        let prefix = &routes[1].path;
+        assert!(prefix.is_match("/api/users"));
+        assert!(!prefix.is_match("/not/api"));
+
+        let exact = &routes[2].path;
+        assert!(exact.is_match("/api"));
+        assert!(!exact.is_match("/api/users"));
+        assert!(!exact.is_match("/not/api"));
+
+        let alternation = &routes[3].path;
+        assert!(alternation.is_match("/b"));
+        assert!(!alternation.is_match("/a/c"));
+        assert!(!alternation.is_match("/c/b"));
+
+        // The configured pattern and mode are stored, not the anchored regex
+        let storable = StorableSession::from(config);
+        let domain = storable
+            .domains
+            .iter()
+            .find(|domain| domain.domain == "example.com")
+            .unwrap();
+        let routes = domain.routes.as_ref().unwrap();
+        assert_eq!(routes[0].path, "/api");
+        assert_eq!(routes[0].match_mode, None);
+        assert_eq!(routes[2].path, "/api");
+        assert_eq!(routes[2].match_mode, Some(RouteMatch::Exact));
+    }
+
+    #[test]
+    fn test_validate_shadowed_routes() {
+        let mut config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        config_value["domains"][0]["routes"] = serde_json::json!([
+            { "path": "^/api/.*", "service": "backend" },
+            { "path": "^/api/v1/users", "service": "frontend" },
+            { "path": "^/static/.*", "service": "frontend" }
+        ]);
+        let config: Session = config_value.try_into().unwrap();
+
+        assert_eq!(
+            config.validate(),
+            vec![ConfigWarning::ShadowedRoute {
+                domain: "example.com".to_string(),
+                pattern: "^/api/v1/users".to_string(),
+                shadowed_by: "^/api/.*".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_no_shadowed_routes() {
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_route_shadows() {
+        // Catch all
+        assert!(route_shadows(".*", "/api/v1/.*"));
+        // Identical
+        assert!(route_shadows(
+            "/api/v1/(users|teams)",
+            "/api/v1/(users|teams)"
+        ));
+        // Unanchored prefix appears in the later route
+        assert!(route_shadows("/api/", "^/v2/api/users"));
+        assert!(route_shadows("/api/v1/.*", "/api/v1/users/.*"));
+        assert!(route_shadows("^/api\\.v1/", "^/api\\.v1/users"));
+
+        // Later route is more general
+        assert!(!route_shadows("^/api/v1/.*", "^/api/.*"));
+        // Earlier is anchored but the later route is not
+        assert!(!route_shadows("^/api/.*", "/api/v1/.*"));
+        // Exact match only
+        assert!(!route_shadows("^/api$", "^/api/v1"));
+        // Optional character makes the prefix shorter than it looks
+        assert!(!route_shadows("^/apis/.*", "^/apis?/v1"));
+    }
+
     #[test]
     fn test_choose_domain_ordering() {
         let input = vec![
@@ -1,16 +1,77 @@
+use std::time::Duration;
+
 use crate::{
     extract_tracestate_session, first_subdomain, headers::HeaderName,
     name_gen::deterministic_six_char_hash, random_animal, random_six_char, session_to_json,
-    ConfigError, HeaderMap, NameKind, Session, SessionError, StringStore,
+    tracestate_members, ConfigError, HeaderMap, NameKind, Session, SessionError, SessionKeyConfig,
+    SessionLookup, SessionSource, StringStore, SESSION_KEY_PREFIX,
 };
 
+// A session name to look up, and the session key it was found under in a tracestate or baggage
+// header. The session has to use that key for the lookup to count.
+struct Candidate {
+    lookup: SessionLookup,
+    session_key: Option<String>,
+}
+
+// How many generated names are tried by default before giving up on a kind of name
+const DEFAULT_NAME_ATTEMPTS: usize = 20;
+
 pub struct SessionAllocator<'a, S: StringStore> {
     store: &'a S,
+    trusted_sources: &'a [SessionSource],
+    name_generator: Option<&'a (dyn Fn() -> String + Sync)>,
+    name_attempts: usize,
+    session_ttl: Option<Duration>,
+    timestamp: Option<u64>,
 }
 
 impl<'a, S: StringStore> SessionAllocator<'a, S> {
     pub fn new(store: &'a S) -> Self {
-        Self { store }
+        Self {
+            store,
+            trusted_sources: &SessionSource::ALL,
+            name_generator: None,
+            name_attempts: DEFAULT_NAME_ATTEMPTS,
+            session_ttl: None,
+            timestamp: None,
+        }
+    }
+
+    /// Only take the session of a request from these sources. All of them are trusted by default.
+    pub fn with_trusted_sources(mut self, trusted_sources: &'a [SessionSource]) -> Self {
+        self.trusted_sources = trusted_sources;
+        self
+    }
+
+    /// Generate the names of `NameKind::Animal` sessions with this instead, e.g. from a custom
+    /// word list. Names that are taken are retried the same way, and after too many attempts a
+    /// random six character name is used.
+    pub fn with_name_generator(mut self, name_generator: &'a (dyn Fn() -> String + Sync)) -> Self {
+        self.name_generator = Some(name_generator);
+        self
+    }
+
+    /// How many generated names of a `NameKind::Animal` session are tried before falling back to
+    /// random six character names, and how many of those are tried before `store_session` gives
+    /// up with `SessionError::NameExhausted`. 20 by default.
+    pub fn with_name_attempts(mut self, name_attempts: usize) -> Self {
+        self.name_attempts = name_attempts;
+        self
+    }
+
+    /// Sessions stored through this allocator expire `ttl` after they are stored. This needs
+    /// `with_timestamp` to know when that is.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    /// The current time in seconds since the unix epoch. Expired sessions are only left out
+    /// when the allocator knows the time.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
     }
 
     pub async fn get_request_session(
@@ -18,65 +79,134 @@ impl<'a, S: StringStore> SessionAllocator<'a, S> {
         url: &str,
         headers: &HeaderMap,
     ) -> Result<(String, Session), SessionError> {
-        let url_name = first_subdomain(url);
-        if let Some(config) = self.get_session_config(url_name.to_string()).await? {
-            return Ok((url_name, config));
-        }
-
-        if let Some(forwarded_host) = headers.get(HeaderName::ForwardedHost) {
-            let forwarded_host_name = first_subdomain(forwarded_host);
-            if let Some(config) = self
-                .get_session_config(forwarded_host_name.to_string())
-                .await?
-            {
-                return Ok((forwarded_host_name, config));
+        // Candidate session names, in the order they are tried
+        let mut candidates = Vec::new();
+        let mut push = |source, key, session_key| {
+            if self.trusted_sources.contains(&source) {
+                candidates.push(Candidate {
+                    lookup: SessionLookup { source, key },
+                    session_key,
+                });
             }
-        }
+        };
+
+        push(SessionSource::Url, first_subdomain(url), None);
 
-        if let Some(referer) = headers.get(HeaderName::Referer) {
-            let referer_name = first_subdomain(referer);
-            if let Some(config) = self.get_session_config(referer_name.to_string()).await? {
-                return Ok((referer_name, config));
+        let host_sources = [
+            (SessionSource::ForwardedHost, HeaderName::ForwardedHost),
+            (SessionSource::Referer, HeaderName::Referer),
+            (SessionSource::Origin, HeaderName::Origin),
+        ];
+        for (source, header) in host_sources {
+            if let Some(value) = headers.get(header) {
+                push(source, first_subdomain(value), None);
             }
         }
 
-        if let Some(origin) = headers.get(HeaderName::Origin) {
-            let origin_name = first_subdomain(origin);
-            if let Some(config) = self.get_session_config(origin_name.to_string()).await? {
-                return Ok((origin_name, config));
+        let tracing_sources = [
+            (SessionSource::Tracestate, HeaderName::TraceState),
+            (SessionSource::Baggage, HeaderName::Baggage),
+        ];
+        for (source, header) in tracing_sources {
+            let Some(value) = headers.get(header) else {
+                continue;
+            };
+
+            // Most sessions use the default key. Sessions with a key of their own can only be
+            // found by trying the other members with the session key prefix, which is why the
+            // key has to match as well.
+            let default_key = SessionKeyConfig::default();
+            push(
+                source,
+                extract_tracestate_session(value, &default_key),
+                Some(default_key.key().to_string()),
+            );
+            let custom_keys = tracestate_members(value).filter(|(key, _)| {
+                key.starts_with(SESSION_KEY_PREFIX) && *key != default_key.key()
+            });
+            for (key, name) in custom_keys {
+                push(source, name.to_string(), Some(key.to_string()));
             }
         }
 
-        if let Some(tracestate) = headers.get(HeaderName::TraceState) {
-            let trace_name = extract_tracestate_session(tracestate);
-            if let Some(config) = self.get_session_config(trace_name.to_string()).await? {
-                return Ok((trace_name, config));
+        for candidate in &candidates {
+            let Some(config) = self
+                .get_session_config(candidate.lookup.key.clone())
+                .await?
+            else {
+                continue;
+            };
+
+            let uses_key = match &candidate.session_key {
+                Some(session_key) => config.session_key.key() == session_key,
+                None => true,
+            };
+            // A session can trust fewer sources than the server, which is how a session is
+            // locked down on the worker
+            let trusts_source = match &config.trusted_session_sources {
+                Some(sources) => sources.contains(&candidate.lookup.source),
+                None => true,
+            };
+            if uses_key && trusts_source {
+                return Ok((candidate.lookup.key.clone(), config));
             }
         }
 
-        if let Some(baggage) = headers.get(HeaderName::Baggage) {
-            let baggage_name = extract_tracestate_session(baggage);
-            if let Some(config) = self.get_session_config(baggage_name.to_string()).await? {
-                return Ok((baggage_name, config));
-            }
+        Err(SessionError::NoRequestSession {
+            url: url.to_string(),
+            attempted: candidates
+                .into_iter()
+                .map(|candidate| candidate.lookup)
+                .collect(),
+        })
+    }
+
+    /// The session stored under `name`, as long as `session_token` is the one it was created with.
+    pub async fn get_authorized_session(
+        &self,
+        name: &str,
+        session_token: &str,
+    ) -> Result<Session, SessionError> {
+        match self.get_session_config(name.to_string()).await? {
+            Some(session) if session.session_token == session_token => Ok(session),
+            // Don't tell apart sessions that don't exist from wrong tokens
+            _ => Err(SessionError::NoSuchSession(name.to_string())),
         }
+    }
 
-        Err(SessionError::NoSuchSession(url.to_string()))
+    /// Delete a session. Removing a session that doesn't exist succeeds too.
+    pub async fn remove_session(&self, name: &str) -> Result<(), SessionError> {
+        self.store.delete(name.to_string()).await
     }
 
     pub async fn store_session(
         &self,
-        config: Session,
+        mut config: Session,
         name_kind: NameKind,
         desired_name: String,
     ) -> Result<String, SessionError> {
-        let config_str = session_to_json(config.clone());
-
+        // Named before the expiry is set, so storing the same preview again gets the same name
         let name = self
-            .choose_name(desired_name, config.session_token, name_kind, &config_str)
+            .choose_name(
+                desired_name,
+                config.session_token.clone(),
+                name_kind,
+                &session_to_json(config.clone()),
+            )
             .await?;
 
-        self.store.put(name.clone(), config_str).await?;
+        let ttl = self.session_ttl.zip(self.timestamp);
+        config.expires_at = ttl.map(|(ttl, timestamp)| timestamp + ttl.as_secs());
+        let config_str = session_to_json(config);
+
+        match ttl {
+            Some((ttl, _)) => {
+                self.store
+                    .put_with_ttl(name.clone(), config_str, ttl)
+                    .await?
+            }
+            None => self.store.put(name.clone(), config_str).await?,
+        }
 
         Ok(name)
     }
@@ -114,10 +244,18 @@ impl<'a, S: StringStore> SessionAllocator<'a, S> {
         let config_value: serde_json::Value =
             serde_json::from_str(&value).map_err(|e| SessionError::ConfigErr(e.to_string()))?;
 
-        let session_config = config_value
+        let session_config: Session = config_value
             .try_into()
             .map_err(|e: ConfigError| SessionError::ConfigErr(e.to_string()))?;
 
+        let expired = session_config
+            .expires_at
+            .zip(self.timestamp)
+            .is_some_and(|(expires_at, timestamp)| expires_at <= timestamp);
+        if expired {
+            return Ok(None);
+        }
+
         Ok(Some(session_config))
     }
 
@@ -131,43 +269,28 @@ impl<'a, S: StringStore> SessionAllocator<'a, S> {
             return Ok(deterministic_six_char_hash(config_json));
         }
 
-        let mut key = String::new();
-
         if !desired_name.is_empty() && !self.store.exists(desired_name.clone()).await? {
-            key = desired_name;
+            return Ok(desired_name);
         }
 
-        if key.is_empty() {
-            let mut tried_animal_key = false;
-            loop {
-                let generated_key = if !tried_animal_key {
-                    tried_animal_key = true;
-                    self.generate_unique_animal_key(20).await?
-                } else {
-                    random_six_char()
-                };
-
-                if !self.store.exists(generated_key.clone()).await? {
-                    key = generated_key;
-                    break;
-                }
+        for _ in 0..self.name_attempts {
+            let generated_key = match self.name_generator {
+                Some(name_generator) => name_generator(),
+                None => random_animal(),
+            };
+            if !self.store.exists(generated_key.clone()).await? {
+                return Ok(generated_key);
             }
         }
 
-        Ok(key)
-    }
-
-    async fn generate_unique_animal_key(
-        &self,
-        max_attempts: usize,
-    ) -> Result<String, SessionError> {
-        for _ in 0..max_attempts {
-            let generated_key = random_animal();
+        // Fallback to SixChar logic
+        for _ in 0..self.name_attempts {
+            let generated_key = random_six_char();
             if !self.store.exists(generated_key.clone()).await? {
                 return Ok(generated_key);
             }
         }
-        // Fallback to SixChar logic
-        Ok(random_six_char())
+
+        Err(SessionError::NameExhausted(self.name_attempts * 2))
     }
 }
@@ -0,0 +1,213 @@
+use url::Url;
+
+use crate::{
+    ConfigError, Session, SessionSource, StorableCacheRoute, StorableDomain, StorableRewrite,
+    StorableRoute, StorableService, StorableSession,
+};
+
+/// Builds a [`Session`] in code, without going through JSON. `build` validates the session the
+/// same way as a session sent by `linkup start`.
+pub struct SessionBuilder {
+    session: StorableSession,
+    // The first problem found while building, reported by `build`
+    error: Option<ConfigError>,
+}
+
+impl SessionBuilder {
+    pub fn new(session_token: impl Into<String>) -> Self {
+        Self {
+            session: StorableSession {
+                session_token: session_token.into(),
+                services: Vec::new(),
+                domains: Vec::new(),
+                cache_routes: None,
+                rewrites: None,
+                rewrite_order: None,
+                frame_ancestors: None,
+                session_key: None,
+                expires_at: None,
+                trusted_session_sources: None,
+            },
+            error: None,
+        }
+    }
+
+    pub fn add_service(mut self, name: impl Into<String>, location: &str) -> Self {
+        match Url::parse(location) {
+            Ok(location) => self.session.services.push(StorableService {
+                name: name.into(),
+                location,
+                alternate_locations: None,
+                sticky_key: None,
+                split: None,
+                retries: None,
+                rewrites: None,
+                signing: None,
+                forwarded_header: None,
+                via_header: None,
+                rewrite_redirects: None,
+                static_dir: None,
+                mock: None,
+                body_rewrites: None,
+                compress_request: None,
+            }),
+            Err(_) => self.fail(ConfigError::InvalidURL(location.to_string())),
+        }
+
+        self
+    }
+
+    pub fn add_domain(
+        mut self,
+        domain: impl Into<String>,
+        default_service: impl Into<String>,
+    ) -> Self {
+        self.session.domains.push(StorableDomain {
+            domain: domain.into(),
+            default_service: default_service.into(),
+            routes: None,
+            response_headers: None,
+            response_headers_mode: None,
+        });
+
+        self
+    }
+
+    /// Route requests to `domain` whose path matches `path` to `service`. The domain has to be
+    /// added first. Routes are tried in the order they are added.
+    pub fn add_route(
+        mut self,
+        domain: &str,
+        path: impl Into<String>,
+        service: impl Into<String>,
+    ) -> Self {
+        let route = StorableRoute {
+            path: path.into(),
+            service: service.into(),
+            match_mode: None,
+            priority: None,
+        };
+
+        match self.session.domains.iter_mut().find(|d| d.domain == domain) {
+            Some(stored_domain) => stored_domain
+                .routes
+                .get_or_insert_with(Vec::new)
+                .push(route),
+            None => self.fail(ConfigError::NoSuchDomain(domain.to_string())),
+        }
+
+        self
+    }
+
+    /// Add a rewrite applied to the requests of every service.
+    pub fn add_rewrite(mut self, source: impl Into<String>, target: impl Into<String>) -> Self {
+        self.session
+            .rewrites
+            .get_or_insert_with(Vec::new)
+            .push(StorableRewrite {
+                source: source.into(),
+                target: target.into(),
+            });
+
+        self
+    }
+
+    pub fn cache_routes<S: Into<String>>(mut self, paths: impl IntoIterator<Item = S>) -> Self {
+        self.session.cache_routes = Some(
+            paths
+                .into_iter()
+                .map(|path| StorableCacheRoute::Path(path.into()))
+                .collect(),
+        );
+
+        self
+    }
+
+    pub fn trusted_session_sources(mut self, sources: &[SessionSource]) -> Self {
+        self.session.trusted_session_sources = Some(sources.to_vec());
+        self
+    }
+
+    pub fn build(self) -> Result<Session, ConfigError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => self.session.try_into(),
+        }
+    }
+
+    fn fail(&mut self, error: ConfigError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{get_target_service, HeaderMap};
+
+    use super::*;
+
+    fn test_session() -> SessionBuilder {
+        SessionBuilder::new("token")
+            .add_service("frontend", "http://localhost:8000")
+            .add_service("backend", "http://localhost:8001")
+            .add_domain("example.com", "frontend")
+            .add_route("example.com", "^/api/.*", "backend")
+            .add_rewrite("^/v1/(.*)", "/$1")
+            .cache_routes(["/static/.*"])
+    }
+
+    #[test]
+    fn test_build_session() {
+        let session = test_session().build().unwrap();
+
+        assert_eq!(session.session_token, "token");
+        assert_eq!(session.services.len(), 2);
+        assert_eq!(session.domains["example.com"].routes.len(), 1);
+        assert_eq!(session.rewrites[0].source.as_str(), "^/v1/(.*)");
+        assert_eq!(session.cache_routes.unwrap()[0].path.as_str(), "/static/.*");
+    }
+
+    #[test]
+    fn test_route_with_built_session() {
+        let session = test_session().build().unwrap();
+        let headers = HeaderMap::new();
+
+        let target = get_target_service(
+            "http://my-session.example.com/api/users",
+            &headers,
+            &session,
+            "my-session",
+        )
+        .unwrap();
+        assert_eq!(target.name, "backend");
+        assert_eq!(target.url, "http://localhost:8001/api/users");
+
+        let target = get_target_service(
+            "http://my-session.example.com/v1/home",
+            &headers,
+            &session,
+            "my-session",
+        )
+        .unwrap();
+        assert_eq!(target.name, "frontend");
+        assert_eq!(target.url, "http://localhost:8000/home");
+    }
+
+    #[test]
+    fn test_build_invalid_session() {
+        let result = test_session()
+            .add_route("unknown.com", "/.*", "backend")
+            .build();
+        assert!(
+            matches!(result, Err(ConfigError::NoSuchDomain(domain)) if domain == "unknown.com")
+        );
+
+        let result = test_session().add_service("broken", "not a url").build();
+        assert!(matches!(result, Err(ConfigError::InvalidURL(_))));
+
+        let result = test_session()
+            .add_route("example.com", "/.*", "missing")
+            .build();
+        assert!(matches!(result, Err(ConfigError::NoSuchService(service)) if service == "missing"));
+    }
+}
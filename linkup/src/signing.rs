@@ -0,0 +1,181 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use url::Url;
+
+use crate::{HeaderMap, Session, TargetService};
+
+pub const DEFAULT_SIGNATURE_HEADER: &str = "x-linkup-signature";
+
+/// Opt-in signing of the requests forwarded to a service. Useful for services that sit behind
+/// an auth proxy expecting a HMAC of the request path and a timestamp.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RequestSigning {
+    pub secret: String,
+    #[serde(default = "default_signature_header")]
+    pub header: String,
+    #[serde(default)]
+    pub scheme: SigningScheme,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SigningScheme {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+}
+
+fn default_signature_header() -> String {
+    DEFAULT_SIGNATURE_HEADER.to_string()
+}
+
+impl RequestSigning {
+    /// Signs `{timestamp}.{path}` with the configured secret, returning the header value
+    /// in the form `t={timestamp},v1={hex encoded signature}`.
+    pub fn sign(&self, path: &str, timestamp: u64) -> String {
+        let message = format!("{}.{}", timestamp, path);
+
+        let signature = match self.scheme {
+            SigningScheme::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+                    .expect("HMAC can take a key of any size");
+                mac.update(message.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+            SigningScheme::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(self.secret.as_bytes())
+                    .expect("HMAC can take a key of any size");
+                mac.update(message.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+        };
+
+        format!("t={},v1={}", timestamp, signature)
+    }
+}
+
+/// Returns the signature header for the target service, if the service has signing configured.
+///
+/// The timestamp (seconds since the unix epoch) is passed in since the worker and the local
+/// server get the current time in different ways.
+pub fn get_signing_headers(
+    config: &Session,
+    target_service: &TargetService,
+    timestamp: u64,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    let signing = match config
+        .services
+        .get(&target_service.name)
+        .and_then(|service| service.signing.as_ref())
+    {
+        Some(signing) => signing,
+        None => return headers,
+    };
+
+    if let Ok(target_url) = Url::parse(&target_service.url) {
+        headers.insert(
+            signing.header.clone(),
+            signing.sign(target_url.path(), timestamp),
+        );
+    }
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF_STR: &str = r#"
+    {
+        "session_token": "abcxyz",
+        "services": [
+            {
+                "name": "frontend",
+                "location": "http://localhost:8000"
+            },
+            {
+                "name": "backend",
+                "location": "http://localhost:8001",
+                "signing": {
+                    "secret": "my-secret",
+                    "header": "x-auth-signature"
+                }
+            }
+        ],
+        "domains": [
+            {
+                "domain": "example.com",
+                "default_service": "frontend"
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn test_sign() {
+        let signing = RequestSigning {
+            secret: "my-secret".to_string(),
+            header: DEFAULT_SIGNATURE_HEADER.to_string(),
+            scheme: SigningScheme::HmacSha256,
+        };
+
+        assert_eq!(
+            signing.sign("/api/v1/users", 1700000000),
+            "t=1700000000,v1=068cd14c59082dd72ae2cc72dd4cb026145b955a269cff6c3fdf72545725c1c5"
+        );
+
+        let signing = RequestSigning {
+            scheme: SigningScheme::HmacSha512,
+            ..signing
+        };
+
+        assert_eq!(
+            signing.sign("/api/v1/users", 1700000000),
+            "t=1700000000,v1=1d9b7b0b6fc6ce069920c54f5157629f0bbf97407d7928683f85de9cdb094838d333a7c4fae0414ab4dc212e60369c7afe07e84561e75728c39bc84778943492"
+        );
+    }
+
+    #[test]
+    fn test_get_signing_headers() {
+        let config_value: serde_json::Value = serde_json::from_str(CONF_STR).unwrap();
+        let config: Session = config_value.try_into().unwrap();
+
+        let signed = get_signing_headers(
+            &config,
+            &TargetService {
+                name: "backend".to_string(),
+                url: "http://localhost:8001/api/v1/users?a=b".to_string(),
+            },
+            1700000000,
+        );
+
+        assert_eq!(
+            signed.get("x-auth-signature").unwrap(),
+            "t=1700000000,v1=068cd14c59082dd72ae2cc72dd4cb026145b955a269cff6c3fdf72545725c1c5"
+        );
+
+        let unsigned = get_signing_headers(
+            &config,
+            &TargetService {
+                name: "frontend".to_string(),
+                url: "http://localhost:8000/api/v1/users".to_string(),
+            },
+            1700000000,
+        );
+
+        assert!(unsigned.get("x-auth-signature").is_none());
+        assert!(unsigned.get(DEFAULT_SIGNATURE_HEADER).is_none());
+    }
+
+    #[test]
+    fn test_signing_defaults() {
+        let signing: RequestSigning = serde_json::from_str(r#"{"secret": "my-secret"}"#).unwrap();
+
+        assert_eq!(signing.header, DEFAULT_SIGNATURE_HEADER);
+        assert_eq!(signing.scheme, SigningScheme::HmacSha256);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{cmp::Ordering, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The oldest CLI release that can still create sessions on the current worker.
+pub const MIN_SUPPORTED_CLIENT_VERSION: &str = "1.0.0";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum VersionError {
+    #[error("invalid version: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionChannel {
+    Stable,
+    /// Any pre-release, e.g. `1.0.0-beta.1` or `1.0.0-rc.2`.
+    Beta,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum PreReleaseIdentifier {
+    // The variant order matters: numeric identifiers have lower precedence than alphanumeric ones.
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+impl Display for PreReleaseIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreReleaseIdentifier::Numeric(n) => write!(f, "{}", n),
+            PreReleaseIdentifier::AlphaNumeric(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// A SemVer version. Ordering follows SemVer precedence: a pre-release sorts before its release
+/// and build metadata is ignored, also for equality.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pre_release: Vec<PreReleaseIdentifier>,
+    build: Option<String>,
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre_release: Vec::new(),
+            build: None,
+        }
+    }
+
+    pub fn is_pre_release(&self) -> bool {
+        !self.pre_release.is_empty()
+    }
+
+    pub fn channel(&self) -> VersionChannel {
+        if self.is_pre_release() {
+            VersionChannel::Beta
+        } else {
+            VersionChannel::Stable
+        }
+    }
+
+    /// Whether `other` is a newer version than this one.
+    pub fn is_outdated(&self, other: &Self) -> bool {
+        self < other
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(
+                || match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                    (true, true) => Ordering::Equal,
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    (false, false) => self.pre_release.cmp(&other.pre_release),
+                },
+            )
+    }
+}
+
+/// Body of the worker's `GET /linkup/version`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    /// Package version of the deployed worker.
+    pub version: String,
+    /// Commit the worker was built from, if it was provided at build time.
+    pub git_sha: Option<String>,
+    pub min_supported_client_version: String,
+}
+
+impl VersionInfo {
+    pub fn new(version: &str, git_sha: Option<&str>) -> Self {
+        Self {
+            version: version.to_string(),
+            git_sha: git_sha.map(str::to_string),
+            min_supported_client_version: MIN_SUPPORTED_CLIENT_VERSION.to_string(),
+        }
+    }
+
+    pub fn supports_client(&self, client: &Version) -> Result<bool, VersionError> {
+        let min_supported = Version::try_from(self.min_supported_client_version.as_str())?;
+
+        Ok(*client >= min_supported)
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+
+        if self.is_pre_release() {
+            let identifiers: Vec<String> = self.pre_release.iter().map(|i| i.to_string()).collect();
+            write!(f, "-{}", identifiers.join("."))?;
+        }
+
+        if let Some(build) = &self.build {
+            write!(f, "+{}", build)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for Version {
+    type Error = VersionError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || VersionError::Invalid(value.to_string());
+
+        // Release tags are usually prefixed, e.g. `v1.2.3`
+        let version = value.trim().trim_start_matches('v');
+
+        let (version, build) = match version.split_once('+') {
+            Some((version, build)) if is_valid_identifier_list(build) => {
+                (version, Some(build.to_string()))
+            }
+            Some(_) => return Err(invalid()),
+            None => (version, None),
+        };
+
+        let (version, pre_release) = match version.split_once('-') {
+            Some((version, pre_release)) if is_valid_identifier_list(pre_release) => {
+                let identifiers = pre_release
+                    .split('.')
+                    .map(|identifier| match identifier.parse::<u64>() {
+                        Ok(n) if !has_leading_zero(identifier) => {
+                            Ok(PreReleaseIdentifier::Numeric(n))
+                        }
+                        Ok(_) => Err(invalid()),
+                        Err(_) => Ok(PreReleaseIdentifier::AlphaNumeric(identifier.to_string())),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                (version, identifiers)
+            }
+            Some(_) => return Err(invalid()),
+            None => (version, Vec::new()),
+        };
+
+        let numbers = version
+            .split('.')
+            .map(|part| {
+                if part.is_empty() || has_leading_zero(part) {
+                    return Err(invalid());
+                }
+                part.parse::<u64>().map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<u64>, _>>()?;
+
+        match numbers[..] {
+            [major, minor, patch] => Ok(Self {
+                major,
+                minor,
+                patch,
+                pre_release,
+                build,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn is_valid_identifier_list(identifiers: &str) -> bool {
+    identifiers.split('.').all(|identifier| {
+        !identifier.is_empty()
+            && identifier
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn has_leading_zero(number: &str) -> bool {
+    number.len() > 1 && number.starts_with('0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        Version::try_from(version).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let version = v("v1.2.3-rc.1+build.5");
+        assert_eq!((version.major, version.minor, version.patch), (1, 2, 3));
+        assert_eq!(version.to_string(), "1.2.3-rc.1+build.5");
+        assert_eq!(version.channel(), VersionChannel::Beta);
+        assert_eq!(v("1.2.3").channel(), VersionChannel::Stable);
+
+        for invalid in [
+            "1.2",
+            "1.2.3.4",
+            "1.02.3",
+            "1.2.x",
+            "1.2.3-",
+            "1.2.3-beta..1",
+            "1.2.3+",
+        ] {
+            assert_eq!(
+                Version::try_from(invalid),
+                Err(VersionError::Invalid(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_pre_release_precedence() {
+        assert!(v("1.0.0-beta.1") < v("1.0.0"));
+        assert!(v("1.0.0-rc.2") > v("1.0.0-rc.1"));
+        assert!(v("1.0.0-rc.1") > v("1.0.0-beta.11"));
+        assert!(v("1.0.0-beta.2") < v("1.0.0-beta.11"));
+        assert!(v("1.0.0-alpha") < v("1.0.0-alpha.1"));
+        assert!(v("1.0.0-alpha.1") < v("1.0.0-alpha.beta"));
+        assert!(v("1.0.0") < v("1.0.1-beta.1"));
+    }
+
+    #[test]
+    fn test_build_metadata_is_ignored() {
+        assert_eq!(v("1.0.0+20240101"), v("1.0.0+abcdef"));
+        assert_eq!(v("1.0.0-rc.1+1"), v("1.0.0-rc.1"));
+        assert!(!v("1.0.0+1").is_outdated(&v("1.0.0+2")));
+    }
+
+    #[test]
+    fn test_is_outdated() {
+        assert!(v("1.4.9").is_outdated(&v("1.5.0")));
+        assert!(v("1.5.0-beta.1").is_outdated(&v("1.5.0")));
+        assert!(!v("2.0.0").is_outdated(&v("1.9.9")));
+        assert!(!v("1.5.0").is_outdated(&v("1.5.0")));
+    }
+
+    #[test]
+    fn test_version_info_supports_client() {
+        let info: VersionInfo = serde_json::from_str(
+            r#"{"version": "0.1.0", "git_sha": null, "min_supported_client_version": "1.5.0"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.supports_client(&v("1.5.0")), Ok(true));
+        assert_eq!(info.supports_client(&v("2.0.0")), Ok(true));
+        assert_eq!(info.supports_client(&v("1.4.9")), Ok(false));
+        assert_eq!(info.supports_client(&v("1.5.0-beta.1")), Ok(false));
+
+        let info = VersionInfo {
+            min_supported_client_version: "latest".to_string(),
+            ..info
+        };
+        assert!(info.supports_client(&v("1.5.0")).is_err());
+
+        assert!(VersionInfo::new("0.1.0", None)
+            .supports_client(&v(MIN_SUPPORTED_CLIENT_VERSION))
+            .unwrap());
+    }
+}
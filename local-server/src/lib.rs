@@ -1,29 +1,47 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use axum::{
     body::Body,
-    extract::{DefaultBodyLimit, Json, Request},
-    http::StatusCode,
+    extract::{ConnectInfo, DefaultBodyLimit, Json, Request},
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+        StatusCode,
+    },
     response::{IntoResponse, Response},
     routing::{any, get, post},
     Extension, Router,
 };
-use http::{header::HeaderMap, Uri};
+use http_body_util::{LengthLimitError, Limited};
 use hyper_rustls::HttpsConnector;
 use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::{TokioExecutor, TokioIo},
 };
 
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
 use linkup::{
-    allow_all_cors, get_additional_headers, get_target_service, MemoryStringStore, NameKind,
-    Session, SessionAllocator, TargetService, UpdateSessionRequest,
+    body_rewrites, compress_request_body, get_forwarded_scheme, get_request_domain,
+    get_target_service_candidates, is_rewritable_body, mock_response, prepare_request,
+    prepare_response_headers, request_compression, rewrite_body, rewrite_redirect_location,
+    BodyRewrite, Domain, MemoryStringStore, NameKind, ProxyContext, ProxyError, RequestCompression,
+    Session, SessionAllocator, SessionSource, TargetService, UpdateSessionRequest,
 };
-use tokio::signal;
+use tokio::{net::TcpListener, signal};
 use tower::ServiceBuilder;
 use tower_http::trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer};
 
+mod static_files;
+
 type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
 
-const LINKUP_LOCALSERVER_PORT: u16 = 9066;
+pub const LINKUP_LOCALSERVER_PORT: u16 = 9066;
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024 * 100;
 
 #[derive(Debug)]
 struct ApiError {
@@ -50,78 +68,203 @@ impl IntoResponse for ApiError {
     }
 }
 
-pub fn linkup_router() -> Router {
+/// Options for the behaviour of the local server.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// Answer requests for session domains with a 308 to the same url over https, instead of
+    /// proxying them.
+    pub redirect_to_https: bool,
+    /// Address the server listens on. Defaults to loopback so the proxy is not reachable
+    /// from other machines on the network.
+    pub bind_address: IpAddr,
+    /// Port the server listens on.
+    pub port: u16,
+    /// Largest request body, in bytes, that is proxied. Bodies are streamed to the target
+    /// service and counted on the way, so they are never buffered as a whole.
+    pub max_body_size: usize,
+    /// Include the session names that were looked up in the error for requests that don't
+    /// belong to any session.
+    pub debug_session_lookup: bool,
+    /// Include how the request was matched against the session's domains and routes, as JSON,
+    /// in the error for requests that have no target service.
+    pub debug_routing: bool,
+    /// Parts of a request the session is taken from. Limit these when the server is reachable
+    /// by others, e.g. to `SessionSource::LOCKED_DOWN`, so a request can't pick another session
+    /// with a crafted referer.
+    pub trusted_session_sources: Vec<SessionSource>,
+    /// Certificate presented to upstream services that require TLS client authentication.
+    pub client_certificate: Option<ClientCertificate>,
+    /// PEM file with CA certificates trusted for upstream services on top of the platform ones,
+    /// e.g. an internal CA.
+    pub upstream_ca_certificates: Option<PathBuf>,
+    /// Release named in the `Via` header of forwarded requests, normally the CLI's version.
+    pub version: &'static str,
+}
+
+/// PEM files with a client certificate chain and its private key.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            redirect_to_https: false,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: LINKUP_LOCALSERVER_PORT,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            debug_session_lookup: false,
+            debug_routing: false,
+            trusted_session_sources: SessionSource::ALL.to_vec(),
+            client_certificate: None,
+            upstream_ca_certificates: None,
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// Fails when the certificates for upstream services in `options` can't be loaded.
+pub fn linkup_router(options: ServerOptions) -> io::Result<Router> {
     let config_store = MemoryStringStore::default();
-    let client = https_client();
+    let client = https_client(&options)?;
+    let max_body_size = options.max_body_size;
 
-    Router::new()
+    let router = Router::new()
         .route("/linkup", post(linkup_config_handler))
         .route("/linkup-check", get(always_ok))
         .fallback(any(linkup_request_handler))
         .layer(Extension(config_store))
         .layer(Extension(client))
+        .layer(Extension(options))
         .layer(
             ServiceBuilder::new()
-                .layer(DefaultBodyLimit::max(1024 * 1024 * 100)) // Set max body size to 100MB
+                .layer(DefaultBodyLimit::max(max_body_size))
                 .layer(
                     TraceLayer::new_for_http()
                         .on_request(DefaultOnRequest::new()) // Log all incoming requests at INFO level
                         .on_response(DefaultOnResponse::new()), // Log all responses at INFO level
                 ),
-        )
+        );
+
+    Ok(router)
+}
+
+pub async fn bind_listener(options: &ServerOptions, port: u16) -> std::io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::new(options.bind_address, port)).await
 }
 
-pub async fn start_server() -> std::io::Result<()> {
-    let app = linkup_router();
+pub async fn start_server(options: ServerOptions) -> std::io::Result<()> {
+    let listener = bind_listener(&options, options.port).await?;
+    let app = linkup_router(options)?;
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", LINKUP_LOCALSERVER_PORT))
-        .await
-        .unwrap();
     println!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
     Ok(())
 }
 
 #[tokio::main]
 pub async fn local_linkup_main() -> std::io::Result<()> {
-    start_server().await
+    start_server(ServerOptions::default()).await
 }
 
 async fn linkup_request_handler(
     Extension(store): Extension<MemoryStringStore>,
     Extension(client): Extension<HttpsClient>,
-    req: Request,
+    Extension(options): Extension<ServerOptions>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request,
 ) -> Response {
-    let sessions = SessionAllocator::new(&store);
+    // Sessions and https redirects depend on the host, so don't guess one
+    let Some(host) = request_host(&req) else {
+        return ApiError::new(
+            "The request has a missing or invalid Host header. - Local Server".to_string(),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    };
+
+    let sessions = SessionAllocator::new(&store)
+        .with_trusted_sources(&options.trusted_session_sources)
+        .with_timestamp(unix_timestamp());
 
     let headers: linkup::HeaderMap = req.headers().into();
-    let url = format!("http://localhost:{}{}", LINKUP_LOCALSERVER_PORT, req.uri());
+    let url = request_url(&req, &host, options.port);
     let (session_name, config) = match sessions.get_request_session(&url, &headers).await {
         Ok(session) => session,
-        Err(_) => {
-            return ApiError::new(
-                "Linkup was unable to determine the session origin of the request. Ensure that your request includes a valid session identifier in the referer or tracestate headers. - Local Server".to_string(),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            )
-            .into_response()
+        Err(e) => {
+            let mut message = "Linkup was unable to determine the session origin of the request. Ensure that your request includes a valid session identifier in the referer or tracestate headers. - Local Server".to_string();
+            if options.debug_session_lookup {
+                message = format!("{}\n\n{}", message, e);
+            }
+
+            return ApiError::new(message, StatusCode::UNPROCESSABLE_ENTITY).into_response();
         }
     };
 
-    let target_service = match get_target_service(&url, &headers, &config, &session_name) {
-        Some(result) => result,
-        None => {
+    if options.redirect_to_https {
+        if let Some(location) = https_location(&req) {
+            return Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(http::header::LOCATION, location)
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+
+    let response_domain = get_request_domain(&url, &headers, &config, &session_name);
+    let context = ProxyContext {
+        timestamp: unix_timestamp(),
+        client_addr: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        version: options.version,
+    };
+    let target_service = match prepare_request(&mut req, &url, &config, &session_name, context) {
+        Ok(target_service) => target_service,
+        Err(ProxyError::NoTarget) => {
+            let mut message = "The request belonged to a session, but there was no target for the request. Check that the routing rules in your linkup config have a match for this request. - Local Server".to_string();
+            if options.debug_routing {
+                let candidates =
+                    get_target_service_candidates(&url, &headers, &config, &session_name);
+                if let Ok(trace) = serde_json::to_string_pretty(&candidates) {
+                    message = format!("{}\n\n{}", message, trace);
+                }
+            }
+
+            return ApiError::new(message, StatusCode::NOT_FOUND).into_response();
+        }
+        Err(e) => {
             return ApiError::new(
-                "The request belonged to a session, but there was no target for the request. Check that the routing rules in your linkup config have a match for this request. - Local Server".to_string(),
-                StatusCode::NOT_FOUND,
+                format!("Failed to build request: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
             )
             .into_response()
         }
     };
 
-    let extra_headers = get_additional_headers(&url, &headers, &session_name, &target_service);
+    if let Some(mock) = mock_response(&config, &target_service) {
+        let mut resp = mock.into_response();
+        prepare_response_headers(resp.headers_mut(), &config, response_domain);
+
+        return resp;
+    }
+
+    let static_dir = config
+        .services
+        .get(&target_service.name)
+        .and_then(|service| service.static_dir.as_ref());
+    if let Some(static_dir) = static_dir {
+        let mut resp = static_files::serve_static(static_dir, req.uri().path()).await;
+        prepare_response_headers(resp.headers_mut(), &config, response_domain);
+
+        return resp;
+    }
 
     if req
         .headers()
@@ -129,27 +272,183 @@ async fn linkup_request_handler(
         .map(|v| v == "websocket")
         .unwrap_or(false)
     {
-        handle_ws_req(req, target_service, extra_headers, client).await
+        handle_ws_req(req, target_service, client).await
     } else {
-        handle_http_req(req, target_service, extra_headers, client).await
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        if content_length.is_some_and(|length| length > options.max_body_size) {
+            return body_too_large(options.max_body_size);
+        }
+        // Chunked bodies have no length up front, so the limit is enforced while streaming.
+        let req = req.map(|body| Body::new(Limited::new(body, options.max_body_size)));
+
+        let req = match request_compression(&config, &target_service) {
+            Some(compression) => {
+                match compress_req(req, compression, options.max_body_size).await {
+                    Ok(req) => req,
+                    Err(resp) => return resp,
+                }
+            }
+            None => req,
+        };
+
+        let body_rewrites = body_rewrites(&config, &target_service);
+        let mut resp = handle_http_req(
+            req,
+            &config,
+            response_domain,
+            body_rewrites,
+            client,
+            options.max_body_size,
+        )
+        .await;
+
+        let status = resp.status();
+        rewrite_redirect_location(
+            resp.headers_mut(),
+            status,
+            &url,
+            &headers,
+            &config,
+            &session_name,
+            &target_service,
+        );
+
+        resp
+    }
+}
+
+fn body_too_large(max_body_size: usize) -> Response {
+    ApiError::new(
+        format!(
+            "Request body is larger than the limit of {} bytes - Local Server",
+            max_body_size
+        ),
+        StatusCode::PAYLOAD_TOO_LARGE,
+    )
+    .into_response()
+}
+
+fn is_body_limit_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+
+    false
+}
+
+async fn compress_req(
+    req: Request,
+    compression: RequestCompression,
+    max_body_size: usize,
+) -> Result<Request, Response> {
+    let (mut parts, body) = req.into_parts();
+    let body = match axum::body::to_bytes(body, max_body_size).await {
+        Ok(body) => body,
+        Err(e) if is_body_limit_error(&e) => return Err(body_too_large(max_body_size)),
+        Err(e) => {
+            return Err(ApiError::new(
+                format!("Failed to read request body: {}", e),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response())
+        }
+    };
+
+    let compressed =
+        compress_request_body(&mut parts.headers, &body, compression).map_err(|e| {
+            ApiError::new(
+                format!("Failed to compress request body: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        })?;
+
+    Ok(Request::from_parts(
+        parts,
+        Body::from(compressed.unwrap_or_else(|| body.to_vec())),
+    ))
+}
+
+// The https url of a plain http request, or None if the request already came in over https
+// through a proxy in front of the server.
+fn https_location(req: &Request) -> Option<String> {
+    let headers: linkup::HeaderMap = req.headers().into();
+    if get_forwarded_scheme(&headers).as_deref() == Some("https") {
+        return None;
     }
+
+    let host = request_host(req)?;
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    Some(format!("https://{}{}", host.host(), path))
+}
+
+/// The url a request was sent to, on the port the server listens on. Requests usually reach the
+/// server through Caddy or the tunnel, so the port of the Host header is theirs.
+fn request_url(req: &Request, host: &http::uri::Authority, port: u16) -> String {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    format!("http://{}:{}{}", host.host(), port, path)
+}
+
+/// The host a request was sent to, from the Host header or, for HTTP/2, the request uri.
+/// `None` when there is none or it is not a valid `host[:port]`.
+fn request_host(req: &Request) -> Option<http::uri::Authority> {
+    let authority = match req.headers().get(http::header::HOST) {
+        Some(host) => host.to_str().ok()?.parse::<http::uri::Authority>().ok()?,
+        None => req.uri().authority()?.clone(),
+    };
+
+    // An authority may carry credentials, a host never does
+    (!authority.as_str().contains('@')).then_some(authority)
+}
+
+/// Whether a request was sent straight to the server from this machine. Requests that come in
+/// through the tunnel or Caddy also arrive over loopback, but carry forwarding headers.
+fn is_local_request(client_addr: Option<SocketAddr>, headers: &http::HeaderMap) -> bool {
+    let from_loopback = client_addr.is_some_and(|addr| addr.ip().is_loopback());
+    let proxied = ["forwarded", "x-forwarded-for", "cf-connecting-ip"]
+        .iter()
+        .any(|name| headers.contains_key(*name));
+
+    from_loopback && !proxied
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
 }
 
 async fn handle_http_req(
-    mut req: Request,
-    target_service: TargetService,
-    extra_headers: linkup::HeaderMap,
+    req: Request,
+    config: &Session,
+    response_domain: Option<&Domain>,
+    body_rewrites: &[BodyRewrite],
     client: HttpsClient,
+    max_body_size: usize,
 ) -> Response {
-    *req.uri_mut() = Uri::try_from(target_service.url).unwrap();
-    let extra_http_headers: HeaderMap = extra_headers.into();
-    req.headers_mut().extend(extra_http_headers);
-    // Request uri and host headers should not conflict
-    req.headers_mut().remove(http::header::HOST);
-
     // Send the modified request to the target service.
     let mut resp = match client.request(req).await {
         Ok(resp) => resp,
+        Err(e) if is_body_limit_error(&e) => return body_too_large(max_body_size),
         Err(e) => {
             return ApiError::new(
                 format!(
@@ -162,19 +461,61 @@ async fn handle_http_req(
         }
     };
 
-    resp.headers_mut().extend(allow_all_cors());
+    prepare_response_headers(resp.headers_mut(), config, response_domain);
 
-    resp.into_response()
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    if body_rewrites.is_empty()
+        || !is_rewritable_body(header(CONTENT_TYPE), header(CONTENT_ENCODING))
+    {
+        return resp.into_response();
+    }
+
+    // Rewriting buffers the whole body, which is only done for bodies within the limit
+    let content_length = header(CONTENT_LENGTH).and_then(|length| length.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max_body_size) {
+        return resp.into_response();
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = match axum::body::to_bytes(Body::new(body), max_body_size).await {
+        Ok(body) => body,
+        Err(e) if is_body_limit_error(&e) => {
+            return ApiError::new(
+                format!(
+                    "Response body is larger than the limit of {} bytes and can't be rewritten - Local Server",
+                    max_body_size
+                ),
+                StatusCode::BAD_GATEWAY,
+            )
+            .into_response()
+        }
+        Err(e) => {
+            return ApiError::new(
+                format!("Failed to read response body: {}", e),
+                StatusCode::BAD_GATEWAY,
+            )
+            .into_response()
+        }
+    };
+
+    match rewrite_body(&body, body_rewrites) {
+        Some(rewritten) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(rewritten))
+        }
+        None => Response::from_parts(parts, Body::from(body)),
+    }
 }
 
 async fn handle_ws_req(
     req: Request,
     target_service: TargetService,
-    extra_headers: linkup::HeaderMap,
     client: HttpsClient,
 ) -> Response {
-    let extra_http_headers: HeaderMap = extra_headers.into();
-
     let target_ws_req_result = Request::builder()
         .uri(target_service.url)
         .method(req.method().clone())
@@ -192,8 +533,6 @@ async fn handle_ws_req(
     };
 
     target_ws_req.headers_mut().extend(req.headers().clone());
-    target_ws_req.headers_mut().extend(extra_http_headers);
-    target_ws_req.headers_mut().remove(http::header::HOST);
 
     // Send the modified request to the target service.
     let target_ws_resp = match client.request(target_ws_req).await {
@@ -282,8 +621,24 @@ async fn handle_ws_req(
 
 async fn linkup_config_handler(
     Extension(store): Extension<MemoryStringStore>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: http::HeaderMap,
     Json(update_req): Json<UpdateSessionRequest>,
 ) -> impl IntoResponse {
+    // A static dir lets the session read files from this machine, so only linkup itself may
+    // set one, not anyone who can reach the server through --expose, the tunnel or Caddy.
+    let has_static_dir = update_req
+        .services
+        .iter()
+        .any(|service| service.static_dir.is_some());
+    if has_static_dir && !is_local_request(connect_info.map(|ConnectInfo(addr)| addr), &headers) {
+        return ApiError::new(
+            "Static dirs can only be set from this machine - local server".to_string(),
+            StatusCode::FORBIDDEN,
+        )
+        .into_response();
+    }
+
     let desired_name = update_req.desired_name.clone();
     let server_conf: Session = match update_req.try_into() {
         Ok(conf) => conf,
@@ -296,7 +651,7 @@ async fn linkup_config_handler(
         }
     };
 
-    let sessions = SessionAllocator::new(&store);
+    let sessions = SessionAllocator::new(&store).with_timestamp(unix_timestamp());
     let session_name = sessions
         .store_session(server_conf, NameKind::Animal, desired_name)
         .await;
@@ -324,17 +679,32 @@ async fn shutdown_signal() {
     println!("signal received, starting graceful shutdown");
 }
 
-fn https_client() -> HttpsClient {
+fn https_client(options: &ServerOptions) -> io::Result<HttpsClient> {
     let _ = rustls::crypto::ring::default_provider().install_default();
 
     let mut roots = rustls::RootCertStore::empty();
     for cert in rustls_native_certs::load_native_certs().expect("could not load platform certs") {
         roots.add(cert).unwrap();
     }
+    if let Some(path) = &options.upstream_ca_certificates {
+        for cert in load_certificates(path)? {
+            roots.add(cert).map_err(|e| invalid_pem(path, e))?;
+        }
+    }
 
-    let tls = rustls::ClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+    let tls = match &options.client_certificate {
+        Some(client_certificate) => {
+            let certs = load_certificates(&client_certificate.cert_path)?;
+            let key = PrivateKeyDer::from_pem_file(&client_certificate.key_path)
+                .map_err(|e| invalid_pem(&client_certificate.key_path, e))?;
+
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| invalid_pem(&client_certificate.key_path, e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
 
     let https = hyper_rustls::HttpsConnectorBuilder::new()
         .with_tls_config(tls)
@@ -342,5 +712,24 @@ fn https_client() -> HttpsClient {
         .enable_http1()
         .build();
 
-    Client::builder(TokioExecutor::new()).build(https)
+    Ok(Client::builder(TokioExecutor::new()).build(https))
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_pem(path, e))?;
+
+    if certs.is_empty() {
+        return Err(invalid_pem(path, "no certificates found"));
+    }
+
+    Ok(certs)
+}
+
+fn invalid_pem(path: &Path, error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), error),
+    )
 }
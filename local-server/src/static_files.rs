@@ -0,0 +1,106 @@
+use std::path::{Component, Path, PathBuf};
+
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use percent_encoding::percent_decode_str;
+
+use crate::ApiError;
+
+/// Serves `path` from `static_dir`. Directories resolve to their `index.html` and paths without a
+/// file extension that don't exist fall back to the root `index.html`, so client side routing
+/// keeps working. A missing asset like `/app.js` is a 404 instead of the index page.
+pub async fn serve_static(static_dir: &Path, path: &str) -> Response {
+    let Some(file_path) = resolve_path(static_dir, path) else {
+        return ApiError::new(
+            format!("Invalid static file path: {}", path),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response();
+    };
+
+    let mut candidates = vec![file_path.clone(), file_path.join("index.html")];
+    if file_path.extension().is_none() {
+        candidates.push(static_dir.join("index.html"));
+    }
+    for candidate in candidates {
+        if !candidate.is_file() {
+            continue;
+        }
+
+        return match tokio::fs::read(&candidate).await {
+            Ok(content) => {
+                let content_type = mime_guess::from_path(&candidate).first_or_octet_stream();
+
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, content_type.as_ref())
+                    .body(Body::from(content))
+                    .unwrap()
+            }
+            Err(e) => ApiError::new(
+                format!("Failed to read {}: {}", candidate.display(), e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+        };
+    }
+
+    ApiError::new(
+        format!("No file found for {} in {}", path, static_dir.display()),
+        StatusCode::NOT_FOUND,
+    )
+    .into_response()
+}
+
+// Only plain path segments are accepted, so requests can't escape the served directory. The path
+// is decoded first, so an encoded `..` is rejected like a plain one.
+fn resolve_path(static_dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let mut file_path = static_dir.to_path_buf();
+
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => file_path.push(segment),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    Some(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let static_dir = Path::new("/srv/static");
+
+        assert_eq!(
+            resolve_path(static_dir, "/assets/app.js"),
+            Some(PathBuf::from("/srv/static/assets/app.js"))
+        );
+        assert_eq!(
+            resolve_path(static_dir, "/my%20file.txt"),
+            Some(PathBuf::from("/srv/static/my file.txt"))
+        );
+
+        for path in [
+            "/../secret",
+            "/assets/%2E%2E/%2E%2E/secret",
+            "/%2e%2e%2fsecret",
+        ] {
+            assert_eq!(
+                resolve_path(static_dir, path),
+                None,
+                "{} should be rejected",
+                path
+            );
+        }
+    }
+}
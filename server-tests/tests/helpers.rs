@@ -1,7 +1,7 @@
 use std::process::Command;
 
 use linkup::{StorableDomain, StorableService, UpdateSessionRequest};
-use linkup_local_server::linkup_router;
+use linkup_local_server::{linkup_router, ServerOptions};
 use reqwest::Url;
 use tokio::net::TcpListener;
 
@@ -13,19 +13,7 @@ pub enum ServerKind {
 
 pub async fn setup_server(kind: ServerKind) -> String {
     match kind {
-        ServerKind::Local => {
-            let app = linkup_router();
-
-            // Bind to a random port assigned by the OS
-            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-            let addr = listener.local_addr().unwrap();
-
-            tokio::spawn(async move {
-                axum::serve(listener, app).await.unwrap();
-            });
-
-            format!("http://{}", addr)
-        }
+        ServerKind::Local => setup_local_server(ServerOptions::default()).await,
         ServerKind::Worker => {
             if !check_worker_running() {
                 panic!("Worker not running! Run npx wrangler@latest dev in the worker dir");
@@ -35,6 +23,25 @@ pub async fn setup_server(kind: ServerKind) -> String {
     }
 }
 
+pub async fn setup_local_server(options: ServerOptions) -> String {
+    let app = linkup_router(options).unwrap();
+
+    // Bind to a random port assigned by the OS
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    format!("http://{}", addr)
+}
+
 pub async fn post(url: String, body: String) -> reqwest::Response {
     let client = reqwest::Client::new();
     client
@@ -58,13 +65,32 @@ pub fn create_session_request(name: String, fe_location: Option<String>) -> Stri
             domain: "example.com".to_string(),
             default_service: "frontend".to_string(),
             routes: None,
+            response_headers: None,
+            response_headers_mode: None,
         }],
         services: vec![StorableService {
             name: "frontend".to_string(),
             location: Url::parse(&location).unwrap(),
+            alternate_locations: None,
+            sticky_key: None,
+            split: None,
+            retries: None,
             rewrites: None,
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
         }],
         cache_routes: None,
+        rewrites: None,
+        rewrite_order: None,
+        frame_ancestors: None,
+        session_key: None,
+        trusted_session_sources: None,
     };
     serde_json::to_string(&req).unwrap()
 }
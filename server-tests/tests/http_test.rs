@@ -1,14 +1,40 @@
 use axum::{
+    body::{Body, Bytes},
     response::{AppendHeaders, Redirect},
-    routing::{any, get},
+    routing::{self, any, get},
     Router,
 };
+use flate2::read::GzDecoder;
+use futures::StreamExt;
 use helpers::ServerKind;
-use http::{header::SET_COOKIE, StatusCode};
+use http::{
+    header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, SET_COOKIE},
+    HeaderMap, StatusCode,
+};
 use rstest::rstest;
-use tokio::net::TcpListener;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+};
+
+use linkup::{SessionSource, UpdateSessionRequest, WarmedUrl};
+use linkup_local_server::{bind_listener, ClientCertificate, ServerOptions};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio_rustls::TlsAcceptor;
 
-use crate::helpers::{create_session_request, post, setup_server};
+use crate::helpers::{create_session_request, post, setup_local_server, setup_server};
 
 mod helpers;
 
@@ -88,6 +114,748 @@ async fn maintains_multiple_set_cookie_headers(
     assert_eq!(cookies[1].to_str().unwrap(), "cookie2=value2; Path=/");
 }
 
+#[rstest]
+#[case::with_client_certificate(true, reqwest::StatusCode::OK)]
+#[case::without_client_certificate(false, reqwest::StatusCode::BAD_GATEWAY)]
+#[tokio::test]
+async fn presents_client_certificate_to_upstream(
+    #[case] with_client_certificate: bool,
+    #[case] expected_status: reqwest::StatusCode,
+) {
+    let client_certificate = with_client_certificate.then(|| ClientCertificate {
+        cert_path: tls_fixture("client.pem"),
+        key_path: tls_fixture("client.key"),
+    });
+    let url = setup_local_server(ServerOptions {
+        client_certificate,
+        upstream_ca_certificates: Some(tls_fixture("ca.pem")),
+        ..Default::default()
+    })
+    .await;
+    let underlying_url = setup_mtls_server().await;
+
+    let session_req = create_session_request("mtlssession".to_string(), Some(underlying_url));
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = get_session(
+        format!("{}/anypath", url),
+        "example.com".to_string(),
+        "mtlssession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), expected_status);
+    if with_client_certificate {
+        assert_eq!(response.text().await.unwrap(), "mtls_fe");
+    }
+}
+
+#[tokio::test]
+async fn redirects_session_domains_to_https() {
+    let url = setup_local_server(ServerOptions {
+        redirect_to_https: true,
+        ..Default::default()
+    })
+    .await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let session_req = create_session_request("potatosession".to_string(), Some(underlying_url));
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("{}/anypath?query=1", url))
+        .header("Host", "potatosession.example.com:80")
+        .header("tracestate", "linkup-session=potatosession")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "https://potatosession.example.com/anypath?query=1"
+    );
+
+    // The check endpoint keeps being served over http
+    let check = client
+        .get(format!("{}/linkup-check", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(check.status(), reqwest::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn binds_to_the_configured_address() {
+    let listener = bind_listener(&ServerOptions::default(), 0).await.unwrap();
+    assert!(listener.local_addr().unwrap().ip().is_loopback());
+
+    let exposed = ServerOptions {
+        bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ..Default::default()
+    };
+    let listener = bind_listener(&exposed, 0).await.unwrap();
+    assert!(listener.local_addr().unwrap().ip().is_unspecified());
+}
+
+#[tokio::test]
+async fn streams_request_bodies_to_the_target() {
+    let url = setup_local_server(ServerOptions::default()).await;
+
+    // The upstream signals its first chunk, which only arrives before the whole body is sent
+    // when the local server streams instead of buffering.
+    let first_chunk = Arc::new(Notify::new());
+    let upstream_first_chunk = first_chunk.clone();
+    let upstream = Router::new().route(
+        "/upload",
+        routing::post(move |body: Body| async move {
+            let mut stream = body.into_data_stream();
+            let mut received = 0;
+            while let Some(chunk) = stream.next().await {
+                received += chunk.unwrap().len();
+                upstream_first_chunk.notify_one();
+            }
+
+            received.to_string()
+        }),
+    );
+    let upstream_url = serve(upstream).await;
+
+    let session_req = create_session_request("uploadsession".to_string(), Some(upstream_url));
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let chunk = vec![b'x'; 1024 * 1024];
+    let chunks = 16;
+    let mut stream = TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    stream
+        .write_all(upload_head("uploadsession", Some(chunk.len() * chunks)).as_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&chunk).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), first_chunk.notified())
+        .await
+        .expect("the upstream did not receive the body before it was complete");
+
+    for _ in 1..chunks {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    let response = read_response(stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with(&(chunk.len() * chunks).to_string()));
+}
+
+#[tokio::test]
+async fn lists_session_lookups_when_debugging() {
+    let url = setup_local_server(ServerOptions {
+        debug_session_lookup: true,
+        ..Default::default()
+    })
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/anypath", url))
+        .header("referer", "http://missing-session.example.com/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains("tried url="), "{body}");
+    assert!(body.contains("referer=\"missing-session\""), "{body}");
+}
+
+#[tokio::test]
+async fn lists_routing_candidates_when_debugging() {
+    let url = setup_local_server(ServerOptions {
+        debug_routing: true,
+        ..Default::default()
+    })
+    .await;
+
+    let session_req = create_session_request("routingsession".to_string(), None);
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    // The session is found from the tracestate, but no domain of the session matches
+    let response = reqwest::Client::new()
+        .get(format!("{}/anypath", url))
+        .header("tracestate", "linkup-session=routingsession")
+        .header("origin", "http://routingsession.other.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains("\"source\": \"url\""), "{body}");
+    assert!(body.contains("\"key\": \"other.com\""), "{body}");
+    assert!(body.contains("\"configured\": false"), "{body}");
+}
+
+#[rstest]
+#[tokio::test]
+async fn refuses_untrusted_session_sources_of_locked_down_sessions(
+    #[values(ServerKind::Local, ServerKind::Worker)] server_kind: ServerKind,
+) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: UpdateSessionRequest = serde_json::from_str(&create_session_request(
+        "lockedsession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req.trusted_session_sources = Some(SessionSource::LOCKED_DOWN.to_vec());
+    let session_resp = post(
+        format!("{}/linkup", url),
+        serde_json::to_string(&session_req).unwrap(),
+    )
+    .await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/anypath", url))
+        .header("referer", "http://lockedsession.example.com/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/anypath", url))
+        .header("tracestate", "linkup-session=lockedsession")
+        .header("referer", "http://example.com/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "under_fe");
+}
+
+#[tokio::test]
+async fn takes_the_session_from_the_host() {
+    let url = setup_local_server(ServerOptions::default()).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let session_req = create_session_request("hostsession".to_string(), Some(underlying_url));
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    // Like requests coming through Caddy, with nothing but the session domain as Host
+    let response = reqwest::Client::new()
+        .get(format!("{}/anypath", url))
+        .header("host", "hostsession.example.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "under_fe");
+}
+
+#[rstest]
+#[case::missing("GET /anypath HTTP/1.0\r\n\r\n")]
+#[case::malformed("GET /anypath HTTP/1.1\r\nHost: bad host\r\nConnection: close\r\n\r\n")]
+#[case::credentials("GET /anypath HTTP/1.1\r\nHost: user@example.com\r\nConnection: close\r\n\r\n")]
+#[tokio::test]
+async fn rejects_requests_without_a_valid_host(#[case] request: &str) {
+    let url = setup_local_server(ServerOptions::default()).await;
+
+    let mut stream = TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let response = read_response(stream).await;
+    assert!(
+        response.starts_with("HTTP/1.0 400") || response.starts_with("HTTP/1.1 400"),
+        "{response}"
+    );
+    assert!(response.contains("Host header"), "{response}");
+}
+
+#[tokio::test]
+async fn rejects_request_bodies_over_the_limit() {
+    let url = setup_local_server(ServerOptions {
+        max_body_size: 1024,
+        ..Default::default()
+    })
+    .await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let session_req = create_session_request("limitsession".to_string(), Some(underlying_url));
+    let session_resp = post(format!("{}/linkup", url), session_req).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    // With a content length the body is rejected up front
+    let response = reqwest::Client::new()
+        .post(format!("{}/anypath", url))
+        .header("tracestate", "linkup-session=limitsession")
+        .header("Referer", "example.com")
+        .body(vec![b'x'; 2048])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    // A chunked body is cut off once it goes over the limit
+    let mut stream = TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    stream
+        .write_all(upload_head("limitsession", None).as_bytes())
+        .await
+        .unwrap();
+    for _ in 0..4 {
+        stream.write_all(b"400\r\n").await.unwrap();
+        stream.write_all(&[b'x'; 1024]).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+    }
+    stream.write_all(b"0\r\n\r\n").await.unwrap();
+    let response = read_response(stream).await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+}
+
+fn upload_head(session_name: &str, content_length: Option<usize>) -> String {
+    let length_header = match content_length {
+        Some(length) => format!("Content-Length: {}", length),
+        None => "Transfer-Encoding: chunked".to_string(),
+    };
+
+    format!(
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\ntracestate: linkup-session={}\r\nReferer: example.com\r\n{}\r\nConnection: close\r\n\r\n",
+        session_name, length_header
+    )
+}
+
+async fn read_response(mut stream: TcpStream) -> String {
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[rstest]
+#[tokio::test]
+async fn rewrites_text_response_bodies(
+    #[values(ServerKind::Local, ServerKind::Worker)] server_kind: ServerKind,
+) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "rewritesession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req["services"][0]["body_rewrites"] = serde_json::json!([
+        { "find": "https://remote.example.com", "replace": "https://rewritesession.example.com" }
+    ]);
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = get_session(
+        format!("{}/page.html", url),
+        "example.com".to_string(),
+        "rewritesession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        response.text().await.unwrap(),
+        r#"<img src="https://rewritesession.example.com/logo.png">"#
+    );
+
+    let response = get_session(
+        format!("{}/logo.png", url),
+        "example.com".to_string(),
+        "rewritesession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "https://remote.example.com");
+
+    // Compressed responses can't be rewritten, so they aren't asked for
+    let response = reqwest::Client::new()
+        .get(format!("{}/encoding.txt", url))
+        .header("tracestate", "linkup-session=rewritesession")
+        .header("Referer", "example.com")
+        .header(ACCEPT_ENCODING, "gzip, br")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.text().await.unwrap(),
+        "identity https://rewritesession.example.com"
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn reports_cache_status(#[values(ServerKind::Worker)] server_kind: ServerKind) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "cachesession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req["cache_routes"] = serde_json::json!(["^/cached$"]);
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let cache_status = |response: &reqwest::Response| {
+        response
+            .headers()
+            .get("x-linkup-cache")
+            .map(|status| status.to_str().unwrap().to_string())
+    };
+
+    let response = get_session(
+        format!("{}/cached", url),
+        "example.com".to_string(),
+        "cachesession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(cache_status(&response).as_deref(), Some("MISS"));
+
+    let response = get_session(
+        format!("{}/cached", url),
+        "example.com".to_string(),
+        "cachesession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(cache_status(&response).as_deref(), Some("HIT"));
+    assert_eq!(response.text().await.unwrap(), "cached");
+
+    // Routes that are not cacheable carry no cache status
+    let response = get_session(
+        format!("{}/anypath", url),
+        "example.com".to_string(),
+        "cachesession".to_string(),
+    )
+    .await;
+    assert_eq!(cache_status(&response), None);
+}
+
+#[rstest]
+#[tokio::test]
+async fn warms_cache(#[values(ServerKind::Worker)] server_kind: ServerKind) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "warmsession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    // Warmed URLs carry no referer, so their own host has to be a session domain
+    session_req["domains"][0]["domain"] = url.trim_start_matches("http://").into();
+    session_req["cache_routes"] = serde_json::json!(["^/cached$"]);
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+    let session_name = session_resp.text().await.unwrap();
+
+    let warm_req = serde_json::json!({
+        "session_name": session_name,
+        "session_token": "wrong",
+        "urls": [format!("{}/cached", url)],
+    });
+    let warm_resp = post(format!("{}/linkup/cache/warm", url), warm_req.to_string()).await;
+    assert_eq!(warm_resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let warm_req = serde_json::json!({
+        "session_name": session_name,
+        "session_token": "token",
+        "urls": [format!("{}/cached", url), format!("{}/anypath", url)],
+    });
+    let warm_resp = post(format!("{}/linkup/cache/warm", url), warm_req.to_string()).await;
+    assert_eq!(warm_resp.status(), reqwest::StatusCode::OK);
+    let warmed: Vec<WarmedUrl> = serde_json::from_str(&warm_resp.text().await.unwrap()).unwrap();
+    assert_eq!(
+        warmed,
+        vec![
+            WarmedUrl {
+                url: format!("{}/cached", url),
+                status: 200,
+                cache: Some("MISS".to_string()),
+            },
+            WarmedUrl {
+                url: format!("{}/anypath", url),
+                status: 200,
+                cache: None,
+            },
+        ]
+    );
+
+    let response = get_session(
+        format!("{}/cached", url),
+        "example.com".to_string(),
+        session_name,
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.headers().get("x-linkup-cache").unwrap(), "HIT");
+}
+
+#[rstest]
+#[tokio::test]
+async fn caches_only_allowed_statuses(#[values(ServerKind::Worker)] server_kind: ServerKind) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "cachestatussession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req["cache_routes"] = serde_json::json!([
+        "^/missing$",
+        { "path": "^/missing-cached$", "statuses": [404] }
+    ]);
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let cache_status = |response: &reqwest::Response| {
+        response
+            .headers()
+            .get("x-linkup-cache")
+            .map(|status| status.to_str().unwrap().to_string())
+    };
+
+    // Only 2xx responses are cached by default
+    for _ in 0..2 {
+        let response = get_session(
+            format!("{}/missing", url),
+            "example.com".to_string(),
+            "cachestatussession".to_string(),
+        )
+        .await;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(cache_status(&response).as_deref(), Some("MISS"));
+    }
+
+    let response = get_session(
+        format!("{}/missing-cached", url),
+        "example.com".to_string(),
+        "cachestatussession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    assert_eq!(cache_status(&response).as_deref(), Some("MISS"));
+
+    let response = get_session(
+        format!("{}/missing-cached", url),
+        "example.com".to_string(),
+        "cachestatussession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    assert_eq!(cache_status(&response).as_deref(), Some("HIT"));
+}
+
+#[rstest]
+#[tokio::test]
+async fn compresses_request_bodies(
+    #[values(ServerKind::Local, ServerKind::Worker)] server_kind: ServerKind,
+) {
+    let url = setup_server(server_kind).await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "compresssession".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req["services"][0]["compress_request"] = "gzip".into();
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/compressed", url))
+        .header("tracestate", "linkup-session=compresssession")
+        .header("Referer", "example.com")
+        .body(r#"{"hello": "world"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), r#"gzip:{"hello": "world"}"#);
+}
+
+#[tokio::test]
+async fn rejects_compressed_request_bodies_over_the_limit() {
+    let url = setup_local_server(ServerOptions {
+        max_body_size: 1024,
+        ..Default::default()
+    })
+    .await;
+    let underlying_url = setup_underlying_server("under_fe".to_string()).await;
+
+    let mut session_req: serde_json::Value = serde_json::from_str(&create_session_request(
+        "compresslimit".to_string(),
+        Some(underlying_url),
+    ))
+    .unwrap();
+    session_req["services"][0]["compress_request"] = "gzip".into();
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let mut stream = TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    stream
+        .write_all(upload_head("compresslimit", None).as_bytes())
+        .await
+        .unwrap();
+    for _ in 0..4 {
+        stream.write_all(b"400\r\n").await.unwrap();
+        stream.write_all(&[b'x'; 1024]).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+    }
+    stream.write_all(b"0\r\n\r\n").await.unwrap();
+    let response = read_response(stream).await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+}
+
+#[rstest]
+#[tokio::test]
+async fn returns_mock_service_response(
+    #[values(ServerKind::Local, ServerKind::Worker)] server_kind: ServerKind,
+) {
+    let url = setup_server(server_kind).await;
+
+    let mut session_req: serde_json::Value =
+        serde_json::from_str(&create_session_request("mocksession".to_string(), None)).unwrap();
+    session_req["services"][0]["mock"] = serde_json::json!({
+        "status": 202,
+        "body": "{\"mocked\": true}",
+        "content_type": "application/json"
+    });
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = get_session(
+        format!("{}/anypath", url),
+        "example.com".to_string(),
+        "mocksession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    assert_eq!(response.text().await.unwrap(), "{\"mocked\": true}");
+}
+
+#[tokio::test]
+async fn serves_files_from_static_dir() {
+    let url = setup_server(ServerKind::Local).await;
+    let static_dir = create_static_dir(&[
+        ("index.html", "<h1>index</h1>"),
+        ("assets/app.js", "console.log('app')"),
+    ]);
+
+    let mut session_req: serde_json::Value =
+        serde_json::from_str(&create_session_request("staticsession".to_string(), None)).unwrap();
+    session_req["services"][0]["static_dir"] = static_dir.to_string_lossy().into();
+    let session_resp = post(format!("{}/linkup", url), session_req.to_string()).await;
+    assert_eq!(session_resp.status(), reqwest::StatusCode::OK);
+
+    let response = get_session(
+        format!("{}/assets/app.js", url),
+        "example.com".to_string(),
+        "staticsession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/javascript"
+    );
+    assert_eq!(response.text().await.unwrap(), "console.log('app')");
+
+    for path in ["/", "/some/client/route"] {
+        let response = get_session(
+            format!("{}{}", url, path),
+            "example.com".to_string(),
+            "staticsession".to_string(),
+        )
+        .await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+        assert_eq!(response.text().await.unwrap(), "<h1>index</h1>");
+    }
+
+    // Paths are decoded before they are resolved
+    let response = get_session(
+        format!("{}/assets/%61pp.js", url),
+        "example.com".to_string(),
+        "staticsession".to_string(),
+    )
+    .await;
+    assert_eq!(response.text().await.unwrap(), "console.log('app')");
+
+    // Missing assets are not answered with the index page
+    let response = get_session(
+        format!("{}/assets/missing.js", url),
+        "example.com".to_string(),
+        "staticsession".to_string(),
+    )
+    .await;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn rejects_static_dir_from_proxied_requests() {
+    let url = setup_server(ServerKind::Local).await;
+    let static_dir = create_static_dir(&[("index.html", "<h1>index</h1>")]);
+
+    let mut session_req: serde_json::Value =
+        serde_json::from_str(&create_session_request("proxiedsession".to_string(), None)).unwrap();
+    session_req["services"][0]["static_dir"] = static_dir.to_string_lossy().into();
+
+    // Like a request that came in through the tunnel
+    let session_resp = reqwest::Client::new()
+        .post(format!("{}/linkup", url))
+        .header("Content-Type", "application/json")
+        .header("cf-connecting-ip", "203.0.113.7")
+        .body(session_req.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(session_resp.status(), reqwest::StatusCode::FORBIDDEN);
+}
+
+fn create_static_dir(files: &[(&str, &str)]) -> std::path::PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!("linkup-static-{}", nanos));
+
+    for (path, content) in files {
+        let file_path = dir.join(path);
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        std::fs::write(file_path, content).unwrap();
+    }
+
+    dir
+}
+
 async fn setup_underlying_server(name: String) -> String {
     let app = Router::new()
         .route("/redirect", get(Redirect::temporary("/somethingelse")))
@@ -103,8 +871,78 @@ async fn setup_underlying_server(name: String) -> String {
                 )
             }),
         )
+        .route(
+            "/page.html",
+            get(|| async {
+                (
+                    [(CONTENT_TYPE, "text/html")],
+                    r#"<img src="https://remote.example.com/logo.png">"#,
+                )
+            }),
+        )
+        .route(
+            "/encoding.txt",
+            get(|headers: HeaderMap| async move {
+                let encoding = headers
+                    .get(ACCEPT_ENCODING)
+                    .map(|encoding| encoding.to_str().unwrap().to_string())
+                    .unwrap_or_default();
+
+                (
+                    [(CONTENT_TYPE, "text/plain")],
+                    format!("{} https://remote.example.com", encoding),
+                )
+            }),
+        )
+        .route(
+            "/logo.png",
+            get(|| async { ([(CONTENT_TYPE, "image/png")], "https://remote.example.com") }),
+        )
+        .route(
+            "/cached",
+            get(|| async { ([(CACHE_CONTROL, "public, max-age=60")], "cached") }),
+        )
+        .route(
+            "/missing",
+            get(|| async {
+                (
+                    StatusCode::NOT_FOUND,
+                    [(CACHE_CONTROL, "public, max-age=60")],
+                    "missing",
+                )
+            }),
+        )
+        .route(
+            "/missing-cached",
+            get(|| async {
+                (
+                    StatusCode::NOT_FOUND,
+                    [(CACHE_CONTROL, "public, max-age=60")],
+                    "missing",
+                )
+            }),
+        )
+        .route(
+            "/compressed",
+            routing::post(|headers: HeaderMap, body: Bytes| async move {
+                let encoding = headers
+                    .get(CONTENT_ENCODING)
+                    .map(|encoding| encoding.to_str().unwrap().to_string())
+                    .unwrap_or_default();
+                let mut decompressed = String::new();
+                GzDecoder::new(body.as_ref())
+                    .read_to_string(&mut decompressed)
+                    .unwrap();
+
+                format!("{}:{}", encoding, decompressed)
+            }),
+        )
         .fallback(any(|| async { name }));
 
+    serve(app).await
+}
+
+async fn serve(app: Router) -> String {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
 
@@ -115,6 +953,62 @@ async fn setup_underlying_server(name: String) -> String {
     format!("http://{}", addr)
 }
 
+// The certificates were generated with openssl: a CA, and a certificate for localhost and a
+// client certificate that are both signed by it.
+fn tls_fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tls")
+        .join(name)
+}
+
+// An https server that only accepts clients with a certificate signed by the test CA
+async fn setup_mtls_server() -> String {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(tls_fixture("ca.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .unwrap();
+    let certs = CertificateDer::pem_file_iter(tls_fixture("server.pem"))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let key = PrivateKeyDer::from_pem_file(tls_fixture("server.key")).unwrap();
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Fails for clients without a valid certificate
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response =
+                    "HTTP/1.1 200 OK\r\ncontent-length: 7\r\nconnection: close\r\n\r\nmtls_fe";
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    format!("https://localhost:{}", port)
+}
+
 async fn get_session(url: String, destination: String, session_name: String) -> reqwest::Response {
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
@@ -1,5 +1,8 @@
 use helpers::ServerKind;
-use linkup::{CreatePreviewRequest, StorableDomain, StorableService};
+use linkup::{
+    CreatePreviewRequest, StorableDomain, StorableService, Version, VersionInfo,
+    MIN_SUPPORTED_CLIENT_VERSION,
+};
 use reqwest::Url;
 use rstest::rstest;
 
@@ -67,6 +70,31 @@ async fn can_create_preview(#[values(ServerKind::Worker)] server_kind: ServerKin
     assert_eq!(response.text().await.unwrap().len(), 6);
 }
 
+#[rstest]
+#[tokio::test]
+async fn can_report_version_without_auth(#[values(ServerKind::Worker)] server_kind: ServerKind) {
+    let url = setup_server(server_kind).await;
+
+    let response = get(format!("{}/linkup/version", url)).await;
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert!(response.headers().contains_key("cache-control"));
+
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let fields = body.as_object().unwrap();
+    assert!(fields["version"].is_string());
+    assert!(fields["git_sha"].is_string() || fields["git_sha"].is_null());
+    assert_eq!(
+        fields["min_supported_client_version"],
+        MIN_SUPPORTED_CLIENT_VERSION
+    );
+
+    let version_info: VersionInfo = serde_json::from_value(body).unwrap();
+    assert!(version_info
+        .supports_client(&Version::try_from(MIN_SUPPORTED_CLIENT_VERSION).unwrap())
+        .unwrap());
+}
+
 pub async fn get(url: String) -> reqwest::Response {
     let client = reqwest::Client::new();
     client
@@ -86,13 +114,32 @@ pub fn create_preview_request(fe_location: Option<String>) -> String {
             domain: "example.com".to_string(),
             default_service: "frontend".to_string(),
             routes: None,
+            response_headers: None,
+            response_headers_mode: None,
         }],
         services: vec![StorableService {
             name: "frontend".to_string(),
             location: Url::parse(&location).unwrap(),
+            alternate_locations: None,
+            sticky_key: None,
+            split: None,
+            retries: None,
             rewrites: None,
+            signing: None,
+            forwarded_header: None,
+            via_header: None,
+            rewrite_redirects: None,
+            static_dir: None,
+            mock: None,
+            body_rewrites: None,
+            compress_request: None,
         }],
         cache_routes: None,
+        rewrites: None,
+        rewrite_order: None,
+        frame_ancestors: None,
+        session_key: None,
+        trusted_session_sources: None,
     };
     serde_json::to_string(&req).unwrap()
 }
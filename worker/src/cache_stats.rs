@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use linkup::{CacheStats, CacheStatus};
+use worker::{
+    console_log, durable_object, DurableObject, Env, Method, ObjectNamespace, Request, RequestInit,
+    Response, State, Stub,
+};
+
+/// Binding of the Durable Object namespace the cache stats are counted in.
+pub const CACHE_STATS_BINDING: &str = "LINKUP_CACHE_STATS";
+
+const STATS_KEY: &str = "stats";
+
+// Not fetched over the network, the Durable Object only looks at the method and body
+const COUNTER_URL: &str = "https://cache-stats.linkup/";
+
+/// Counts the cache stats of one session. A Durable Object handles one request at a time, so
+/// concurrent requests add to the counters instead of overwriting each other.
+#[durable_object]
+pub struct CacheStatsCounter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for CacheStatsCounter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    /// `POST` adds the `CacheStats` in the body to the counters, any other method only reads
+    /// them. Both answer with the counters.
+    async fn fetch(&mut self, mut req: Request) -> worker::Result<Response> {
+        let mut storage = self.state.storage();
+        let mut stats: CacheStats = storage.get(STATS_KEY).await.unwrap_or_default();
+
+        if req.method() == Method::Post {
+            let counted: CacheStats = req.json().await?;
+            stats.add(&counted);
+            storage.put(STATS_KEY, stats).await?;
+        }
+
+        Response::from_json(&stats)
+    }
+}
+
+/// Collects the cache stats of the requests served by a worker invocation, so they can be added
+/// to the counters of their sessions after the response is sent.
+#[derive(Clone, Default)]
+pub struct CacheStatsRecorder {
+    counted: Arc<Mutex<HashMap<String, CacheStats>>>,
+}
+
+impl CacheStatsRecorder {
+    pub fn record_status(&self, session_name: &str, status: CacheStatus) {
+        self.update(session_name, |stats| stats.record_status(status));
+    }
+
+    pub fn record_stored(&self, session_name: &str, bytes: u64) {
+        self.update(session_name, |stats| stats.record_stored(bytes));
+    }
+
+    fn update(&self, session_name: &str, update: impl FnOnce(&mut CacheStats)) {
+        let mut counted = self.counted.lock().expect("cache stats lock is poisoned");
+        update(counted.entry(session_name.to_string()).or_default());
+    }
+
+    /// Adds everything recorded so far to the counters. Cache stats are best effort, failing to
+    /// add them is only logged.
+    pub async fn flush(self, namespace: ObjectNamespace) {
+        let counted =
+            std::mem::take(&mut *self.counted.lock().expect("cache stats lock is poisoned"));
+
+        for (session_name, stats) in counted {
+            if stats == CacheStats::default() {
+                continue;
+            }
+
+            if let Err(e) = add_cache_stats(&namespace, &session_name, &stats).await {
+                console_log!("Failed to record cache stats: {}", e);
+            }
+        }
+    }
+}
+
+/// The cache stats counted for a session so far.
+pub async fn get_cache_stats(
+    namespace: &ObjectNamespace,
+    session_name: &str,
+) -> worker::Result<CacheStats> {
+    counter(namespace, session_name)?
+        .fetch_with_str(COUNTER_URL)
+        .await?
+        .json()
+        .await
+}
+
+async fn add_cache_stats(
+    namespace: &ObjectNamespace,
+    session_name: &str,
+    stats: &CacheStats,
+) -> worker::Result<()> {
+    let body = serde_json::to_string(stats)?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(body.into()));
+
+    let req = Request::new_with_init(COUNTER_URL, &init)?;
+    counter(namespace, session_name)?
+        .fetch_with_request(req)
+        .await?;
+
+    Ok(())
+}
+
+fn counter(namespace: &ObjectNamespace, session_name: &str) -> worker::Result<Stub> {
+    namespace.id_from_name(session_name)?.get_stub()
+}
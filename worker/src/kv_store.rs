@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use linkup::{SessionError, StringStore};
 use worker::kv::KvStore;
 
@@ -44,4 +46,29 @@ impl StringStore for CfWorkerStringStore {
             .await
             .map_err(|e| SessionError::PutError(e.to_string()))
     }
+
+    async fn put_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> Result<(), SessionError> {
+        let put = match self.kv.put(&key, value) {
+            Ok(p) => p,
+            Err(e) => return Err(SessionError::PutError(e.to_string())),
+        };
+
+        // KV doesn't take expirations of less than a minute
+        put.expiration_ttl(ttl.as_secs().max(60))
+            .execute()
+            .await
+            .map_err(|e| SessionError::PutError(e.to_string()))
+    }
+
+    async fn delete(&self, key: String) -> Result<(), SessionError> {
+        self.kv
+            .delete(&key)
+            .await
+            .map_err(|e| SessionError::DeleteError(e.to_string()))
+    }
 }
@@ -1,41 +1,88 @@
+use std::time::Duration;
+
 use axum::{
-    extract::{Json, Request, State},
-    http::StatusCode,
+    extract::{FromRef, Json, Query, Request, State},
+    http::{header::CACHE_CONTROL, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     routing::{any, get, post},
     Router,
 };
 
-use http::{HeaderMap, Uri};
+use cache_stats::{get_cache_stats, CacheStatsRecorder, CACHE_STATS_BINDING};
+use futures::StreamExt;
+use http_body_util::LengthLimitError;
 use http_error::HttpError;
 use kv_store::CfWorkerStringStore;
 use linkup::{
-    allow_all_cors, get_additional_headers, get_target_service, CreatePreviewRequest, NameKind,
-    Session, SessionAllocator, UpdateSessionRequest,
+    body_rewrites, compress_request_body, get_request_domain, is_retryable_status,
+    is_rewritable_body, mock_response, not_modified_response, prepare_request,
+    prepare_response_headers, request_compression, request_retries, rewrite_body,
+    rewrite_redirect_location, send_with_retries, BodyRewrite, CachePolicy, CacheRoute,
+    CacheStatsRequest, CacheStatus, CreatePreviewRequest, Domain, NameKind, ProxyContext,
+    ProxyError, RequestCompression, Session, SessionAllocator, SessionError, UpdateSessionRequest,
+    VersionInfo, WarmCacheRequest, WarmedUrl, CACHE_STATUS_HEADER,
 };
 use tower_service::Service;
-use worker::{event, kv::KvStore, Env, Fetch, HttpRequest, HttpResponse};
+use worker::{event, kv::KvStore, Env, Fetch, HttpRequest, HttpResponse, ObjectNamespace};
 use ws::handle_ws_resp;
 
+mod cache_stats;
 mod http_error;
 mod kv_store;
 mod ws;
 
-pub fn linkup_router(kv: KvStore) -> Router {
+// Largest body that is buffered to compress a request or rewrite a response, the same as the
+// default limit of the local server.
+const MAX_BODY_SIZE: usize = 1024 * 1024 * 100;
+
+// Previews are removed once they haven't been created again for this long. Creating the same
+// preview again keeps its name, so a preview that is still deployed renews its expiry.
+const PREVIEW_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// The bindings the handlers use, and the cache stats of the requests they served.
+#[derive(Clone)]
+pub struct LinkupState {
+    kv: KvStore,
+    cache_stats: ObjectNamespace,
+    recorder: CacheStatsRecorder,
+}
+
+impl FromRef<LinkupState> for KvStore {
+    fn from_ref(state: &LinkupState) -> Self {
+        state.kv.clone()
+    }
+}
+
+impl FromRef<LinkupState> for ObjectNamespace {
+    fn from_ref(state: &LinkupState) -> Self {
+        state.cache_stats.clone()
+    }
+}
+
+impl FromRef<LinkupState> for CacheStatsRecorder {
+    fn from_ref(state: &LinkupState) -> Self {
+        state.recorder.clone()
+    }
+}
+
+pub fn linkup_router(state: LinkupState) -> Router {
     Router::new()
         .route("/linkup", post(linkup_session_handler))
         .route("/preview", post(linkup_preview_handler))
+        .route("/linkup/version", get(version_handler))
+        .route("/linkup/cache/warm", post(linkup_cache_warm_handler))
+        .route("/linkup/cache/stats", get(linkup_cache_stats_handler))
         .route("/linkup-check", get(always_ok))
         .route("/linkup-no-tunnel", get(no_tunnel))
         .fallback(any(linkup_request_handler))
-        .with_state(kv)
+        .with_state(state)
 }
 
 #[event(fetch)]
 async fn fetch(
     req: HttpRequest,
     env: Env,
-    _ctx: worker::Context,
+    ctx: worker::Context,
 ) -> Result<axum::http::Response<axum::body::Body>, worker::Error> {
     console_error_panic_hook::set_once();
 
@@ -49,13 +96,39 @@ async fn fetch(
         }
     };
 
-    Ok(linkup_router(kv).call(req).await?)
+    let cache_stats = match env.durable_object(CACHE_STATS_BINDING) {
+        Ok(cache_stats) => cache_stats,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Failed to get Durable Object namespace: {}", e).into())
+                .unwrap())
+        }
+    };
+
+    let recorder = CacheStatsRecorder::default();
+    let state = LinkupState {
+        kv,
+        cache_stats: cache_stats.clone(),
+        recorder: recorder.clone(),
+    };
+    let resp = linkup_router(state).call(req).await?;
+
+    // The counters are only updated once the response is on its way, to keep them off the
+    // request path
+    ctx.wait_until(recorder.flush(cache_stats));
+
+    Ok(resp)
 }
 
 #[worker::send]
-async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) -> impl IntoResponse {
+async fn linkup_request_handler(
+    State(kv): State<KvStore>,
+    State(recorder): State<CacheStatsRecorder>,
+    req: Request,
+) -> impl IntoResponse {
     let store = CfWorkerStringStore::new(kv);
-    let sessions = SessionAllocator::new(&store);
+    let sessions = SessionAllocator::new(&store).with_timestamp(unix_timestamp());
 
     let headers: linkup::HeaderMap = req.headers().into();
     let url = req.uri().to_string();
@@ -70,18 +143,142 @@ async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) ->
         }
     };
 
-    let target_service = match get_target_service(&url, &headers, &config, &session_name) {
-        Some(result) => result,
-        None => {
+    proxy_session_request(req, &session_name, &config, &recorder).await
+}
+
+#[worker::send]
+async fn linkup_cache_warm_handler(
+    State(kv): State<KvStore>,
+    State(recorder): State<CacheStatsRecorder>,
+    Json(warm_req): Json<WarmCacheRequest>,
+) -> impl IntoResponse {
+    let store = CfWorkerStringStore::new(kv);
+    let sessions = SessionAllocator::new(&store).with_timestamp(unix_timestamp());
+
+    let config = match sessions
+        .get_authorized_session(&warm_req.session_name, &warm_req.session_token)
+        .await
+    {
+        Ok(config) => config,
+        Err(SessionError::NoSuchSession(_)) => {
+            return HttpError::new(
+                "Invalid session name or token".to_string(),
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response()
+        }
+        Err(e) => {
+            return HttpError::new(
+                format!("Failed to get session: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        }
+    };
+
+    let mut warmed = Vec::with_capacity(warm_req.urls.len());
+    for url in warm_req.urls {
+        let resp = match Request::get(&url).body(axum::body::Body::empty()) {
+            Ok(req) => proxy_session_request(req, &warm_req.session_name, &config, &recorder).await,
+            Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        };
+
+        warmed.push(WarmedUrl {
+            url,
+            status: resp.status().as_u16(),
+            cache: resp
+                .headers()
+                .get(CACHE_STATUS_HEADER)
+                .and_then(|status| status.to_str().ok())
+                .map(String::from),
+        });
+    }
+
+    Json(warmed).into_response()
+}
+
+#[worker::send]
+async fn linkup_cache_stats_handler(
+    State(kv): State<KvStore>,
+    State(cache_stats): State<ObjectNamespace>,
+    Query(stats_req): Query<CacheStatsRequest>,
+) -> impl IntoResponse {
+    let store = CfWorkerStringStore::new(kv);
+    let sessions = SessionAllocator::new(&store).with_timestamp(unix_timestamp());
+
+    match sessions
+        .get_authorized_session(&stats_req.session_name, &stats_req.session_token)
+        .await
+    {
+        Ok(_) => (),
+        Err(SessionError::NoSuchSession(_)) => {
+            return HttpError::new(
+                "Invalid session name or token".to_string(),
+                StatusCode::UNAUTHORIZED,
+            )
+            .into_response()
+        }
+        Err(e) => {
+            return HttpError::new(
+                format!("Failed to get session: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        }
+    };
+
+    match get_cache_stats(&cache_stats, &stats_req.session_name).await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => HttpError::new(
+            format!("Failed to get cache stats: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+async fn proxy_session_request(
+    mut req: Request,
+    session_name: &str,
+    config: &Session,
+    recorder: &CacheStatsRecorder,
+) -> Response {
+    let headers: linkup::HeaderMap = req.headers().into();
+    let url = req.uri().to_string();
+
+    let response_domain = get_request_domain(&url, &headers, config, session_name);
+    let context = ProxyContext {
+        timestamp: unix_timestamp(),
+        client_addr: headers
+            .get("cf-connecting-ip")
+            .and_then(|ip| ip.parse().ok()),
+        version: env!("CARGO_PKG_VERSION"),
+    };
+    let target_service = match prepare_request(&mut req, &url, config, session_name, context) {
+        Ok(target_service) => target_service,
+        Err(ProxyError::NoTarget) => {
             return HttpError::new(
                 "The request belonged to a session, but there was no target for the request. Check that the routing rules in your linkup config have a match for this request. - Local Server".to_string(),
                 StatusCode::NOT_FOUND,
             )
             .into_response()
         }
+        Err(e) => {
+            return HttpError::new(
+                format!("Failed to build request: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        }
     };
 
-    let extra_headers = get_additional_headers(&url, &headers, &session_name, &target_service);
+    let body_rewrites = body_rewrites(config, &target_service);
+    if let Some(mock) = mock_response(config, &target_service) {
+        let mut resp = mock.into_response();
+        prepare_response_headers(resp.headers_mut(), config, response_domain);
+
+        return resp;
+    }
 
     let is_websocket = req
         .headers()
@@ -89,11 +286,18 @@ async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) ->
         .map(|v| v == "websocket")
         .unwrap_or(false);
 
-    *req.uri_mut() = Uri::try_from(target_service.url).unwrap();
-    let extra_http_headers: HeaderMap = extra_headers.into();
-    req.headers_mut().extend(extra_http_headers);
-    // Request uri and host headers should not conflict
-    req.headers_mut().remove(http::header::HOST);
+    let retries = match is_websocket {
+        true => 0,
+        false => request_retries(config, &target_service, req.method()),
+    };
+
+    let req = match request_compression(config, &target_service) {
+        Some(compression) if !is_websocket => match compress_req(req, compression).await {
+            Ok(req) => req,
+            Err(e) => return e.into_response(),
+        },
+        _ => req,
+    };
 
     let worker_req: worker::Request = match req.try_into() {
         Ok(req) => req,
@@ -106,11 +310,25 @@ async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) ->
         }
     };
 
-    let cacheable_req = is_cacheable_request(&worker_req, &config);
-    let cache_key = get_cache_key(&worker_req, &session_name).unwrap();
-    if cacheable_req {
+    let cache_route = get_cache_route(&worker_req, config);
+    let cacheable_req = cache_route.is_some();
+    let cache_policy = CachePolicy::for_request(&headers);
+    let cache_key = get_cache_key(&worker_req, session_name).unwrap();
+    if cacheable_req && cache_policy.read {
         if let Some(worker_resp) = get_cached_req(cache_key.clone()).await {
-            let resp: HttpResponse = match worker_resp.try_into() {
+            recorder.record_status(session_name, CacheStatus::Hit);
+
+            let worker_resp = match rewrite_resp_body(worker_resp, body_rewrites).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    return HttpError::new(
+                        format!("Failed to rewrite response body: {}", e),
+                        StatusCode::BAD_GATEWAY,
+                    )
+                    .into_response()
+                }
+            };
+            let mut resp: HttpResponse = match worker_resp.try_into() {
                 Ok(resp) => resp,
                 Err(e) => {
                     return HttpError::new(
@@ -120,26 +338,51 @@ async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) ->
                     .into_response()
                 }
             };
+            if let Some(not_modified) = not_modified_response(&headers, resp.headers()) {
+                let (parts, ()) = not_modified.into_parts();
+                let mut not_modified = Response::from_parts(parts, axum::body::Body::empty());
+                prepare_response_headers(not_modified.headers_mut(), config, response_domain);
+                not_modified.headers_mut().insert(
+                    CACHE_STATUS_HEADER,
+                    HeaderValue::from_static(CacheStatus::Hit.as_str()),
+                );
+
+                return not_modified;
+            }
+
+            prepare_response_headers(resp.headers_mut(), config, response_domain);
+            resp.headers_mut().insert(
+                CACHE_STATUS_HEADER,
+                HeaderValue::from_static(CacheStatus::Hit.as_str()),
+            );
 
             return resp.into_response();
         }
     }
 
-    let mut worker_resp = match Fetch::Request(worker_req).send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            return HttpError::new(
-                format!("Failed to fetch from target service: {}", e),
-                StatusCode::BAD_GATEWAY,
-            )
-            .into_response()
-        }
+    let send = || {
+        let req = worker_req.clone();
+        async move { Fetch::Request(req?).send().await }
     };
+    let is_retryable = |resp: &worker::Response| {
+        StatusCode::from_u16(resp.status_code()).is_ok_and(is_retryable_status)
+    };
+    let mut worker_resp =
+        match send_with_retries(retries, send, is_retryable, worker::Delay::from).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                return HttpError::new(
+                    format!("Failed to fetch from target service: {}", e),
+                    StatusCode::BAD_GATEWAY,
+                )
+                .into_response()
+            }
+        };
 
     if is_websocket {
         handle_ws_resp(worker_resp).await.into_response()
     } else {
-        if cacheable_req {
+        if let Some(cache_route) = cache_route.filter(|_| cache_policy.write) {
             let cache_clone = match worker_resp.cloned() {
                 Ok(resp) => resp,
                 Err(e) => {
@@ -151,19 +394,154 @@ async fn linkup_request_handler(State(kv): State<KvStore>, mut req: Request) ->
                 }
             };
 
-            if let Err(e) = set_cached_req(cache_key, cache_clone).await {
+            let content_length = cache_clone
+                .headers()
+                .get("content-length")
+                .ok()
+                .flatten()
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+
+            match set_cached_req(cache_key, cache_clone, cache_route).await {
+                Ok(true) => recorder.record_stored(session_name, content_length),
+                Ok(false) => (),
+                Err(e) => {
+                    return HttpError::new(
+                        format!("Failed to cache response: {}", e),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response();
+                }
+            }
+        }
+        let worker_resp = match rewrite_resp_body(worker_resp, body_rewrites).await {
+            Ok(resp) => resp,
+            Err(e) => {
                 return HttpError::new(
-                    format!("Failed to cache response: {}", e),
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to rewrite response body: {}", e),
+                    StatusCode::BAD_GATEWAY,
                 )
-                .into_response();
+                .into_response()
             }
+        };
+        let mut resp = handle_http_resp(worker_resp, config, response_domain)
+            .await
+            .into_response();
+        let status = resp.status();
+        rewrite_redirect_location(
+            resp.headers_mut(),
+            status,
+            &url,
+            &headers,
+            config,
+            session_name,
+            &target_service,
+        );
+        // Anything cacheable that gets here was not served from the cache
+        if cacheable_req {
+            let cache_status = cache_policy.status(false);
+            recorder.record_status(session_name, cache_status);
+            resp.headers_mut().insert(
+                CACHE_STATUS_HEADER,
+                HeaderValue::from_static(cache_status.as_str()),
+            );
+        }
+
+        resp
+    }
+}
+
+async fn compress_req(req: Request, compression: RequestCompression) -> Result<Request, HttpError> {
+    let (mut parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, MAX_BODY_SIZE)
+        .await
+        .map_err(|e| {
+            let status = match is_body_limit_error(&e) {
+                true => StatusCode::PAYLOAD_TOO_LARGE,
+                false => StatusCode::BAD_REQUEST,
+            };
+
+            HttpError::new(format!("Failed to read request body: {}", e), status)
+        })?;
+
+    let compressed =
+        compress_request_body(&mut parts.headers, &body, compression).map_err(|e| {
+            HttpError::new(
+                format!("Failed to compress request body: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
+    Ok(Request::from_parts(
+        parts,
+        axum::body::Body::from(compressed.unwrap_or_else(|| body.to_vec())),
+    ))
+}
+
+fn is_body_limit_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
         }
-        handle_http_resp(worker_resp).await.into_response()
+        source = error.source();
     }
+
+    false
 }
 
-async fn handle_http_resp(worker_resp: worker::Response) -> impl IntoResponse {
+async fn rewrite_resp_body(
+    mut worker_resp: worker::Response,
+    body_rewrites: &[BodyRewrite],
+) -> worker::Result<worker::Response> {
+    let content_type = worker_resp.headers().get("content-type")?;
+    let content_encoding = worker_resp.headers().get("content-encoding")?;
+    let content_length = worker_resp
+        .headers()
+        .get("content-length")?
+        .and_then(|length| length.parse::<usize>().ok());
+    if body_rewrites.is_empty()
+        || !is_rewritable_body(content_type.as_deref(), content_encoding.as_deref())
+        || content_length.is_some_and(|length| length > MAX_BODY_SIZE)
+    {
+        return Ok(worker_resp);
+    }
+
+    let status = worker_resp.status_code();
+    let mut headers = worker_resp.headers().clone();
+
+    // Bodies without a length are counted while they are read
+    let mut body = Vec::new();
+    let mut stream = worker_resp.stream()?;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(worker::Error::RustError(format!(
+                "response body is larger than the limit of {} bytes",
+                MAX_BODY_SIZE
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    match rewrite_body(&body, body_rewrites) {
+        Some(rewritten) => {
+            headers.delete("content-length")?;
+            Ok(worker::Response::from_bytes(rewritten.into_bytes())?
+                .with_status(status)
+                .with_headers(headers))
+        }
+        None => Ok(worker::Response::from_bytes(body)?
+            .with_status(status)
+            .with_headers(headers)),
+    }
+}
+
+async fn handle_http_resp(
+    worker_resp: worker::Response,
+    config: &Session,
+    response_domain: Option<&Domain>,
+) -> impl IntoResponse {
     let mut resp: HttpResponse = match worker_resp.try_into() {
         Ok(resp) => resp,
         Err(e) => {
@@ -175,7 +553,7 @@ async fn handle_http_resp(worker_resp: worker::Response) -> impl IntoResponse {
         }
     };
 
-    resp.headers_mut().extend(allow_all_cors());
+    prepare_response_headers(resp.headers_mut(), config, response_domain);
 
     resp.into_response()
 }
@@ -186,7 +564,7 @@ async fn linkup_session_handler(
     Json(update_req): Json<UpdateSessionRequest>,
 ) -> impl IntoResponse {
     let store = CfWorkerStringStore::new(kv);
-    let sessions = SessionAllocator::new(&store);
+    let sessions = SessionAllocator::new(&store).with_timestamp(unix_timestamp());
 
     let desired_name = update_req.desired_name.clone();
     let server_conf: Session = match update_req.try_into() {
@@ -224,7 +602,9 @@ async fn linkup_preview_handler(
     Json(update_req): Json<CreatePreviewRequest>,
 ) -> impl IntoResponse {
     let store = CfWorkerStringStore::new(kv);
-    let sessions = SessionAllocator::new(&store);
+    let sessions = SessionAllocator::new(&store)
+        .with_timestamp(unix_timestamp())
+        .with_session_ttl(PREVIEW_TTL);
 
     let server_conf: Session = match update_req.try_into() {
         Ok(conf) => conf,
@@ -255,6 +635,12 @@ async fn linkup_preview_handler(
     (StatusCode::OK, name).into_response()
 }
 
+async fn version_handler() -> impl IntoResponse {
+    let version_info = VersionInfo::new(env!("CARGO_PKG_VERSION"), option_env!("LINKUP_GIT_SHA"));
+
+    ([(CACHE_CONTROL, "public, max-age=300")], Json(version_info)).into_response()
+}
+
 async fn always_ok() -> &'static str {
     "OK"
 }
@@ -267,22 +653,24 @@ async fn no_tunnel() -> impl IntoResponse {
         .into_response()
 }
 
-fn is_cacheable_request(req: &worker::Request, config: &Session) -> bool {
-    if req.method() != worker::Method::Get {
-        return false;
-    }
+fn unix_timestamp() -> u64 {
+    worker::Date::now().as_millis() / 1000
+}
 
-    if let Some(routes) = &config.cache_routes {
-        let path = req.path();
-        if routes.iter().any(|route| route.is_match(&path)) {
-            return true;
-        }
+fn get_cache_route<'a>(req: &worker::Request, config: &'a Session) -> Option<&'a CacheRoute> {
+    if req.method() != worker::Method::Get {
+        return None;
     }
 
-    false
+    let path = req.path();
+    config
+        .cache_routes
+        .as_ref()?
+        .iter()
+        .find(|route| route.matches(&path))
 }
 
-fn get_cache_key(req: &worker::Request, session_name: &String) -> Option<String> {
+fn get_cache_key(req: &worker::Request, session_name: &str) -> Option<String> {
     let mut cache_url = match req.url() {
         Ok(url) => url,
         Err(_) => return None,
@@ -306,13 +694,23 @@ async fn get_cached_req(cache_key: String) -> Option<worker::Response> {
     }
 }
 
-async fn set_cached_req(cache_key: String, resp: worker::Response) -> worker::Result<()> {
+// Whether the response was stored
+async fn set_cached_req(
+    cache_key: String,
+    resp: worker::Response,
+    cache_route: &CacheRoute,
+) -> worker::Result<bool> {
     // Cache API throws error on 206 partial content
-    if resp.status_code() > 499 || resp.status_code() == 206 {
-        return Ok(());
+    if resp.status_code() == 206 {
+        return Ok(false);
+    }
+
+    match StatusCode::from_u16(resp.status_code()) {
+        Ok(status) if cache_route.caches_status(status) => (),
+        _ => return Ok(false),
     }
 
     worker::Cache::default().put(cache_key, resp).await?;
 
-    Ok(())
+    Ok(true)
 }